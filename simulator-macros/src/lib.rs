@@ -6,9 +6,21 @@ use quote::ToTokens;
 #[derive(Debug)]
 enum OperandEncoding {
 	SuffixReg,
+	/// The low bits of the opcode selecting a byte register, which are ah to bh instead of
+	/// spl to dil without REX.
+	SuffixByteReg,
 	ModReg,
 	ModRM,
+	/// The reg field of ModRM selecting an xmm register.
+	XmmReg,
+	/// The rm field of ModRM, where a register operand is an xmm register.
+	XmmRM,
 	Immediate(u8),
+	Accumulator,
+	/// The condition code in the low four bits of the opcode.
+	Condition,
+	/// The extra register in the vvvv field of a VEX prefix.
+	VexReg,
 	Implicit,
 }
 
@@ -16,30 +28,43 @@ impl OperandEncoding {
 	fn generate(&self) -> impl ToTokens {
 		match self {
 			OperandEncoding::SuffixReg => quote::quote! {Reg::parse_suffix(byte, rex)},
-			OperandEncoding::ModReg => quote::quote! {Reg(reg)},
-			OperandEncoding::ModRM => quote::quote! {rm},
+			OperandEncoding::SuffixByteReg => quote::quote! {Reg::parse_suffix_byte(byte, rex)},
+			OperandEncoding::ModReg | OperandEncoding::XmmReg => quote::quote! {Reg(reg)},
+			OperandEncoding::ModRM | OperandEncoding::XmmRM => quote::quote! {rm},
 			OperandEncoding::Immediate(_) => quote::quote! {Immediate::parse(immediate)},
+			OperandEncoding::Accumulator => quote::quote! {Reg(0)},
+			OperandEncoding::Condition => quote::quote! {Condition::parse(byte)},
+			OperandEncoding::VexReg => quote::quote! {Reg(vvvv)},
 			_ => unreachable!(),
 		}
 	}
 
-	fn operand0(&self) -> Option<impl ToTokens> {
+	/// Decodes the operand into the given field of the instruction.
+	fn field(&self, field: &str) -> Option<impl ToTokens> {
 		match self {
 			OperandEncoding::Implicit => None,
 			_ => {
+				let field = syn::Ident::new(field, proc_macro::Span::call_site().into());
 				let code = self.generate();
-				Some(quote::quote! {operand0: #code,})
+				Some(quote::quote! {#field: #code,})
 			}
 		}
 	}
 
-	fn operand1(&self) -> Option<impl ToTokens> {
+	/// The declaration of the given field of the instruction.
+	fn field_type(&self, field: &str) -> Option<impl ToTokens> {
+		let field = syn::Ident::new(field, proc_macro::Span::call_site().into());
 		match self {
+			OperandEncoding::SuffixReg
+			| OperandEncoding::SuffixByteReg
+			| OperandEncoding::ModReg
+			| OperandEncoding::XmmReg
+			| OperandEncoding::VexReg
+			| OperandEncoding::Accumulator => Some(quote::quote! {#field: Reg,}),
+			OperandEncoding::ModRM | OperandEncoding::XmmRM => Some(quote::quote! {#field: RM,}),
+			OperandEncoding::Condition => Some(quote::quote! {#field: Condition,}),
+			OperandEncoding::Immediate(_) => Some(quote::quote! {#field: Immediate,}),
 			OperandEncoding::Implicit => None,
-			_ => {
-				let code = self.generate();
-				Some(quote::quote! {operand1: #code,})
-			}
 		}
 	}
 }
@@ -49,8 +74,13 @@ struct InstructionEncoding {
 	/// The name of the instruction
 	name: String,
 
-	/// Opcodes. opcode1 and opcode2 are undefined if opcode0 and opcode1 respectively are not
-	/// extension opcodes.
+	/// The opcode is in the 0F 38 map, where opcode1 is the third opcode byte.
+	map_0f38: bool,
+
+	/// Opcodes. If opcode0 is 0x0F then opcode1 is the second opcode byte and opcode2 is the
+	/// reg field extension, otherwise opcode1 is the reg field extension. An extension of 0xFF
+	/// means that the reg field is not used as an opcode extension, and an extension of 0xC0 or
+	/// above is a whole ModRM byte selecting a register form.
 	opcode0: u8,
 	opcode1: u8,
 	opcode2: u8,
@@ -61,40 +91,141 @@ struct InstructionEncoding {
 	/// Modrm mode is not allowed to be reg (0b11) (Example: lea).
 	modrm_only_mem: bool,
 
+	/// The F2 prefix is part of the opcode (Example: movsd).
+	mandatory_f2: bool,
+
+	/// The F3 prefix is part of the opcode (Example: rdfsbase).
+	mandatory_f3: bool,
+
+	/// The F2 and F3 prefixes select other instructions (Example: movups).
+	no_f2_f3: bool,
+
 	/// Operand encodings.
 	operand0: OperandEncoding,
 	operand1: OperandEncoding,
+	operand2: OperandEncoding,
 
 	/// Encoding requires size override prefix
 	size_override: bool,
 
 	/// Requires REX.w
 	wide: bool,
+
+	/// Encoded with a VEX prefix instead of the legacy prefixes.
+	vex: bool,
+
+	/// The semantics are specific to the simulator rather than those of the encoding on
+	/// hardware (Example: wrmsr writing the config registers).
+	simulator_specific: bool,
 }
 impl InstructionEncoding {
+	/// Whether the size override prefix selects an SSE instruction rather than the operand size,
+	/// so it is required in every mode (Example: movd).
+	fn mandatory_66(&self) -> bool {
+		self.size_override
+			&& [&self.operand0, &self.operand1, &self.operand2]
+				.iter()
+				.any(|encoding| {
+					matches!(encoding, OperandEncoding::XmmReg | OperandEncoding::XmmRM)
+				})
+	}
+
+	fn two_byte(&self) -> bool {
+		self.opcode0 == 0x0F
+	}
+
+	/// The opcode byte which selects this instruction in its opcode map.
+	fn opcode(&self) -> u8 {
+		if self.two_byte() {
+			self.opcode1
+		} else {
+			self.opcode0
+		}
+	}
+
+	/// The reg field opcode extension or 0xFF if there is none.
+	fn extension(&self) -> u8 {
+		if self.two_byte() {
+			self.opcode2
+		} else {
+			self.opcode1
+		}
+	}
+
+	/// The ModRM byte which selects this instruction, if it is encoded by the whole byte.
+	fn modrm_byte(&self) -> Option<u8> {
+		let extension = self.extension();
+		(extension != 0xFF && extension >= 0xC0).then_some(extension)
+	}
+
+	fn operands(&self) -> [&OperandEncoding; 3] {
+		[&self.operand0, &self.operand1, &self.operand2]
+	}
+
+	fn condition(&self) -> bool {
+		matches!(self.operand0, OperandEncoding::Condition)
+	}
+
 	fn suffix_reg(&self) -> bool {
-		matches!(self.operand0, OperandEncoding::SuffixReg)
-			|| matches!(self.operand1, OperandEncoding::SuffixReg)
+		self.operands().iter().any(|operand| {
+			matches!(
+				operand,
+				OperandEncoding::SuffixReg | OperandEncoding::SuffixByteReg
+			)
+		})
 	}
 
 	fn needs_modrm(&self) -> bool {
-		(matches!(
-			self.operand0,
-			OperandEncoding::ModRM | OperandEncoding::ModReg
-		) || matches!(
-			self.operand1,
-			OperandEncoding::ModRM | OperandEncoding::ModReg
-		)) && (self.opcode0 == 0x0F || self.opcode1 == 0xFF)
+		self.operands().iter().any(|operand| {
+			matches!(
+				operand,
+				OperandEncoding::ModRM
+					| OperandEncoding::ModReg
+					| OperandEncoding::XmmRM
+					| OperandEncoding::XmmReg
+			)
+		}) && self.extension() == 0xFF
+	}
+
+	/// The mnemonic, which is the name up to the operand kinds in lower case (Example: add for
+	/// AddRM64Imm8).
+	fn mnemonic(&self) -> String {
+		let end = self.name[1..]
+			.find(|c: char| c.is_ascii_uppercase() || c.is_ascii_digit())
+			.map_or(self.name.len(), |i| i + 1);
+		self.name[..end].to_lowercase()
+	}
+
+	/// The width of the register and memory operands, which is the first number in the
+	/// operand kinds of the name, or 64 when there is none (Example: 8 for AddRM8Imm8).
+	fn operand_width(&self) -> u32 {
+		let kinds = &self.name[self.mnemonic().len()..];
+		let digits: String = kinds
+			.chars()
+			.skip_while(|c| !c.is_ascii_digit())
+			.take_while(|c| c.is_ascii_digit())
+			.collect();
+		digits.parse().unwrap_or(64)
+	}
+
+	/// A 32 bit immediate which is sign extended to the 64 bit operand size (Example: add
+	/// rax, imm32).
+	fn sign_extended_immediate(&self) -> bool {
+		self.wide
+			&& self
+				.operands()
+				.iter()
+				.any(|operand| matches!(operand, OperandEncoding::Immediate(32)))
 	}
 
 	fn immediate_size(&self) -> u8 {
-		if let OperandEncoding::Immediate(size) = self.operand0 {
-			return size / 8;
-		}
-		if let OperandEncoding::Immediate(size) = self.operand1 {
-			return size / 8;
-		}
-		0
+		self.operands()
+			.iter()
+			.find_map(|operand| match operand {
+				OperandEncoding::Immediate(size) => Some(size / 8),
+				_ => None,
+			})
+			.unwrap_or(0)
 	}
 }
 
@@ -102,7 +233,13 @@ fn parse_operand(src: Option<&str>) -> OperandEncoding {
 	match src {
 		Some("R") => OperandEncoding::ModReg,
 		Some("RM") => OperandEncoding::ModRM,
+		Some("XR") => OperandEncoding::XmmReg,
+		Some("XRM") => OperandEncoding::XmmRM,
 		Some("SR") => OperandEncoding::SuffixReg,
+		Some("SR8") => OperandEncoding::SuffixByteReg,
+		Some("CC") => OperandEncoding::Condition,
+		Some("V") => OperandEncoding::VexReg,
+		Some("A") => OperandEncoding::Accumulator,
 		Some("Imm8") => OperandEncoding::Immediate(8),
 		Some("Imm16") => OperandEncoding::Immediate(16),
 		Some("Imm32") => OperandEncoding::Immediate(32),
@@ -116,7 +253,13 @@ fn parse_instruction(src: &str) -> InstructionEncoding {
 	let (base, modifiers) = src.split_once(":").unwrap();
 	let mut tokens = base.split_whitespace();
 	let name = tokens.next().unwrap().to_string();
-	let opcode = tokens.next().unwrap();
+	// Opcodes like 6E, which Rust would lex as a malformed float, are written as 0x6E.
+	let opcode = tokens.next().unwrap().trim_start_matches("0x");
+	// The 0F 38 map is parsed like the 0F map with the 38 escape removed.
+	let (map_0f38, opcode) = match opcode.strip_prefix("0F38") {
+		Some(opcode) => (true, format!("0F{opcode}")),
+		None => (false, opcode.to_string()),
+	};
 	let opcode0 = opcode
 		.get(0..2)
 		.map(|x| u8::from_str_radix(x, 16).unwrap())
@@ -128,25 +271,40 @@ fn parse_instruction(src: &str) -> InstructionEncoding {
 	let opcode2 = opcode
 		.get(4..6)
 		.map(|x| u8::from_str_radix(x, 16).unwrap())
-		.unwrap_or(0);
+		.unwrap_or(0xFF);
 	let operand0 = parse_operand(tokens.next());
 	let operand1 = parse_operand(tokens.next());
+	let operand2 = parse_operand(tokens.next());
 	let mut instruction = InstructionEncoding {
 		name,
+		map_0f38,
 		opcode0,
 		opcode1,
 		opcode2,
 		modrm_only_reg: false,
 		modrm_only_mem: false,
+		mandatory_f2: false,
+		mandatory_f3: false,
+		no_f2_f3: false,
 		operand0,
 		operand1,
+		operand2,
 		size_override: false,
 		wide: false,
+		vex: false,
+		simulator_specific: false,
 	};
 	for modifier in modifiers.split_whitespace() {
 		match modifier {
 			"so" => instruction.size_override = true,
 			"w" => instruction.wide = true,
+			"mem" => instruction.modrm_only_mem = true,
+			"reg" => instruction.modrm_only_reg = true,
+			"f2" => instruction.mandatory_f2 = true,
+			"f3" => instruction.mandatory_f3 = true,
+			"np" => instruction.no_f2_f3 = true,
+			"vex" => instruction.vex = true,
+			"sim" => instruction.simulator_specific = true,
 			_ => (),
 		}
 	}
@@ -155,29 +313,53 @@ fn parse_instruction(src: &str) -> InstructionEncoding {
 
 fn generate_instruction_decode(instruction: &InstructionEncoding) -> impl ToTokens {
 	let name = syn::Ident::new(&instruction.name, proc_macro::Span::call_site().into());
-	let immediate = instruction.immediate_size();
-	let operand0 = instruction.operand0.operand0();
-	let operand1 = instruction.operand1.operand1();
+	let immediate = instruction.immediate_size() as usize;
+	let operand0 = instruction.operand0.field("operand0");
+	let operand1 = instruction.operand1.field("operand1");
+	let operand2 = instruction.operand2.field("operand2");
 	let modrm = instruction.needs_modrm().then(|| quote::quote! {
 		let (reg, rm) = read_modrm(mmu, &mut size, instruction_pointer, address_override, segment_override, rex)?;
+		let rm = legacy_operand(rm, mode, short_addressing, segment_override)?;
+	});
+	let only_mem = instruction.modrm_only_mem.then(|| {
+		quote::quote! {
+			if matches!(rm, RM::Reg(_)) {
+				return Err(Interrupt::UNDEFINED);
+			}
+		}
+	});
+	let only_reg = instruction.modrm_only_reg.then(|| {
+		quote::quote! {
+			if !matches!(rm, RM::Reg(_)) {
+				return Err(Interrupt::UNDEFINED);
+			}
+		}
+	});
+	let no_f2_f3 = instruction.no_f2_f3.then(|| {
+		quote::quote! {
+			if matches!(lock_rep, Some(LockRep::Repe | LockRep::Repne)) {
+				return Err(Interrupt::UNDEFINED);
+			}
+		}
 	});
 	quote::quote! {
 		#modrm
-		let immediate = read_immediate(mmu, &mut size, instruction_pointer, #immediate)?;
-		return Ok((Instruction:: #name {#operand0 #operand1}, size));
+		#only_mem
+		#only_reg
+		#no_f2_f3
+		let immediate = read_immediate::<#immediate, _>(mmu, &mut size, instruction_pointer)?;
+		return Ok((Instruction:: #name {#operand0 #operand1 #operand2}, size));
 	}
 }
 
 fn generate_opcode_arm(instructions: Vec<&InstructionEncoding>, reg_opcode: bool) -> impl ToTokens {
-	assert!(instructions[0].opcode0 != 0x0F);
-
-	if instructions[0].opcode1 != 0xFF && !reg_opcode {
+	if instructions[0].extension() != 0xFF && !reg_opcode {
 		// This means that reg field is used as an opcode extension
 		let mut groups = BTreeMap::<u8, Vec<&InstructionEncoding>>::new();
 
-		for instruction in &instructions {
+		for instruction in instructions.iter().filter(|x| x.modrm_byte().is_none()) {
 			groups
-				.entry(instruction.opcode1)
+				.entry(instruction.extension())
 				.or_default()
 				.push(instruction);
 		}
@@ -187,20 +369,84 @@ fn generate_opcode_arm(instructions: Vec<&InstructionEncoding>, reg_opcode: bool
 			quote::quote! {#code => #handler, }
 		});
 
+		// Register forms selected by the whole ModRM byte take precedence over the group.
+		let modrm_arms = instructions.iter().filter_map(|instruction| {
+			let code = instruction.modrm_byte()?;
+			let handler = generate_instruction_decode(instruction);
+			Some(quote::quote! {#code => { size += 1; #handler } })
+		});
+
 		return quote::quote! {{
-			let (reg, rm) = read_modrm(mmu, &mut size, instruction_pointer, address_override, segment_override, rex)?;
-			match reg {
-				#(#arms)*
-				_ => Err(Interrupt::Undefined),
+			match mmu.fetch_u8(instruction_pointer + size)? {
+				#(#modrm_arms)*
+				_ => {
+					let (reg, rm) = read_modrm(mmu, &mut size, instruction_pointer, address_override, segment_override, rex)?;
+					let rm = legacy_operand(rm, mode, short_addressing, segment_override)?;
+					match reg {
+						#(#arms)*
+						_ => Err(Interrupt::UNDEFINED),
+					}
+				}
 			}
 		}};
 	}
 
+	// The mandatory prefixes select other instructions, so they are matched before the size
+	// prefixes (Example: movups and movsd).
+	let mandatory_prefix = |prefix: &str, selected: fn(&InstructionEncoding) -> bool| {
+		let instructions: Vec<_> = instructions
+			.iter()
+			.copied()
+			.filter(|instruction| selected(instruction))
+			.collect();
+		(!instructions.is_empty()).then(|| {
+			let prefix = syn::Ident::new(prefix, proc_macro::Span::call_site().into());
+			let handler = generate_size_arm(&instructions);
+			quote::quote! {
+				if matches!(lock_rep, Some(LockRep:: #prefix)) #handler else
+			}
+		})
+	};
+	let f2 = mandatory_prefix("Repne", |instruction| instruction.mandatory_f2);
+	let f3 = mandatory_prefix("Repe", |instruction| instruction.mandatory_f3);
+	let instructions: Vec<_> = instructions
+		.into_iter()
+		.filter(|instruction| !instruction.mandatory_f2 && !instruction.mandatory_f3)
+		.collect();
+	let default = if instructions.is_empty() {
+		quote::quote! {{ Err(Interrupt::UNDEFINED) }}
+	} else {
+		generate_size_arm(&instructions).into_token_stream()
+	};
+
+	quote::quote! {
+		#f2
+		#f3
+		#default
+	}
+}
+
+/// Selects between the instructions of an opcode by the size override prefix and REX.W.
+fn generate_size_arm(instructions: &[&InstructionEncoding]) -> impl ToTokens {
 	let names = instructions.iter().map(|x| &x.name);
 
+	// Some instructions need both REX.W and the size override prefix (Example: movq), which
+	// are matched before the instructions needing only one of them (Example: shlx and bextr).
+	let wide_so_instruction = instructions
+		.iter()
+		.find(|instruction| instruction.wide && instruction.size_override)
+		.map(|instruction| {
+			let instruction = generate_instruction_decode(instruction);
+			quote::quote! {
+				if rex_w(rex) && size_override {
+					#instruction
+				}
+			}
+		});
+
 	let wide_instruction = instructions
 		.iter()
-		.find(|instruction| instruction.wide)
+		.find(|instruction| instruction.wide && !instruction.size_override)
 		.map(|instruction| {
 			let instruction = generate_instruction_decode(instruction);
 			quote::quote! {
@@ -212,11 +458,16 @@ fn generate_opcode_arm(instructions: Vec<&InstructionEncoding>, reg_opcode: bool
 
 	let so_instruction = instructions
 		.iter()
-		.find(|instruction| instruction.size_override)
+		.find(|instruction| instruction.size_override && !instruction.wide)
 		.map(|instruction| {
+			let selected = if instruction.mandatory_66() {
+				quote::quote! { size_override }
+			} else {
+				quote::quote! { operand_override }
+			};
 			let instruction = generate_instruction_decode(instruction);
 			quote::quote! {
-				if size_override {
+				if #selected {
 					#instruction
 				}
 			}
@@ -229,16 +480,48 @@ fn generate_opcode_arm(instructions: Vec<&InstructionEncoding>, reg_opcode: bool
 			let instruction = generate_instruction_decode(instruction);
 			quote::quote! { #instruction }
 		})
-		.unwrap_or_else(|| quote::quote! { Err(Interrupt::Undefined) });
+		.unwrap_or_else(|| quote::quote! { Err(Interrupt::UNDEFINED) });
 
 	quote::quote! {{
 		let x = [#(#names), *];
+		#wide_so_instruction
 		#wide_instruction
 		#so_instruction
 		#default
 	}}
 }
 
+fn generate_opcode_map<'a>(
+	instructions: impl Iterator<Item = &'a InstructionEncoding>,
+) -> Vec<impl ToTokens> {
+	let mut groups = BTreeMap::<u8, Vec<&InstructionEncoding>>::new();
+
+	for instruction in instructions {
+		if instruction.suffix_reg() {
+			for opcode in instruction.opcode()..instruction.opcode() + 8 {
+				groups.entry(opcode).or_default().push(instruction);
+			}
+		} else if instruction.condition() {
+			for opcode in instruction.opcode()..instruction.opcode() + 16 {
+				groups.entry(opcode).or_default().push(instruction);
+			}
+		} else {
+			groups
+				.entry(instruction.opcode())
+				.or_default()
+				.push(instruction);
+		}
+	}
+
+	groups
+		.into_iter()
+		.map(|(code, instructions)| {
+			let handler = generate_opcode_arm(instructions, false);
+			quote::quote! {#code => #handler, }
+		})
+		.collect()
+}
+
 #[proc_macro]
 pub fn generate_instructions(tokens: TokenStream) -> TokenStream {
 	let src = tokens.to_string();
@@ -252,101 +535,249 @@ pub fn generate_instructions(tokens: TokenStream) -> TokenStream {
 		.iter()
 		.map(|x| {
 			let name = syn::Ident::new(&x.name, proc_macro::Span::call_site().into());
-			let operand0 = match x.operand0 {
-				OperandEncoding::SuffixReg | OperandEncoding::ModReg => {
-					quote::quote! {operand0: Reg,}
-				}
-				OperandEncoding::ModRM => quote::quote! {operand0: RM,},
-				OperandEncoding::Immediate(_) => quote::quote! {operand0: Immediate,},
-				OperandEncoding::Implicit => quote::quote! {},
-			};
-			let operand1 = match x.operand1 {
-				OperandEncoding::SuffixReg | OperandEncoding::ModReg => {
-					quote::quote! {operand1: Reg,}
-				}
-				OperandEncoding::ModRM => quote::quote! {operand1: RM,},
-				OperandEncoding::Immediate(_) => quote::quote! {operand1: Immediate,},
-				OperandEncoding::Implicit => quote::quote! {},
-			};
-			quote::quote! {#name {#operand0 #operand1},}
+			let operand0 = x.operand0.field_type("operand0");
+			let operand1 = x.operand1.field_type("operand1");
+			let operand2 = x.operand2.field_type("operand2");
+			quote::quote! {#name {#operand0 #operand1 #operand2},}
 		})
 		.collect();
 
-	let instruction_definition =
-		quote::quote! { #[derive(Debug, Eq, PartialEq)] pub enum Instruction {#(#enum_variants)*}};
+	let instruction_definition = quote::quote! { #[derive(Clone, Copy, Debug, Eq, PartialEq)] pub enum Instruction {#(#enum_variants)*}};
 
-	let decode_function = quote::quote! {
-		pub fn decode(mmu: &mut MemoryManagementUnit, instruction_pointer: u64) -> Result<(Instruction, u64), Interrupt> {
-			decode_internal(mmu, instruction_pointer, false, false, None, SegmentOverride::None, None)
-		}
-	};
+	let accessors: Vec<_> = instructions
+		.iter()
+		.map(|x| {
+			let name = syn::Ident::new(&x.name, proc_macro::Span::call_site().into());
+			let name_string = &x.name;
+			let fields = [
+				(&x.operand0, "operand0"),
+				(&x.operand1, "operand1"),
+				(&x.operand2, "operand2"),
+			];
+			let operands = fields.into_iter().filter_map(|(encoding, field)| {
+				let field = syn::Ident::new(field, proc_macro::Span::call_site().into());
+				match encoding {
+					OperandEncoding::SuffixReg
+					| OperandEncoding::SuffixByteReg
+					| OperandEncoding::ModReg
+					| OperandEncoding::VexReg
+					| OperandEncoding::Accumulator => Some(quote::quote! {Operand::Reg(*#field)}),
+					OperandEncoding::ModRM => Some(quote::quote! {Operand::RM(*#field)}),
+					OperandEncoding::XmmReg => {
+						Some(quote::quote! {Operand::Xmm(RM::Reg(#field.0))})
+					}
+					OperandEncoding::XmmRM => Some(quote::quote! {Operand::Xmm(*#field)}),
+					// The condition is part of the mnemonic.
+					OperandEncoding::Condition => None,
+					OperandEncoding::Immediate(_) => {
+						Some(quote::quote! {Operand::Immediate(*#field)})
+					}
+					OperandEncoding::Implicit => None,
+				}
+			});
+			let fields = fields
+				.into_iter()
+				.filter(|(encoding, _)| !matches!(encoding, OperandEncoding::Implicit))
+				.map(|(_, field)| syn::Ident::new(field, proc_macro::Span::call_site().into()));
+			let fields: Vec<_> = fields.collect();
+			(
+				quote::quote! { Instruction::#name { .. } => #name_string, },
+				quote::quote! { Instruction::#name { #(#fields),* } => vec![#(#operands),*], },
+			)
+		})
+		.collect();
+	let names = accessors.iter().map(|(name, _)| name);
+	let operands = accessors.iter().map(|(_, operands)| operands);
+	let name_strings = instructions.iter().map(|x| &x.name);
+	let variant = |x: &InstructionEncoding| syn::Ident::new(&x.name, proc_macro::Span::call_site().into());
+	let mnemonics = instructions.iter().map(|x| {
+		let (name, mnemonic) = (variant(x), x.mnemonic());
+		quote::quote! { Instruction::#name { .. } => #mnemonic, }
+	});
+	let widths = instructions.iter().map(|x| {
+		let (name, width) = (variant(x), x.operand_width());
+		quote::quote! { Instruction::#name { .. } => #width, }
+	});
+	let sign_extended = instructions
+		.iter()
+		.filter(|x| x.sign_extended_immediate())
+		.map(|x| {
+			let name = variant(x);
+			quote::quote! { Instruction::#name { .. } }
+		});
+	let simulator_specific = instructions
+		.iter()
+		.filter(|x| x.simulator_specific)
+		.map(|x| &x.name);
 
-	let mut groups = BTreeMap::<u8, Vec<&InstructionEncoding>>::new();
+	let instruction_accessors = quote::quote! {
+		impl Instruction {
+			/// The names of all instruction variants in definition order.
+			pub const NAMES: &[&str] = &[#(#name_strings),*];
 
-	for instruction in &instructions {
-		if instruction.suffix_reg() {
-			for opcode0 in instruction.opcode0..instruction.opcode0 + 8 {
-				groups.entry(opcode0).or_default().push(instruction);
-			}
-		} else {
-			groups
-				.entry(instruction.opcode0)
-				.or_default()
-				.push(instruction);
-		}
-	}
+			/// The names of the instruction variants with simulator specific semantics.
+			pub const SIMULATOR_SPECIFIC: &[&str] = &[#(#simulator_specific),*];
 
-	let opcode1 = groups.into_iter().map(|(code, instructions)| {
-		let handler = generate_opcode_arm(instructions, false);
-		quote::quote! {#code => #handler, }
-	});
+			/// Whether the instruction has simulator specific semantics instead of those of
+			/// its encoding on hardware.
+			pub fn simulator_specific(&self) -> bool {
+				Self::SIMULATOR_SPECIFIC.contains(&self.name())
+			}
 
-	let decode_internal_function = quote::quote! {
-		fn decode_internal(mmu: &mut MemoryManagementUnit, instruction_pointer: u64, size_override: bool, address_override: bool, lock_rep: Option<LockRep>, segment_override: SegmentOverride, rex: Option<Rex>) -> Result<(Instruction, u64), Interrupt> {
-			let byte = mmu.read_u8(instruction_pointer)?;
-			let mut size = 1;
-			match byte {
-				0x26 | 0x2E | 0x36 | 0x3E => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, lock_rep, segment_override, None)?;
-					return Ok((instruction, size + 1));
+			/// The name of the instruction variant.
+			pub fn name(&self) -> &'static str {
+				match self {
+					#(#names)*
 				}
-				0x64 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, lock_rep, SegmentOverride::Fs, None)?;
-					return Ok((instruction, size + 1));
+			}
+
+			/// The explicit operands in encoding order.
+			pub fn operands(&self) -> Vec<Operand> {
+				match self {
+					#(#operands)*
 				}
-				0x65 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, lock_rep, SegmentOverride::Gs, None)?;
-					return Ok((instruction, size + 1));
+			}
+
+			/// The mnemonic in NASM syntax, where conditional instructions have `cc` in place
+			/// of the condition.
+			pub fn mnemonic(&self) -> &'static str {
+				match self {
+					#(#mnemonics)*
 				}
-				0x66 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, true, address_override, lock_rep, segment_override, None)?;
-					return Ok((instruction, size + 1));
+			}
+
+			/// The width in bits of the register and memory operands.
+			fn operand_width(&self) -> u32 {
+				match self {
+					#(#widths)*
 				}
-				0x67 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, true, lock_rep, segment_override, None)?;
-					return Ok((instruction, size + 1));
+			}
+
+			/// Whether the immediate is 32 bits sign extended to the 64 bit operand size.
+			fn sign_extended_immediate(&self) -> bool {
+				matches!(self, #(#sign_extended)|*)
+			}
+		}
+	};
+
+	let decode_function = quote::quote! {
+		pub fn decode_from<F: FetchSource + ?Sized>(mmu: &mut F, instruction_pointer: u64, mode: Mode) -> Result<(Instruction, u64), Interrupt> {
+			decode_prefixed(mmu, instruction_pointer, mode, &mut Prefixes::new())
+		}
+
+		/// Decodes like `decode_from`, leaving the prefixes the instruction was decoded with in
+		/// `prefixes`.
+		fn decode_prefixed<F: FetchSource + ?Sized>(mmu: &mut F, instruction_pointer: u64, mode: Mode, prefixes: &mut Prefixes) -> Result<(Instruction, u64), Interrupt> {
+			decode_internal(mmu, instruction_pointer, mode, prefixes)
+				.and_then(|(instruction, size)| {
+					if size > MAX_INSTRUCTION_LENGTH {
+						Err(Interrupt::UNDEFINED)
+					} else {
+						Ok((instruction, size))
+					}
+				})
+				.map_err(|interrupt| interrupt.with_opcode(mmu, instruction_pointer))
+		}
+	};
+
+	let legacy = instructions.iter().filter(|x| !x.vex);
+	let one_byte_arms = generate_opcode_map(legacy.clone().filter(|x| !x.two_byte()));
+	let two_byte_arms = generate_opcode_map(legacy.clone().filter(|x| x.two_byte() && !x.map_0f38));
+	let three_byte_arms = generate_opcode_map(legacy.filter(|x| x.map_0f38));
+	let vex = instructions.iter().filter(|x| x.vex);
+	let vex_0f_arms = generate_opcode_map(vex.clone().filter(|x| !x.map_0f38));
+	let vex_0f38_arms = generate_opcode_map(vex.filter(|x| x.map_0f38));
+
+	let decode_vex_function = quote::quote! {
+		/// Decodes an instruction with a VEX prefix, which replaces the REX and mandatory prefixes.
+		fn decode_vex<F: FetchSource + ?Sized>(mmu: &mut F, instruction_pointer: u64, mode: Mode, address_override: bool, segment_override: SegmentOverride) -> Result<(Instruction, u64), Interrupt> {
+			let (address_override, short_addressing) = address_size(mode, address_override);
+			let mut size = 2;
+			let vex = match mmu.fetch_u8(instruction_pointer)? {
+				0xC5 => Vex::two_byte(mmu.fetch_u8(instruction_pointer + 1)?),
+				_ => {
+					size += 1;
+					Vex::three_byte(mmu.fetch_u8(instruction_pointer + 1)?, mmu.fetch_u8(instruction_pointer + 2)?)
 				}
-				0xF0 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, Some(LockRep::Lock), segment_override, None)?;
-					return Ok((instruction, size + 1));
-				},
-				0xF2 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, Some(LockRep::Repne), segment_override, None)?;
-					return Ok((instruction, size + 1));
+			};
+			// Only the scalar forms are implemented, which require VEX.L to be clear.
+			if vex.l {
+				return Err(Interrupt::UNDEFINED);
+			}
+			let rex = Some(vex.rex);
+			let vvvv = vex.vvvv;
+			let size_override = vex.pp == 1;
+			// The VEX prefix has no operand size override, pp only selects the instruction.
+			let operand_override = size_override;
+			let lock_rep = match vex.pp {
+				2 => Some(LockRep::Repe),
+				3 => Some(LockRep::Repne),
+				_ => None,
+			};
+			let byte = mmu.fetch_u8(instruction_pointer + size)?;
+			size += 1;
+			match vex.map {
+				1 => match byte {
+					#(#vex_0f_arms)*
+					_ => Err(Interrupt::UNDEFINED),
 				},
-				0xF3 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, Some(LockRep::Repe), segment_override, None)?;
-					return Ok((instruction, size + 1));
+				2 => match byte {
+					#(#vex_0f38_arms)*
+					_ => Err(Interrupt::UNDEFINED),
 				},
-				0x40..0x50 => {
-					let (instruction, size) = decode_internal(mmu, instruction_pointer + 1, size_override, address_override, lock_rep, segment_override, Some(Rex::new(byte)))?;
-					return Ok((instruction, size + 1));
+				_ => Err(Interrupt::UNDEFINED),
+			}
+		}
+	};
+
+	let decode_internal_function = quote::quote! {
+		fn decode_internal<F: FetchSource + ?Sized>(mmu: &mut F, instruction_pointer: u64, mode: Mode, prefixes: &mut Prefixes) -> Result<(Instruction, u64), Interrupt> {
+			let mut size = 0;
+			let byte = loop {
+				if size == MAX_INSTRUCTION_LENGTH {
+					// Stop before reading past the longest possible instruction.
+					return Err(Interrupt::UNDEFINED);
 				}
-				_ => (),
+				let byte = mmu.fetch_u8(instruction_pointer + size)?;
+				size += 1;
+				match Prefix::parse(byte, mode) {
+					Some(prefix) => prefixes.apply(prefix),
+					None => break byte,
+				}
+			};
+			let Prefixes { size_override, address_override, lock_rep, segment_override, rex, .. } = *prefixes;
+			if let 0xC4 | 0xC5 = byte {
+				// The REX and mandatory prefixes are part of the VEX prefix.
+				if size_override || lock_rep.is_some() || rex.is_some() {
+					return Err(Interrupt::UNDEFINED);
+				}
+				prefixes.record(Prefix::Vex(if byte == 0xC4 { 3 } else { 2 }));
+				let prefix_size = size - 1;
+				let (instruction, size) = decode_vex(mmu, instruction_pointer + prefix_size, mode, address_override, segment_override)?;
+				return Ok((instruction, size + prefix_size));
 			}
+			// Outside long mode the size prefixes select the other size of the mode.
+			let operand_override = size_override != (mode == Mode::Bits16);
+			let (address_override, short_addressing) = address_size(mode, address_override);
 			match byte {
-				#(#opcode1)*
-				_ => Err(Interrupt::Undefined),
+				0x0F => {
+					let byte = mmu.fetch_u8(instruction_pointer + size)?;
+					size += 1;
+					match byte {
+						0x38 => {
+							let byte = mmu.fetch_u8(instruction_pointer + size)?;
+							size += 1;
+							match byte {
+								#(#three_byte_arms)*
+								_ => Err(Interrupt::UNDEFINED),
+							}
+						}
+						#(#two_byte_arms)*
+						_ => Err(Interrupt::UNDEFINED),
+					}
+				}
+				#(#one_byte_arms)*
+				_ => Err(Interrupt::UNDEFINED),
 			}
 		}
 	};
@@ -354,9 +785,13 @@ pub fn generate_instructions(tokens: TokenStream) -> TokenStream {
 	quote::quote! {
 		#instruction_definition
 
+		#instruction_accessors
+
 		#decode_function
 
 		#decode_internal_function
+
+		#decode_vex_function
 	}
 	.into()
 }
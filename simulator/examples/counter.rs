@@ -0,0 +1,71 @@
+//! The simulator with a device model of its own, as a crate depending on x86rs would add one.
+//! It takes the same command line as `x86rs`, and configs can use the device as
+//!
+//! ```toml
+//! [[device]]
+//! ports = [0x60]
+//! device_type = { External = { kind = "Counter", irq = 1, options = { limit = 100 } } }
+//! ```
+//!
+//! Run it with `cargo run --example counter -- config.toml`.
+
+use clap::Parser;
+use x86rs::{Args, Config, Device, Irq, MachineBuilder, error};
+
+/// Adds the bytes written to it and reads back the sum. Its irq is raised every time the sum
+/// passes the limit, which starts the count again.
+struct Counter {
+	count: u64,
+	limit: u64,
+	irq: Option<Irq>,
+}
+
+impl Device for Counter {
+	fn out_u8(&mut self, _port: u16, byte: u8) {
+		self.count += byte as u64;
+		if self.count >= self.limit {
+			self.count = 0;
+			if let Some(irq) = &self.irq {
+				irq.raise();
+			}
+		}
+	}
+
+	fn in_u8(&mut self, _port: u16) -> u8 {
+		self.count as u8
+	}
+}
+
+/// Creates a counter from the options of its config entry. The limit is optional, and an
+/// option of the wrong type is an error the simulator reports for the config.
+fn counter(options: &toml::Table, irq: Option<Irq>) -> Result<Box<dyn Device>, String> {
+	let limit = match options.get("limit") {
+		Some(limit) => limit
+			.as_integer()
+			.and_then(|limit| u64::try_from(limit).ok())
+			.ok_or("limit must be a positive integer")?,
+		None => 256,
+	};
+	Ok(Box::new(Counter {
+		count: 0,
+		limit,
+		irq,
+	}))
+}
+
+fn main() {
+	let args = Args::parse();
+	let config: Config = match &args.config {
+		Some(path) => toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap(),
+		None => Config::flat(&args.load),
+	};
+	let machine = MachineBuilder::new(args, config)
+		.register_device_factory("Counter", counter)
+		.build()
+		.unwrap_or_else(|err| error::fatal(&err));
+	let mut processor = machine.processor();
+	loop {
+		processor.step_instruction();
+		processor.wait_for_interrupt();
+	}
+}
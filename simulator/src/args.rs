@@ -1,15 +1,279 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
+
+use crate::{
+	device::LogLevel,
+	error,
+	instruction::Mode,
+	memory::{Permissions, PoisonPolicy},
+	state::{Costs, DEFAULT_EXCEPTION_LIMIT, DEFAULT_RED_ZONE, DumpFormat, WatchdogAction},
+};
 
 #[derive(clap::Parser, Clone)]
 pub struct Args {
 	/// Path to config file
-	pub config: PathBuf,
+	#[arg(required_unless_present_any = ["load", "info", "bench"])]
+	pub config: Option<PathBuf>,
+
+	/// Load a flat binary at a physical address instead of using a config file. Can be repeated
+	#[arg(long, value_name = "PATH@ADDRESS", conflicts_with = "config")]
+	pub load: Vec<Load>,
+
+	/// Start executing at this virtual address instead of 0
+	#[arg(long, value_name = "ADDRESS", value_parser = parse_address)]
+	pub entry: Option<u64>,
+
+	/// Write a little endian value of 1, 2, 4 or 8 bytes (default 8) to a physical address
+	/// before starting, even if it is ROM. Can be repeated
+	#[arg(long, value_name = "ADDRESS=VALUE[:SIZE]")]
+	pub poke: Vec<Poke>,
+
+	/// Print the implemented instructions, devices, paging modes and exception vectors, and exit
+	#[arg(
+		long,
+		value_name = "FORMAT",
+		num_args = 0..=1,
+		default_missing_value = "human"
+	)]
+	pub info: Option<DumpFormat>,
+
+	/// Run a built-in hot loop for this many steps, print the steps per second, and exit
+	#[arg(
+		long,
+		value_name = "STEPS",
+		num_args = 0..=1,
+		default_missing_value = "1000000"
+	)]
+	pub bench: Option<u64>,
+
+	/// Start in the interactive monitor instead of running the guest
+	#[arg(long)]
+	pub monitor: bool,
+
+	/// Dump the processor state when the simulation terminates
+	#[arg(long, value_name = "FORMAT")]
+	pub dump_on_exit: Option<DumpFormat>,
+
+	/// Report a guest whose registers, including rip, did not change for this many steps
+	#[arg(long, value_name = "STEPS", value_parser = clap::value_parser!(u64).range(1..))]
+	pub watchdog: Option<u64>,
+
+	/// What the watchdog does when it triggers
+	#[arg(
+		long,
+		value_name = "ACTION",
+		default_value = "warn",
+		requires = "watchdog"
+	)]
+	pub watchdog_action: WatchdogAction,
+
+	/// Abort after this many consecutive exceptions without a retired instruction
+	#[arg(long, value_name = "COUNT", default_value_t = DEFAULT_EXCEPTION_LIMIT)]
+	pub exception_limit: u32,
+
+	/// Bytes below rsp left alone when ring 0 code is interrupted on the interrupt stack
+	#[arg(long, value_name = "BYTES", default_value_t = DEFAULT_RED_ZONE)]
+	pub red_zone: u64,
+
+	/// Sample the instruction pointer and write the hottest addresses to this file on exit
+	#[arg(long, value_name = "PATH")]
+	pub profile: Option<PathBuf>,
+
+	/// Take a profile sample every this many retired instructions
+	#[arg(
+		long,
+		value_name = "COUNT",
+		default_value_t = 1000,
+		requires = "profile"
+	)]
+	pub profile_interval: u64,
+
+	/// Group profile samples into lines of this many bytes
+	#[arg(long, value_name = "BYTES", default_value_t = 16, requires = "profile")]
+	pub profile_granularity: u64,
+
+	/// Name the lines of the profile with the symbols in this file, in the format printed by nm
+	#[arg(long, value_name = "PATH", requires = "profile")]
+	pub symbols: Option<PathBuf>,
+
+	/// Save the state every checkpoint interval to PATH.0, PATH.1 and so on
+	#[arg(long, value_name = "PATH")]
+	pub checkpoint: Option<PathBuf>,
+
+	/// Write a checkpoint every this many retired instructions
+	#[arg(
+		long,
+		value_name = "COUNT",
+		default_value_t = 1_000_000,
+		value_parser = clap::value_parser!(u64).range(1..),
+		requires = "checkpoint"
+	)]
+	pub checkpoint_interval: u64,
+
+	/// Rotate through this many checkpoint files, overwriting the oldest
+	#[arg(
+		long,
+		value_name = "COUNT",
+		default_value_t = 3,
+		value_parser = clap::value_parser!(u64).range(1..),
+		requires = "checkpoint"
+	)]
+	pub checkpoint_count: u64,
+
+	/// Log the most recent page faults, 64 unless COUNT is given, and print them on exit
+	#[arg(
+		long,
+		value_name = "COUNT",
+		num_args = 0..=1,
+		default_missing_value = "64",
+		value_parser = clap::value_parser!(u64).range(1..=1 << 20)
+	)]
+	pub page_fault_log: Option<u64>,
+
+	/// Start from the state saved in a checkpoint instead of the reset state
+	#[arg(long, value_name = "PATH")]
+	pub restore: Option<PathBuf>,
+
+	/// Run this many processors sharing the memory, stepped one instruction each in turn
+	#[arg(
+		long,
+		value_name = "COUNT",
+		default_value_t = 1,
+		value_parser = clap::value_parser!(u64).range(1..),
+		conflicts_with = "monitor"
+	)]
+	pub processors: u64,
+
+	/// Put the terminal in raw mode, so the console receives key presses immediately
+	#[arg(long)]
+	pub raw_terminal: bool,
+
+	/// Decode with the default operand and address sizes of 16-bit, 32-bit or long mode
+	#[arg(long, value_name = "BITS", default_value = "64")]
+	pub mode: Mode,
+
+	/// Fault on accesses reaching from a memory region into unmapped memory
+	#[arg(long)]
+	pub strict_memory: bool,
+
+	/// Fault on a misaligned cr3 or paging entries with reserved bits set
+	#[arg(long)]
+	pub strict_page_tables: bool,
+
+	/// Write events as lines of JSON to this file, such as /dev/fd/3 for an inherited descriptor
+	#[arg(long, value_name = "PATH")]
+	pub events: Option<PathBuf>,
+
+	/// Also emit every this many retired instructions as an event
+	#[arg(long, value_name = "COUNT", requires = "events")]
+	pub events_retired: Option<u64>,
+
+	/// Log every port access, or only those of the device with this name
+	#[arg(long, value_name = "DEVICE", num_args = 0..=1)]
+	pub trace_io: Option<Option<String>>,
+
+	/// Seed the random device and count timers in processor steps, making runs reproducible
+	#[arg(long)]
+	pub seed: Option<u64>,
+
+	/// Stop after this many retired instructions
+	#[arg(long, value_name = "COUNT")]
+	pub max_instructions: Option<u64>,
+
+	/// Count the deterministic timer and --max-instructions in cycles of the cost table
+	#[arg(long)]
+	pub count_cycles: bool,
+}
+
+/// Parses a decimal number or a hexadecimal number prefixed with 0x.
+pub fn parse_number(src: &str) -> Option<u64> {
+	match src.strip_prefix("0x") {
+		Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+		None => src.parse().ok(),
+	}
+}
+
+fn parse_address(src: &str) -> Result<u64, String> {
+	parse_number(src).ok_or_else(|| format!("invalid address `{src}`"))
+}
+
+/// A flat binary given as `path@address`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Load {
+	pub path: PathBuf,
+	pub address: u64,
+}
+
+impl FromStr for Load {
+	type Err = String;
+
+	fn from_str(src: &str) -> Result<Load, String> {
+		// The address is split off at the last @, so paths may contain @.
+		let Some((path, address)) = src.rsplit_once('@') else {
+			return Err(format!("expected PATH@ADDRESS, found `{src}`"));
+		};
+		Ok(Load {
+			path: PathBuf::from(path),
+			address: parse_address(address)?,
+		})
+	}
+}
+
+/// A value patched into physical memory given as `address=value[:size]`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Poke {
+	pub address: u64,
+	pub value: u64,
+	pub size: usize,
+}
+
+impl Poke {
+	/// The little endian bytes written.
+	pub fn bytes(&self) -> Vec<u8> {
+		self.value.to_le_bytes()[..self.size].to_vec()
+	}
+}
+
+impl FromStr for Poke {
+	type Err = String;
+
+	fn from_str(src: &str) -> Result<Poke, String> {
+		let Some((address, value)) = src.split_once('=') else {
+			return Err(format!("expected ADDRESS=VALUE[:SIZE], found `{src}`"));
+		};
+		let (value, size) = match value.split_once(':') {
+			Some((value, size)) => (value, size.parse().unwrap_or(0)),
+			None => (value, 8),
+		};
+		if ![1, 2, 4, 8].contains(&size) {
+			return Err(format!("the size of `{src}` must be 1, 2, 4 or 8"));
+		}
+		let value = parse_number(value).ok_or_else(|| format!("invalid value `{value}`"))?;
+		if size < 8 && value >> (8 * size) != 0 {
+			return Err(format!("`{value:#X}` does not fit in {size} bytes"));
+		}
+		Ok(Poke {
+			address: parse_address(address)?,
+			value,
+			size,
+		})
+	}
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub enum MemoryType {
 	RAM,
-	ROM { path: PathBuf },
+	ROM {
+		path: PathBuf,
+	},
+	/// ROM which the device on `port` switches to RAM.
+	Shadow {
+		path: PathBuf,
+		port: u16,
+	},
+	/// Memory which should not be used, reporting accesses according to `policy`.
+	Poison {
+		policy: PoisonPolicy,
+	},
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
@@ -17,6 +281,79 @@ pub struct Memory {
 	pub start: u64,
 	pub size: u64,
 	pub memory_type: MemoryType,
+
+	/// File copied to the start of a RAM region before the simulation starts.
+	#[serde(default)]
+	pub image: Option<PathBuf>,
+
+	/// Accesses allowed to the region independent of paging. Everything is allowed by default.
+	#[serde(default)]
+	pub permissions: Permissions,
+}
+
+/// The kinds of memory of a slice, named like [`MemoryType`].
+#[allow(clippy::upper_case_acronyms)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SliceType {
+	RAM,
+	ROM,
+}
+
+/// A part of an image placed in its own region.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct Slice {
+	/// Offset of the slice in the image file.
+	pub offset: u64,
+	/// Number of bytes taken from the image file, copied to the start of the region.
+	pub length: u64,
+	pub start: u64,
+	pub size: u64,
+	pub memory_type: SliceType,
+
+	/// Accesses allowed to the region independent of paging. Everything is allowed by default.
+	#[serde(default)]
+	pub permissions: Permissions,
+}
+
+/// A single file sliced into several regions, for example the text and data of a linked
+/// binary.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct Image {
+	pub path: PathBuf,
+	pub slice: Vec<Slice>,
+}
+
+impl Image {
+	/// Pairs each slice with its bytes of the image, checking that they are within the image
+	/// and fit in their region.
+	pub fn slices<'a>(&'a self, data: &'a [u8]) -> Result<Vec<(&'a Slice, &'a [u8])>, String> {
+		self.slice
+			.iter()
+			.map(|slice| {
+				let bytes = slice
+					.offset
+					.checked_add(slice.length)
+					.filter(|&end| end <= data.len() as u64)
+					.map(|end| &data[slice.offset as usize..end as usize])
+					.ok_or_else(|| {
+						format!(
+							"Slice at offset {} of length {} is outside of {} ({} bytes)",
+							slice.offset,
+							slice.length,
+							self.path.display(),
+							data.len()
+						)
+					})?;
+				if slice.length > slice.size {
+					return Err(format!(
+						"Slice of length {} is larger than its region of {} bytes",
+						slice.length, slice.size
+					));
+				}
+				Ok((slice, bytes))
+			})
+			.collect()
+	}
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
@@ -24,6 +361,69 @@ pub enum DeviceType {
 	UTF8Console,
 	Timer {
 		irq: u8,
+	},
+	Random,
+	DebugLog {
+		level: LogLevel,
+	},
+	MemoryController,
+	/// Injects faults for testing the guest, on its commands or at random. Spurious irqs are
+	/// raised on `irq`, and the random faults are seeded with `seed`, or `--seed`.
+	FaultInjector {
+		#[serde(default)]
+		seed: Option<u64>,
+		#[serde(default)]
+		irq: Option<u8>,
+	},
+	/// Queues interrupts and raises NMIs written by the guest, for test configs.
+	InterruptInjector,
+	/// A device of a kind registered in the [`DeviceRegistry`](crate::device::DeviceRegistry),
+	/// created from the options and the vector of the irq.
+	External {
+		kind: String,
+		#[serde(default)]
+		options: toml::Table,
+		#[serde(default)]
+		irq: Option<u8>,
+	},
+}
+
+impl DeviceType {
+	/// The names of the device types, as written in the config. They are the variant names the
+	/// derived deserializer hands to the deserializer when reading an enum.
+	pub fn names() -> &'static [&'static str] {
+		struct VariantNames<'a>(&'a mut &'static [&'static str]);
+
+		impl<'de> serde::Deserializer<'de> for VariantNames<'_> {
+			type Error = serde::de::value::Error;
+
+			fn deserialize_any<V: serde::de::Visitor<'de>>(
+				self,
+				_: V,
+			) -> Result<V::Value, Self::Error> {
+				Err(serde::de::Error::custom("not an enum"))
+			}
+
+			fn deserialize_enum<V: serde::de::Visitor<'de>>(
+				self,
+				_name: &'static str,
+				variants: &'static [&'static str],
+				_visitor: V,
+			) -> Result<V::Value, Self::Error> {
+				*self.0 = variants;
+				Err(serde::de::Error::custom("only the variant names are read"))
+			}
+
+			serde::forward_to_deserialize_any! {
+				bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+				option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+				ignored_any
+			}
+		}
+
+		let mut names: &'static [&'static str] = &[];
+		let _ = <DeviceType as serde::Deserialize>::deserialize(VariantNames(&mut names));
+		names
 	}
 }
 
@@ -31,10 +431,315 @@ pub enum DeviceType {
 pub struct Device {
 	pub ports: Vec<u16>,
 	pub device_type: DeviceType,
+
+	/// Shown by the io trace instead of the device type, and used to trace only this device.
+	#[serde(default)]
+	pub name: Option<String>,
 }
 
 #[derive(serde::Deserialize, serde::Serialize, Debug)]
 pub struct Config {
 	pub memory: Vec<Memory>,
 	pub device: Vec<Device>,
+
+	/// Images sliced into regions in addition to the memory regions.
+	#[serde(default)]
+	pub image: Vec<Image>,
+
+	/// The number of paging levels, 4 or 5. Five levels widen virtual addresses from 48 to 57
+	/// bits.
+	#[serde(default = "default_paging_levels")]
+	pub paging_levels: u32,
+
+	/// The cycles added to the time stamp counter by each instruction.
+	#[serde(default)]
+	pub cost: Costs,
+
+	/// Generate page tables mapping the regions, instead of the guest setting up paging.
+	#[serde(default)]
+	pub identity_map: Option<IdentityMap>,
+
+	/// Start with cr3 pointing at page tables the regions already hold.
+	#[serde(default)]
+	pub boot: Option<Boot>,
+
+	/// The vector device irq 0 is delivered through. Irq n uses the vector `irq_base + n`,
+	/// which must be above the exception vectors.
+	#[serde(default = "default_irq_base")]
+	pub irq_base: u8,
+
+	/// RAM for the stack, with rsp starting at its top.
+	#[serde(default)]
+	pub stack: Option<Stack>,
+}
+
+/// A stack region with a guard page below it, which faults on overflow.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct Stack {
+	/// The lowest address of the stack. The guard page is the page below it.
+	pub base: u64,
+	pub size: u64,
+}
+
+/// Page tables generated before the guest runs, such that it starts with paging set up.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct IdentityMap {
+	/// The physical address of the tables, which are placed in a read only region of their
+	/// own. cr3 points at them.
+	pub tables: u64,
+
+	/// Also map every region at this virtual address plus its physical address.
+	#[serde(default)]
+	pub alias: Option<u64>,
+}
+
+/// Page tables loaded with the regions, which the guest runs with from its first fetch.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+pub struct Boot {
+	/// The physical address of the top level table, which must be page aligned and in a
+	/// region. cr3 points at it, also after a reset.
+	pub cr3: u64,
+
+	/// The virtual address of the first instruction, unless `--entry` is given.
+	#[serde(default)]
+	pub entry: Option<u64>,
+}
+
+fn default_paging_levels() -> u32 {
+	4
+}
+
+/// The first vector after those reserved for exceptions.
+pub(crate) fn default_irq_base() -> u8 {
+	32
+}
+
+impl Config {
+	/// A config with a RAM region holding each flat binary, rounded up to whole pages, and no
+	/// devices.
+	pub fn flat(loads: &[Load]) -> Config {
+		let memory = loads
+			.iter()
+			.map(|load| {
+				let size = match std::fs::metadata(&load.path) {
+					Ok(metadata) => metadata.len(),
+					Err(err) => {
+						error::fatal(&format!("Could not read {}: {err}", load.path.display()))
+					}
+				};
+				Memory {
+					start: load.address,
+					size: size.max(1).next_multiple_of(1 << 12),
+					memory_type: MemoryType::RAM,
+					image: Some(load.path.clone()),
+					permissions: Permissions::default(),
+				}
+			})
+			.collect();
+		Config {
+			memory,
+			device: Vec::new(),
+			image: Vec::new(),
+			paging_levels: default_paging_levels(),
+			cost: Costs::default(),
+			identity_map: None,
+			boot: None,
+			irq_base: default_irq_base(),
+			stack: None,
+		}
+	}
+
+	/// The vector a device irq is delivered through, or why the irq cannot be used.
+	pub fn irq_vector(&self, irq: u8) -> Result<u8, String> {
+		let vector = self.irq_base as u16 + irq as u16;
+		if vector < default_irq_base() as u16 {
+			Err(format!(
+				"Irq {irq} would be delivered through exception vector {vector}, \
+				 irq_base must leave irqs at vector 32 or above"
+			))
+		} else {
+			u8::try_from(vector).map_err(|_| {
+				format!(
+					"Irq {irq} with irq_base {} is beyond the last vector 255",
+					self.irq_base
+				)
+			})
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::path::PathBuf;
+
+	use clap::Parser;
+
+	use crate::args::{Args, Config, DeviceType, Load, Poke, SliceType};
+
+	#[test]
+	fn watchdog_steps() {
+		let parse =
+			|extra: &[&str]| Args::try_parse_from([&["x86rs", "config.toml"], extra].concat());
+		assert_eq!(parse(&["--watchdog", "1"]).unwrap().watchdog, Some(1));
+		assert!(parse(&["--watchdog", "0"]).is_err());
+	}
+
+	#[test]
+	fn page_fault_log_count() {
+		let parse =
+			|extra: &[&str]| Args::try_parse_from([&["x86rs", "config.toml"], extra].concat());
+		assert_eq!(
+			parse(&["--page-fault-log"]).unwrap().page_fault_log,
+			Some(64)
+		);
+		assert_eq!(
+			parse(&["--page-fault-log", "8"]).unwrap().page_fault_log,
+			Some(8)
+		);
+		assert!(parse(&["--page-fault-log", "0"]).is_err());
+		assert!(parse(&["--page-fault-log", "18446744073709551615"]).is_err());
+	}
+
+	#[test]
+	fn load() {
+		assert_eq!(
+			"prog.bin@0x1000".parse(),
+			Ok(Load {
+				path: PathBuf::from("prog.bin"),
+				address: 0x1000,
+			})
+		);
+		assert_eq!(
+			"user@host.bin@4096".parse::<Load>().unwrap().path,
+			PathBuf::from("user@host.bin")
+		);
+		assert!("prog.bin".parse::<Load>().is_err());
+		assert!("prog.bin@0xZZ".parse::<Load>().is_err());
+	}
+
+	#[test]
+	fn poke() {
+		let poke: Poke = "0xFFFF0=0x1234:2".parse().unwrap();
+		assert_eq!((poke.address, poke.value), (0xFFFF0, 0x1234));
+		assert_eq!(poke.bytes(), [0x34, 0x12]);
+		assert_eq!(
+			"16=1".parse::<Poke>().unwrap().bytes(),
+			[1, 0, 0, 0, 0, 0, 0, 0]
+		);
+		assert!("16=0x100:1".parse::<Poke>().is_err());
+		assert!("16=1:3".parse::<Poke>().is_err());
+		assert!("16".parse::<Poke>().is_err());
+	}
+
+	#[test]
+	fn irq_vectors() {
+		let mut config: Config = toml::from_str("memory = []\ndevice = []").unwrap();
+		assert_eq!(config.irq_vector(0), Ok(32));
+		assert_eq!(config.irq_vector(223), Ok(255));
+		assert!(config.irq_vector(224).is_err());
+		config.irq_base = 0;
+		assert_eq!(
+			config.irq_vector(6),
+			Err(
+				"Irq 6 would be delivered through exception vector 6, irq_base must leave irqs \
+				 at vector 32 or above"
+					.to_string()
+			)
+		);
+	}
+
+	#[test]
+	fn cost_table() {
+		let config: Config = toml::from_str(
+			r#"
+			memory = []
+			device = []
+
+			[cost]
+			memory_operand = 2
+			instructions = { div = 20 }
+			"#,
+		)
+		.unwrap();
+		assert_eq!(config.cost.default, 1);
+		assert_eq!(config.cost.memory_operand, 2);
+		assert_eq!(config.cost.instructions["div"], 20);
+	}
+
+	#[test]
+	fn execute_only_rom() {
+		let config: Config = toml::from_str(
+			r#"
+			device = []
+
+			[[memory]]
+			start = 0x100000
+			size = 0x1000
+			memory_type = { ROM = { path = "firmware.bin" } }
+			permissions = { read = false, write = false }
+			"#,
+		)
+		.unwrap();
+		let permissions = config.memory[0].permissions;
+		assert!(!permissions.read && !permissions.write && permissions.execute);
+	}
+
+	#[test]
+	fn external_device() {
+		let config: Config = toml::from_str(
+			r#"
+			memory = []
+
+			[[device]]
+			ports = [0x100]
+			device_type = { External = { kind = "Uart", irq = 4, options = { baud = 9600 } } }
+			"#,
+		)
+		.unwrap();
+		let DeviceType::External { kind, options, irq } = &config.device[0].device_type else {
+			panic!("not an external device");
+		};
+		assert_eq!(kind, "Uart");
+		assert_eq!(options["baud"].as_integer(), Some(9600));
+		assert_eq!(*irq, Some(4));
+	}
+
+	#[test]
+	fn image_slices() {
+		let config: Config = toml::from_str(
+			r#"
+			memory = []
+			device = []
+
+			[[image]]
+			path = "kernel.bin"
+
+			[[image.slice]]
+			offset = 0
+			length = 4
+			start = 0x100000
+			size = 0x1000
+			memory_type = "ROM"
+
+			[[image.slice]]
+			offset = 4
+			length = 2
+			start = 0x200000
+			size = 0x2000
+			memory_type = "RAM"
+			permissions = { execute = false }
+			"#,
+		)
+		.unwrap();
+		let image = &config.image[0];
+		let slices = image.slices(&[1, 2, 3, 4, 5, 6]).unwrap();
+		assert_eq!(slices.len(), 2);
+		assert_eq!(slices[0].0.memory_type, SliceType::ROM);
+		assert_eq!(slices[0].1, [1, 2, 3, 4]);
+		assert_eq!(slices[1].0.start, 0x200000);
+		assert!(!slices[1].0.permissions.execute);
+		assert_eq!(slices[1].1, [5, 6]);
+		// The second slice ends past a shorter file.
+		assert!(image.slices(&[1, 2, 3, 4, 5]).is_err());
+	}
 }
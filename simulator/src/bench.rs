@@ -0,0 +1,167 @@
+use std::{
+	fmt::Display,
+	time::{Duration, Instant},
+};
+
+use crate::{
+	device::PortDevices,
+	instruction::decode,
+	memory::{
+		ConventionalMemory, Memory, MemoryManagementUnit, Permissions, PhysicalMemoryManagementUnit,
+	},
+	state::ProcessorState,
+};
+
+/// The hot loop of the benchmark, which copies a quadword 1000 times in a counted loop and
+/// starts over. There is no `rep movs`, so the copy is done with `mov`.
+const WORKLOAD: &[u8] = &[
+	0xBB, 0x00, 0x20, 0x00, 0x00, // 0x00: mov ebx, 0x2000
+	0xB8, 0xE8, 0x03, 0x00, 0x00, // 0x05: mov eax, 1000
+	0x48, 0x8B, 0x0B, // 0x0A: mov rcx, [rbx]
+	0x48, 0x89, 0x4B, 0x08, // 0x0D: mov [rbx + 8], rcx
+	0x2D, 0x01, 0x00, 0x00, 0x00, // 0x11: sub eax, 1
+	0x75, 0xF2, // 0x16: jnz 0x0A
+	0xEB, 0xEB, // 0x18: jmp 0x05
+];
+
+/// The number of instruction pointers recorded to replay the decoding of the workload, which
+/// covers two restarts of the loop.
+const TRACE: usize = 1 << 13;
+
+/// The page tables are placed after the RAM.
+const PAGE_TABLES: u64 = 1 << 20;
+
+/// The time the workload took, with the share of decoding measured separately.
+pub struct BenchReport {
+	pub steps: u64,
+	pub elapsed: Duration,
+
+	/// The time decoding the same instructions took on their own, including fetching them
+	/// through paging.
+	pub decode: Duration,
+}
+
+impl BenchReport {
+	pub fn steps_per_second(&self) -> f64 {
+		self.steps as f64 / self.elapsed.as_secs_f64()
+	}
+}
+
+impl Display for BenchReport {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let share = |time: Duration| 100.0 * time.as_secs_f64() / self.elapsed.as_secs_f64();
+		let per_step = |time: Duration| time.as_nanos() as f64 / self.steps as f64;
+		let execute = self.elapsed.saturating_sub(self.decode);
+		writeln!(
+			f,
+			"{} steps in {:.3} s: {:.0} steps per second",
+			self.steps,
+			self.elapsed.as_secs_f64(),
+			self.steps_per_second(),
+		)?;
+		writeln!(
+			f,
+			"Decode (fetch and translate the code): {:.1} ns per step, {:.0}%",
+			per_step(self.decode),
+			share(self.decode),
+		)?;
+		writeln!(
+			f,
+			"Execute (translate the data and the rest): {:.1} ns per step, {:.0}%",
+			per_step(execute),
+			share(execute),
+		)
+	}
+}
+
+/// A machine with the workload at address 0 in 1 MiB of identity mapped RAM.
+fn machine() -> ProcessorState {
+	let mut pmu = PhysicalMemoryManagementUnit::new();
+	pmu.add(0, PAGE_TABLES, Permissions::default(), || {
+		let mut ram = ConventionalMemory::create(PAGE_TABLES);
+		ram.load(0, WORKLOAD);
+		ram
+	});
+	pmu.add_identity_page_tables(PAGE_TABLES, 4, None).unwrap();
+	let mut mmu = MemoryManagementUnit::new(pmu);
+	mmu.set_reset_paging_table_address(PAGE_TABLES);
+	ProcessorState::new(mmu, PortDevices::new())
+}
+
+/// Runs `steps` steps of the workload and times them. Decoding is timed by decoding the
+/// instructions of a previous run of the workload again in the same order, and the rest of
+/// the time is execution. There is no TLB, so both include walking the page tables.
+pub fn run(steps: u64) -> BenchReport {
+	let mut state = machine();
+	let mut trace = Vec::with_capacity(TRACE);
+	while trace.len() < TRACE {
+		trace.push(state.instruction_pointer());
+		state.step_instruction();
+	}
+
+	let mut state = machine();
+	let start = Instant::now();
+	for _ in 0..steps {
+		state.step_instruction();
+	}
+	let elapsed = start.elapsed();
+
+	let memory = state.memory();
+	let start = Instant::now();
+	for instruction_pointer in trace.iter().cycle().take(steps as usize) {
+		std::hint::black_box(decode(memory, *instruction_pointer).unwrap());
+	}
+	let decode = start.elapsed();
+
+	BenchReport {
+		steps,
+		elapsed,
+		decode,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::Duration;
+
+	use crate::{
+		bench::{BenchReport, machine, run},
+		state::RegName,
+	};
+
+	#[test]
+	fn workload() {
+		let mut state = machine();
+		state.memory().write_u64(0x2000, 0x1234).unwrap();
+		// The setup, one iteration and the next five.
+		for _ in 0..2 + 4 + 5 * 4 {
+			state.step_instruction();
+		}
+		assert_eq!(state.get_reg(RegName::Rax), 1000 - 6);
+		assert_eq!(state.memory().read_u64(0x2008).unwrap(), 0x1234);
+		// The rest of the iterations and the restart.
+		for _ in 0..994 * 4 + 1 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer(), 0x05);
+
+		let report = run(1000);
+		assert_eq!(report.steps, 1000);
+		assert!(report.steps_per_second() > 0.0);
+	}
+
+	#[test]
+	fn report() {
+		let report = BenchReport {
+			steps: 2_000_000,
+			elapsed: Duration::from_secs(2),
+			decode: Duration::from_millis(500),
+		};
+		assert_eq!(
+			report.to_string(),
+			"2000000 steps in 2.000 s: 1000000 steps per second\n\
+			 Decode (fetch and translate the code): 250.0 ns per step, 25%\n\
+			 Execute (translate the data and the rest): 750.0 ns per step, 75%\n"
+		);
+	}
+}
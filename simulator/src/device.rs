@@ -1,61 +1,335 @@
 use std::{
-	collections::HashMap,
+	cell::RefCell,
+	collections::{BTreeMap, HashMap, VecDeque},
+	hash::{BuildHasher, Hasher, RandomState},
 	io::{Read, Write},
+	rc::Rc,
 	sync::{
-		Arc,
-		atomic::{AtomicU8, Ordering},
+		Arc, Mutex, OnceLock,
+		atomic::{AtomicBool, AtomicU8, Ordering},
 	},
 	thread,
 	time::Duration,
 };
 
-use crate::state::schedule_interrupt;
+use crate::{
+	error::info,
+	events::{DeviceEvents, Event, Events},
+	memory::MemoryManagementUnit,
+};
+
+/// The longest message accepted by the debug log, so a garbage length is not read.
+const MAX_DEBUG_MESSAGE: u64 = 1 << 20;
+
+/// Raises the irq of the fault injector.
+const FAULT_SPURIOUS_IRQ: u32 = 1;
+/// Xors the parameter into the next read of the target port.
+const FAULT_CORRUPT: u32 = 2;
+/// Lets reads of the target port float high for the parameter number of steps.
+const FAULT_DELAY: u32 = 3;
+/// Injects a random fault in one of every parameter steps, or stops doing so when it is 0.
+const FAULT_RANDOM: u32 = 4;
+
+/// The longest delay injected at random, in steps.
+const MAX_RANDOM_DELAY: u32 = 64;
+
+/// The interrupt request line from the devices to their processor. It holds the vector of
+/// the last request which was not taken yet, or 0.
+#[derive(Clone, Default)]
+pub(crate) struct InterruptLine {
+	request: Arc<AtomicU8>,
+	/// The thread waiting for a request in hlt, which raising the line wakes.
+	waiter: Arc<Mutex<Option<thread::Thread>>>,
+}
+
+impl InterruptLine {
+	pub(crate) fn raise(&self, vector: u8) {
+		self.request.store(vector, Ordering::Relaxed);
+		if let Some(waiter) = &*self.waiter.lock().unwrap() {
+			waiter.unpark();
+		}
+	}
+
+	/// Takes the pending request. Swapping does not lose a request raised between reading and
+	/// clearing.
+	pub(crate) fn take(&self) -> Option<u8> {
+		Some(self.request.swap(0, Ordering::Relaxed)).filter(|&vector| vector != 0)
+	}
+
+	/// Parks the current thread until the line is raised, or the thread is unparked for another
+	/// reason. The thread is registered before checking for a request, so a request raised
+	/// in between wakes it.
+	pub(crate) fn wait(&self) {
+		*self.waiter.lock().unwrap() = Some(thread::current());
+		if self.request.load(Ordering::Relaxed) == 0 {
+			thread::park();
+		}
+	}
+}
+
+/// The irq of a device, which interrupts the processor through the vector the config assigns
+/// to it.
+#[derive(Clone)]
+pub struct Irq {
+	vector: u8,
+	line: InterruptLine,
+}
+
+impl Irq {
+	pub fn vector(&self) -> u8 {
+		self.vector
+	}
+
+	/// Requests the interrupt, which the processor takes before its next instruction while
+	/// IF is set. A request which was not taken yet is replaced. Device threads may raise it,
+	/// which wakes the processor if it waits in hlt.
+	pub fn raise(&self) {
+		self.line.raise(self.vector);
+	}
+}
+
+/// The machine as seen by a device ringing its doorbell.
+pub struct Bus<'a> {
+	pub memory: &'a mut MemoryManagementUnit,
+	pub instruction_pointer: u64,
+	pub time_stamp_counter: u64,
+}
 
 pub trait Device {
 	fn out_u8(&mut self, port: u16, byte: u8);
 
 	fn in_u8(&mut self, port: u16) -> u8;
+
+	/// Writes a 16-bit register at a single port. By default the bytes are written to the port
+	/// from the lowest to the highest.
+	fn out_u16(&mut self, port: u16, value: u16) {
+		for byte in value.to_le_bytes() {
+			self.out_u8(port, byte);
+		}
+	}
+
+	/// Writes a 32-bit register at a single port. By default the bytes are written to the port
+	/// from the lowest to the highest.
+	fn out_u32(&mut self, port: u16, value: u32) {
+		for byte in value.to_le_bytes() {
+			self.out_u8(port, byte);
+		}
+	}
+
+	/// Reads a 16-bit register at a single port. By default the bytes are read from the port
+	/// from the lowest to the highest.
+	fn in_u16(&mut self, port: u16) -> u16 {
+		u16::from_le_bytes(std::array::from_fn(|_| self.in_u8(port)))
+	}
+
+	/// Reads a 32-bit register at a single port. By default the bytes are read from the port
+	/// from the lowest to the highest.
+	fn in_u32(&mut self, port: u16) -> u32 {
+		u32::from_le_bytes(std::array::from_fn(|_| self.in_u8(port)))
+	}
+
+	/// Called once per processor step, which is the clock of deterministic devices.
+	fn tick(&mut self) {}
+
+	/// Called after the guest wrote a port of the device, for devices which access memory.
+	fn doorbell(&mut self, _port: u16, _bus: &mut Bus) {}
+
+	/// Called when `--events` is given, with the hook the device emits its own events through.
+	fn attach_events(&mut self, _events: DeviceEvents) {}
+}
+
+/// Lets the guest add RAM at runtime, like hotplugged memory. The guest writes the physical
+/// base as two 32-bit halves to the first (low) and second (high) port, and writing a size in
+/// pages to the third port adds zeroed RAM there. Reading the third port returns 1 if the last
+/// region was added, and 0 if it was rejected because it was empty, not page aligned or
+/// overlapped other memory. Every attempt is a `hot_add` event.
+pub struct MemoryController {
+	base: u64,
+	/// The number of pages to add at the next doorbell.
+	pending: Option<u64>,
+	added: bool,
+	events: Option<DeviceEvents>,
 }
 
-pub struct UTF8Console;
+impl MemoryController {
+	pub fn new() -> MemoryController {
+		MemoryController {
+			base: 0,
+			pending: None,
+			added: false,
+			events: None,
+		}
+	}
+}
+
+impl Device for MemoryController {
+	fn out_u8(&mut self, port: u16, byte: u8) {
+		self.out_u32(port, byte as u32);
+	}
+
+	fn in_u8(&mut self, port: u16) -> u8 {
+		self.in_u32(port) as u8
+	}
+
+	fn out_u16(&mut self, port: u16, value: u16) {
+		self.out_u32(port, value as u32);
+	}
+
+	fn out_u32(&mut self, port: u16, value: u32) {
+		match port {
+			0 => self.base = self.base & !0xFFFF_FFFF | value as u64,
+			1 => self.base = self.base & 0xFFFF_FFFF | (value as u64) << 32,
+			2 => self.pending = Some(value as u64),
+			_ => unreachable!(),
+		}
+	}
+
+	fn in_u16(&mut self, port: u16) -> u16 {
+		self.in_u32(port) as u16
+	}
+
+	fn in_u32(&mut self, port: u16) -> u32 {
+		match port {
+			2 => self.added as u32,
+			_ => 0xFFFF_FFFF,
+		}
+	}
+
+	fn doorbell(&mut self, _port: u16, bus: &mut Bus) {
+		let Some(pages) = self.pending.take() else {
+			return;
+		};
+		let result = if self.base & 0xFFF != 0 {
+			Err(format!("Cannot add memory at unaligned 0x{:X}", self.base))
+		} else {
+			bus.memory.hot_add(self.base, pages << 12)
+		};
+		self.added = result
+			.inspect_err(|err| info(&format!("{err} (rip 0x{:016X})", bus.instruction_pointer)))
+			.is_ok();
+		if let Some(events) = &self.events {
+			let data = [
+				("base", self.base),
+				("pages", pages),
+				("added", self.added as u64),
+			];
+			events.emit("hot_add", &data);
+		}
+	}
+
+	fn attach_events(&mut self, events: DeviceEvents) {
+		self.events = Some(events);
+	}
+}
+
+/// Writes bytes to stdout at port 0 and reads stdin without blocking the guest. Reading port
+/// 0 returns the next input byte or 0 if there is none, and bit 0 of port 1 is set while a
+/// byte is available.
+pub struct UTF8Console {
+	/// Filled from stdin by a background thread.
+	input: Arc<Mutex<VecDeque<u8>>>,
+}
+
+impl UTF8Console {
+	pub fn new() -> UTF8Console {
+		let input = Arc::new(Mutex::new(VecDeque::new()));
+		let reader = input.clone();
+		thread::spawn(move || {
+			let mut buffer = [0; 256];
+			loop {
+				match std::io::stdin().read(&mut buffer) {
+					Ok(0) | Err(_) => break,
+					Ok(length) => reader.lock().unwrap().extend(&buffer[..length]),
+				}
+			}
+		});
+		UTF8Console { input }
+	}
+}
 
 impl Device for UTF8Console {
-	fn out_u8(&mut self, _port: u16, byte: u8) {
-		let _ = std::io::stdout().write(&[byte]);
-		let _ = std::io::stdout().flush();
+	fn out_u8(&mut self, port: u16, byte: u8) {
+		if port == 0 {
+			let _ = std::io::stdout().write(&[byte]);
+			let _ = std::io::stdout().flush();
+		}
 	}
 
-	fn in_u8(&mut self, _port: u16) -> u8 {
-		let mut buf = [0];
-		match std::io::stdin().read_exact(&mut buf) {
-			Ok(_) => buf[0],
-			Err(_) => 0xFF,
+	fn in_u8(&mut self, port: u16) -> u8 {
+		let mut input = self.input.lock().unwrap();
+		match port {
+			0 => input.pop_front().unwrap_or(0),
+			_ => !input.is_empty() as u8,
+		}
+	}
+}
+
+/// The settings of the terminal before it was switched to raw mode.
+static SAVED_TERMINAL: OnceLock<libc::termios> = OnceLock::new();
+
+/// Switches the terminal on stdin to raw mode, so key presses reach the console immediately
+/// and are not echoed. Ctrl-C still interrupts the simulator. The settings are restored when
+/// the process exits or panics, except when it is killed by a second Ctrl-C.
+pub fn raw_terminal() {
+	// SAFETY: The termios structure is plain data and the calls only access it.
+	unsafe {
+		let mut termios = std::mem::zeroed();
+		if libc::isatty(0) == 0 || libc::tcgetattr(0, &mut termios) != 0 {
+			return;
+		}
+		let _ = SAVED_TERMINAL.set(termios);
+		termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+		termios.c_cc[libc::VMIN] = 1;
+		termios.c_cc[libc::VTIME] = 0;
+		libc::tcsetattr(0, libc::TCSANOW, &termios);
+		libc::atexit(restore_terminal);
+	}
+	let hook = std::panic::take_hook();
+	std::panic::set_hook(Box::new(move |info| {
+		restore_terminal();
+		hook(info);
+	}));
+}
+
+extern "C" fn restore_terminal() {
+	if let Some(termios) = SAVED_TERMINAL.get() {
+		// SAFETY: The settings were read by tcgetattr.
+		unsafe {
+			libc::tcsetattr(0, libc::TCSANOW, termios);
 		}
 	}
 }
 
 pub struct Timer {
 	counter: u32,
-	irq: u8,
+	irq: Irq,
 	mode: Arc<AtomicU8>,
+
+	/// Count the counter in processor steps instead of microseconds.
+	deterministic: bool,
+
+	/// Steps until the next interrupt in deterministic mode.
+	remaining: u32,
 }
 
 impl Timer {
-	pub fn new(irq: u8) -> Timer {
+	pub fn new(irq: Irq, deterministic: bool) -> Timer {
 		Timer {
 			counter: 0,
 			irq,
 			mode: Arc::new(AtomicU8::new(0)),
+			deterministic,
+			remaining: 0,
 		}
 	}
 }
 
-fn run_timer(counter: u32, irq: u8, mode: Arc<AtomicU8>) {
+fn run_timer(counter: u32, irq: Irq, mode: Arc<AtomicU8>) {
 	thread::spawn(move || {
 		thread::sleep(Duration::from_micros(counter as u64));
 		let timer_mode = mode.load(Ordering::Relaxed);
 		if timer_mode & 0x01 == 0x01 {
-			schedule_interrupt(irq);
+			irq.raise();
 			run_timer(counter, irq, mode);
 		}
 	});
@@ -70,7 +344,11 @@ impl Device for Timer {
 			3 => self.counter ^= (self.counter & 0xFF000000) ^ ((byte as u32) << 24),
 			4 => {
 				self.mode.store(byte, Ordering::Relaxed);
-				run_timer(self.counter, self.irq, self.mode.clone());
+				if self.deterministic {
+					self.remaining = self.counter;
+				} else {
+					run_timer(self.counter, self.irq.clone(), self.mode.clone());
+				}
 			}
 			_ => unreachable!(),
 		}
@@ -79,49 +357,987 @@ impl Device for Timer {
 	fn in_u8(&mut self, _port: u16) -> u8 {
 		0xFF
 	}
+
+	/// The counter is written at once at port 0. The mode register is a byte wide, so only the
+	/// low byte is written to it.
+	fn out_u32(&mut self, port: u16, value: u32) {
+		match port {
+			0 => self.counter = value,
+			_ => self.out_u8(port, value as u8),
+		}
+	}
+
+	fn tick(&mut self) {
+		if !self.deterministic || self.mode.load(Ordering::Relaxed) & 0x01 == 0 {
+			return;
+		}
+		if self.remaining <= 1 {
+			self.remaining = self.counter;
+			self.irq.raise();
+		} else {
+			self.remaining -= 1;
+		}
+	}
+}
+
+/// Switches a shadow memory region between ROM and RAM. Writing 1 makes the region writable
+/// and 0 read-only again, and reading returns the current mode.
+pub struct ShadowControl {
+	writable: Arc<AtomicBool>,
+}
+
+impl ShadowControl {
+	pub fn new(writable: Arc<AtomicBool>) -> ShadowControl {
+		ShadowControl { writable }
+	}
+}
+
+impl Device for ShadowControl {
+	fn out_u8(&mut self, _port: u16, byte: u8) {
+		self.writable.store(byte & 1 == 1, Ordering::Relaxed);
+	}
+
+	fn in_u8(&mut self, _port: u16) -> u8 {
+		self.writable.load(Ordering::Relaxed) as u8
+	}
+}
+
+/// Pseudo random number generator (xorshift64*). Every read returns the next byte.
+pub struct Random {
+	state: u64,
+}
+
+impl Random {
+	/// Without a seed the generator is seeded from the host.
+	pub fn new(seed: Option<u64>) -> Random {
+		let seed = seed.unwrap_or_else(|| RandomState::new().build_hasher().finish());
+		// Scramble the seed with splitmix64, such that similar seeds give unrelated sequences.
+		let mut state = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+		state = (state ^ (state >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+		state = (state ^ (state >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+		state ^= state >> 31;
+		Random {
+			// The state must never be zero.
+			state: state.max(1),
+		}
+	}
+}
+
+impl Device for Random {
+	fn out_u8(&mut self, _port: u16, _byte: u8) {}
+
+	fn in_u8(&mut self, _port: u16) -> u8 {
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		(self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+	}
+}
+
+/// The faults a [`FaultInjector`] has armed against the port of another device, which
+/// [`PortDevices`] applies to the reads of that port.
+#[derive(Default)]
+pub struct ArmedFaults {
+	target: Option<u16>,
+	/// Xored into the next read of the target.
+	corruption: Option<u32>,
+	/// The number of steps for which reads of the target float high.
+	delay: u32,
+}
+
+impl ArmedFaults {
+	/// Whether the response of `port` is delayed, so reading it does not reach the device.
+	fn delays(&self, port: u16) -> bool {
+		self.target == Some(port) && self.delay > 0
+	}
+
+	/// Takes the corruption armed for the next read of `port`.
+	fn take_corruption(&mut self, port: u16) -> u32 {
+		if self.target == Some(port) {
+			self.corruption.take().unwrap_or(0)
+		} else {
+			0
+		}
+	}
+}
+
+/// Injects faults for testing how the guest copes with flaky hardware: spurious irqs, corrupted
+/// reads and delayed responses. It takes three 32-bit ports: the guest writes the port the
+/// faults apply to at the first port, a parameter at the second, and a command at the third.
+/// Narrower writes are zero extended. Reading the first two ports returns them and the third
+/// returns the number of faults injected so far. Every injected fault is an event.
+pub struct FaultInjector {
+	armed: Rc<RefCell<ArmedFaults>>,
+	parameter: u32,
+	/// The configured irq, which spurious irqs are raised on.
+	irq: Option<Irq>,
+	/// One in this many steps injects a random fault, or none when 0.
+	odds: u32,
+	random: Random,
+	injected: u32,
+	events: Option<DeviceEvents>,
+}
+
+impl FaultInjector {
+	/// The random faults are drawn from a generator seeded like the [`Random`] device.
+	pub fn new(irq: Option<Irq>, seed: Option<u64>) -> FaultInjector {
+		FaultInjector {
+			armed: Rc::new(RefCell::new(ArmedFaults::default())),
+			parameter: 0,
+			irq,
+			odds: 0,
+			random: Random::new(seed),
+			injected: 0,
+			events: None,
+		}
+	}
+
+	/// The faults to hand to [`PortDevices::inject_faults`].
+	pub fn armed(&self) -> Rc<RefCell<ArmedFaults>> {
+		self.armed.clone()
+	}
+
+	fn injected(&mut self, name: &str, data: &[(&str, u64)]) {
+		self.injected += 1;
+		if let Some(events) = &self.events {
+			events.emit(name, data);
+		}
+	}
+
+	/// Faults which need an irq or a target port which is not configured are ignored.
+	fn spurious_irq(&mut self) {
+		if let Some(irq) = &self.irq {
+			irq.raise();
+			let vector = irq.vector() as u64;
+			self.injected("spurious_irq", &[("vector", vector)]);
+		}
+	}
+
+	fn corrupt(&mut self, mask: u32) {
+		let Some(port) = self.armed.borrow().target else {
+			return;
+		};
+		self.armed.borrow_mut().corruption = Some(mask);
+		self.injected("corrupt", &[("port", port as u64), ("mask", mask as u64)]);
+	}
+
+	fn delay(&mut self, steps: u32) {
+		let Some(port) = self.armed.borrow().target else {
+			return;
+		};
+		self.armed.borrow_mut().delay = steps;
+		self.injected("delay", &[("port", port as u64), ("steps", steps as u64)]);
+	}
+}
+
+impl Device for FaultInjector {
+	fn out_u8(&mut self, port: u16, byte: u8) {
+		self.out_u32(port, byte as u32);
+	}
+
+	fn in_u8(&mut self, port: u16) -> u8 {
+		self.in_u32(port) as u8
+	}
+
+	fn out_u16(&mut self, port: u16, value: u16) {
+		self.out_u32(port, value as u32);
+	}
+
+	fn out_u32(&mut self, port: u16, value: u32) {
+		match port {
+			0 => self.armed.borrow_mut().target = Some(value as u16),
+			1 => self.parameter = value,
+			2 => match value {
+				FAULT_SPURIOUS_IRQ => self.spurious_irq(),
+				FAULT_CORRUPT => self.corrupt(self.parameter),
+				FAULT_DELAY => self.delay(self.parameter),
+				FAULT_RANDOM => self.odds = self.parameter,
+				_ => info(&format!("Unknown fault injector command {value}")),
+			},
+			_ => unreachable!(),
+		}
+	}
+
+	fn in_u16(&mut self, port: u16) -> u16 {
+		self.in_u32(port) as u16
+	}
+
+	fn in_u32(&mut self, port: u16) -> u32 {
+		match port {
+			0 => self
+				.armed
+				.borrow()
+				.target
+				.map_or(0xFFFF_FFFF, |port| port as u32),
+			1 => self.parameter,
+			_ => self.injected,
+		}
+	}
+
+	/// Counts down the delay, and injects a random fault at the odds set by the guest. A
+	/// random corruption flips one bit of the low byte.
+	fn tick(&mut self) {
+		{
+			let mut armed = self.armed.borrow_mut();
+			armed.delay = armed.delay.saturating_sub(1);
+		}
+		if self.odds == 0 || !self.random.in_u32(0).is_multiple_of(self.odds) {
+			return;
+		}
+		let draw = self.random.in_u32(0);
+		match draw % 3 {
+			0 => self.spurious_irq(),
+			1 => self.corrupt(1 << (draw / 3 % 8)),
+			_ => self.delay(1 + draw / 3 % MAX_RANDOM_DELAY),
+		}
+	}
+
+	fn attach_events(&mut self, events: DeviceEvents) {
+		self.events = Some(events);
+	}
+}
+
+/// The vector of the non-maskable interrupt.
+pub const NMI_VECTOR: u8 = 2;
+
+/// Interrupts raised by [`InterruptInjector`] devices or the host, which the first processor
+/// takes at the next instruction boundary. The NMI is taken even while IF is clear, and
+/// before the queued interrupts.
+#[derive(Default)]
+pub struct InjectedInterrupts {
+	queue: VecDeque<u8>,
+	nmi: bool,
+	/// An NMI was taken, so the next one waits until the processor executes iret.
+	nmi_blocked: bool,
+	/// The number of injected interrupts taken by the processor.
+	delivered: u32,
+}
+
+impl InjectedInterrupts {
+	/// Queues an interrupt through `vector`.
+	pub fn raise(&mut self, vector: u8) {
+		self.queue.push_back(vector);
+	}
+
+	pub fn raise_nmi(&mut self) {
+		self.nmi = true;
+	}
+
+	pub fn delivered(&self) -> u32 {
+		self.delivered
+	}
+
+	/// Whether an interrupt would be taken, when the maskable ones are taken if `maskable`.
+	pub fn pending(&self, maskable: bool) -> bool {
+		self.nmi && !self.nmi_blocked || maskable && !self.queue.is_empty()
+	}
+
+	/// Takes the next interrupt, when the maskable ones are taken if `maskable`.
+	pub fn take(&mut self, maskable: bool) -> Option<u8> {
+		let vector = if self.nmi && !self.nmi_blocked {
+			self.nmi = false;
+			self.nmi_blocked = true;
+			NMI_VECTOR
+		} else if maskable {
+			self.queue.pop_front()?
+		} else {
+			return None;
+		};
+		self.delivered += 1;
+		Some(vector)
+	}
+
+	/// Unblocks the NMI, as every iret does like on hardware.
+	pub fn end_nmi(&mut self) {
+		self.nmi_blocked = false;
+	}
+}
+
+/// Raises interrupts for testing interrupt handling. Writing a vector to the first port
+/// queues an interrupt through it, writing the second port raises an NMI, and reading the
+/// third port returns the number of injected interrupts the processor has taken. Reading the
+/// other ports returns all ones.
+pub struct InterruptInjector {
+	interrupts: Rc<RefCell<InjectedInterrupts>>,
+}
+
+impl InterruptInjector {
+	pub fn new(interrupts: Rc<RefCell<InjectedInterrupts>>) -> InterruptInjector {
+		InterruptInjector { interrupts }
+	}
+}
+
+impl Device for InterruptInjector {
+	fn out_u8(&mut self, port: u16, byte: u8) {
+		let mut interrupts = self.interrupts.borrow_mut();
+		match port {
+			0 => interrupts.raise(byte),
+			1 => interrupts.raise_nmi(),
+			_ => {}
+		}
+	}
+
+	fn in_u8(&mut self, port: u16) -> u8 {
+		self.in_u32(port) as u8
+	}
+
+	/// The vector is the low byte, and the NMI is raised once.
+	fn out_u16(&mut self, port: u16, value: u16) {
+		self.out_u8(port, value as u8);
+	}
+
+	fn out_u32(&mut self, port: u16, value: u32) {
+		self.out_u8(port, value as u8);
+	}
+
+	fn in_u16(&mut self, port: u16) -> u16 {
+		self.in_u32(port) as u16
+	}
+
+	fn in_u32(&mut self, port: u16) -> u32 {
+		match port {
+			2 => self.interrupts.borrow().delivered(),
+			_ => 0xFFFF_FFFF,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, serde::Deserialize, serde::Serialize)]
+pub enum LogLevel {
+	Error,
+	Warning,
+	Info,
+	Debug,
+}
+
+/// Logs messages from a buffer in guest memory. The physical address of the buffer is written
+/// as 32-bit registers at port 0 (low half) and port 1 (high half), and writing the length in
+/// bytes to port 2 logs the message tagged with rip and the time stamp counter. Narrower
+/// writes are zero extended.
+pub struct DebugLog {
+	level: LogLevel,
+	address: u64,
+	/// The length of a message to log at the next doorbell.
+	pending: Option<u64>,
+	sink: Box<dyn FnMut(String)>,
+}
+
+impl DebugLog {
+	pub fn new(level: LogLevel, sink: impl FnMut(String) + 'static) -> DebugLog {
+		DebugLog {
+			level,
+			address: 0,
+			pending: None,
+			sink: Box::new(sink),
+		}
+	}
+}
+
+impl Device for DebugLog {
+	fn out_u8(&mut self, port: u16, byte: u8) {
+		self.out_u32(port, byte as u32);
+	}
+
+	fn in_u8(&mut self, _port: u16) -> u8 {
+		0xFF
+	}
+
+	fn out_u16(&mut self, port: u16, value: u16) {
+		self.out_u32(port, value as u32);
+	}
+
+	fn out_u32(&mut self, port: u16, value: u32) {
+		match port {
+			0 => self.address = self.address & !0xFFFF_FFFF | value as u64,
+			1 => self.address = self.address & 0xFFFF_FFFF | (value as u64) << 32,
+			2 => self.pending = Some(value as u64),
+			_ => unreachable!(),
+		}
+	}
+
+	fn doorbell(&mut self, _port: u16, bus: &mut Bus) {
+		let Some(length) = self.pending.take() else {
+			return;
+		};
+		let tag = format!(
+			"rip 0x{:016X} tsc {}",
+			bus.instruction_pointer, bus.time_stamp_counter
+		);
+		if length > MAX_DEBUG_MESSAGE {
+			info(&format!(
+				"Debug log message of {length} bytes is too long ({tag})"
+			));
+			return;
+		}
+		if !bus.memory.physical_mapped(self.address, length) {
+			info(&format!(
+				"Debug log message at 0x{:016X} of {length} bytes is not in memory ({tag})",
+				self.address
+			));
+			return;
+		}
+		let mut buffer = vec![0; length as usize];
+		bus.memory.read_physical(self.address, &mut buffer);
+		let message = String::from_utf8_lossy(&buffer);
+		(self.sink)(format!(
+			"Guest {:?} ({tag}): {}",
+			self.level,
+			message.trim_end()
+		));
+	}
+}
+
+/// Where port accesses are logged.
+struct IoTrace {
+	/// Only accesses of the device with this name are logged.
+	device: Option<String>,
+	sink: Box<dyn FnMut(String)>,
+}
+
+/// Creates a device of an external kind from the options in its config entry and its irq, if
+/// it has one.
+pub type DeviceFactory = fn(&toml::Table, Option<Irq>) -> Result<Box<dyn Device>, String>;
+
+/// The kinds of devices which are not built in, so a device model can be added without
+/// changing the config format.
+#[derive(Default)]
+pub struct DeviceRegistry {
+	factories: BTreeMap<String, DeviceFactory>,
+}
+
+impl DeviceRegistry {
+	pub fn new() -> DeviceRegistry {
+		DeviceRegistry::default()
+	}
+
+	/// Makes `kind` available to `External` devices in the config, replacing an earlier
+	/// factory of the same kind.
+	pub fn register(&mut self, kind: &str, factory: DeviceFactory) {
+		self.factories.insert(kind.to_string(), factory);
+	}
+
+	/// Creates a device of a registered kind. An unknown kind is an error listing the
+	/// registered ones.
+	pub fn create(
+		&self,
+		kind: &str,
+		options: &toml::Table,
+		irq: Option<Irq>,
+	) -> Result<Box<dyn Device>, String> {
+		let Some(factory) = self.factories.get(kind) else {
+			let kinds: Vec<_> = self.factories.keys().map(String::as_str).collect();
+			return Err(if kinds.is_empty() {
+				format!("Unknown device kind {kind}, no external kinds are registered")
+			} else {
+				format!(
+					"Unknown device kind {kind}, the registered kinds are {}",
+					kinds.join(", ")
+				)
+			});
+		};
+		factory(options, irq).map_err(|err| format!("Device kind {kind}: {err}"))
+	}
 }
 
 pub struct PortDevices {
-	devices: Vec<Box<dyn Device>>,
+	devices: Vec<(String, Box<dyn Device>)>,
 	ports: HashMap<u16, (usize, u16)>,
+	trace: Option<IoTrace>,
+	events: Option<Rc<Events>>,
+	faults: Vec<Rc<RefCell<ArmedFaults>>>,
+	/// The instruction making the accesses and the number of instructions retired before it,
+	/// for the trace.
+	instruction: (u64, u64),
+	/// Raised by the irqs of the devices.
+	line: InterruptLine,
 }
+
 impl PortDevices {
 	pub fn new() -> Self {
 		Self {
 			devices: Vec::new(),
 			ports: HashMap::new(),
+			trace: None,
+			events: None,
+			faults: Vec::new(),
+			instruction: (0, 0),
+			line: InterruptLine::default(),
 		}
 	}
 
+	/// An irq delivered through `vector` to the processor owning the devices.
+	pub fn irq(&self, vector: u8) -> Irq {
+		Irq {
+			vector,
+			line: self.line.clone(),
+		}
+	}
+
+	/// The line the irqs of the devices raise.
+	pub(crate) fn interrupt_line(&self) -> &InterruptLine {
+		&self.line
+	}
+
+	/// Adds a device named after its type.
 	pub fn add<T>(&mut self, ports: &[u16], device: T)
 	where
 		T: Device + 'static,
 	{
+		let name = std::any::type_name::<T>().rsplit("::").next().unwrap();
+		self.add_named(name, ports, Box::new(device));
+	}
+
+	/// Adds a device with a name, which the io trace shows and can be filtered by.
+	pub fn add_named(&mut self, name: &str, ports: &[u16], device: Box<dyn Device>) {
 		let index = self.devices.len();
-		self.devices.push(Box::new(device));
+		self.devices.push((name.to_string(), device));
 		for (port, i) in ports.iter().zip(0..) {
 			self.ports.insert(*port, (index, i));
 		}
 	}
 
+	/// Logs every port access, or only those of the device named `device`, to the sink.
+	pub fn trace(
+		&mut self,
+		device: Option<String>,
+		sink: impl FnMut(String) + 'static,
+	) -> Result<(), String> {
+		if let Some(name) = &device
+			&& !self.devices.iter().any(|(device, _)| device == name)
+		{
+			return Err(format!("There is no device named {name} to trace"));
+		}
+		self.trace = Some(IoTrace {
+			device,
+			sink: Box::new(sink),
+		});
+		Ok(())
+	}
+
+	/// Applies the faults armed by a [`FaultInjector`] to the reads of the ports, along with
+	/// those of the injectors added before. Only the guest arms faults, through the ports of
+	/// the injector.
+	pub fn inject_faults(&mut self, faults: Rc<RefCell<ArmedFaults>>) {
+		self.faults.push(faults);
+	}
+
+	/// Emits the port accesses as events, and gives the devices their hooks.
+	pub fn set_events(&mut self, events: Rc<Events>) {
+		for (name, device) in &mut self.devices {
+			device.attach_events(DeviceEvents::new(events.clone(), name));
+		}
+		self.events = Some(events);
+	}
+
+	/// Sets the instruction pointer and the number of retired instructions shown by the trace
+	/// and the events.
+	pub fn set_instruction(&mut self, instruction_pointer: u64, retired: u64) {
+		self.instruction = (instruction_pointer, retired);
+		if let Some(events) = &self.events {
+			events.set_position(instruction_pointer, retired);
+		}
+	}
+
+	/// Logs an access of `size` bytes if it is traced, and emits it as an event.
+	fn record(&mut self, port: u16, size: usize, value: u32, write: bool) {
+		if self.trace.is_none() && self.events.is_none() {
+			return;
+		}
+		let device = self
+			.ports
+			.get(&port)
+			.map(|&(device, _)| self.devices[device].0.as_str());
+		let (instruction_pointer, retired) = self.instruction;
+		if let Some(events) = &self.events {
+			let event = Event::Io {
+				port,
+				bits: 8 * size,
+				value,
+				write,
+				device,
+			};
+			events.emit(instruction_pointer, retired, event);
+		}
+		let Some(trace) = &mut self.trace else {
+			return;
+		};
+		let name = device.unwrap_or("no device");
+		if trace.device.as_ref().is_some_and(|device| device != name) {
+			return;
+		}
+		(trace.sink)(format!(
+			"Io {} 0x{port:04X} {}-bit 0x{value:0width$X} {name} (rip 0x{instruction_pointer:016X} \
+			 instruction {retired})",
+			if write { "out" } else { "in" },
+			8 * size,
+			width = 2 * size,
+		));
+	}
+
 	pub fn out_u8(&mut self, port: u16, byte: u8) {
 		if let Some(&(device, port)) = self.ports.get(&port) {
-			self.devices[device].out_u8(port, byte);
+			self.devices[device].1.out_u8(port, byte);
+		}
+		self.record(port, 1, byte as u32, true);
+	}
+
+	/// Wide accesses go to the device owning `port` as a whole, so bytes are never written to
+	/// the devices at the following ports.
+	pub fn out_u16(&mut self, port: u16, value: u16) {
+		if let Some(&(device, port)) = self.ports.get(&port) {
+			self.devices[device].1.out_u16(port, value);
 		}
+		self.record(port, 2, value as u32, true);
 	}
 
 	pub fn out_u32(&mut self, port: u16, value: u32) {
-		for (byte, port) in value.to_le_bytes().into_iter().zip(port..) {
-			if let Some(&(device, port)) = self.ports.get(&port) {
-				self.devices[device].out_u8(port, byte);
-			}
+		if let Some(&(device, port)) = self.ports.get(&port) {
+			self.devices[device].1.out_u32(port, value);
 		}
+		self.record(port, 4, value, true);
+	}
+
+	/// Reads `size` bytes from the device owning `port` with `read`. A port without a device,
+	/// or with a delayed response, floats high and the device is not read.
+	fn read(
+		&mut self,
+		port: u16,
+		size: usize,
+		read: impl FnOnce(&mut dyn Device, u16) -> u32,
+	) -> u32 {
+		let floating = u32::MAX >> (32 - 8 * size);
+		// The faults are not borrowed while the device is read, as it may be the injector.
+		let faults = self.faults.clone();
+		let value = match self.ports.get(&port) {
+			_ if faults.iter().any(|faults| faults.borrow().delays(port)) => floating,
+			Some(&(device, port)) => read(self.devices[device].1.as_mut(), port),
+			None => floating,
+		};
+		let corruption = faults.iter().fold(0, |corruption, faults| {
+			corruption ^ faults.borrow_mut().take_corruption(port)
+		});
+		let value = (value ^ corruption) & floating;
+		self.record(port, size, value, false);
+		value
 	}
 
 	pub fn in_u8(&mut self, port: u16) -> u8 {
-		match self.ports.get(&port) {
-			Some(&(device, port)) => self.devices[device].in_u8(port),
-			None => 0xFF,
+		self.read(port, 1, |device, port| device.in_u8(port) as u32) as u8
+	}
+
+	pub fn in_u16(&mut self, port: u16) -> u16 {
+		self.read(port, 2, |device, port| device.in_u16(port) as u32) as u16
+	}
+
+	pub fn in_u32(&mut self, port: u16) -> u32 {
+		self.read(port, 4, |device, port| device.in_u32(port))
+	}
+
+	pub fn tick(&mut self) {
+		for (_, device) in &mut self.devices {
+			device.tick();
+		}
+	}
+
+	pub fn doorbell(&mut self, port: u16, bus: &mut Bus) {
+		if let Some(&(device, port)) = self.ports.get(&port) {
+			self.devices[device].1.doorbell(port, bus);
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		cell::RefCell,
+		collections::VecDeque,
+		rc::Rc,
+		sync::{Arc, Mutex},
+	};
+
+	use super::{
+		Bus, DebugLog, Device, DeviceRegistry, FaultInjector, LogLevel, PortDevices, Random, Timer,
+		UTF8Console,
+	};
+	use crate::memory::{
+		ConventionalMemory, MemoryManagementUnit, Permissions, PhysicalMemoryManagementUnit,
+	};
+
+	/// Records the ports and bytes written to it.
+	struct Recorder(Rc<RefCell<Vec<(u16, u8)>>>);
+
+	impl Device for Recorder {
+		fn out_u8(&mut self, port: u16, byte: u8) {
+			self.0.borrow_mut().push((port, byte));
+		}
+
+		fn in_u8(&mut self, _port: u16) -> u8 {
+			0xFF
+		}
+	}
+
+	#[test]
+	fn wide_access() {
+		let mut devices = PortDevices::new();
+		let register = Rc::new(RefCell::new(Vec::new()));
+		let next = Rc::new(RefCell::new(Vec::new()));
+		devices.add(&[0x10], Recorder(register.clone()));
+		devices.add(&[0x11], Recorder(next.clone()));
+
+		// Without an override the bytes go to the port, and the next device gets nothing.
+		devices.out_u32(0x10, 0x4433_2211);
+		devices.out_u16(0x10, 0x6655);
+		assert_eq!(
+			*register.borrow(),
+			[
+				(0, 0x11),
+				(0, 0x22),
+				(0, 0x33),
+				(0, 0x44),
+				(0, 0x55),
+				(0, 0x66)
+			]
+		);
+		assert!(next.borrow().is_empty());
+		assert_eq!(devices.in_u16(0x10), 0xFFFF);
+		assert_eq!(devices.in_u32(0x12), 0xFFFF_FFFF);
+
+		let mut random = Random::new(Some(1));
+		let mut wide = Random::new(Some(1));
+		let bytes: [u8; 4] = std::array::from_fn(|_| random.in_u8(0));
+		assert_eq!(wide.in_u32(0), u32::from_le_bytes(bytes));
+	}
+
+	#[test]
+	fn timer_counter() {
+		let mut timer = Timer::new(PortDevices::new().irq(0x20), true);
+		timer.out_u32(0, 0x1234_5678);
+		assert_eq!(timer.counter, 0x1234_5678);
+		// Writing the bytes one port at a time gives the same counter.
+		for (port, byte) in (0..4).zip(0x8765_4321u32.to_le_bytes()) {
+			timer.out_u8(port, byte);
+		}
+		assert_eq!(timer.counter, 0x8765_4321);
+	}
+
+	#[test]
+	fn debug_log() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 1 << 16, Permissions::default(), || {
+			ConventionalMemory::create(1 << 16)
+		});
+		let mut memory = MemoryManagementUnit::new(pmu);
+		// The message starts just before a page boundary and spans three pages.
+		let text: String = (0..3 * 4096)
+			.map(|i| (b'a' + (i % 26) as u8) as char)
+			.collect();
+		memory.write_physical(0x1F80, text.as_bytes());
+		let mut devices = PortDevices::new();
+		let messages = Rc::new(RefCell::new(Vec::new()));
+		let sink = messages.clone();
+		devices.add(
+			&[0x30, 0x31, 0x32],
+			DebugLog::new(LogLevel::Info, move |message| {
+				sink.borrow_mut().push(message)
+			}),
+		);
+		let mut log = |address: u64, length: u32| {
+			devices.out_u32(0x30, address as u32);
+			devices.out_u32(0x31, (address >> 32) as u32);
+			devices.out_u32(0x32, length);
+			let mut bus = Bus {
+				memory: &mut memory,
+				instruction_pointer: 0x1234,
+				time_stamp_counter: 56,
+			};
+			devices.doorbell(0x32, &mut bus);
+		};
+
+		log(0x1F80, text.len() as u32);
+		assert_eq!(
+			*messages.borrow(),
+			[format!(
+				"Guest Info (rip 0x0000000000001234 tsc 56): {text}"
+			)]
+		);
+
+		// Malformed descriptors are reported without logging anything.
+		log(0xFFFF, 2);
+		log(0x1_0000_0000, 1);
+		log(0, u32::MAX);
+		assert_eq!(messages.borrow().len(), 1);
+	}
+
+	#[test]
+	fn console_input() {
+		let input = Arc::new(Mutex::new(VecDeque::new()));
+		let mut devices = PortDevices::new();
+		devices.add(
+			&[0x3F8, 0x3F9],
+			UTF8Console {
+				input: input.clone(),
+			},
+		);
+		assert_eq!(devices.in_u8(0x3F9), 0);
+		assert_eq!(devices.in_u8(0x3F8), 0);
+		input.lock().unwrap().extend(b"ok");
+		let mut received = Vec::new();
+		while devices.in_u8(0x3F9) & 1 == 1 {
+			received.push(devices.in_u8(0x3F8));
+		}
+		assert_eq!(received, b"ok");
+		assert_eq!(devices.in_u8(0x3F8), 0);
+	}
+
+	#[test]
+	fn seeded_random_is_reproducible() {
+		let mut a = Random::new(Some(42));
+		let mut b = Random::new(Some(42));
+		let a: Vec<_> = (0..64).map(|_| a.in_u8(0)).collect();
+		let b: Vec<_> = (0..64).map(|_| b.in_u8(0)).collect();
+		assert_eq!(a, b);
+		let mut c = Random::new(Some(43));
+		assert_ne!(a, (0..64).map(|_| c.in_u8(0)).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn fault_injector() {
+		let input = Arc::new(Mutex::new(VecDeque::from(*b"abc")));
+		let mut devices = PortDevices::new();
+		devices.add(&[0x3F8], UTF8Console { input });
+		let injector = FaultInjector::new(None, Some(1));
+		devices.inject_faults(injector.armed());
+		devices.add(&[0x70, 0x71, 0x72], injector);
+		devices.out_u16(0x70, 0x3F8);
+
+		// A corruption applies to a single read.
+		devices.out_u32(0x71, 0x20);
+		devices.out_u8(0x72, 2);
+		assert_eq!(devices.in_u8(0x3F8), b'A');
+		assert_eq!(devices.in_u8(0x3F8), b'b');
+
+		// A delayed response floats high without reaching the device, so no input is lost.
+		devices.out_u32(0x71, 2);
+		devices.out_u8(0x72, 3);
+		assert_eq!(devices.in_u8(0x3F8), 0xFF);
+		devices.tick();
+		assert_eq!(devices.in_u16(0x3F8), 0xFFFF);
+		devices.tick();
+		assert_eq!(devices.in_u8(0x3F8), b'c');
+
+		// Without an irq spurious irqs are not injected.
+		devices.out_u8(0x72, 1);
+		assert_eq!(devices.in_u32(0x72), 2);
+		assert_eq!(devices.in_u16(0x70), 0x3F8);
+	}
+
+	#[test]
+	fn several_fault_injectors() {
+		let mut devices = PortDevices::new();
+		for ports in [[0x70, 0x71, 0x72], [0x74, 0x75, 0x76]] {
+			let injector = FaultInjector::new(None, Some(1));
+			devices.inject_faults(injector.armed());
+			devices.add(&ports, injector);
+			devices.out_u16(ports[0], 0x80);
 		}
+
+		// The corruptions of both injectors apply to the same read.
+		devices.out_u32(0x71, 0x01);
+		devices.out_u8(0x72, 2);
+		devices.out_u32(0x75, 0x10);
+		devices.out_u8(0x76, 2);
+		assert_eq!(devices.in_u8(0x80), 0xEE);
+		assert_eq!(devices.in_u8(0x80), 0xFF);
+
+		// A delay of either injector holds the response.
+		devices.out_u32(0x75, 1);
+		devices.out_u8(0x76, 3);
+		devices.out_u32(0x71, 0x01);
+		devices.out_u8(0x72, 2);
+		assert_eq!(devices.in_u8(0x80), 0xFE);
+		assert_eq!((devices.in_u32(0x72), devices.in_u32(0x76)), (2, 2));
+	}
+
+	#[test]
+	fn random_faults() {
+		let run = |seed| {
+			let mut devices = PortDevices::new();
+			let injector = FaultInjector::new(None, Some(seed));
+			devices.inject_faults(injector.armed());
+			devices.add(&[0x70, 0x71, 0x72], injector);
+			devices.out_u16(0x70, 0x80);
+			devices.out_u32(0x71, 4);
+			devices.out_u8(0x72, 4);
+			let reads: Vec<_> = (0..1000)
+				.map(|_| {
+					devices.tick();
+					devices.in_u8(0x80)
+				})
+				.collect();
+			(reads, devices.in_u32(0x72))
+		};
+		let (reads, injected) = run(7);
+		// About one in four steps injects a fault, of which the spurious irqs are dropped.
+		assert!((100..250).contains(&injected), "{injected}");
+		assert!(reads.iter().any(|read| read.count_ones() == 7));
+		assert_eq!(run(7), (reads.clone(), injected));
+		assert_ne!(run(8).0, reads);
+	}
+
+	#[test]
+	fn registry() {
+		/// A register which is read back, with its reset value taken from the options.
+		struct Latch(u8);
+
+		impl Device for Latch {
+			fn out_u8(&mut self, _port: u16, byte: u8) {
+				self.0 = byte;
+			}
+
+			fn in_u8(&mut self, _port: u16) -> u8 {
+				self.0
+			}
+		}
+
+		let mut registry = DeviceRegistry::new();
+		let err = registry
+			.create("Latch", &toml::Table::new(), None)
+			.err()
+			.unwrap();
+		assert_eq!(
+			err,
+			"Unknown device kind Latch, no external kinds are registered"
+		);
+
+		registry.register("Latch", |options, irq| {
+			assert_eq!(irq.map(|irq| irq.vector()), Some(0x24));
+			match options.get("reset").and_then(|value| value.as_integer()) {
+				Some(reset) => Ok(Box::new(Latch(reset as u8))),
+				None => Err("missing the reset option".to_string()),
+			}
+		});
+		registry.register("Uart", |_, _| Ok(Box::new(Latch(0))));
+		let options: toml::Table = toml::from_str("reset = 0x5A").unwrap();
+		let mut latch = registry
+			.create("Latch", &options, Some(PortDevices::new().irq(0x24)))
+			.unwrap();
+		assert_eq!(latch.in_u8(0), 0x5A);
+		latch.out_u8(0, 1);
+		assert_eq!(latch.in_u8(0), 1);
+		assert_eq!(
+			registry
+				.create(
+					"Latch",
+					&toml::Table::new(),
+					Some(PortDevices::new().irq(0x24))
+				)
+				.err()
+				.unwrap(),
+			"Device kind Latch: missing the reset option"
+		);
+		assert_eq!(
+			registry.create("Nic", &options, None).err().unwrap(),
+			"Unknown device kind Nic, the registered kinds are Latch, Uart"
+		);
 	}
 }
@@ -0,0 +1,353 @@
+use std::{
+	cell::{Cell, RefCell},
+	fmt::Write as _,
+	io::{BufWriter, Write},
+	rc::Rc,
+	sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel},
+	thread::{self, JoinHandle},
+};
+
+/// The version of the event format, which is in every event. It changes when a field is
+/// removed or changes its meaning, while new fields and event types keep it.
+pub const EVENT_VERSION: u32 = 1;
+
+/// The number of events waiting for the writer, beyond which new events are dropped instead of
+/// stalling the guest.
+const EVENT_BUFFER: usize = 4096;
+
+/// Something that happened in the machine, written as its `type` and the fields of the
+/// variant.
+pub enum Event<'a> {
+	/// A sampled retired instruction.
+	Retired {
+		instruction: &'a str,
+	},
+
+	/// An interrupt entered its service routine.
+	Interrupt {
+		vector: u64,
+		interrupt: &'a str,
+		error_code: u64,
+	},
+
+	/// A port access, where `device` is `None` for a port without a device.
+	Io {
+		port: u16,
+		bits: usize,
+		value: u32,
+		write: bool,
+		device: Option<&'a str>,
+	},
+
+	PageFault {
+		cr2: u64,
+		error_code: u32,
+	},
+
+	/// An event defined by a device, with named integer values.
+	Device {
+		device: &'a str,
+		name: &'a str,
+		data: &'a [(&'a str, u64)],
+	},
+
+	/// The last event, with the number of events dropped because the reader fell behind.
+	RunEnded {
+		reason: &'a str,
+		dropped: u64,
+	},
+}
+
+impl Event<'_> {
+	fn kind(&self) -> &'static str {
+		match self {
+			Event::Retired { .. } => "retired",
+			Event::Interrupt { .. } => "interrupt",
+			Event::Io { .. } => "io",
+			Event::PageFault { .. } => "page_fault",
+			Event::Device { .. } => "device",
+			Event::RunEnded { .. } => "run_ended",
+		}
+	}
+
+	/// The event as a line of JSON.
+	fn to_json(&self, instruction_pointer: u64, instructions: u64) -> String {
+		let mut line = format!(
+			"{{\"version\":{EVENT_VERSION},\"type\":\"{}\",\"instructions\":{instructions},\"rip\":{instruction_pointer}",
+			self.kind()
+		);
+		match self {
+			Event::Retired { instruction } => {
+				write!(line, ",\"instruction\":{}", json_string(instruction))
+			}
+			Event::Interrupt {
+				vector,
+				interrupt,
+				error_code,
+			} => write!(
+				line,
+				",\"vector\":{vector},\"interrupt\":{},\"error_code\":{error_code}",
+				json_string(interrupt)
+			),
+			Event::Io {
+				port,
+				bits,
+				value,
+				write,
+				device,
+			} => write!(
+				line,
+				",\"port\":{port},\"bits\":{bits},\"value\":{value},\"direction\":\"{}\",\"device\":{}",
+				if *write { "out" } else { "in" },
+				device.map_or("null".to_string(), json_string)
+			),
+			Event::PageFault { cr2, error_code } => {
+				write!(line, ",\"cr2\":{cr2},\"error_code\":{error_code}")
+			}
+			Event::Device { device, name, data } => {
+				let data: Vec<_> = data
+					.iter()
+					.map(|(key, value)| format!("{}:{value}", json_string(key)))
+					.collect();
+				write!(
+					line,
+					",\"device\":{},\"name\":{},\"data\":{{{}}}",
+					json_string(device),
+					json_string(name),
+					data.join(",")
+				)
+			}
+			Event::RunEnded { reason, dropped } => write!(
+				line,
+				",\"reason\":{},\"dropped\":{dropped}",
+				json_string(reason)
+			),
+		}
+		.unwrap();
+		line += "}\n";
+		line
+	}
+}
+
+/// Quotes a string for JSON.
+fn json_string(value: &str) -> String {
+	let mut quoted = String::from("\"");
+	for char in value.chars() {
+		match char {
+			'"' => quoted += "\\\"",
+			'\\' => quoted += "\\\\",
+			'\n' => quoted += "\\n",
+			char if char.is_control() => write!(quoted, "\\u{:04x}", char as u32).unwrap(),
+			char => quoted.push(char),
+		}
+	}
+	quoted.push('"');
+	quoted
+}
+
+/// Writes events as lines of JSON on a thread of its own, so a slow reader does not block the
+/// guest. Events which do not fit in the buffer are dropped and counted.
+pub struct Events {
+	sender: RefCell<Option<SyncSender<String>>>,
+	writer: RefCell<Option<JoinHandle<()>>>,
+	dropped: Cell<u64>,
+	/// The instruction pointer and the number of retired instructions, for events of devices.
+	position: Cell<(u64, u64)>,
+	/// Every this many retired instructions one is emitted.
+	retired_interval: Option<u64>,
+}
+
+impl Events {
+	pub fn new(out: impl Write + Send + 'static, retired_interval: Option<u64>) -> Events {
+		let (sender, receiver) = sync_channel(EVENT_BUFFER);
+		let writer = thread::spawn(move || write_events(BufWriter::new(out), receiver));
+		Events {
+			sender: RefCell::new(Some(sender)),
+			writer: RefCell::new(Some(writer)),
+			dropped: Cell::new(0),
+			position: Cell::new((0, 0)),
+			retired_interval: retired_interval.filter(|interval| *interval > 0),
+		}
+	}
+
+	pub fn emit(&self, instruction_pointer: u64, instructions: u64, event: Event) {
+		let Some(sender) = &*self.sender.borrow() else {
+			return;
+		};
+		let line = event.to_json(instruction_pointer, instructions);
+		if let Err(TrySendError::Full(_)) = sender.try_send(line) {
+			self.dropped.set(self.dropped.get() + 1);
+		}
+	}
+
+	/// Whether the instruction retired as number `count` is sampled.
+	pub fn samples_retired(&self, count: u64) -> bool {
+		self.retired_interval
+			.is_some_and(|interval| count.is_multiple_of(interval))
+	}
+
+	pub fn set_position(&self, instruction_pointer: u64, instructions: u64) {
+		self.position.set((instruction_pointer, instructions));
+	}
+
+	/// Emits `run_ended` and waits until every event is written. Later events are discarded.
+	pub fn finish(&self, instruction_pointer: u64, instructions: u64, reason: &str) {
+		if let Some(sender) = self.sender.borrow_mut().take() {
+			let event = Event::RunEnded {
+				reason,
+				dropped: self.dropped.get(),
+			};
+			// The last event waits for room rather than being dropped.
+			let _ = sender.send(event.to_json(instruction_pointer, instructions));
+		}
+		if let Some(writer) = self.writer.borrow_mut().take() {
+			let _ = writer.join();
+		}
+	}
+}
+
+/// Writes the events until the sender is gone, flushing whenever the buffer runs empty so the
+/// reader sees them promptly.
+fn write_events(mut out: impl Write, receiver: Receiver<String>) {
+	while let Ok(line) = receiver.recv() {
+		let _ = out.write_all(line.as_bytes());
+		for line in receiver.try_iter() {
+			let _ = out.write_all(line.as_bytes());
+		}
+		let _ = out.flush();
+	}
+}
+
+/// The hook a device emits its own events through, at the instruction which is executing.
+pub struct DeviceEvents {
+	events: Rc<Events>,
+	device: String,
+}
+
+impl DeviceEvents {
+	pub fn new(events: Rc<Events>, device: &str) -> DeviceEvents {
+		DeviceEvents {
+			events,
+			device: device.to_string(),
+		}
+	}
+
+	pub fn emit(&self, name: &str, data: &[(&str, u64)]) {
+		let (instruction_pointer, instructions) = self.events.position.get();
+		self.events.emit(
+			instruction_pointer,
+			instructions,
+			Event::Device {
+				device: &self.device,
+				name,
+				data,
+			},
+		);
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		io::Write,
+		sync::{Arc, Barrier, Mutex},
+	};
+
+	use crate::events::{EVENT_BUFFER, Event, Events, json_string};
+
+	/// Collects the written bytes. The first write waits at the first barrier, to tell it has
+	/// started, and at the second, until it is released.
+	struct Shared(Arc<Mutex<Vec<u8>>>, Option<(Arc<Barrier>, Arc<Barrier>)>);
+
+	impl Write for Shared {
+		fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+			if let Some((started, released)) = self.1.take() {
+				started.wait();
+				released.wait();
+			}
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> std::io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn event_format() {
+		assert_eq!(json_string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+		let io = Event::Io {
+			port: 0x3F8,
+			bits: 8,
+			value: 0x41,
+			write: true,
+			device: None,
+		};
+		assert_eq!(
+			io.to_json(0x1000, 3),
+			"{\"version\":1,\"type\":\"io\",\"instructions\":3,\"rip\":4096,\"port\":1016,\
+			 \"bits\":8,\"value\":65,\"direction\":\"out\",\"device\":null}\n"
+		);
+		let device = Event::Device {
+			device: "MemoryController",
+			name: "hot_add",
+			data: &[("base", 0x100000), ("pages", 16)],
+		};
+		assert!(
+			device
+				.to_json(0, 0)
+				.ends_with(",\"data\":{\"base\":1048576,\"pages\":16}}\n")
+		);
+	}
+
+	#[test]
+	fn slow_reader() {
+		let output = Arc::new(Mutex::new(Vec::new()));
+		let started = Arc::new(Barrier::new(2));
+		let released = Arc::new(Barrier::new(2));
+		let shared = Shared(output.clone(), Some((started.clone(), released.clone())));
+		let events = Events::new(shared, Some(2));
+		assert!(events.samples_retired(4));
+		assert!(!events.samples_retired(5));
+
+		// The writer is stuck writing the first event, so the buffer fills and the rest is
+		// dropped without blocking.
+		let fault = Event::PageFault {
+			cr2: 0,
+			error_code: 0,
+		};
+		events.emit(0, 0, fault);
+		started.wait();
+		let count = EVENT_BUFFER as u64 + 100;
+		for instructions in 1..=count {
+			let fault = Event::PageFault {
+				cr2: 0,
+				error_code: 0,
+			};
+			events.emit(0, instructions, fault);
+		}
+		released.wait();
+		events.finish(0x10, count, "test");
+		// Events after the end are discarded.
+		events.emit(
+			0,
+			0,
+			Event::RunEnded {
+				reason: "late",
+				dropped: 0,
+			},
+		);
+
+		let output = String::from_utf8(output.lock().unwrap().clone()).unwrap();
+		let lines: Vec<_> = output.lines().collect();
+		assert_eq!(lines.len(), 1 + EVENT_BUFFER + 1);
+		assert_eq!(
+			*lines.last().unwrap(),
+			format!(
+				"{{\"version\":1,\"type\":\"run_ended\",\"instructions\":{count},\"rip\":16,\
+				 \"reason\":\"test\",\"dropped\":100}}"
+			)
+		);
+	}
+}
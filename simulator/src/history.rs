@@ -0,0 +1,52 @@
+use std::collections::VecDeque;
+
+/// A bounded log which keeps the most recent entries.
+pub struct History<T> {
+	entries: VecDeque<T>,
+	capacity: usize,
+}
+
+impl<T> History<T> {
+	pub fn new(capacity: usize) -> History<T> {
+		History {
+			entries: VecDeque::with_capacity(capacity),
+			capacity,
+		}
+	}
+
+	pub fn push(&mut self, entry: T) {
+		if self.entries.len() == self.capacity {
+			self.entries.pop_front();
+		}
+		self.entries.push_back(entry);
+	}
+
+	/// Iterates the entries from oldest to newest.
+	pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> {
+		self.entries.iter()
+	}
+
+	/// Iterates the entries mutably from oldest to newest.
+	pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+		self.entries.iter_mut()
+	}
+
+	/// The newest entry.
+	pub fn last_mut(&mut self) -> Option<&mut T> {
+		self.entries.back_mut()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::History;
+
+	#[test]
+	fn keeps_most_recent() {
+		let mut history = History::new(3);
+		for i in 0..5 {
+			history.push(i);
+		}
+		assert_eq!(history.iter().copied().collect::<Vec<_>>(), [2, 3, 4]);
+	}
+}
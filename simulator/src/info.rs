@@ -0,0 +1,103 @@
+use crate::{
+	args::{DeviceType, default_irq_base},
+	instruction::Instruction,
+	interupt::Interrupt,
+	memory::PAGING_LEVELS,
+	state::DumpFormat,
+};
+
+/// Describes what the simulator implements, so users can tell whether a guest will run
+/// without reading the source. The lists come from the definitions themselves.
+pub fn machine_info(format: DumpFormat) -> String {
+	let levels = PAGING_LEVELS.map(|levels| (levels, 12 + 9 * levels));
+	match format {
+		DumpFormat::Human => {
+			let mut info = format!("Instructions ({}):\n", Instruction::NAMES.len());
+			for names in Instruction::NAMES.chunks(8) {
+				info += &format!("  {}\n", names.join(" "));
+			}
+			info += &format!("Devices: {}\n", DeviceType::names().join(", "));
+			info += "Paging: 4 KiB pages";
+			for (levels, bits) in levels {
+				info += &format!(", {levels} levels with {bits}-bit virtual addresses");
+			}
+			info += "\nExceptions:\n";
+			for exception in Interrupt::EXCEPTIONS {
+				info += &format!("  0x{:02X} {}\n", exception.vector(), exception.name());
+			}
+			info += &format!(
+				"Interrupt requests are delivered through vector irq_base + irq, where irq_base \
+				 defaults to {}\n",
+				default_irq_base()
+			);
+			info
+		}
+		DumpFormat::Json => {
+			let quoted = |names: &[&str]| {
+				let names: Vec<_> = names.iter().map(|name| format!("\"{name}\"")).collect();
+				format!("[{}]", names.join(","))
+			};
+			let levels: Vec<_> = levels
+				.iter()
+				.map(|(levels, bits)| {
+					format!("{{\"levels\":{levels},\"virtual_address_bits\":{bits}}}")
+				})
+				.collect();
+			let exceptions: Vec<_> = Interrupt::EXCEPTIONS
+				.iter()
+				.map(|exception| {
+					format!(
+						"{{\"vector\":{},\"name\":\"{}\"}}",
+						exception.vector(),
+						exception.name()
+					)
+				})
+				.collect();
+			format!(
+				"{{\"instructions\":{},\"devices\":{},\"page_size\":4096,\"paging\":[{}],\"exceptions\":[{}]}}\n",
+				quoted(Instruction::NAMES),
+				quoted(DeviceType::names()),
+				levels.join(","),
+				exceptions.join(","),
+			)
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use crate::{
+		args::{Device, DeviceType},
+		info::machine_info,
+		instruction::Instruction,
+		state::DumpFormat,
+	};
+
+	#[test]
+	fn machine_info_lists() {
+		assert!(Instruction::NAMES.contains(&"Hlt"));
+		let human = machine_info(DumpFormat::Human);
+		assert!(human.contains(&format!("Instructions ({}):", Instruction::NAMES.len())));
+		assert!(human.contains("  0x0E page fault\n"));
+		assert!(human.ends_with("vector irq_base + irq, where irq_base defaults to 32\n"));
+		let json = machine_info(DumpFormat::Json);
+		assert!(json.contains("{\"levels\":5,\"virtual_address_bits\":57}"));
+		assert!(json.contains("{\"vector\":13,\"name\":\"general protection\"}"));
+
+		// The listed names are the ones the config accepts. The device types with options fail
+		// on the missing options instead of the name.
+		let names = DeviceType::names();
+		assert_eq!(names.len(), 8);
+		assert!(human.contains(&format!("Devices: {}\n", names.join(", "))));
+		let parse = |device: &str| {
+			let src = format!("ports = []\ndevice_type = \"{device}\"");
+			toml::from_str::<Device>(&src).map_err(|err| err.to_string())
+		};
+		for device in names {
+			if let Err(err) = parse(device) {
+				assert!(!err.contains("unknown variant"), "{device}: {err}");
+			}
+		}
+		assert!(parse("Keyboard").unwrap_err().contains("unknown variant"));
+	}
+}
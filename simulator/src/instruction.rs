@@ -1,5 +1,337 @@
-use crate::{interupt::Interrupt, memory::MemoryManagementUnit};
+use std::fmt::Display;
 
+use crate::{interupt::Interrupt, memory::MemoryManagementUnit, state::REGISTER_NAMES};
+
+/// Instructions including prefixes can be at most 15 bytes long.
+const MAX_INSTRUCTION_LENGTH: u64 = 15;
+
+/// The default operand and address sizes of the decoder. Only decoding follows the mode, so
+/// the stack, branches and address arithmetic still behave as in long mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+	/// 16-bit operands, and 32-bit operands with the size override prefix. 16-bit addressing
+	/// is not implemented, so memory operands need the address size prefix.
+	#[value(name = "16")]
+	Bits16,
+
+	/// 32-bit operands and addresses. There is no REX prefix and the rip relative ModRM form
+	/// addresses its displacement absolutely.
+	#[value(name = "32")]
+	Bits32,
+
+	/// Long mode.
+	#[default]
+	#[value(name = "64")]
+	Bits64,
+}
+
+/// Whether addresses are computed in 32 bits and whether they would be 16-bit, given the mode
+/// and the address size prefix.
+fn address_size(mode: Mode, prefix: bool) -> (bool, bool) {
+	match mode {
+		Mode::Bits16 => (true, !prefix),
+		Mode::Bits32 => (true, prefix),
+		Mode::Bits64 => (prefix, false),
+	}
+}
+
+/// Adjusts a ModRM operand decoded with the long mode rules to the mode.
+fn legacy_operand(
+	rm: RM,
+	mode: Mode,
+	short_addressing: bool,
+	segment_override: SegmentOverride,
+) -> Result<RM, Interrupt> {
+	match rm {
+		RM::Reg(_) => Ok(rm),
+		_ if short_addressing => Err(Interrupt::UNDEFINED),
+		RM::RipRel {
+			displacement,
+			address_override,
+		} if mode != Mode::Bits64 => Ok(RM::Mem {
+			index: 4,
+			scale: 0,
+			base: 0xFF,
+			displacement,
+			address_override,
+			segment_override,
+		}),
+		_ => Ok(rm),
+	}
+}
+
+/// Opcodes of 16 and 32-bit code which are invalid in long mode, with their mnemonic.
+const LEGACY_OPCODES: [(u8, &str); 21] = [
+	(0x06, "push es"),
+	(0x07, "pop es"),
+	(0x0E, "push cs"),
+	(0x16, "push ss"),
+	(0x17, "pop ss"),
+	(0x1E, "push ds"),
+	(0x1F, "pop ds"),
+	(0x27, "daa"),
+	(0x2F, "das"),
+	(0x37, "aaa"),
+	(0x3F, "aas"),
+	(0x60, "pusha"),
+	(0x61, "popa"),
+	(0x62, "bound"),
+	(0x82, "alias of 0x80"),
+	(0x9A, "call far"),
+	(0xCE, "into"),
+	(0xD4, "aam"),
+	(0xD5, "aad"),
+	(0xD6, "salc"),
+	(0xEA, "jmp far"),
+];
+
+/// Explains an undefined instruction in long mode which would be valid in 16 or 32-bit code,
+/// either because its opcode was removed or because it starts with a REX prefix which was inc
+/// or dec. Such code was likely assembled for another mode.
+pub fn legacy_hint(opcode: &[u8]) -> Option<String> {
+	let start = opcode.iter().position(|byte| {
+		!matches!(
+			byte,
+			0x26 | 0x2E | 0x36 | 0x3E | 0x40..=0x4F | 0x64..=0x67 | 0xF0 | 0xF2 | 0xF3
+		)
+	})?;
+	let rex = opcode[..start]
+		.iter()
+		.find(|byte| (0x40..=0x4F).contains(*byte));
+	let legacy = LEGACY_OPCODES
+		.iter()
+		.find(|(byte, _)| *byte == opcode[start]);
+	let reason = match (legacy, rex) {
+		(Some((byte, name)), _) => format!("0x{byte:02X} ({name}) is invalid in 64-bit mode"),
+		(None, Some(rex)) => format!("0x{rex:02X} is a REX prefix in 64-bit mode, not inc or dec"),
+		(None, None) => return None,
+	};
+	Some(format!("{reason}, the code may be for 16 or 32 bits"))
+}
+
+/// Where the decoder reads instruction bytes from.
+pub trait FetchSource {
+	fn fetch_u8(&mut self, address: u64) -> Result<u8, Interrupt>;
+}
+
+/// Fetches through paging at a virtual address, like the processor.
+impl FetchSource for MemoryManagementUnit {
+	fn fetch_u8(&mut self, address: u64) -> Result<u8, Interrupt> {
+		MemoryManagementUnit::fetch_u8(self, address)
+	}
+}
+
+/// Code at address 0, so instructions can be decoded without a machine. Fetching past the end
+/// raises a page fault like code which is not mapped.
+impl FetchSource for &[u8] {
+	fn fetch_u8(&mut self, address: u64) -> Result<u8, Interrupt> {
+		usize::try_from(address)
+			.ok()
+			.and_then(|address| self.get(address))
+			.copied()
+			.ok_or(Interrupt::PageFault {
+				error_code: 0b10000,
+				cr2: address,
+			})
+	}
+}
+
+/// Decodes the instruction at a virtual address.
+pub fn decode(
+	mmu: &mut MemoryManagementUnit,
+	instruction_pointer: u64,
+) -> Result<(Instruction, u64), Interrupt> {
+	decode_in(mmu, instruction_pointer, Mode::Bits64)
+}
+
+/// Decodes the instruction at a virtual address with the default sizes of `mode`.
+pub fn decode_in(
+	mmu: &mut MemoryManagementUnit,
+	instruction_pointer: u64,
+	mode: Mode,
+) -> Result<(Instruction, u64), Interrupt> {
+	decode_from(mmu, instruction_pointer, mode)
+}
+
+/// A prefix byte of an instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Prefix {
+	/// A segment override, with the name of the segment register.
+	Segment(&'static str),
+	/// 66, which is also a mandatory prefix of sse instructions.
+	OperandSize,
+	/// 67
+	AddressSize,
+	Lock,
+	/// F2, which is also a mandatory prefix of sse instructions.
+	Repne,
+	/// F3, which is also a mandatory prefix of sse and string instructions.
+	Rep,
+	/// A REX prefix with its W, R, X and B bits.
+	Rex(u8),
+	/// A VEX prefix of this many bytes, which holds the REX and mandatory prefixes.
+	Vex(u8),
+}
+
+impl Display for Prefix {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Prefix::Segment(segment) => write!(f, "{segment}"),
+			Prefix::OperandSize => write!(f, "o16"),
+			Prefix::AddressSize => write!(f, "a32"),
+			Prefix::Lock => write!(f, "lock"),
+			Prefix::Repne => write!(f, "repne"),
+			Prefix::Rep => write!(f, "rep"),
+			Prefix::Rex(bits) => {
+				write!(f, "rex")?;
+				if *bits != 0 {
+					write!(f, ".")?;
+				}
+				for (bit, name) in [(8, 'w'), (4, 'r'), (2, 'x'), (1, 'b')] {
+					if bits & bit != 0 {
+						write!(f, "{name}")?;
+					}
+				}
+				Ok(())
+			}
+			Prefix::Vex(length) => write!(f, "vex{length}"),
+		}
+	}
+}
+
+impl Prefix {
+	/// The legacy or REX prefix encoded by `byte`. 40 to 4F are only REX prefixes in long mode,
+	/// as outside it they are the one byte inc and dec, which are not implemented and raise an
+	/// undefined opcode. VEX prefixes are not included, as they are followed by their payload.
+	fn parse(byte: u8, mode: Mode) -> Option<Prefix> {
+		Some(match byte {
+			0x26 => Prefix::Segment("es"),
+			0x2E => Prefix::Segment("cs"),
+			0x36 => Prefix::Segment("ss"),
+			0x3E => Prefix::Segment("ds"),
+			0x64 => Prefix::Segment("fs"),
+			0x65 => Prefix::Segment("gs"),
+			0x66 => Prefix::OperandSize,
+			0x67 => Prefix::AddressSize,
+			0xF0 => Prefix::Lock,
+			0xF2 => Prefix::Repne,
+			0xF3 => Prefix::Rep,
+			0x40..0x50 if mode == Mode::Bits64 => Prefix::Rex(byte & 0xF),
+			_ => return None,
+		})
+	}
+}
+
+/// The prefixes in effect for the opcode, collected by the decoder. The legacy prefixes form
+/// the groups of the segment overrides, the operand size override, the address size override,
+/// and lock with the two rep prefixes. Of several prefixes in a group the last one wins, so
+/// `F2 F3` is `F3` and `64 2E` has no segment override, as the cs, ds, es and ss overrides are
+/// null in long mode. A REX prefix is only used directly before the opcode, so one followed by
+/// a legacy prefix is ignored, like on hardware.
+struct Prefixes {
+	size_override: bool,
+	address_override: bool,
+	lock_rep: Option<LockRep>,
+	segment_override: SegmentOverride,
+	rex: Option<Rex>,
+	/// The prefixes in encoding order without the ignored REX prefixes, when recording them for
+	/// disassembly.
+	encoded: Option<Vec<Prefix>>,
+}
+
+impl Prefixes {
+	fn new() -> Prefixes {
+		Prefixes {
+			size_override: false,
+			address_override: false,
+			lock_rep: None,
+			segment_override: SegmentOverride::None,
+			rex: None,
+			encoded: None,
+		}
+	}
+
+	/// Also records the prefixes in encoding order. Execution does not need them, so it
+	/// does not pay for the allocation.
+	fn recording() -> Prefixes {
+		Prefixes {
+			encoded: Some(Vec::new()),
+			..Prefixes::new()
+		}
+	}
+
+	fn record(&mut self, prefix: Prefix) {
+		if let Some(encoded) = &mut self.encoded {
+			encoded.push(prefix);
+		}
+	}
+
+	fn apply(&mut self, prefix: Prefix) {
+		// A REX prefix followed by another prefix is ignored.
+		if self.rex.take().is_some()
+			&& let Some(encoded) = &mut self.encoded
+		{
+			encoded.pop();
+		}
+		self.record(prefix);
+		match prefix {
+			Prefix::Segment("fs") => self.segment_override = SegmentOverride::Fs,
+			Prefix::Segment("gs") => self.segment_override = SegmentOverride::Gs,
+			Prefix::Segment(_) => self.segment_override = SegmentOverride::None,
+			Prefix::OperandSize => self.size_override = true,
+			Prefix::AddressSize => self.address_override = true,
+			Prefix::Lock => self.lock_rep = Some(LockRep::Lock),
+			Prefix::Repne => self.lock_rep = Some(LockRep::Repne),
+			Prefix::Rep => self.lock_rep = Some(LockRep::Repe),
+			Prefix::Rex(bits) => self.rex = Some(Rex::new(bits)),
+			Prefix::Vex(_) => unreachable!("VEX prefixes are decoded with their payload"),
+		}
+	}
+}
+
+/// An instruction with the prefixes it was encoded with, for disassembly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decoded {
+	pub instruction: Instruction,
+	pub size: u64,
+	/// The prefixes in encoding order.
+	pub prefixes: Vec<Prefix>,
+}
+
+/// Writes the instruction with a lock prefix, which is accepted and ignored, unless a rep
+/// prefix follows it and wins. The rep prefixes are part of the string instructions, and the
+/// others are part of the operands or the mandatory prefixes of sse instructions, so they are
+/// not repeated.
+impl Display for Decoded {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let lock_rep = self
+			.prefixes
+			.iter()
+			.rfind(|prefix| matches!(prefix, Prefix::Lock | Prefix::Repne | Prefix::Rep));
+		if lock_rep == Some(&Prefix::Lock) {
+			write!(f, "lock ")?;
+		}
+		write!(f, "{}", self.instruction)
+	}
+}
+
+/// Decodes the instruction at a virtual address along with the prefixes the decoder used, so
+/// an ignored REX prefix is left out. Execution uses [`decode`], which does not collect them.
+pub fn decode_with_prefixes<F: FetchSource + ?Sized>(
+	mmu: &mut F,
+	instruction_pointer: u64,
+	mode: Mode,
+) -> Result<Decoded, Interrupt> {
+	let mut prefixes = Prefixes::recording();
+	let (instruction, size) = decode_prefixed(mmu, instruction_pointer, mode, &mut prefixes)?;
+	Ok(Decoded {
+		instruction,
+		size,
+		prefixes: prefixes.encoded.unwrap_or_default(),
+	})
+}
+
+#[derive(Clone, Copy)]
 enum LockRep {
 	Lock,
 	Rep,
@@ -26,6 +358,44 @@ impl Rex {
 	}
 }
 
+/// The fields of a VEX prefix. The register extension bits and vvvv are stored inverted.
+#[derive(Clone, Copy)]
+struct Vex {
+	rex: Rex,
+	/// The opcode map, where 1 is 0F and 2 is 0F 38.
+	map: u8,
+	/// The extra register operand.
+	vvvv: u8,
+	/// The vector length is 256 bits.
+	l: bool,
+	/// The implied mandatory prefix, where 1 is 66, 2 is F3 and 3 is F2.
+	pp: u8,
+}
+
+impl Vex {
+	/// The two byte form (C5), which implies the 0F map and clear REX.W, REX.X and REX.B.
+	fn two_byte(byte: u8) -> Vex {
+		Vex {
+			rex: Rex::new(!byte >> 5 & 0b100),
+			map: 1,
+			vvvv: !byte >> 3 & 0xF,
+			l: (byte >> 2) & 1 == 1,
+			pp: byte & 0b11,
+		}
+	}
+
+	/// The three byte form (C4).
+	fn three_byte(byte1: u8, byte2: u8) -> Vex {
+		Vex {
+			rex: Rex::new(byte2 >> 4 & 0b1000 | !byte1 >> 5 & 0b111),
+			map: byte1 & 0x1F,
+			vvvv: !byte2 >> 3 & 0xF,
+			l: (byte2 >> 2) & 1 == 1,
+			pp: byte2 & 0b11,
+		}
+	}
+}
+
 fn rex_w(rex: Option<Rex>) -> bool {
 	match rex {
 		Some(rex) => rex.w,
@@ -67,7 +437,7 @@ pub enum RM {
 	RipRel {
 		displacement: u32,
 
-		/// If this flag is set the address should truncated to 32 bits.
+		/// If this flag is set the address is computed in 32 bits.
 		address_override: bool,
 	},
 	Mem {
@@ -76,7 +446,7 @@ pub enum RM {
 		base: u8,
 		displacement: u32,
 
-		/// If this flag is set the address should truncated to 32 bits.
+		/// If this flag is set the address is computed in 32 bits.
 		address_override: bool,
 
 		/// Add fsbase or gsbase.
@@ -88,14 +458,44 @@ pub enum RM {
 pub struct Reg(pub u8);
 
 impl Reg {
+	/// ah, ch, dh and bh are numbered after the 16 general purpose registers.
+	pub const HIGH_BYTE: u8 = 16;
+
 	fn parse_suffix(opcode: u8, rex: Option<Rex>) -> Reg {
 		Reg(((rex_b(rex) as u8) << 3) | (opcode & 0x07))
 	}
+
+	/// Without REX the registers 4 to 7 are ah to bh instead of spl to dil.
+	fn parse_suffix_byte(opcode: u8, rex: Option<Rex>) -> Reg {
+		match rex {
+			None if opcode & 0x04 != 0 => Reg(Reg::HIGH_BYTE + (opcode & 0x03)),
+			_ => Reg::parse_suffix(opcode, rex),
+		}
+	}
+}
+
+/// A condition code of the conditional instructions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition(pub u8);
+
+impl Condition {
+	fn parse(opcode: u8) -> Condition {
+		Condition(opcode & 0x0F)
+	}
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Immediate(pub u64);
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+	Reg(Reg),
+	RM(RM),
+	Immediate(Immediate),
+	/// An xmm register or a 128 bit memory operand.
+	Xmm(RM),
+}
+
 impl Immediate {
 	fn parse(immediate: u64) -> Immediate {
 		Immediate(immediate)
@@ -143,27 +543,27 @@ fn parse_sib_no_base(
 	}
 }
 
-fn read_modrm(
-	mmu: &mut MemoryManagementUnit,
+fn read_modrm<F: FetchSource + ?Sized>(
+	mmu: &mut F,
 	size: &mut u64,
 	instruction_pointer: u64,
 	address_override: bool,
 	segment_override: SegmentOverride,
 	rex: Option<Rex>,
 ) -> Result<(u8, RM), Interrupt> {
-	let modrm_byte = mmu.read_u8(instruction_pointer + *size)?;
+	let modrm_byte = mmu.fetch_u8(instruction_pointer + *size)?;
 	*size += 1;
 	let reg = (modrm_byte >> 3) & 0x7;
 	let rm_field = modrm_byte & 0x7;
 	let rm = match modrm_byte >> 6 {
 		0x00 => match rm_field {
 			4 => {
-				let sib_byte = mmu.read_u8(instruction_pointer + *size)?;
+				let sib_byte = mmu.fetch_u8(instruction_pointer + *size)?;
 				*size += 1;
 				if sib_byte & 7 == 5 {
 					let mut displacement_bytes = [0; 4];
 					for i in 0..4 {
-						displacement_bytes[i] = mmu.read_u8(instruction_pointer + *size)?;
+						displacement_bytes[i] = mmu.fetch_u8(instruction_pointer + *size)?;
 						*size += 1;
 					}
 					parse_sib_no_base(
@@ -180,7 +580,7 @@ fn read_modrm(
 			5 => {
 				let mut displacement_bytes = [0; 4];
 				for i in 0..4 {
-					displacement_bytes[i] = mmu.read_u8(instruction_pointer + *size)?;
+					displacement_bytes[i] = mmu.fetch_u8(instruction_pointer + *size)?;
 					*size += 1;
 				}
 				RM::RipRel {
@@ -199,9 +599,9 @@ fn read_modrm(
 		},
 		0x01 => {
 			if rm_field == 4 {
-				let sib_byte = mmu.read_u8(instruction_pointer + *size)?;
+				let sib_byte = mmu.fetch_u8(instruction_pointer + *size)?;
 				*size += 1;
-				let displacement = mmu.read_u8(instruction_pointer + *size)? as u32;
+				let displacement = mmu.fetch_u8(instruction_pointer + *size)? as i8 as u32;
 				*size += 1;
 				parse_sib(
 					sib_byte,
@@ -211,7 +611,7 @@ fn read_modrm(
 					rex,
 				)
 			} else {
-				let displacement = mmu.read_u8(instruction_pointer + *size)? as u32;
+				let displacement = mmu.fetch_u8(instruction_pointer + *size)? as i8 as u32;
 				*size += 1;
 				RM::Mem {
 					index: 4,
@@ -225,11 +625,11 @@ fn read_modrm(
 		}
 		0x02 => {
 			if rm_field == 4 {
-				let sib_byte = mmu.read_u8(instruction_pointer + *size)?;
+				let sib_byte = mmu.fetch_u8(instruction_pointer + *size)?;
 				*size += 1;
 				let mut displacement_bytes = [0; 4];
 				for i in 0..4 {
-					displacement_bytes[i] = mmu.read_u8(instruction_pointer + *size)?;
+					displacement_bytes[i] = mmu.fetch_u8(instruction_pointer + *size)?;
 					*size += 1;
 				}
 				let displacement = u32::from_le_bytes(displacement_bytes);
@@ -243,7 +643,7 @@ fn read_modrm(
 			} else {
 				let mut displacement_bytes = [0; 4];
 				for i in 0..4 {
-					displacement_bytes[i] = mmu.read_u8(instruction_pointer + *size)?;
+					displacement_bytes[i] = mmu.fetch_u8(instruction_pointer + *size)?;
 					*size += 1;
 				}
 				let displacement = u32::from_le_bytes(displacement_bytes);
@@ -263,23 +663,81 @@ fn read_modrm(
 	Ok((((rex_r(rex) as u8) << 3) | reg, rm))
 }
 
-fn read_immediate(
-	mmu: &mut MemoryManagementUnit,
+/// Reads an immediate of `N` bytes following the `size` bytes decoded so far. `size` is only
+/// advanced once every byte has been fetched, so a fault leaves it at the start of the
+/// immediate.
+fn read_immediate<const N: usize, F: FetchSource + ?Sized>(
+	mmu: &mut F,
 	size: &mut u64,
 	instruction_pointer: u64,
-	nbytes: u8,
 ) -> Result<u64, Interrupt> {
+	const { assert!(N <= 8, "immediates are at most 8 bytes") };
 	let mut bytes = [0; 8];
-	for i in 0..nbytes {
-		bytes[i as usize] = mmu.read_u8(instruction_pointer + *size)?;
-		*size += 1;
+	for (i, byte) in bytes[..N].iter_mut().enumerate() {
+		*byte = mmu.fetch_u8(instruction_pointer + *size + i as u64)?;
 	}
+	*size += N as u64;
 	Ok(u64::from_le_bytes(bytes))
 }
 
 // so: Size override prefix
 // w: REX.w
+// mem: ModRM must address memory
+// reg: ModRM must address a register
+// f2: F2 prefix is required
+// f3: F3 prefix is required
+// np: F2 and F3 prefixes are not allowed
+// vex: VEX encoded, where V is the vvvv register
+// sim: Simulator specific semantics, listed in Instruction::SIMULATOR_SPECIFIC. The
+//      simulator specific operations reuse standard encodings which are either meaningless
+//      without the hardware they control (wrmsr, rdmsr) or trap to a hypervisor (vmcall), so
+//      any assembler can emit them and the rest of the opcode space is left to x86.
+// Opcodes starting with 0F38 are in the 0F 38 map.
+// Opcodes which would not lex as a number are prefixed with 0x.
 simulator_macros::generate_instructions!(
+	AdcA8Imm 14 A Imm8 :;
+	AdcA16Imm 15 A Imm16 : so;
+	AdcA32Imm 15 A Imm32 :;
+	AdcA64Imm 15 A Imm32 : w;
+	AdcxReg32RM 0F38F6 R RM : so;
+	AdcxReg64RM 0F38F6 R RM : so w;
+	AddA8Imm 04 A Imm8 :;
+	AddA16Imm 05 A Imm16 : so;
+	AddA32Imm 05 A Imm32 :;
+	AddA64Imm 05 A Imm32 : w;
+	AddsdXmmRM 0F58 XR XRM : f2;
+	AdoxReg32RM 0F38F6 R RM : f3;
+	AdoxReg64RM 0F38F6 R RM : f3 w;
+	AndA8Imm 24 A Imm8 :;
+	AndA16Imm 25 A Imm16 : so;
+	AndA32Imm 25 A Imm32 :;
+	AndA64Imm 25 A Imm32 : w;
+	AndnReg32RM 0F38F2 R V RM : vex;
+	AndnReg64RM 0F38F2 R V RM : vex w;
+	BextrReg32RM 0F38F7 R RM V : vex;
+	BextrReg64RM 0F38F7 R RM V : vex w;
+	BzhiReg32RM 0F38F5 R RM V : vex;
+	BzhiReg64RM 0F38F5 R RM V : vex w;
+	Cld FC :;
+	Clflush 0FAE07 RM : mem;
+	Cli FA :;
+	CmpA8Imm 3C A Imm8 :;
+	CmpA16Imm 3D A Imm16 : so;
+	CmpA32Imm 3D A Imm32 :;
+	CmpA64Imm 3D A Imm32 : w;
+	Cmps8 0xA6 :;
+	Cmps16 0xA7 : so;
+	Cmps32 0xA7 :;
+	Cmps64 0xA7 : w;
+	Cvtsi2sdXmmRM32 0F2A XR RM : f2;
+	Cvtsi2sdXmmRM64 0F2A XR RM : f2 w;
+	Cvttsd2siReg32RM 0F2C R XRM : f2;
+	Cvttsd2siReg64RM 0F2C R XRM : f2 w;
+	DivsdXmmRM 0F5E XR XRM : f2;
+	Fldcw D905 RM : mem;
+	Fninit DBE3 :;
+	Fnstcw D907 RM : mem;
+	Fwait 9B :;
 	Hlt F4 :;
 	In8 E4 Imm8 :;
 	In16 E5 Imm8 : so;
@@ -291,10 +749,24 @@ simulator_macros::generate_instructions!(
 	IncRM16 FF00 RM : so;
 	IncRM32 FF00 RM :;
 	IncRM64 FF00 RM : w;
+	Ins8 6C :;
+	Ins16 6D : so;
+	Ins32 6D :;
+	Int CD Imm8 :;
+	Int3 CC :;
+	Invlpg 0F0107 RM : mem;
 	Iret CF :;
+	JccRel8 70 CC Imm8 :;
+	JmpRM64 FF04 RM :;
 	JmpRel8 EB Imm8 :;
 	JmpRel32 E9 Imm32 :;
-	MovReg8Imm B0 SR Imm8 :;
+	Ldmxcsr 0FAE02 RM : mem np;
+	Lfence 0FAEE8 : np;
+	Lidt 0F0103 RM : mem;
+	Lmsw 0F0106 RM :;
+	Mfence 0FAEF0 : np;
+	MovDrRM 0F23 R RM : reg;
+	MovReg8Imm B0 SR8 Imm8 :;
 	MovReg16Imm B8 SR Imm16 : so;
 	MovReg32Imm B8 SR Imm32 :;
 	MovReg64Imm B8 SR Imm64 : w;
@@ -306,45 +778,673 @@ simulator_macros::generate_instructions!(
 	MovRM16Reg 89 RM R : so;
 	MovRM32Reg 89 RM R :;
 	MovRM64Reg 89 RM R : w;
+	MovRMDr 0F21 RM R : reg;
+	MovapsRMXmm 0F29 XRM XR : np;
+	MovapsXmmRM 0F28 XR XRM : np;
+	MovdRM32Xmm 0F7E RM XR : so;
+	MovdXmmRM32 0F6E XR RM : so;
+	MovdqaRMXmm 0F7F XRM XR : so;
+	MovdqaXmmRM 0F6F XR XRM : so;
+	MovdquRMXmm 0F7F XRM XR : f3;
+	MovdquXmmRM 0F6F XR XRM : f3;
+	MovqRM64Xmm 0F7E RM XR : so w;
+	MovqXmmRM64 0F6E XR RM : so w;
+	MovsdRMXmm 0F11 XRM XR : f2;
+	MovsdXmmRM 0F10 XR XRM : f2;
+	MovupsRMXmm 0F11 XRM XR : np;
+	MovupsXmmRM 0F10 XR XRM : np;
+	MulsdXmmRM 0F59 XR XRM : f2;
+	MulxReg32RM 0F38F6 R V RM : vex f2;
+	MulxReg64RM 0F38F6 R V RM : vex f2 w;
+	OrA8Imm 0C A Imm8 :;
+	OrA16Imm 0D A Imm16 : so;
+	OrA32Imm 0D A Imm32 :;
+	OrA64Imm 0D A Imm32 : w;
 	Out8 E6 Imm8 :;
 	Out16 E7 Imm8 : so;
 	Out32 E7 Imm8 :;
+	Out8D EE :;
+	Out16D EF : so;
+	Out32D EF :;
+	Outs8 0x6E :;
+	Outs16 6F : so;
+	Outs32 6F :;
+	PaddbXmmRM 0FFC XR XRM : so;
+	PadddXmmRM 0FFE XR XRM : so;
+	PaddqXmmRM 0FD4 XR XRM : so;
+	PaddwXmmRM 0FFD XR XRM : so;
+	PandXmmRM 0FDB XR XRM : so;
+	PandnXmmRM 0FDF XR XRM : so;
+	PcmpeqbXmmRM 0F74 XR XRM : so;
+	PcmpeqdXmmRM 0F76 XR XRM : so;
+	PcmpeqwXmmRM 0F75 XR XRM : so;
+	PmovmskbReg32Xmm 0FD7 R XRM : so reg;
 	PopReg16 58 SR : so;
 	PopReg64 58 SR :;
+	PorXmmRM 0FEB XR XRM : so;
+	PsubbXmmRM 0FF8 XR XRM : so;
+	PsubdXmmRM 0FFA XR XRM : so;
+	PsubqXmmRM 0FFB XR XRM : so;
+	PsubwXmmRM 0FF9 XR XRM : so;
 	PushReg16 50 SR : so;
 	PushReg64 50 SR :;
-	Swi4 3F01 RM :;
-	Wrcr 3F00 Imm8 RM :;
+	PxorXmmRM 0FEF XR XRM : so;
+	Rdcr 0F32 : sim;
+	Rdfsbase32 0FAE00 RM : f3 reg;
+	Rdfsbase64 0FAE00 RM : f3 reg w;
+	Rdgsbase32 0FAE01 RM : f3 reg;
+	Rdgsbase64 0FAE01 RM : f3 reg w;
+	Rdpmc 0F33 :;
+	Rdtsc 0F31 :;
+	Rdtscp 0F01F9 :;
+	RepIns8 6C : f3;
+	RepIns16 6D : f3 so;
+	RepIns32 6D : f3;
+	RepOuts8 0x6E : f3;
+	RepOuts16 6F : f3 so;
+	RepOuts32 6F : f3;
+	RepeCmps8 0xA6 : f3;
+	RepeCmps16 0xA7 : f3 so;
+	RepeCmps32 0xA7 : f3;
+	RepeCmps64 0xA7 : f3 w;
+	RepeScas8 0xAE : f3;
+	RepeScas16 0xAF : f3 so;
+	RepeScas32 0xAF : f3;
+	RepeScas64 0xAF : f3 w;
+	RepneCmps8 0xA6 : f2;
+	RepneCmps16 0xA7 : f2 so;
+	RepneCmps32 0xA7 : f2;
+	RepneCmps64 0xA7 : f2 w;
+	RepneScas8 0xAE : f2;
+	RepneScas16 0xAF : f2 so;
+	RepneScas32 0xAF : f2;
+	RepneScas64 0xAF : f2 w;
+	SarxReg32RM 0F38F7 R RM V : vex f3;
+	SarxReg64RM 0F38F7 R RM V : vex f3 w;
+	SbbA8Imm 1C A Imm8 :;
+	SbbA16Imm 1D A Imm16 : so;
+	SbbA32Imm 1D A Imm32 :;
+	SbbA64Imm 1D A Imm32 : w;
+	Scas8 0xAE :;
+	Scas16 0xAF : so;
+	Scas32 0xAF :;
+	Scas64 0xAF : w;
+	Sfence 0FAEF8 : np;
+	Sgdt 0F0100 RM : mem;
+	ShlxReg32RM 0F38F7 R RM V : vex so;
+	ShlxReg64RM 0F38F7 R RM V : vex so w;
+	ShrxReg32RM 0F38F7 R RM V : vex f2;
+	ShrxReg64RM 0F38F7 R RM V : vex f2 w;
+	Sidt 0F0101 RM : mem;
+	Smsw 0F0104 RM :;
+	Std FD :;
+	Sti FB :;
+	Stmxcsr 0FAE03 RM : mem np;
+	SubA8Imm 2C A Imm8 :;
+	SubA16Imm 2D A Imm16 : so;
+	SubA32Imm 2D A Imm32 :;
+	SubA64Imm 2D A Imm32 : w;
+	SubsdXmmRM 0F5C XR XRM : f2;
+	Swapgs 0F01F8 :;
+	Swi4 0F2203 RM :;
+	TestRM8Reg 84 RM R :;
+	TestRM16Reg 85 RM R : so;
+	TestRM32Reg 85 RM R :;
+	TestRM64Reg 85 RM R : w;
+	TzcntReg16RM 0FBC R RM : f3 so;
+	TzcntReg32RM 0FBC R RM : f3;
+	TzcntReg64RM 0FBC R RM : f3 w;
+	UcomisdXmmRM 0F2E XR XRM : so;
+	Vmcall 0F01C1 : sim;
+	Wbinvd 0F09 :;
+	Wrcr 0F30 : sim;
+	Wrfsbase32 0FAE02 RM : f3 reg;
+	Wrfsbase64 0FAE02 RM : f3 reg w;
+	Wrgsbase32 0FAE03 RM : f3 reg;
+	Wrgsbase64 0FAE03 RM : f3 reg w;
+	XchgRM8Reg 86 RM R :;
+	XchgRM16Reg 87 RM R : so;
+	XchgRM32Reg 87 RM R :;
+	XchgRM64Reg 87 RM R : w;
+	Xgetbv 0F01D0 :;
+	XorA8Imm 34 A Imm8 :;
+	XorA16Imm 35 A Imm16 : so;
+	XorA32Imm 35 A Imm32 :;
+	XorA64Imm 35 A Imm32 : w;
+	Xsetbv 0F01D1 :;
 );
 
+/// Followed by the high byte registers.
+const REGISTER_NAMES_8: [&str; 20] = [
+	"al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil", "r8b", "r9b", "r10b", "r11b", "r12b",
+	"r13b", "r14b", "r15b", "ah", "ch", "dh", "bh",
+];
+
+const REGISTER_NAMES_16: [&str; 16] = [
+	"ax", "cx", "dx", "bx", "sp", "bp", "si", "di", "r8w", "r9w", "r10w", "r11w", "r12w", "r13w",
+	"r14w", "r15w",
+];
+
+const REGISTER_NAMES_32: [&str; 16] = [
+	"eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi", "r8d", "r9d", "r10d", "r11d", "r12d",
+	"r13d", "r14d", "r15d",
+];
+
+const CONDITION_NAMES: [&str; 16] = [
+	"o", "no", "b", "ae", "e", "ne", "be", "a", "s", "ns", "p", "np", "l", "ge", "le", "g",
+];
+
+fn register_name(Reg(reg): Reg, width: u32) -> &'static str {
+	match width {
+		8 => REGISTER_NAMES_8[reg as usize],
+		16 => REGISTER_NAMES_16[reg as usize],
+		32 => REGISTER_NAMES_32[reg as usize],
+		_ => REGISTER_NAMES[reg as usize],
+	}
+}
+
+fn signed_hex(value: i64) -> String {
+	if value < 0 {
+		format!("-0x{:x}", value.unsigned_abs())
+	} else {
+		format!("0x{value:x}")
+	}
+}
+
+/// Formats a value to be added to a preceding term.
+fn signed_term(value: i64) -> String {
+	if value < 0 {
+		format!("- 0x{:x}", value.unsigned_abs())
+	} else {
+		format!("+ 0x{value:x}")
+	}
+}
+
+/// Formats a memory operand in NASM syntax without a size keyword.
+fn format_address(rm: RM) -> String {
+	match rm {
+		RM::Reg(_) => unreachable!("register operands have no address"),
+		RM::RipRel {
+			displacement,
+			address_override,
+		} => {
+			let rip = if address_override { "eip" } else { "rip" };
+			format!("[{rip} {}]", signed_term(displacement as i32 as i64))
+		}
+		RM::Mem {
+			index,
+			scale,
+			base,
+			displacement,
+			address_override,
+			segment_override,
+		} => {
+			let width = if address_override { 32 } else { 64 };
+			let mut address = match segment_override {
+				SegmentOverride::None => String::new(),
+				SegmentOverride::Fs => "fs:".to_string(),
+				SegmentOverride::Gs => "gs:".to_string(),
+			};
+			let mut terms = Vec::new();
+			if base != 0xFF {
+				terms.push(register_name(Reg(base), width).to_string());
+			}
+			if index != 4 {
+				terms.push(format!(
+					"{}*{}",
+					register_name(Reg(index), width),
+					1 << scale
+				));
+			}
+			let displacement = displacement as i32 as i64;
+			if terms.is_empty() {
+				address += &format!("0x{:x}", displacement as u32);
+			} else {
+				address += &terms.join(" + ");
+				if displacement != 0 {
+					address += &format!(" {}", signed_term(displacement));
+				}
+			}
+			format!("[{address}]")
+		}
+	}
+}
+
+/// Formats an operand of the given width in NASM syntax.
+fn format_operand(operand: Operand, width: u32) -> String {
+	match operand {
+		Operand::Reg(Reg(reg)) | Operand::RM(RM::Reg(reg)) => {
+			register_name(Reg(reg), width).to_string()
+		}
+		Operand::RM(rm) => {
+			let size = match width {
+				8 => "byte",
+				16 => "word",
+				32 => "dword",
+				_ => "qword",
+			};
+			format!("{size} {}", format_address(rm))
+		}
+		Operand::Immediate(Immediate(value)) => {
+			let mask = u64::MAX >> (64 - width);
+			format!("0x{:x}", value & mask)
+		}
+		Operand::Xmm(RM::Reg(reg)) => format!("xmm{reg}"),
+		Operand::Xmm(rm) => format_address(rm),
+	}
+}
+
+impl Display for Instruction {
+	/// Formats the instruction in NASM syntax. Relative jumps are written relative to `$`
+	/// assuming no prefixes.
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match *self {
+			Instruction::Hlt {} => return write!(f, "hlt"),
+			Instruction::Iret {} => return write!(f, "iret"),
+			Instruction::Int { operand0 } => return write!(f, "int 0x{:x}", operand0.0),
+			Instruction::Int3 {} => return write!(f, "int3"),
+			Instruction::Wrcr {} => return write!(f, "wrmsr"),
+			Instruction::Rdcr {} => return write!(f, "rdmsr"),
+			Instruction::In8 { operand0 } => return write!(f, "in al, 0x{:x}", operand0.0),
+			Instruction::In16 { operand0 } => return write!(f, "in ax, 0x{:x}", operand0.0),
+			Instruction::In32 { operand0 } => return write!(f, "in eax, 0x{:x}", operand0.0),
+			Instruction::In8D {} => return write!(f, "in al, dx"),
+			Instruction::In16D {} => return write!(f, "in ax, dx"),
+			Instruction::In32D {} => return write!(f, "in eax, dx"),
+			Instruction::Out8 { operand0 } => return write!(f, "out 0x{:x}, al", operand0.0),
+			Instruction::Out16 { operand0 } => return write!(f, "out 0x{:x}, ax", operand0.0),
+			Instruction::Out32 { operand0 } => return write!(f, "out 0x{:x}, eax", operand0.0),
+			Instruction::Out8D {} => return write!(f, "out dx, al"),
+			Instruction::Out16D {} => return write!(f, "out dx, ax"),
+			Instruction::Out32D {} => return write!(f, "out dx, eax"),
+			Instruction::Ins8 {} => return write!(f, "insb"),
+			Instruction::Ins16 {} => return write!(f, "insw"),
+			Instruction::Ins32 {} => return write!(f, "insd"),
+			Instruction::Outs8 {} => return write!(f, "outsb"),
+			Instruction::Outs16 {} => return write!(f, "outsw"),
+			Instruction::Outs32 {} => return write!(f, "outsd"),
+			Instruction::RepIns8 {} => return write!(f, "rep insb"),
+			Instruction::RepIns16 {} => return write!(f, "rep insw"),
+			Instruction::RepIns32 {} => return write!(f, "rep insd"),
+			Instruction::RepOuts8 {} => return write!(f, "rep outsb"),
+			Instruction::RepOuts16 {} => return write!(f, "rep outsw"),
+			Instruction::RepOuts32 {} => return write!(f, "rep outsd"),
+			Instruction::Cmps8 {} => return write!(f, "cmpsb"),
+			Instruction::Cmps16 {} => return write!(f, "cmpsw"),
+			Instruction::Cmps32 {} => return write!(f, "cmpsd"),
+			Instruction::Cmps64 {} => return write!(f, "cmpsq"),
+			Instruction::Scas8 {} => return write!(f, "scasb"),
+			Instruction::Scas16 {} => return write!(f, "scasw"),
+			Instruction::Scas32 {} => return write!(f, "scasd"),
+			Instruction::Scas64 {} => return write!(f, "scasq"),
+			Instruction::RepeCmps8 {} => return write!(f, "repe cmpsb"),
+			Instruction::RepeCmps16 {} => return write!(f, "repe cmpsw"),
+			Instruction::RepeCmps32 {} => return write!(f, "repe cmpsd"),
+			Instruction::RepeCmps64 {} => return write!(f, "repe cmpsq"),
+			Instruction::RepeScas8 {} => return write!(f, "repe scasb"),
+			Instruction::RepeScas16 {} => return write!(f, "repe scasw"),
+			Instruction::RepeScas32 {} => return write!(f, "repe scasd"),
+			Instruction::RepeScas64 {} => return write!(f, "repe scasq"),
+			Instruction::RepneCmps8 {} => return write!(f, "repne cmpsb"),
+			Instruction::RepneCmps16 {} => return write!(f, "repne cmpsw"),
+			Instruction::RepneCmps32 {} => return write!(f, "repne cmpsd"),
+			Instruction::RepneCmps64 {} => return write!(f, "repne cmpsq"),
+			Instruction::RepneScas8 {} => return write!(f, "repne scasb"),
+			Instruction::RepneScas16 {} => return write!(f, "repne scasw"),
+			Instruction::RepneScas32 {} => return write!(f, "repne scasd"),
+			Instruction::RepneScas64 {} => return write!(f, "repne scasq"),
+			Instruction::JccRel8 {
+				operand0: Condition(condition),
+				operand1,
+			} => {
+				let target = 2 + operand1.0 as i8 as i64;
+				let condition = CONDITION_NAMES[condition as usize];
+				return write!(
+					f,
+					"j{condition} short ${}",
+					signed_term(target).replace(' ', "")
+				);
+			}
+			Instruction::JmpRel8 { operand0 } => {
+				let target = 2 + operand0.0 as i8 as i64;
+				return write!(f, "jmp short ${}", signed_term(target).replace(' ', ""));
+			}
+			Instruction::JmpRel32 { operand0 } => {
+				let target = 5 + operand0.0 as i32 as i64;
+				return write!(f, "jmp near ${}", signed_term(target).replace(' ', ""));
+			}
+			Instruction::Swi4 { operand0 } => {
+				return write!(f, "mov cr3, {}", format_operand(Operand::RM(operand0), 64));
+			}
+			Instruction::MovDrRM { operand0, operand1 } => {
+				let operand1 = format_operand(Operand::RM(operand1), 64);
+				return write!(f, "mov dr{}, {operand1}", operand0.0);
+			}
+			Instruction::MovRMDr { operand0, operand1 } => {
+				let operand0 = format_operand(Operand::RM(operand0), 64);
+				return write!(f, "mov {operand0}, dr{}", operand1.0);
+			}
+			Instruction::Lidt { operand0 }
+			| Instruction::Sidt { operand0 }
+			| Instruction::Sgdt { operand0 }
+			| Instruction::Invlpg { operand0 }
+			| Instruction::Clflush { operand0 }
+			| Instruction::Ldmxcsr { operand0 }
+			| Instruction::Fldcw { operand0 }
+			| Instruction::Fnstcw { operand0 }
+			| Instruction::Stmxcsr { operand0 } => {
+				return write!(f, "{} {}", self.mnemonic(), format_address(operand0));
+			}
+			Instruction::Cvtsi2sdXmmRM32 { operand0, operand1 } => {
+				let operand1 = format_operand(Operand::RM(operand1), 32);
+				return write!(f, "cvtsi2sd xmm{}, {operand1}", operand0.0);
+			}
+			Instruction::Cvtsi2sdXmmRM64 { operand0, operand1 } => {
+				let operand1 = format_operand(Operand::RM(operand1), 64);
+				return write!(f, "cvtsi2sd xmm{}, {operand1}", operand0.0);
+			}
+			Instruction::Cvttsd2siReg32RM { operand0, operand1 } => {
+				let operand1 = format_operand(Operand::Xmm(operand1), 64);
+				return write!(f, "cvttsd2si {}, {operand1}", register_name(operand0, 32));
+			}
+			Instruction::Cvttsd2siReg64RM { operand0, operand1 } => {
+				let operand1 = format_operand(Operand::Xmm(operand1), 64);
+				return write!(f, "cvttsd2si {}, {operand1}", register_name(operand0, 64));
+			}
+			Instruction::Smsw {
+				operand0: RM::Reg(reg),
+			} => {
+				return write!(f, "smsw {}", register_name(Reg(reg), 32));
+			}
+			Instruction::Smsw { operand0 } | Instruction::Lmsw { operand0 } => {
+				return write!(
+					f,
+					"{} {}",
+					self.mnemonic(),
+					format_operand(Operand::RM(operand0), 16)
+				);
+			}
+			_ => (),
+		}
+
+		let width = self.operand_width();
+		let operands: Vec<_> = self
+			.operands()
+			.into_iter()
+			.map(|operand| match operand {
+				Operand::Immediate(Immediate(value)) if self.sign_extended_immediate() => {
+					signed_hex(value as i32 as i64)
+				}
+				operand => format_operand(operand, width),
+			})
+			.collect();
+		write!(f, "{}", self.mnemonic())?;
+		if !operands.is_empty() {
+			write!(f, " {}", operands.join(", "))?;
+		}
+		Ok(())
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use std::process::Command;
 
 	use crate::{
-		instruction::{Instruction, decode},
-		memory::{MemoryManagementUnit, PhysicalMemoryManagementUnit, ReadOnlyMemory},
+		instruction::{
+			Instruction, Mode, Prefix, RM, Reg, SegmentOverride, decode, decode_from,
+			decode_with_prefixes, legacy_hint,
+		},
+		interupt::Interrupt,
+		memory::{MemoryManagementUnit, Permissions, PhysicalMemoryManagementUnit, ReadOnlyMemory},
 	};
 
-	fn test_instruction(data: &[u8], expected: Instruction) {
+	/// Decodes the bytes followed by the ones read from unmapped physical memory.
+	fn decode_bytes(data: &[u8]) -> Result<(Instruction, u64), Interrupt> {
+		let mut padded = data.to_vec();
+		padded.resize(data.len() + 16, 0xFF);
+		decode_from(&mut padded.as_slice(), 0, Mode::Bits64)
+	}
+
+	#[test]
+	fn prefixes() {
+		let decoded = |code: &[u8]| {
+			let mut padded = code.to_vec();
+			padded.resize(code.len() + 16, 0xFF);
+			decode_with_prefixes(&mut padded.as_slice(), 0, Mode::Bits64).unwrap()
+		};
+		// REX.W takes precedence over the operand size prefix.
+		let mov = decoded(&[0x66, 0x48, 0x89, 0xC8]);
+		assert_eq!(
+			mov.instruction,
+			Instruction::MovRM64Reg {
+				operand0: RM::Reg(0),
+				operand1: Reg(1)
+			}
+		);
+		assert_eq!(mov.size, 4);
+		assert_eq!(mov.prefixes, [Prefix::OperandSize, Prefix::Rex(0b1000)]);
+		assert_eq!(mov.to_string(), "mov rax, rcx");
+
+		let inc = decoded(&[0xF0, 0x64, 0x49, 0xFF, 0x00]);
+		assert_eq!(
+			inc.prefixes,
+			[Prefix::Lock, Prefix::Segment("fs"), Prefix::Rex(0b1001)]
+		);
+		assert_eq!(inc.to_string(), format!("lock {}", inc.instruction));
+		let names: Vec<_> = inc.prefixes.iter().map(|x| x.to_string()).collect();
+		assert_eq!(names, ["lock", "fs", "rex.wb"]);
+
+		assert_eq!(decoded(&[0x0F, 0x30]).prefixes, []);
+
+		// A REX prefix followed by another prefix is ignored and not reported.
+		let mov = decoded(&[0x48, 0x66, 0x89, 0xC8]);
+		assert_eq!(mov.to_string(), "mov ax, cx");
+		assert_eq!(mov.prefixes, [Prefix::OperandSize]);
+		assert_eq!(
+			decoded(&[0x41, 0x48, 0x89, 0xC8]).prefixes,
+			[Prefix::Rex(0b1000)]
+		);
+		assert_eq!(
+			decoded(&[0x2E, 0xC4, 0xE2, 0x7A, 0xF7, 0xC1]).prefixes,
+			[Prefix::Segment("cs"), Prefix::Vex(3)]
+		);
+	}
+
+	#[test]
+	fn prefix_combinations() {
+		let disassemble = |code: &[u8]| match decode_bytes(code) {
+			Ok((instruction, size)) => {
+				assert_eq!(size, code.len() as u64, "{code:02X?}");
+				instruction.to_string()
+			}
+			Err(_) => "undefined".to_string(),
+		};
+		// Of several prefixes in a group the last one wins, and REX only counts directly
+		// before the opcode.
+		for (code, expected) in [
+			(&[0x66, 0x66, 0x89, 0xC8][..], "mov ax, cx"),
+			(&[0x48, 0x66, 0x89, 0xC8], "mov ax, cx"),
+			(&[0x66, 0x48, 0x89, 0xC8], "mov rax, rcx"),
+			(&[0x41, 0x48, 0x89, 0xC8], "mov rax, rcx"),
+			(&[0x48, 0x41, 0x89, 0xC8], "mov r8d, ecx"),
+			(&[0x48, 0xF3, 0x0F, 0xAE, 0xC0], "rdfsbase eax"),
+			(&[0xF3, 0x48, 0x0F, 0xAE, 0xC0], "rdfsbase rax"),
+			(&[0x64, 0x65, 0x8B, 0x00], "mov eax, dword [gs:rax]"),
+			(&[0x65, 0x64, 0x8B, 0x00], "mov eax, dword [fs:rax]"),
+			// The cs override is null in long mode, but still replaces the fs override.
+			(&[0x64, 0x2E, 0x8B, 0x00], "mov eax, dword [rax]"),
+			(&[0x2E, 0x64, 0x8B, 0x00], "mov eax, dword [fs:rax]"),
+			(&[0x67, 0x67, 0x8B, 0x00], "mov eax, dword [eax]"),
+			// The mandatory prefixes take precedence over the size override in any order, and
+			// F3 0F 10 is movss, which is not implemented.
+			(&[0xF3, 0xF2, 0x0F, 0x10, 0xC1], "movsd xmm0, xmm1"),
+			(&[0xF2, 0xF3, 0x0F, 0x10, 0xC1], "undefined"),
+			(&[0x66, 0xF2, 0x0F, 0x10, 0xC1], "movsd xmm0, xmm1"),
+			(&[0xF2, 0x66, 0x0F, 0x10, 0xC1], "movsd xmm0, xmm1"),
+			// The last rep prefix selects between repe and repne.
+			(&[0x66, 0xA7], "cmpsw"),
+			(&[0xF3, 0xF2, 0xA6], "repne cmpsb"),
+			(&[0xF2, 0xF3, 0x48, 0xAF], "repe scasq"),
+			// A VEX prefix may follow a segment override but not a REX prefix.
+			(&[0x2E, 0xC4, 0xE2, 0x7A, 0xF7, 0xC1], "sarx eax, ecx, eax"),
+			(&[0x48, 0xC4, 0xE2, 0x7A, 0xF7, 0xC1], "undefined"),
+		] {
+			assert_eq!(disassemble(code), expected, "{code:02X?}");
+		}
+		assert_eq!(disassemble(&[0x66; 15]), "undefined");
+
+		// A rep prefix after lock wins.
+		let decoded = |code: &[u8]| decode_with_prefixes(&mut &code[..], 0, Mode::Bits64).unwrap();
+		let inc = decoded(&[0xF0, 0xF3, 0x48, 0xFF, 0x00]);
+		assert_eq!(inc.to_string(), inc.instruction.to_string());
+		let inc = decoded(&[0xF3, 0xF0, 0x48, 0xFF, 0x00]);
+		assert_eq!(inc.to_string(), format!("lock {}", inc.instruction));
+	}
+
+	#[test]
+	fn mnemonics() {
+		let mnemonic = |code: &[u8]| decode_bytes(code).unwrap().0.mnemonic();
+		assert_eq!(mnemonic(&[0x48, 0x05, 0x01, 0x00, 0x00, 0x00]), "add");
+		assert_eq!(mnemonic(&[0x75, 0x00]), "jcc");
+		assert_eq!(mnemonic(&[0x0F, 0x01, 0xF9]), "rdtscp");
+		assert_eq!(decode_bytes(&[0xCC]).unwrap(), (Instruction::Int3 {}, 1));
+		let (int, _) = decode_bytes(&[0xCD, 0x80]).unwrap();
+		assert_eq!(int.to_string(), "int 0x80");
+		// The sign extended immediate of the 64 bit form is printed as a negative number.
+		let (add, _) = decode_bytes(&[0x48, 0x05, 0xFF, 0xFF, 0xFF, 0xFF]).unwrap();
+		assert_eq!(add.to_string(), "add rax, -0x1");
+	}
+
+	#[test]
+	fn legacy_opcodes() {
+		let hint = |code: &[u8]| match decode_bytes(code) {
+			Err(Interrupt::Undefined { .. }) => legacy_hint(code),
+			_ => panic!("{code:02X?} is not undefined"),
+		};
+		assert_eq!(
+			hint(&[0x27]).unwrap(),
+			"0x27 (daa) is invalid in 64-bit mode, the code may be for 16 or 32 bits"
+		);
+		assert!(hint(&[0x06]).unwrap().starts_with("0x06 (push es)"));
+		assert!(hint(&[0x66, 0x61]).unwrap().starts_with("0x61 (popa)"));
+		// inc eax followed by pop ds
+		assert!(hint(&[0x40, 0x1F]).unwrap().starts_with("0x1F (pop ds)"));
+		// dec ecx followed by an undefined opcode
+		assert_eq!(
+			hint(&[0x49, 0x0F, 0x0B]).unwrap(),
+			"0x49 is a REX prefix in 64-bit mode, not inc or dec, the code may be for 16 or 32 \
+			 bits"
+		);
+		assert_eq!(hint(&[0x0F, 0x0B]), None);
+	}
+
+	#[test]
+	fn legacy_modes() {
+		let decoded = |mut code: &[u8], mode| decode_from(&mut code, 0, mode).ok();
+		// mov bx, ax and with the size prefix mov ebx, eax
+		assert_eq!(
+			decoded(&[0x89, 0xC3], Mode::Bits16),
+			Some((
+				Instruction::MovRM16Reg {
+					operand0: RM::Reg(3),
+					operand1: Reg(0),
+				},
+				2
+			))
+		);
+		assert_eq!(
+			decoded(&[0x66, 0x89, 0xC3], Mode::Bits16),
+			Some((
+				Instruction::MovRM32Reg {
+					operand0: RM::Reg(3),
+					operand1: Reg(0),
+				},
+				3
+			))
+		);
+		// The prefix of movd xmm0, eax is not a size override.
+		assert!(matches!(
+			decoded(&[0x66, 0x0F, 0x6E, 0xC0], Mode::Bits16),
+			Some((Instruction::MovdXmmRM32 { .. }, 4))
+		));
+		// 16-bit addressing is not implemented.
+		assert_eq!(decoded(&[0x89, 0x03], Mode::Bits16), None);
+		assert_eq!(decoded(&[0x67, 0x66, 0x8B, 0x03], Mode::Bits32), None);
+
+		let absolute = RM::Mem {
+			index: 4,
+			scale: 0,
+			base: 0xFF,
+			displacement: 0x1000,
+			address_override: true,
+			segment_override: SegmentOverride::None,
+		};
+		// mov eax, [0x1000] without rip relative addressing
+		assert_eq!(
+			decoded(&[0x8B, 0x05, 0x00, 0x10, 0x00, 0x00], Mode::Bits32),
+			Some((
+				Instruction::MovReg32RM {
+					operand0: Reg(0),
+					operand1: absolute,
+				},
+				6
+			))
+		);
+		assert_eq!(
+			decoded(&[0x67, 0x8B, 0x05, 0x00, 0x10, 0x00, 0x00], Mode::Bits16),
+			Some((
+				Instruction::MovReg16RM {
+					operand0: Reg(0),
+					operand1: absolute,
+				},
+				7
+			))
+		);
+		// 0x48 is no REX.W but dec eax, which is not implemented.
+		assert_eq!(decoded(&[0x48, 0x89, 0xC3], Mode::Bits32), None);
+		assert_eq!(decoded(&[0x48, 0x89, 0xC3], Mode::Bits64).unwrap().1, 3);
+	}
+
+	#[test]
+	fn decode_through_memory() {
+		let code = [0x48, 0x89, 0xD8, 0x0F, 0x0B, 0x90];
 		let mut pmu = PhysicalMemoryManagementUnit::new();
-		let mut rom = vec![0; (4 << 12) + data.len()];
-		rom[0..8].copy_from_slice(&0x0000_0000_0000_1001u64.to_le_bytes());
-		rom[1 << 12..(1 << 12) + 8].copy_from_slice(&0x0000_0000_0000_2001u64.to_le_bytes());
-		rom[2 << 12..(2 << 12) + 8].copy_from_slice(&0x0000_0000_0000_3001u64.to_le_bytes());
-		rom[3 << 12..(3 << 12) + 8].copy_from_slice(&0x0000_0000_0000_4001u64.to_le_bytes());
-		rom[4 << 12..].copy_from_slice(data);
-		pmu.add(0, rom.len() as u64, || {
+		let mut rom = vec![0; (4 << 12) + code.len()];
+		rom[0..8].copy_from_slice(&0x1001u64.to_le_bytes());
+		rom[1 << 12..(1 << 12) + 8].copy_from_slice(&0x2001u64.to_le_bytes());
+		rom[2 << 12..(2 << 12) + 8].copy_from_slice(&0x3001u64.to_le_bytes());
+		rom[3 << 12..(3 << 12) + 8].copy_from_slice(&0x4001u64.to_le_bytes());
+		rom[4 << 12..].copy_from_slice(&code);
+		pmu.add(0, rom.len() as u64, Permissions::default(), || {
 			ReadOnlyMemory::create(&rom, rom.len() as u64)
 		});
 		let mut mmu = MemoryManagementUnit::new(pmu);
-		let (instruction, size) = decode(&mut mmu, 0).unwrap();
+		assert_eq!(decode(&mut mmu, 0).unwrap(), decode_bytes(&code).unwrap());
+		// The bytes of an undefined instruction are fetched through the source as well.
+		let Interrupt::Undefined { opcode } = decode(&mut mmu, 3).unwrap_err() else {
+			panic!("not undefined");
+		};
+		assert_eq!(opcode, [0x0F, 0x0B, 0x90, 0xFF]);
+		let Interrupt::Undefined { opcode } =
+			decode_from(&mut &code[3..], 0, Mode::Bits64).unwrap_err()
+		else {
+			panic!("not undefined");
+		};
+		assert_eq!(opcode, [0x0F, 0x0B, 0x90, 0x00]);
+		assert!(matches!(
+			decode_from(&mut [0x48, 0x89].as_slice(), 0, Mode::Bits64),
+			Err(Interrupt::PageFault { cr2: 2, .. })
+		));
+	}
+
+	fn test_instruction(data: &[u8], expected: Instruction) {
+		let (instruction, size) = decode_bytes(data).unwrap();
 		assert_eq!(size, data.len() as u64);
 		assert_eq!(instruction, expected);
 	}
 
-	fn test_nasm(instruction: &str, expected: Instruction) {
-		let file_name = format!("__{}", instruction.split_whitespace().collect::<String>());
+	fn assemble(file_name: &str, instruction: &str) -> Vec<u8> {
 		std::fs::write(
 			format!("{file_name}.s"),
 			format!("[bits 64]\n{instruction}"),
@@ -357,7 +1457,7 @@ mod test {
 				"bin",
 				"-O0",
 				"-o",
-				&file_name,
+				file_name,
 			])
 			.spawn()
 			.unwrap()
@@ -365,6 +1465,12 @@ mod test {
 			.unwrap();
 		let data = std::fs::read(file_name).unwrap();
 		eprintln!("{:x?}", data);
+		data
+	}
+
+	fn test_nasm(instruction: &str, expected: Instruction) {
+		let file_name = format!("__{}", instruction.split_whitespace().collect::<String>());
+		let data = assemble(&file_name, instruction);
 		test_instruction(&data, expected);
 	}
 
@@ -385,4 +1491,914 @@ mod test {
 			},
 		);
 	}
+
+	#[test]
+	fn swi4() {
+		test_nasm(
+			"mov cr3, rax",
+			Instruction::Swi4 {
+				operand0: super::RM::Reg(0),
+			},
+		);
+		test_nasm(
+			"mov cr3, r9",
+			Instruction::Swi4 {
+				operand0: super::RM::Reg(9),
+			},
+		);
+	}
+
+	#[test]
+	fn wrcr() {
+		test_nasm("wrmsr", Instruction::Wrcr {});
+		test_nasm("rdmsr", Instruction::Rdcr {});
+		test_nasm("vmcall", Instruction::Vmcall {});
+	}
+
+	#[test]
+	fn simulator_specific() {
+		let decoded = |code: &[u8]| decode_bytes(code).unwrap();
+		assert_eq!(decoded(&[0x0F, 0x30]), (Instruction::Wrcr {}, 2));
+		assert_eq!(decoded(&[0x0F, 0x32]), (Instruction::Rdcr {}, 2));
+		assert_eq!(decoded(&[0x0F, 0x01, 0xC1]), (Instruction::Vmcall {}, 3));
+		assert!(Instruction::Vmcall {}.simulator_specific());
+		assert!(!Instruction::Hlt {}.simulator_specific());
+		assert_eq!(Instruction::SIMULATOR_SPECIFIC, ["Rdcr", "Vmcall", "Wrcr"]);
+		assert_eq!(Instruction::Rdcr {}.to_string(), "rdmsr");
+		assert_eq!(Instruction::Vmcall {}.to_string(), "vmcall");
+	}
+
+	#[test]
+	fn system_group() {
+		test_nasm(
+			"sidt [rax]",
+			Instruction::Sidt {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 0,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		test_nasm(
+			"lidt [rbx + 8]",
+			Instruction::Lidt {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 3,
+					displacement: 8,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		test_nasm(
+			"smsw eax",
+			Instruction::Smsw {
+				operand0: RM::Reg(0),
+			},
+		);
+		test_nasm(
+			"lmsw cx",
+			Instruction::Lmsw {
+				operand0: RM::Reg(1),
+			},
+		);
+		test_nasm(
+			"invlpg [rdi]",
+			Instruction::Invlpg {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 7,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		test_nasm(
+			"sgdt [rsp]",
+			Instruction::Sgdt {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 4,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		// Full ModRM bytes in the register form of /7 which otherwise is invlpg.
+		test_nasm("swapgs", Instruction::Swapgs {});
+		test_nasm("rdtscp", Instruction::Rdtscp {});
+		test_nasm("rdtsc", Instruction::Rdtsc {});
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x01, 0xFA]),
+			Err(Interrupt::Undefined { .. })
+		));
+		// lidt with a register operand.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x01, 0xD8]),
+			Err(Interrupt::Undefined { .. })
+		));
+		// The unassigned /5 extension.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x01, 0x28]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn port_immediate() {
+		// The port is always a byte, the operand size only selects the data width.
+		test_nasm(
+			"in ax, 0x60",
+			Instruction::In16 {
+				operand0: super::Immediate(0x60),
+			},
+		);
+		test_nasm(
+			"out 0x60, eax",
+			Instruction::Out32 {
+				operand0: super::Immediate(0x60),
+			},
+		);
+		assert_eq!(decode_bytes(&[0x66, 0xE7, 0x60, 0x90]).unwrap().1, 3);
+	}
+
+	#[test]
+	fn undefined_opcode() {
+		let interrupt = decode_bytes(&[0x0F, 0x01, 0x28, 0x90]).unwrap_err();
+		assert!(matches!(
+			interrupt,
+			Interrupt::Undefined {
+				opcode: [0x0F, 0x01, 0x28, 0x90]
+			}
+		));
+		assert_eq!(interrupt.to_string(), "UD(0F 01 28 90)");
+		// Bytes past the end of physical memory read as all ones.
+		let interrupt = decode_bytes(&[0x0F, 0x0B]).unwrap_err();
+		assert_eq!(interrupt.to_string(), "UD(0F 0B FF FF)");
+	}
+
+	#[test]
+	fn accumulator_immediate() {
+		test_nasm(
+			"add rax, 0x100",
+			Instruction::AddA64Imm {
+				operand0: super::Reg(0),
+				operand1: super::Immediate(0x100),
+			},
+		);
+		test_nasm(
+			"cmp al, 0x7f",
+			Instruction::CmpA8Imm {
+				operand0: super::Reg(0),
+				operand1: super::Immediate(0x7F),
+			},
+		);
+		test_nasm(
+			"sub ax, 0x1234",
+			Instruction::SubA16Imm {
+				operand0: super::Reg(0),
+				operand1: super::Immediate(0x1234),
+			},
+		);
+	}
+
+	/// Instructions covering the implemented encodings, used for the round trip test.
+	const GOLDEN: &[&str] = &[
+		"hlt",
+		"in al, 0x60",
+		"in ax, 0x60",
+		"in eax, 0x60",
+		"in al, dx",
+		"in ax, dx",
+		"in eax, dx",
+		"inc byte [rax]",
+		"inc word [rbx + 0x8]",
+		"inc dword [rcx + rdx*4 - 0x10]",
+		"inc qword [r12]",
+		"inc r9",
+		"int 0x80",
+		"int3",
+		"iret",
+		"jne short $+0x10",
+		"jle short $-0x20",
+		"jmp short $+0x10",
+		"jmp short $-0x10",
+		"jmp near $-0x100",
+		"jmp near $+0x12345",
+		"mov al, 0x12",
+		"mov ah, 0x12",
+		"mov bh, 0x7f",
+		"mov spl, 0x1",
+		"mov r9b, 0x80",
+		"mov r10w, 0x1234",
+		"mov esi, 0x12345678",
+		"mov r15, 0x123456789abcdef",
+		"mov al, byte [rbx]",
+		"mov cl, byte [rsi]",
+		"mov ax, word [rbx]",
+		"mov r8w, word [rdi + 0x40]",
+		"mov edx, dword [rsp]",
+		"mov rax, qword [r13 + 0x1000]",
+		"mov rax, qword [fs:rbx]",
+		"mov rax, qword [0x1000]",
+		"mov byte [rax], bl",
+		"mov word [rbp - 0x8], ax",
+		"mov dword [rax + r11*8 + 0x100], r9d",
+		"mov qword [rsp + 0x8], rbp",
+		"out 0x80, al",
+		"out 0x80, ax",
+		"out 0x80, eax",
+		"out dx, al",
+		"out dx, ax",
+		"out dx, eax",
+		"push r15",
+		"push cx",
+		"pop r12",
+		"pop dx",
+		"mov cr3, rax",
+		"mov dr0, rax",
+		"movaps xmm1, xmm15",
+		"movaps [rax], xmm3",
+		"movdqa xmm0, [rax]",
+		"movdqu [rbx + 0x10], xmm9",
+		"movups xmm2, [rsp]",
+		"movd xmm3, eax",
+		"movd dword [rdi], xmm4",
+		"movq xmm5, rsi",
+		"movq r11, xmm14",
+		"pand xmm0, xmm1",
+		"pandn xmm2, [rax]",
+		"por xmm8, xmm9",
+		"pxor xmm0, xmm0",
+		"paddb xmm1, xmm2",
+		"paddq xmm3, [rax]",
+		"psubw xmm4, xmm5",
+		"psubd xmm10, xmm11",
+		"pcmpeqb xmm1, xmm0",
+		"pcmpeqd xmm2, [rbx + 0x10]",
+		"pmovmskb ecx, xmm1",
+		"test ecx, ecx",
+		"test byte [rax], dl",
+		"test qword [rsp + 0x8], r9",
+		"tzcnt ecx, ecx",
+		"tzcnt r8, qword [rdi]",
+		"tzcnt ax, bx",
+		"mov r9, dr7",
+		"wrmsr",
+		"wbinvd",
+		"addsd xmm0, [rax + 0x8]",
+		"subsd xmm9, xmm2",
+		"mulsd xmm1, xmm15",
+		"divsd xmm3, [rsp]",
+		"movsd xmm2, [rdi]",
+		"movsd [rdi + 0x10], xmm10",
+		"cvtsi2sd xmm1, eax",
+		"cvtsi2sd xmm1, qword [rbx]",
+		"cvttsd2si r8d, xmm3",
+		"cvttsd2si rax, [rcx]",
+		"ucomisd xmm0, xmm1",
+		"ldmxcsr [rsp]",
+		"stmxcsr [rsp + 0x4]",
+		"clflush [rdi + 0x40]",
+		"lfence",
+		"mfence",
+		"sfence",
+		"sgdt [rax + 0x10]",
+		"jmp rax",
+		"jmp qword [rbx + 0x8]",
+		"swapgs",
+		"rdtscp",
+		"rdtsc",
+		"insb",
+		"insw",
+		"rep insd",
+		"outsb",
+		"rep outsw",
+		"outsd",
+		"cmpsb",
+		"repe cmpsw",
+		"repne cmpsq",
+		"scasd",
+		"repne scasb",
+		"repe scasq",
+		"cld",
+		"std",
+		"cli",
+		"sti",
+		"fninit",
+		"fnstcw [rsp + 0x2]",
+		"fldcw [rax]",
+		"fwait",
+		"adcx eax, ebx",
+		"adcx r10, qword [rsi + 0x8]",
+		"adox r9d, dword [rdx]",
+		"adox rax, r15",
+		"andn eax, ebx, ecx",
+		"andn r8, r15, qword [rax + rbx * 4 + 0x12345678]",
+		"bextr rax, qword [rbx + 0x8], rcx",
+		"bzhi r8d, r9d, r10d",
+		"shlx rax, rbx, rcx",
+		"sarx r9d, dword [rsp], r14d",
+		"shrx r13, r12, r11",
+		"mulx r11, r12, qword [rsp]",
+		"mulx eax, ebx, ecx",
+		"rdfsbase eax",
+		"rdgsbase r9",
+		"wrfsbase rbx",
+		"wrgsbase r10d",
+		"xchg byte [rax], cl",
+		"xchg word [rbx + 0x8], dx",
+		"xchg ecx, edx",
+		"xchg qword [rdi], r8",
+		"xgetbv",
+		"xsetbv",
+		"lidt [rax]",
+		"sidt [rbx + 0x10]",
+		"invlpg [rcx]",
+		"smsw eax",
+		"lmsw ax",
+		"add al, 0x80",
+		"or ax, 0x1234",
+		"adc eax, 0x12345678",
+		"sbb rax, -0x1000",
+		"and al, 0xf0",
+		"sub eax, 0x100",
+		"xor rax, 0x12345",
+		"cmp ax, 0x8000",
+	];
+
+	/// Every golden instruction must decode to its assembled length and format to source
+	/// which assembles to the same instruction.
+	#[test]
+	fn golden() {
+		for (i, source) in GOLDEN.iter().enumerate() {
+			let data = assemble(&format!("__golden{i}"), source);
+			let (instruction, size) =
+				decode_bytes(&data).unwrap_or_else(|interrupt| panic!("{source}: {interrupt}"));
+			assert_eq!(size, data.len() as u64, "{source}");
+			let formatted = instruction.to_string();
+			let data = assemble(&format!("__golden{i}_formatted"), &formatted);
+			let (reassembled, _) =
+				decode_bytes(&data).unwrap_or_else(|interrupt| panic!("{formatted}: {interrupt}"));
+			assert_eq!(
+				reassembled, instruction,
+				"{source} formatted as {formatted}"
+			);
+		}
+	}
+
+	#[test]
+	fn rex() {
+		test_instruction(
+			&[0x48, 0xFF, 0x00],
+			Instruction::IncRM64 {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 0,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		// REX is ignored when it does not immediately precede the opcode.
+		test_instruction(
+			&[0x48, 0x66, 0xFF, 0xC0],
+			Instruction::IncRM16 {
+				operand0: RM::Reg(0),
+			},
+		);
+		for rex in 0x40..0x50 {
+			assert!(matches!(
+				decode_bytes(&[rex; 15]),
+				Err(Interrupt::Undefined { .. })
+			));
+			assert!(matches!(
+				decode_bytes(&[rex; 32]),
+				Err(Interrupt::Undefined { .. })
+			));
+		}
+	}
+
+	#[test]
+	fn maximum_length() {
+		let mut data = [0x66; 15];
+		data[14] = 0xF4;
+		test_instruction(&data, Instruction::Hlt {});
+		let mut data = [0x66; 16];
+		data[15] = 0xF4;
+		assert!(matches!(
+			decode_bytes(&data),
+			Err(Interrupt::Undefined { .. })
+		));
+		// The limit counts the whole instruction, not only the prefixes.
+		let mut data = [0x66; 15];
+		data[11] = 0x48;
+		data[12..].copy_from_slice(&[0xB8, 0x00, 0x00]);
+		assert!(matches!(
+			decode_bytes(&data),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn segment_override() {
+		let operand = |segment_override| Instruction::MovReg64RM {
+			operand0: super::Reg(0),
+			operand1: RM::Mem {
+				index: 4,
+				scale: 0,
+				base: 3,
+				displacement: 0,
+				address_override: false,
+				segment_override,
+			},
+		};
+		// The es, cs, ss and ds bases are zero in long mode, so they are ignored.
+		for prefix in [0x26, 0x2E, 0x36, 0x3E] {
+			test_instruction(&[prefix, 0x48, 0x8B, 0x03], operand(SegmentOverride::None));
+		}
+		test_instruction(&[0x64, 0x48, 0x8B, 0x03], operand(SegmentOverride::Fs));
+		test_instruction(&[0x65, 0x48, 0x8B, 0x03], operand(SegmentOverride::Gs));
+	}
+
+	#[test]
+	fn push_pop() {
+		for (i, name) in super::REGISTER_NAMES.iter().enumerate() {
+			let operand0 = super::Reg(i as u8);
+			test_nasm(&format!("push {name}"), Instruction::PushReg64 { operand0 });
+			test_nasm(&format!("pop {name}"), Instruction::PopReg64 { operand0 });
+		}
+		test_nasm(
+			"push r8w",
+			Instruction::PushReg16 {
+				operand0: super::Reg(8),
+			},
+		);
+		test_nasm(
+			"pop r15w",
+			Instruction::PopReg16 {
+				operand0: super::Reg(15),
+			},
+		);
+		// REX.W does not change the operand size of push and pop, but REX.B still extends the
+		// register.
+		test_instruction(
+			&[0x49, 0x50],
+			Instruction::PushReg64 {
+				operand0: super::Reg(8),
+			},
+		);
+	}
+
+	#[test]
+	fn debug_registers() {
+		test_nasm(
+			"mov dr3, r9",
+			Instruction::MovDrRM {
+				operand0: super::Reg(3),
+				operand1: RM::Reg(9),
+			},
+		);
+		test_nasm(
+			"mov rbx, dr7",
+			Instruction::MovRMDr {
+				operand0: RM::Reg(3),
+				operand1: super::Reg(7),
+			},
+		);
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x23, 0x00]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn extended_control_registers() {
+		test_nasm("xgetbv", Instruction::Xgetbv {});
+		test_nasm("xsetbv", Instruction::Xsetbv {});
+		// The other register forms of the group are still undefined.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x01, 0xD2]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn segment_base() {
+		test_nasm(
+			"rdfsbase eax",
+			Instruction::Rdfsbase32 {
+				operand0: RM::Reg(0),
+			},
+		);
+		test_nasm(
+			"rdgsbase r9",
+			Instruction::Rdgsbase64 {
+				operand0: RM::Reg(9),
+			},
+		);
+		test_nasm(
+			"wrfsbase rbx",
+			Instruction::Wrfsbase64 {
+				operand0: RM::Reg(3),
+			},
+		);
+		test_nasm(
+			"wrgsbase r10d",
+			Instruction::Wrgsbase32 {
+				operand0: RM::Reg(10),
+			},
+		);
+		// The F3 prefix is mandatory and the operand must be a register.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0xAE, 0xC0]),
+			Err(Interrupt::Undefined { .. })
+		));
+		assert!(matches!(
+			decode_bytes(&[0xF3, 0x0F, 0xAE, 0x00]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn xmm_move() {
+		test_nasm(
+			"movdqa xmm8, [rax]",
+			Instruction::MovdqaXmmRM {
+				operand0: super::Reg(8),
+				operand1: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 0,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		test_nasm(
+			"movdqu xmm1, xmm2",
+			Instruction::MovdquXmmRM {
+				operand0: super::Reg(1),
+				operand1: RM::Reg(2),
+			},
+		);
+		test_nasm(
+			"movaps xmm3, xmm12",
+			Instruction::MovapsXmmRM {
+				operand0: super::Reg(3),
+				operand1: RM::Reg(12),
+			},
+		);
+		test_nasm(
+			"movups [rcx], xmm4",
+			Instruction::MovupsRMXmm {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 1,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+				operand1: super::Reg(4),
+			},
+		);
+		// Without a prefix 0F 6F is the mmx movq, and with F3 0F 10 is movss.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x6F, 0xC1]),
+			Err(Interrupt::Undefined { .. })
+		));
+		assert!(matches!(
+			decode_bytes(&[0xF3, 0x0F, 0x10, 0xC1]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn xmm_logic() {
+		test_nasm(
+			"pxor xmm0, xmm0",
+			Instruction::PxorXmmRM {
+				operand0: super::Reg(0),
+				operand1: RM::Reg(0),
+			},
+		);
+		test_nasm(
+			"por xmm9, xmm10",
+			Instruction::PorXmmRM {
+				operand0: super::Reg(9),
+				operand1: RM::Reg(10),
+			},
+		);
+		test_nasm(
+			"pand xmm1, xmm2",
+			Instruction::PandXmmRM {
+				operand0: super::Reg(1),
+				operand1: RM::Reg(2),
+			},
+		);
+		test_nasm(
+			"pandn xmm3, xmm4",
+			Instruction::PandnXmmRM {
+				operand0: super::Reg(3),
+				operand1: RM::Reg(4),
+			},
+		);
+		test_nasm(
+			"movd xmm1, ecx",
+			Instruction::MovdXmmRM32 {
+				operand0: super::Reg(1),
+				operand1: RM::Reg(1),
+			},
+		);
+		test_nasm(
+			"movd edx, xmm2",
+			Instruction::MovdRM32Xmm {
+				operand0: RM::Reg(2),
+				operand1: super::Reg(2),
+			},
+		);
+		test_nasm(
+			"movq xmm3, rbx",
+			Instruction::MovqXmmRM64 {
+				operand0: super::Reg(3),
+				operand1: RM::Reg(3),
+			},
+		);
+		test_nasm(
+			"movq r12, xmm13",
+			Instruction::MovqRM64Xmm {
+				operand0: RM::Reg(12),
+				operand1: super::Reg(13),
+			},
+		);
+		// Without the size override prefix these are the mmx forms.
+		for data in [[0x0F, 0xEF, 0xC0], [0x0F, 0x6E, 0xC0]] {
+			assert!(matches!(
+				decode_bytes(&data),
+				Err(Interrupt::Undefined { .. })
+			));
+		}
+		assert!(matches!(
+			decode_bytes(&[0x48, 0x0F, 0x6E, 0xC0]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn conditional_jump() {
+		for (i, condition) in super::CONDITION_NAMES.iter().enumerate() {
+			test_nasm(
+				&format!("j{condition} short $+0x10"),
+				Instruction::JccRel8 {
+					operand0: super::Condition(i as u8),
+					operand1: super::Immediate(0x0E),
+				},
+			);
+		}
+	}
+
+	#[test]
+	fn packed_integer() {
+		test_nasm(
+			"pcmpeqb xmm1, xmm0",
+			Instruction::PcmpeqbXmmRM {
+				operand0: super::Reg(1),
+				operand1: RM::Reg(0),
+			},
+		);
+		test_nasm(
+			"psubq xmm9, xmm15",
+			Instruction::PsubqXmmRM {
+				operand0: super::Reg(9),
+				operand1: RM::Reg(15),
+			},
+		);
+		test_nasm(
+			"pmovmskb r10d, xmm12",
+			Instruction::PmovmskbReg32Xmm {
+				operand0: super::Reg(10),
+				operand1: RM::Reg(12),
+			},
+		);
+		test_nasm(
+			"tzcnt ecx, edx",
+			Instruction::TzcntReg32RM {
+				operand0: super::Reg(1),
+				operand1: RM::Reg(2),
+			},
+		);
+		// Without the F3 prefix 0F BC is bsf.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0xBC, 0xCA]),
+			Err(Interrupt::Undefined { .. })
+		));
+		// pmovmskb only takes a register source.
+		assert!(matches!(
+			decode_bytes(&[0x66, 0x0F, 0xD7, 0x08]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn cache() {
+		test_nasm("wbinvd", Instruction::Wbinvd {});
+		test_nasm(
+			"clflush [rax]",
+			Instruction::Clflush {
+				operand0: RM::Mem {
+					index: 4,
+					scale: 0,
+					base: 0,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		// clflush only takes a memory operand, the register form is sfence.
+		test_instruction(&[0x0F, 0xAE, 0xF8], Instruction::Sfence {});
+	}
+
+	#[test]
+	fn scalar_double() {
+		test_nasm(
+			"addsd xmm0, xmm1",
+			Instruction::AddsdXmmRM {
+				operand0: super::Reg(0),
+				operand1: RM::Reg(1),
+			},
+		);
+		test_nasm(
+			"cvtsi2sd xmm2, rax",
+			Instruction::Cvtsi2sdXmmRM64 {
+				operand0: super::Reg(2),
+				operand1: RM::Reg(0),
+			},
+		);
+		test_nasm(
+			"cvttsd2si eax, xmm2",
+			Instruction::Cvttsd2siReg32RM {
+				operand0: super::Reg(0),
+				operand1: RM::Reg(2),
+			},
+		);
+		test_nasm(
+			"ucomisd xmm3, xmm4",
+			Instruction::UcomisdXmmRM {
+				operand0: super::Reg(3),
+				operand1: RM::Reg(4),
+			},
+		);
+		// The mandatory prefix selects between movups and movsd.
+		test_nasm(
+			"movsd xmm0, xmm1",
+			Instruction::MovsdXmmRM {
+				operand0: super::Reg(0),
+				operand1: RM::Reg(1),
+			},
+		);
+		test_instruction(
+			&[0x0F, 0x10, 0xC1],
+			Instruction::MovupsXmmRM {
+				operand0: super::Reg(0),
+				operand1: RM::Reg(1),
+			},
+		);
+		// Without the size override prefix 0F 2E is ucomiss.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x2E, 0xC1]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn fences() {
+		test_nasm("lfence", Instruction::Lfence {});
+		test_nasm("mfence", Instruction::Mfence {});
+		test_nasm("sfence", Instruction::Sfence {});
+		// The fences are only defined for a zero rm field.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0xAE, 0xE9]),
+			Err(Interrupt::Undefined { .. })
+		));
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0xAE, 0xF9]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn vex() {
+		test_nasm(
+			"andn eax, ebx, ecx",
+			Instruction::AndnReg32RM {
+				operand0: super::Reg(0),
+				operand1: super::Reg(3),
+				operand2: RM::Reg(1),
+			},
+		);
+		// VEX.R, VEX.X, VEX.B and vvvv extend the registers.
+		test_nasm(
+			"shrx r13, r12, r11",
+			Instruction::ShrxReg64RM {
+				operand0: super::Reg(13),
+				operand1: RM::Reg(12),
+				operand2: super::Reg(11),
+			},
+		);
+		test_nasm(
+			"mulx r11, r12, qword [r9 + r10 * 2]",
+			Instruction::MulxReg64RM {
+				operand0: super::Reg(11),
+				operand1: super::Reg(12),
+				operand2: RM::Mem {
+					index: 10,
+					scale: 1,
+					base: 9,
+					displacement: 0,
+					address_override: false,
+					segment_override: SegmentOverride::None,
+				},
+			},
+		);
+		// The three byte form with a SIB byte and a 32 bit displacement.
+		let data = assemble("__vex_length", "andn r8, r15, [rax + rbx * 4 + 0x12345678]");
+		assert_eq!(data.len(), 10);
+		assert_eq!(decode_bytes(&data).unwrap().1, 10);
+		// The two byte form only encodes the 0F map, where nothing is implemented yet.
+		let vex = super::Vex::two_byte(0xF8);
+		assert!(!vex.rex.r && vex.map == 1 && vex.vvvv == 0 && vex.pp == 0 && !vex.l);
+		assert!(matches!(
+			decode_bytes(&[0xC5, 0xF8, 0x77]),
+			Err(Interrupt::Undefined { .. })
+		));
+		// REX and the mandatory prefixes can not precede VEX, and VEX.L must be clear.
+		for data in [
+			&[0x48, 0xC4, 0xE2, 0x60, 0xF2, 0xC1][..],
+			&[0x66, 0xC4, 0xE2, 0x60, 0xF2, 0xC1],
+			&[0xC4, 0xE2, 0x64, 0xF2, 0xC1],
+		] {
+			assert!(matches!(
+				decode_bytes(data),
+				Err(Interrupt::Undefined { .. })
+			));
+		}
+	}
+
+	#[test]
+	fn carry_chains() {
+		test_nasm(
+			"adcx rax, rbx",
+			Instruction::AdcxReg64RM {
+				operand0: super::Reg(0),
+				operand1: RM::Reg(3),
+			},
+		);
+		test_nasm(
+			"adox ecx, edx",
+			Instruction::AdoxReg32RM {
+				operand0: super::Reg(1),
+				operand1: RM::Reg(2),
+			},
+		);
+		// The mandatory prefix selects the instruction.
+		assert!(matches!(
+			decode_bytes(&[0x0F, 0x38, 0xF6, 0xC3]),
+			Err(Interrupt::Undefined { .. })
+		));
+	}
+
+	#[test]
+	fn x87() {
+		test_nasm("fninit", Instruction::Fninit {});
+		test_nasm("fwait", Instruction::Fwait {});
+		// Only the control word instructions of the escape opcodes are implemented.
+		for data in [
+			&[0xD8, 0xC1][..], // fadd st0, st1
+			&[0xD9, 0xE8],     // fld1
+			&[0xD9, 0x37],     // fnstenv [rdi]
+			&[0xDB, 0xE2],     // fnclex
+			&[0xDD, 0x07],     // fld qword [rdi]
+			&[0xDF, 0xE0],     // fnstsw ax
+		] {
+			assert!(matches!(
+				decode_bytes(data),
+				Err(Interrupt::Undefined { .. })
+			));
+		}
+	}
 }
@@ -1,6 +1,11 @@
 use std::fmt::Display;
 
-#[derive(Debug)]
+use crate::instruction::FetchSource;
+
+/// The number of instruction bytes kept with an undefined exception.
+pub const UNDEFINED_OPCODE_BYTES: usize = 4;
+
+#[derive(Clone, Copy, Debug, serde::Serialize)]
 pub enum Interrupt {
 	/// General Protection Interrupt. Unlike x86 this does not have any error code, since
 	/// segments do not exist.
@@ -12,8 +17,19 @@ pub enum Interrupt {
 		cr2: u64,
 	},
 
-	/// Undefined exception. Identical to x86.
-	Undefined,
+	/// Undefined exception. Identical to x86, except that it carries the first bytes at the
+	/// faulting instruction pointer. Bytes which could not be fetched are zero.
+	Undefined {
+		opcode: [u8; UNDEFINED_OPCODE_BYTES],
+	},
+
+	/// Debug exception. It is a trap, so it is raised after the instruction which caused it has
+	/// completed and has no error code. Identical to x86.
+	Debug,
+
+	/// Breakpoint exception raised by int3. It is a trap like the debug exception. Identical to
+	/// x86.
+	Breakpoint,
 
 	// Faault on fetch of interrupt. Identical to x86.
 	DoubleFault,
@@ -22,28 +38,177 @@ pub enum Interrupt {
 	InterruptRequest(u8),
 }
 
+impl Interrupt {
+	/// An undefined exception before the opcode bytes are known. They are filled in with
+	/// `with_opcode` when the exception is raised.
+	pub const UNDEFINED: Interrupt = Interrupt::Undefined {
+		opcode: [0; UNDEFINED_OPCODE_BYTES],
+	};
+
+	/// The exceptions the processor raises, one of each kind.
+	pub const EXCEPTIONS: [Interrupt; 6] = [
+		Interrupt::Debug,
+		Interrupt::Breakpoint,
+		Interrupt::UNDEFINED,
+		Interrupt::DoubleFault,
+		Interrupt::GeneralProtection,
+		Interrupt::PageFault {
+			error_code: 0,
+			cr2: 0,
+		},
+	];
+
+	/// The idt entry the interrupt is delivered through.
+	pub fn vector(&self) -> u64 {
+		match self {
+			Interrupt::Debug => 0x01,
+			Interrupt::Breakpoint => 0x03,
+			Interrupt::Undefined { .. } => 0x06,
+			Interrupt::DoubleFault => 0x08,
+			Interrupt::GeneralProtection => 0x0D,
+			Interrupt::PageFault { .. } => 0x0E,
+			Interrupt::InterruptRequest(irq) => *irq as u64,
+		}
+	}
+
+	/// The name of the kind of interrupt.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Interrupt::GeneralProtection => "general protection",
+			Interrupt::PageFault { .. } => "page fault",
+			Interrupt::Undefined { .. } => "undefined opcode",
+			Interrupt::Debug => "debug",
+			Interrupt::Breakpoint => "breakpoint",
+			Interrupt::DoubleFault => "double fault",
+			Interrupt::InterruptRequest(_) => "interrupt request",
+		}
+	}
+
+	/// Fills in the bytes at `instruction_pointer` if this is an undefined exception.
+	pub fn with_opcode<F: FetchSource + ?Sized>(
+		self,
+		mmu: &mut F,
+		instruction_pointer: u64,
+	) -> Interrupt {
+		match self {
+			Interrupt::Undefined { .. } => {
+				let mut opcode = [0; UNDEFINED_OPCODE_BYTES];
+				for (i, byte) in opcode.iter_mut().enumerate() {
+					match mmu.fetch_u8(instruction_pointer.wrapping_add(i as u64)) {
+						Ok(value) => *byte = value,
+						Err(_) => break,
+					}
+				}
+				Interrupt::Undefined { opcode }
+			}
+			interrupt => interrupt,
+		}
+	}
+}
+
 impl Display for Interrupt {
 	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
 		match self {
 			Interrupt::GeneralProtection => write!(f, "GP"),
 			Interrupt::PageFault { error_code, cr2 } => write!(f, "PF({error_code:X}, {cr2:X})"),
-			Interrupt::Undefined => write!(f, "UD"),
+			Interrupt::Undefined { opcode } => {
+				let bytes: Vec<_> = opcode.iter().map(|byte| format!("{byte:02X}")).collect();
+				write!(f, "UD({})", bytes.join(" "))
+			}
+			Interrupt::Debug => write!(f, "DB"),
+			Interrupt::Breakpoint => write!(f, "BP"),
 			Interrupt::DoubleFault => write!(f, "DF"),
 			Interrupt::InterruptRequest(irq) => write!(f, "IRQ({irq})"),
 		}
 	}
 }
 
-pub fn is_cannonical(address: u64) -> Result<(), Interrupt> {
-	let shifted = address >> 47;
-	if shifted == 0 || shifted == 0x1FFFF {
+/// The reason an interrupt could not be delivered, which turns it into a double fault.
+#[derive(Debug)]
+pub enum DeliveryFailure {
+	/// Reading the idt entry faulted.
+	EntryFault { address: u64, fault: Interrupt },
+
+	/// The idt entry has a value other than 0 or 1 in a flag byte, or an rpl above 3.
+	Malformed {
+		vector: u64,
+		offset: usize,
+		byte: u8,
+	},
+
+	/// The idt entry is not marked present.
+	NotPresent { vector: u64 },
+
+	/// The service routine of the idt entry is not a canonical address.
+	NonCanonical { vector: u64, service_routine: u64 },
+
+	/// The rpl of the idt entry is below the current privilege level.
+	Privilege { vector: u64, rpl: i8, cpl: i8 },
+
+	/// Writing the interrupt frame to the interrupt stack faulted.
+	StackFault { address: u64, fault: Interrupt },
+}
+
+impl Display for DeliveryFailure {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			DeliveryFailure::EntryFault { address, fault } => {
+				write!(f, "reading the idt entry at 0x{address:X} raised {fault}")
+			}
+			DeliveryFailure::Malformed {
+				vector,
+				offset,
+				byte,
+			} => write!(
+				f,
+				"the idt entry for vector 0x{vector:X} has the malformed byte 0x{byte:02X} at offset {offset}"
+			),
+			DeliveryFailure::NotPresent { vector } => {
+				write!(f, "the idt entry for vector 0x{vector:X} is not present")
+			}
+			DeliveryFailure::NonCanonical {
+				vector,
+				service_routine,
+			} => write!(
+				f,
+				"the service routine 0x{service_routine:X} for vector 0x{vector:X} is not canonical"
+			),
+			DeliveryFailure::Privilege { vector, rpl, cpl } => write!(
+				f,
+				"the idt entry for vector 0x{vector:X} has rpl {rpl} below cpl {cpl}"
+			),
+			DeliveryFailure::StackFault { address, fault } => write!(
+				f,
+				"writing the interrupt frame to 0x{address:X} raised {fault}"
+			),
+		}
+	}
+}
+
+/// Checks that the bits above the `virtual_address_bits` lowest are copies of the highest of
+/// them.
+pub fn is_cannonical(address: u64, virtual_address_bits: u32) -> Result<(), Interrupt> {
+	let shifted = address as i64 >> (virtual_address_bits - 1);
+	if shifted == 0 || shifted == -1 {
 		Ok(())
 	} else {
 		Err(Interrupt::GeneralProtection)
 	}
 }
 
-#[repr(C)]
+/// An entry of the idt, which is 16 bytes long and indexed by vector from the address in config
+/// register 0. The layout is
+///
+/// | Offset | Width | Field |
+/// |--------|-------|-------|
+/// | 0 | 1 | present, 0 or 1 |
+/// | 1 | 1 | disable interrupts on entry, 0 or 1 |
+/// | 2 | 1 | rpl, 0 to 3 |
+/// | 3 | 5 | reserved, ignored |
+/// | 8 | 8 | service routine, little endian |
+///
+/// An entry with any other value in the flag bytes or the rpl is malformed, and delivering
+/// through it is a double fault.
 pub struct InteruptDescriptorEntry {
 	/// Marking this as a prsent entry
 	pub present: bool,
@@ -58,3 +223,69 @@ pub struct InteruptDescriptorEntry {
 	/// The location of the service_routine
 	pub service_routine: u64,
 }
+
+impl InteruptDescriptorEntry {
+	/// Parses an entry from its bytes in guest memory, returning the offset of the first
+	/// malformed byte on failure.
+	pub fn parse(bytes: &[u8; 16]) -> Result<Self, usize> {
+		let flag = |offset: usize| match bytes[offset] {
+			0 => Ok(false),
+			1 => Ok(true),
+			_ => Err(offset),
+		};
+		let present = flag(0)?;
+		let disable_interrupt = flag(1)?;
+		if bytes[2] > 3 {
+			Err(2usize)?;
+		}
+		Ok(Self {
+			present,
+			disable_interrupt,
+			rpl: bytes[2] as i8,
+			service_routine: u64::from_le_bytes(bytes[8..].try_into().unwrap()),
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::InteruptDescriptorEntry;
+
+	fn entry(present: u8, disable_interrupt: u8, rpl: u8) -> [u8; 16] {
+		let mut bytes = [0xAA; 16];
+		bytes[0] = present;
+		bytes[1] = disable_interrupt;
+		bytes[2] = rpl;
+		bytes[8..].copy_from_slice(&0xFFFF_8000_0000_1234u64.to_le_bytes());
+		bytes
+	}
+
+	// Run under Miri along with the malformed entries delivered by the processor with
+	// `cargo +nightly miri test --lib idt_entry`.
+	#[test]
+	fn parse_idt_entry() {
+		let parsed = InteruptDescriptorEntry::parse(&entry(1, 0, 3)).unwrap();
+		assert!(parsed.present);
+		assert!(!parsed.disable_interrupt);
+		assert_eq!(parsed.rpl, 3);
+		assert_eq!(parsed.service_routine, 0xFFFF_8000_0000_1234);
+
+		// The reserved bytes are ignored, so only the flags and the rpl can be malformed.
+		for byte in 2..=u8::MAX {
+			assert_eq!(
+				InteruptDescriptorEntry::parse(&entry(byte, 1, 0)).err(),
+				Some(0)
+			);
+			assert_eq!(
+				InteruptDescriptorEntry::parse(&entry(1, byte, 0)).err(),
+				Some(1)
+			);
+		}
+		for byte in 4..=u8::MAX {
+			assert_eq!(
+				InteruptDescriptorEntry::parse(&entry(0, 0, byte)).err(),
+				Some(2)
+			);
+		}
+	}
+}
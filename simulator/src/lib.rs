@@ -0,0 +1,29 @@
+#![feature(array_try_from_fn)]
+#![feature(btree_cursors)]
+#![feature(macro_metavar_expr_concat)]
+#![feature(try_blocks)]
+
+//! The simulator as a library, for embedding it in other programs. A [`MachineBuilder`] takes
+//! the command line and the config, and device models living outside the simulator are added
+//! to it as kinds of `External` devices. The [`Machine`] it builds creates the processor.
+
+mod args;
+pub mod bench;
+mod device;
+pub mod error;
+mod events;
+mod history;
+pub mod info;
+mod instruction;
+mod interupt;
+mod machine;
+mod memory;
+mod monitor;
+mod profile;
+mod state;
+
+pub use args::{Args, Config};
+pub use device::{Bus, Device, DeviceFactory, Irq, raw_terminal};
+pub use machine::{Machine, MachineBuilder};
+pub use monitor::Monitor;
+pub use state::{Controller, Multiprocessor, ProcessorState, RegName};
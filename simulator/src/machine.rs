@@ -0,0 +1,435 @@
+use std::{
+	rc::Rc,
+	sync::{Arc, atomic::AtomicBool},
+};
+
+use crate::{
+	args::{self, Args, Config},
+	device::{
+		DebugLog, Device, DeviceFactory, DeviceRegistry, FaultInjector, InterruptInjector,
+		MemoryController, PortDevices, Random, ShadowControl, Timer, UTF8Console,
+	},
+	error,
+	events::Events,
+	memory::{
+		ConventionalMemory, Memory, MemoryManagementUnit, PAGING_LEVELS,
+		PhysicalMemoryManagementUnit, PoisonMemory, PoisonPolicy, ReadOnlyMemory, ShadowMemory,
+	},
+	profile::{Profile, Symbols},
+	state::{Controller, ProcessorState, Snapshot},
+};
+
+/// Collects what a [`Machine`] is made of: the command line, the config, and the kinds of
+/// devices which are not built in.
+pub struct MachineBuilder {
+	args: Args,
+	config: Config,
+	registry: DeviceRegistry,
+}
+
+impl MachineBuilder {
+	pub fn new(args: Args, config: Config) -> MachineBuilder {
+		MachineBuilder {
+			args,
+			config,
+			registry: DeviceRegistry::new(),
+		}
+	}
+
+	/// Makes `kind` available to `External` devices in the config, replacing an earlier
+	/// factory of the same kind.
+	pub fn register_device_factory(mut self, kind: &str, factory: DeviceFactory) -> MachineBuilder {
+		self.registry.register(kind, factory);
+		self
+	}
+
+	/// Checks the settings of the config which do not depend on the files it names.
+	pub fn build(self) -> Result<Machine, String> {
+		let config = &self.config;
+		if !PAGING_LEVELS.contains(&config.paging_levels) {
+			return Err("paging_levels must be 4 or 5".to_string());
+		}
+		if config.boot.is_some() && config.identity_map.is_some() {
+			return Err("boot and identity_map cannot both set cr3".to_string());
+		}
+		Ok(Machine {
+			args: self.args,
+			config: self.config,
+			registry: self.registry,
+		})
+	}
+}
+
+/// A machine described by a config, which creates its processor with the memory and devices.
+pub struct Machine {
+	args: Args,
+	config: Config,
+	registry: DeviceRegistry,
+}
+
+impl Machine {
+	/// Runs the processor on a thread of its own, where it waits paused for the commands of
+	/// the returned controller.
+	pub fn spawn(self) -> Controller {
+		Controller::spawn(move || self.processor())
+	}
+
+	/// Creates the processor with the memory and devices of the config. Files which can not be
+	/// read and devices which can not be created end the simulation.
+	pub fn processor(&self) -> ProcessorState {
+		let (args, toml) = (&self.args, &self.config);
+		let mut memory_management_unit = PhysicalMemoryManagementUnit::new();
+		let mut devices = PortDevices::new();
+		for memory in &toml.memory {
+			let permissions = memory.permissions;
+			match &memory.memory_type {
+				args::MemoryType::RAM => {
+					let image = memory
+						.image
+						.as_ref()
+						.map(|path| std::fs::read(path).unwrap());
+					if image
+						.as_ref()
+						.is_some_and(|image| image.len() as u64 > memory.size)
+					{
+						error::fatal("RAM image is larger than alloted size");
+					}
+					memory_management_unit.add(memory.start, memory.size, permissions, || {
+						let mut ram = ConventionalMemory::create(memory.size);
+						if let Some(image) = &image {
+							ram.load(0, image);
+						}
+						ram
+					})
+				}
+				args::MemoryType::ROM { path } => {
+					let data = std::fs::read(path).unwrap();
+					memory_management_unit.add(memory.start, memory.size, permissions, || {
+						ReadOnlyMemory::create(&data, memory.size)
+					})
+				}
+				args::MemoryType::Shadow { path, port } => {
+					let data = std::fs::read(path).unwrap();
+					let writable = Arc::new(AtomicBool::new(false));
+					devices.add(&[*port], ShadowControl::new(writable.clone()));
+					memory_management_unit.add(memory.start, memory.size, permissions, || {
+						ShadowMemory::create(&data, memory.size, writable)
+					})
+				}
+				args::MemoryType::Poison { policy } => {
+					let policy = *policy;
+					let context = memory_management_unit.access_context();
+					memory_management_unit.add(memory.start, memory.size, permissions, || {
+						PoisonMemory::create(
+							memory.start,
+							memory.size,
+							policy,
+							context,
+							move |report| match policy {
+								PoisonPolicy::Fatal => error::fatal(report),
+								_ => error::info(report),
+							},
+						)
+					})
+				}
+			}
+		}
+
+		for image in &toml.image {
+			let data = std::fs::read(&image.path).unwrap();
+			let slices = image.slices(&data).unwrap_or_else(|err| error::fatal(&err));
+			for (slice, bytes) in slices {
+				match slice.memory_type {
+					args::SliceType::RAM => memory_management_unit.add(
+						slice.start,
+						slice.size,
+						slice.permissions,
+						|| {
+							let mut ram = ConventionalMemory::create(slice.size);
+							ram.load(0, bytes);
+							ram
+						},
+					),
+					args::SliceType::ROM => memory_management_unit.add(
+						slice.start,
+						slice.size,
+						slice.permissions,
+						|| ReadOnlyMemory::create(bytes, slice.size),
+					),
+				}
+			}
+		}
+
+		let mut injected = None;
+		let vector = |irq| {
+			toml.irq_vector(irq)
+				.unwrap_or_else(|err| error::fatal(&err))
+		};
+		for device in &toml.device {
+			let (name, instance): (_, Box<dyn Device>) = match device.device_type {
+				args::DeviceType::UTF8Console => ("UTF8Console", Box::new(UTF8Console::new())),
+				args::DeviceType::Timer { irq } => (
+					"Timer",
+					Box::new(Timer::new(devices.irq(vector(irq)), args.seed.is_some())),
+				),
+				args::DeviceType::Random => ("Random", Box::new(Random::new(args.seed))),
+				args::DeviceType::DebugLog { level } => (
+					"DebugLog",
+					Box::new(DebugLog::new(level, |message| eprintln!("{message}"))),
+				),
+				args::DeviceType::MemoryController => {
+					("MemoryController", Box::new(MemoryController::new()))
+				}
+				args::DeviceType::FaultInjector { seed, irq } => {
+					let injector = FaultInjector::new(
+						irq.map(|irq| devices.irq(vector(irq))),
+						seed.or(args.seed),
+					);
+					devices.inject_faults(injector.armed());
+					("FaultInjector", Box::new(injector))
+				}
+				args::DeviceType::InterruptInjector => {
+					let interrupts = injected.get_or_insert_default();
+					let injector = InterruptInjector::new(Rc::clone(interrupts));
+					("InterruptInjector", Box::new(injector))
+				}
+				args::DeviceType::External {
+					ref kind,
+					ref options,
+					irq,
+				} => {
+					let instance = self
+						.registry
+						.create(kind, options, irq.map(|irq| devices.irq(vector(irq))))
+						.unwrap_or_else(|err| error::fatal(&err));
+					(kind.as_str(), instance)
+				}
+			};
+			let name = device.name.as_deref().unwrap_or(name);
+			devices.add_named(name, &device.ports, instance);
+		}
+		if let Some(device) = &args.trace_io {
+			devices
+				.trace(device.clone(), |line| error::info(&line))
+				.unwrap_or_else(|err| error::fatal(&err));
+		}
+
+		let stack = toml.stack.as_ref().map(|stack| {
+			memory_management_unit
+				.add_stack(stack.base, stack.size)
+				.unwrap_or_else(|err| error::fatal(&err))
+		});
+
+		if let Some(identity_map) = &toml.identity_map {
+			memory_management_unit
+				.add_identity_page_tables(
+					identity_map.tables,
+					toml.paging_levels,
+					identity_map.alias,
+				)
+				.unwrap_or_else(|err| error::fatal(&err));
+		}
+
+		if let Some(boot) = &toml.boot {
+			memory_management_unit
+				.check_page_tables(boot.cr3)
+				.unwrap_or_else(|err| error::fatal(&err));
+		}
+
+		let mut memory = MemoryManagementUnit::new(memory_management_unit);
+		memory.set_paging_levels(toml.paging_levels);
+		memory.set_strict(args.strict_memory);
+		memory.set_strict_page_tables(args.strict_page_tables);
+		if let Some(identity_map) = &toml.identity_map {
+			memory.set_reset_paging_table_address(identity_map.tables);
+		}
+		if let Some(boot) = &toml.boot {
+			memory.set_reset_paging_table_address(boot.cr3);
+		}
+		let mut state = ProcessorState::new(memory, devices);
+		if let Some(interrupts) = injected {
+			state.set_injected_interrupts(interrupts);
+		}
+		state.set_dump_on_exit(args.dump_on_exit);
+		state.set_deterministic(args.seed.is_some());
+		state.set_mode(args.mode);
+		state.set_exception_limit(args.exception_limit);
+		state.set_red_zone(args.red_zone);
+		state.set_costs(toml.cost.clone());
+		state.set_count_cycles(args.count_cycles);
+		state.set_max_instructions(args.max_instructions);
+		if let Some(stack) = stack {
+			state.set_stack(stack);
+		}
+		if let Some(path) = &args.profile {
+			let symbols = match &args.symbols {
+				Some(symbols) => match std::fs::read_to_string(symbols) {
+					Ok(src) => Symbols::parse(&src),
+					Err(err) => {
+						error::fatal(&format!("Could not read {}: {err}", symbols.display()))
+					}
+				},
+				None => Symbols::new(),
+			};
+			let profile = Profile::new(
+				args.profile_interval,
+				args.profile_granularity,
+				symbols,
+				path.clone(),
+			);
+			state.set_profile(profile);
+		}
+		if let Some(threshold) = args.watchdog {
+			state.set_watchdog(threshold, args.watchdog_action);
+		}
+		if let Some(entry) = args
+			.entry
+			.or(toml.boot.as_ref().and_then(|boot| boot.entry))
+		{
+			state.set_reset_vector(entry);
+		}
+		if let Some(path) = &args.checkpoint {
+			state.set_checkpoints(
+				args.checkpoint_interval,
+				args.checkpoint_count,
+				path.clone(),
+			);
+		}
+		if let Some(capacity) = args.page_fault_log {
+			state.set_page_fault_log(capacity as usize);
+		}
+		if let Some(path) = &args.events {
+			let file = std::fs::File::create(path).unwrap_or_else(|err| {
+				error::fatal(&format!("Could not create {}: {err}", path.display()))
+			});
+			state.set_events(Rc::new(Events::new(file, args.events_retired)));
+		}
+		if let Some(path) = &args.restore {
+			// A snapshot of another memory layout is rejected before anything is restored.
+			std::fs::read(path)
+				.map_err(|err| err.to_string())
+				.and_then(|data| Snapshot::from_bytes(&data))
+				.and_then(|snapshot| state.restore(&snapshot))
+				.unwrap_or_else(|err| {
+					error::fatal(&format!("Could not restore {}: {err}", path.display()))
+				});
+		}
+		for poke in &args.poke {
+			state.memory().write_physical(poke.address, &poke.bytes());
+		}
+		state
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use clap::Parser;
+
+	use crate::{
+		args::{Args, Config},
+		device::{Device, Irq},
+		machine::MachineBuilder,
+	};
+
+	/// A register which is read back.
+	struct Latch(u8);
+
+	impl Device for Latch {
+		fn out_u8(&mut self, _port: u16, byte: u8) {
+			self.0 = byte;
+		}
+
+		fn in_u8(&mut self, _port: u16) -> u8 {
+			self.0
+		}
+	}
+
+	fn latch(options: &toml::Table, irq: Option<Irq>) -> Result<Box<dyn Device>, String> {
+		assert!(options.is_empty());
+		// Irq 3 is delivered above the exception vectors.
+		assert_eq!(irq.map(|irq| irq.vector()), Some(35));
+		Ok(Box::new(Latch(0)))
+	}
+
+	#[test]
+	fn external_device() {
+		let config: Config = toml::from_str(
+			r#"
+			[[memory]]
+			start = 0
+			size = 0x10000
+			memory_type = "RAM"
+
+			[[device]]
+			ports = [0x60]
+			device_type = { External = { kind = "Latch", irq = 3 } }
+
+			[identity_map]
+			tables = 0x100000
+			"#,
+		)
+		.unwrap();
+		let machine = MachineBuilder::new(Args::parse_from(["x86rs", "config.toml"]), config)
+			.register_device_factory("Latch", latch)
+			.build()
+			.unwrap();
+		let mut processor = machine.processor();
+		processor.memory().write_physical(
+			0,
+			&[
+				0xB0, 0x07, // mov al, 7
+				0xE6, 0x60, // out 0x60, al
+				0xB0, 0x00, // mov al, 0
+				0xE4, 0x60, // in al, 0x60
+			],
+		);
+		for _ in 0..4 {
+			processor.step_instruction();
+		}
+		assert_eq!(processor.get_reg("rax".parse().unwrap()), 7);
+	}
+
+	#[test]
+	fn boot_tables() {
+		let config = |extra: &str| -> Config {
+			toml::from_str(&format!(
+				r#"
+				device = []
+
+				[[memory]]
+				start = 0
+				size = 0x10000
+				memory_type = "RAM"
+
+				[boot]
+				cr3 = 0x8000
+				entry = 0xFFFF800000001000
+				{extra}
+				"#
+			))
+			.unwrap()
+		};
+		let args = |extra: &[&str]| Args::parse_from([&["x86rs", "config.toml"], extra].concat());
+
+		let mut processor = MachineBuilder::new(args(&[]), config(""))
+			.build()
+			.unwrap()
+			.processor();
+		assert_eq!(processor.memory().paging_table_address(), 0x8000);
+		assert_eq!(processor.instruction_pointer(), 0xFFFF_8000_0000_1000);
+
+		// --entry takes precedence over the entry of the config.
+		let processor = MachineBuilder::new(args(&["--entry", "0x2000"]), config(""))
+			.build()
+			.unwrap()
+			.processor();
+		assert_eq!(processor.instruction_pointer(), 0x2000);
+
+		// Both would set cr3.
+		let conflict = MachineBuilder::new(args(&[]), config("[identity_map]\ntables = 0x100000"));
+		assert_eq!(
+			conflict.build().err().as_deref(),
+			Some("boot and identity_map cannot both set cr3")
+		);
+	}
+}
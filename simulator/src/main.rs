@@ -1,60 +1,84 @@
-#![feature(array_try_from_fn)]
-#![feature(btree_cursors)]
-#![feature(macro_metavar_expr_concat)]
-#![feature(try_blocks)]
+use std::{
+	io::Write,
+	process::exit,
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
+	thread,
+	time::Duration,
+};
 
 use clap::Parser;
+use signal_hook::consts::SIGINT;
 
-use args::{Args, Config};
-use memory::{
-	ConventionalMemory, MemoryManagementUnit, PhysicalMemoryManagementUnit, ReadOnlyMemory,
+use x86rs::{
+	Args, Config, MachineBuilder, Monitor, Multiprocessor, bench, error, info, raw_terminal,
 };
-use state::ProcessorState;
-
-use crate::device::{PortDevices, Timer, UTF8Console};
-
-mod args;
-mod device;
-mod error;
-mod instruction;
-mod interupt;
-mod memory;
-mod state;
 
 fn main() {
 	let args = Args::parse();
-	let config = std::fs::read_to_string(args.config).unwrap();
-	let toml: Config = toml::from_str(&config).unwrap();
-
-	let mut memory_management_unit = PhysicalMemoryManagementUnit::new();
-	for memory in &toml.memory {
-		match &memory.memory_type {
-			args::MemoryType::RAM => memory_management_unit.add(memory.start, memory.size, || {
-				ConventionalMemory::create(memory.size)
-			}),
-			args::MemoryType::ROM { path } => {
-				let data = std::fs::read(path).unwrap();
-				memory_management_unit.add(memory.start, memory.size, || {
-					ReadOnlyMemory::create(&data, memory.size)
-				})
-			}
-		}
+	if let Some(format) = args.info {
+		print!("{}", info::machine_info(format));
+		return;
+	}
+	if let Some(steps) = args.bench {
+		print!("{}", bench::run(steps));
+		return;
 	}
+	let toml: Config = match &args.config {
+		Some(path) => toml::from_str(&std::fs::read_to_string(path).unwrap()).unwrap(),
+		None => Config::flat(&args.load),
+	};
 
-	let mut devices = PortDevices::new();
+	if args.raw_terminal {
+		raw_terminal();
+	}
+	let machine = MachineBuilder::new(args.clone(), toml)
+		.build()
+		.unwrap_or_else(|err| error::fatal(&err));
 
-	for device in &toml.device {
-		match device.device_type {
-			args::DeviceType::UTF8Console => devices.add(&device.ports, UTF8Console),
-			args::DeviceType::Timer { irq } => devices.add(&device.ports, Timer::new(irq)),
-		}
+	if args.monitor {
+		Monitor::new(machine.processor()).run();
 	}
 
-	let memory = MemoryManagementUnit::new(memory_management_unit);
-	let mut state = ProcessorState::new(memory, devices);
+	let interrupted = Arc::new(AtomicBool::new(false));
+	// The first Ctrl-C sets the flag and the second one terminates immediately.
+	signal_hook::flag::register_conditional_shutdown(SIGINT, 130, interrupted.clone()).unwrap();
+	signal_hook::flag::register(SIGINT, interrupted.clone()).unwrap();
+
+	if args.processors > 1 {
+		// Halted processors are parked, so they have to be woken to notice the flag.
+		let processor = thread::current();
+		let watcher = interrupted.clone();
+		thread::spawn(move || {
+			while !watcher.load(Ordering::Relaxed) {
+				thread::sleep(Duration::from_millis(50));
+			}
+			processor.unpark();
+		});
 
-	loop {
-		state.step_instruction();
-		//state.eprint_primary_registers();
+		let state = machine.processor();
+		let mut multiprocessor = Multiprocessor::new(state, args.processors as usize);
+		while !interrupted.load(Ordering::Relaxed) {
+			multiprocessor.step();
+		}
+		multiprocessor.processors()[0].end_events("interrupted");
+		for (index, processor) in multiprocessor.processors().iter().enumerate() {
+			eprintln!("Processor {index}:");
+			processor.report();
+		}
+	} else {
+		let controller = machine.spawn();
+		controller.resume();
+		while !interrupted.load(Ordering::Relaxed) {
+			thread::sleep(Duration::from_millis(50));
+		}
+		controller.pause();
+		controller.end_events("interrupted");
+		controller.report();
+		controller.shutdown();
 	}
+	let _ = std::io::stdout().flush();
+	exit(130);
 }
@@ -1,7 +1,13 @@
 use std::{
-	collections::{BTreeMap, HashMap},
+	cell::{Cell, RefCell},
+	collections::{BTreeMap, BTreeSet, HashMap, HashSet},
 	iter::repeat_n,
 	ops::Bound,
+	rc::Rc,
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
 };
 
 use crate::{
@@ -9,6 +15,9 @@ use crate::{
 	interupt::{Interrupt, is_cannonical},
 };
 
+/// The supported numbers of paging levels.
+pub const PAGING_LEVELS: [u32; 2] = [4, 5];
+
 pub trait Memory {
 	/// The adrees is in [0, size), where size is the size that this memory module was created
 	/// with.
@@ -17,6 +26,22 @@ pub trait Memory {
 	/// The adrees is in [0, size), where size is the size that this memory module was created
 	/// with.
 	fn write_u8(&mut self, address: u64, value: u8);
+
+	/// Zeroes writable memory on a reset. Read only memory keeps its contents.
+	fn clear(&mut self) {}
+
+	/// The offsets of the pages which have to be saved by a snapshot. Memory which cannot be
+	/// written has none.
+	fn pages(&self) -> Vec<u64> {
+		Vec::new()
+	}
+
+	/// Writes `data` starting at `offset`. The whole range must be in [0, size).
+	fn load(&mut self, offset: u64, data: &[u8]) {
+		for (i, byte) in data.iter().enumerate() {
+			self.write_u8(offset + i as u64, *byte);
+		}
+	}
 }
 
 pub struct ConventionalMemory {
@@ -44,6 +69,30 @@ impl Memory for ConventionalMemory {
 	fn write_u8(&mut self, address: u64, value: u8) {
 		self.get_page(address)[(address & 0xFFF) as usize] = value;
 	}
+
+	fn clear(&mut self) {
+		self.pages.clear();
+	}
+
+	/// Pages which are only zeroes are skipped, as they are the same as unallocated pages.
+	fn pages(&self) -> Vec<u64> {
+		self.pages
+			.iter()
+			.filter(|(_, page)| page.iter().any(|byte| *byte != 0))
+			.map(|(page, _)| *page)
+			.collect()
+	}
+
+	fn load(&mut self, offset: u64, mut data: &[u8]) {
+		let mut address = offset;
+		while !data.is_empty() {
+			let start = (address & 0xFFF) as usize;
+			let length = data.len().min((1 << 12) - start);
+			self.get_page(address)[start..start + length].copy_from_slice(&data[..length]);
+			data = &data[length..];
+			address += length as u64;
+		}
+	}
 }
 
 pub struct ReadOnlyMemory {
@@ -75,6 +124,209 @@ impl Memory for ReadOnlyMemory {
 	}
 
 	fn write_u8(&mut self, _address: u64, _value: u8) {}
+
+	/// Loading is how the host patches ROM, so it is not ignored like writes.
+	fn load(&mut self, offset: u64, data: &[u8]) {
+		let offset = offset as usize;
+		self.data[offset..offset + data.len()].copy_from_slice(data);
+	}
+}
+
+/// ROM which can be switched to writable RAM holding the ROM contents, like the shadow RAM of
+/// chipsets. The switch is shared with the device controlling it.
+pub struct ShadowMemory {
+	rom: ReadOnlyMemory,
+	writable: Arc<AtomicBool>,
+}
+
+impl ShadowMemory {
+	pub fn create(prefix: &[u8], size: u64, writable: Arc<AtomicBool>) -> Self {
+		ShadowMemory {
+			rom: ReadOnlyMemory::create(prefix, size),
+			writable,
+		}
+	}
+}
+
+impl Memory for ShadowMemory {
+	fn read_u8(&mut self, address: u64) -> u8 {
+		self.rom.read_u8(address)
+	}
+
+	/// Writes are ignored like for ROM until the region is switched to RAM.
+	fn write_u8(&mut self, address: u64, value: u8) {
+		if self.writable.load(Ordering::Relaxed) {
+			self.rom.data[address as usize] = value;
+		}
+	}
+
+	fn pages(&self) -> Vec<u64> {
+		(0..self.rom.data.len() as u64).step_by(1 << 12).collect()
+	}
+
+	/// Loading ignores the switch, so snapshots can be restored while it is ROM.
+	fn load(&mut self, offset: u64, data: &[u8]) {
+		self.rom.load(offset, data);
+	}
+}
+
+/// What poisoned memory does when it is accessed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub enum PoisonPolicy {
+	/// Report the access and otherwise behave like RAM.
+	Log,
+
+	/// Report the access like a machine check, which ends the simulation.
+	Fatal,
+
+	/// Silently read as the byte and ignore writes.
+	Fill { byte: u8 },
+}
+
+/// Memory which should not be used, such as memory which is not initialized yet or was freed.
+/// The accesses are reported with the instruction making them, taken from the access context
+/// of the physical memory.
+pub struct PoisonMemory {
+	base: u64,
+	policy: PoisonPolicy,
+	ram: ConventionalMemory,
+	context: Rc<Cell<Option<AccessContext>>>,
+	report: Box<dyn FnMut(&str)>,
+}
+
+impl PoisonMemory {
+	/// Poisoned memory at physical address `base`. Reports are passed to `report`, which is
+	/// expected not to return for the fatal policy.
+	pub fn create(
+		base: u64,
+		size: u64,
+		policy: PoisonPolicy,
+		context: Rc<Cell<Option<AccessContext>>>,
+		report: impl FnMut(&str) + 'static,
+	) -> Self {
+		PoisonMemory {
+			base,
+			policy,
+			ram: ConventionalMemory::create(size),
+			context,
+			report: Box::new(report),
+		}
+	}
+
+	/// Reports the access of the byte at `offset`. An access through the memory management
+	/// unit reaches memory one byte at a time, so it is only reported at its first poisoned
+	/// byte.
+	fn report(&mut self, offset: u64, write: bool) {
+		let prefix = match self.policy {
+			PoisonPolicy::Log => "Poisoned memory",
+			PoisonPolicy::Fatal => "Machine check: poisoned memory",
+			PoisonPolicy::Fill { .. } => return,
+		};
+		let accessed = if write { "written" } else { "read" };
+		let by = match self.context.get() {
+			Some(AccessContext { reported: true, .. }) => return,
+			Some(context) => {
+				self.context.set(Some(AccessContext {
+					reported: true,
+					..context
+				}));
+				format!(
+					"an access of {} bytes to 0x{:X} at rip 0x{:X}",
+					context.size, context.virtual_address, context.instruction_pointer
+				)
+			}
+			None => "the host".to_string(),
+		};
+		let address = self.base + offset;
+		(self.report)(&format!("{prefix} at 0x{address:X} {accessed} by {by}"));
+	}
+}
+
+impl Memory for PoisonMemory {
+	fn read_u8(&mut self, address: u64) -> u8 {
+		self.report(address, false);
+		match self.policy {
+			PoisonPolicy::Log => self.ram.read_u8(address),
+			PoisonPolicy::Fatal => 0xFF,
+			PoisonPolicy::Fill { byte } => byte,
+		}
+	}
+
+	fn write_u8(&mut self, address: u64, value: u8) {
+		self.report(address, true);
+		if self.policy == PoisonPolicy::Log {
+			self.ram.write_u8(address, value);
+		}
+	}
+
+	fn clear(&mut self) {
+		self.ram.clear();
+	}
+
+	/// Loading is done by the host setting up memory, so it is not reported.
+	fn load(&mut self, offset: u64, data: &[u8]) {
+		if self.policy == PoisonPolicy::Log {
+			self.ram.load(offset, data);
+		}
+	}
+}
+
+/// Accesses which are allowed to a memory region independent of paging.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Permissions {
+	pub read: bool,
+	pub write: bool,
+	pub execute: bool,
+}
+
+impl Default for Permissions {
+	fn default() -> Self {
+		Permissions {
+			read: true,
+			write: true,
+			execute: true,
+		}
+	}
+}
+
+/// A stack set up for the guest, with the page below it guarding against overflow.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stack {
+	/// The initial stack pointer, aligned to 16 bytes.
+	pub top: u64,
+
+	/// The addresses of the guard page.
+	pub guard: std::ops::Range<u64>,
+}
+
+#[derive(Clone, Copy)]
+enum Access {
+	Read,
+	Write,
+	Execute,
+}
+
+impl Access {
+	/// The page fault error code bits describing the access, which are the write bit and the
+	/// instruction fetch bit.
+	fn error_code(self) -> u32 {
+		match self {
+			Access::Read => 0b00000,
+			Access::Write => 0b00010,
+			Access::Execute => 0b10000,
+		}
+	}
+}
+
+impl Permissions {
+	fn allows(&self, access: Access) -> bool {
+		match access {
+			Access::Read => self.read,
+			Access::Write => self.write,
+			Access::Execute => self.execute,
+		}
+	}
 }
 
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -89,19 +341,54 @@ impl Range {
 	}
 }
 
+/// An access of the processor which physical memory is serving, such that memory can report
+/// who accessed it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccessContext {
+	/// The instruction making the access.
+	pub instruction_pointer: u64,
+
+	/// The first byte of the access.
+	pub virtual_address: u64,
+	pub size: u8,
+
+	/// Set by memory which reported the access, as it sees the access one byte at a time.
+	pub reported: bool,
+}
+
+/// The contents of memory pages by the physical address they start at. Pages are aligned to
+/// the start of their region and the last page of a region may be short.
+pub type Pages = BTreeMap<u64, Vec<u8>>;
+
 pub struct PhysicalMemoryManagementUnit {
-	ranges: BTreeMap<Range, Box<dyn Memory>>,
+	ranges: BTreeMap<Range, (Box<dyn Memory>, Permissions)>,
+	/// The pages written since tracking was started. Nothing is tracked while it is `None`.
+	dirty: Option<BTreeSet<u64>>,
+	/// The access being served. It is `None` for accesses of the host.
+	context: Rc<Cell<Option<AccessContext>>>,
 }
 
 impl PhysicalMemoryManagementUnit {
 	pub fn new() -> PhysicalMemoryManagementUnit {
 		PhysicalMemoryManagementUnit {
 			ranges: BTreeMap::new(),
+			dirty: None,
+			context: Rc::new(Cell::new(None)),
 		}
 	}
 
-	pub fn add<T>(&mut self, base: u64, size: u64, init: impl FnOnce() -> T)
-	where
+	/// The context of the access being served, shared with memory reporting on accesses.
+	pub fn access_context(&self) -> Rc<Cell<Option<AccessContext>>> {
+		self.context.clone()
+	}
+
+	pub fn add<T>(
+		&mut self,
+		base: u64,
+		size: u64,
+		permissions: Permissions,
+		init: impl FnOnce() -> T,
+	) where
 		T: Memory + 'static,
 	{
 		let Some(end) = base.checked_add(size) else {
@@ -110,7 +397,148 @@ impl PhysicalMemoryManagementUnit {
 			));
 		};
 		let range = Range::new(base, end);
-		self.ranges.insert(range, Box::new(init()));
+		self.ranges.insert(range, (Box::new(init()), permissions));
+	}
+
+	/// Adds read only memory at `base` holding page tables which map every region at its
+	/// physical address, and with `alias` also at `alias` plus the physical address, like the
+	/// higher half of a kernel. The pages are writable and for the supervisor. The table for
+	/// cr3 is at `base`, and the tables themselves are not mapped.
+	pub fn add_identity_page_tables(
+		&mut self,
+		base: u64,
+		levels: u32,
+		alias: Option<u64>,
+	) -> Result<(), String> {
+		let mut tables = vec![[0u64; 512]];
+		for offset in [Some(0), alias].into_iter().flatten() {
+			for range in self.ranges.keys() {
+				for page in (range.begin & !0xFFF..range.end).step_by(1 << 12) {
+					let virtual_address = page.wrapping_add(offset);
+					if is_cannonical(virtual_address, 12 + 9 * levels).is_err() {
+						return Err(format!(
+							"Page 0x{page:016X} would be mapped at 0x{virtual_address:016X}, \
+							 which is not canonical"
+						));
+					}
+					let mut table = 0;
+					for level in (2..=levels).rev() {
+						let index = ((virtual_address >> (3 + 9 * level)) & 0x1FF) as usize;
+						if tables[table][index] & 1 == 0 {
+							let address = base + ((tables.len() as u64) << 12);
+							tables[table][index] = address | 3;
+							tables.push([0; 512]);
+						}
+						table = (((tables[table][index] & !0xFFF) - base) >> 12) as usize;
+					}
+					tables[table][((virtual_address >> 12) & 0x1FF) as usize] = page | 3;
+				}
+			}
+		}
+		let data: Vec<u8> = tables
+			.iter()
+			.flatten()
+			.flat_map(|entry| entry.to_le_bytes())
+			.collect();
+		let size = data.len() as u64;
+		let end = base
+			.checked_add(size)
+			.ok_or("The page tables overflow the 64-bit address space")?;
+		if let Some(range) = self.overlapping(base, end) {
+			return Err(format!(
+				"The page tables at 0x{base:X}-0x{end:X} overlap the region at 0x{:X}-0x{:X}",
+				range.begin, range.end
+			));
+		}
+		self.add(base, size, Permissions::default(), || {
+			ReadOnlyMemory::create(&data, size)
+		});
+		Ok(())
+	}
+
+	/// Checks that cr3 can point at page tables in the regions before the guest runs, which
+	/// needs it to be page aligned and the top level table to be backed by memory.
+	pub fn check_page_tables(&self, cr3: u64) -> Result<(), String> {
+		if cr3 & 0xFFF != 0 {
+			return Err(format!("cr3 0x{cr3:X} is not page aligned"));
+		}
+		if !self.mapped(cr3, 1 << 12) {
+			return Err(format!(
+				"cr3 0x{cr3:X} does not point at a page table in a memory region"
+			));
+		}
+		Ok(())
+	}
+
+	/// Adds zeroed RAM while the guest is running, which must not overlap another region.
+	pub fn hot_add(&mut self, base: u64, size: u64) -> Result<(), String> {
+		self.add_exclusive(base, size, Permissions::default())
+	}
+
+	/// Adds a zeroed stack of `size` bytes at `base` with a guard page below it, which allows
+	/// no access, such that running off the bottom of the stack page faults.
+	pub fn add_stack(&mut self, base: u64, size: u64) -> Result<Stack, String> {
+		let guard = base.checked_sub(1 << 12).ok_or(format!(
+			"The stack at 0x{base:X} leaves no room for a guard page below it"
+		))?;
+		self.add_exclusive(base, size, Permissions::default())?;
+		let none = Permissions {
+			read: false,
+			write: false,
+			execute: false,
+		};
+		self.add_exclusive(guard, 1 << 12, none)?;
+		Ok(Stack {
+			top: (base + size) & !0xF,
+			guard: guard..base,
+		})
+	}
+
+	/// Adds zeroed memory which must not overlap another region.
+	fn add_exclusive(
+		&mut self,
+		base: u64,
+		size: u64,
+		permissions: Permissions,
+	) -> Result<(), String> {
+		let end = base
+			.checked_add(size)
+			.filter(|&end| end > base)
+			.ok_or(format!("Cannot add {size} bytes at 0x{base:X}"))?;
+		if let Some(range) = self.overlapping(base, end) {
+			return Err(format!(
+				"The memory at 0x{base:X}-0x{end:X} overlaps the region at 0x{:X}-0x{:X}",
+				range.begin, range.end
+			));
+		}
+		self.add(base, size, permissions, || ConventionalMemory::create(size));
+		Ok(())
+	}
+
+	/// The first region sharing an address with `begin..end`.
+	fn overlapping(&self, begin: u64, end: u64) -> Option<&Range> {
+		self.ranges
+			.keys()
+			.find(|range| range.begin < end && begin < range.end)
+	}
+
+	/// Zeroes the writable memory of every region.
+	fn clear(&mut self) {
+		for (memory, _) in self.ranges.values_mut() {
+			memory.clear();
+		}
+	}
+
+	/// The permissions of the region containing the address. Unmapped addresses allow every
+	/// access.
+	fn permissions(&self, address: u64) -> Permissions {
+		let cursor = self
+			.ranges
+			.lower_bound(Bound::Excluded(&Range::new(address, u64::MAX)));
+		match cursor.peek_prev() {
+			Some((range, (_, permissions))) if range.end > address => *permissions,
+			_ => Permissions::default(),
+		}
 	}
 
 	fn read_u8(&mut self, address: u64) -> u8 {
@@ -118,7 +546,9 @@ impl PhysicalMemoryManagementUnit {
 			.ranges
 			.lower_bound_mut(Bound::Excluded(&Range::new(address, u64::MAX)));
 		match cursor.prev() {
-			Some((range, memory)) if range.end > address => memory.read_u8(address - range.begin),
+			Some((range, (memory, _))) if range.end > address => {
+				memory.read_u8(address - range.begin)
+			}
 			_ => 0xFF,
 		}
 	}
@@ -132,8 +562,12 @@ impl PhysicalMemoryManagementUnit {
 			.ranges
 			.lower_bound_mut(Bound::Excluded(&Range::new(address, u64::MAX)));
 		match cursor.prev() {
-			Some((range, memory)) if range.end > address => {
-				memory.write_u8(address - range.begin, value)
+			Some((range, (memory, _))) if range.end > address => {
+				let offset = address - range.begin;
+				if let Some(dirty) = &mut self.dirty {
+					dirty.insert(range.begin + (offset & !0xFFF));
+				}
+				memory.write_u8(offset, value)
 			}
 			_ => (),
 		}
@@ -146,104 +580,1268 @@ impl PhysicalMemoryManagementUnit {
 			.enumerate()
 			.for_each(|(i, value)| self.write_u8(address + i as u64, value));
 	}
+
+	/// Whether every byte of the range is in a region.
+	fn mapped(&self, mut address: u64, length: u64) -> bool {
+		let Some(end) = address.checked_add(length) else {
+			return false;
+		};
+		while address < end {
+			let cursor = self
+				.ranges
+				.lower_bound(Bound::Excluded(&Range::new(address, u64::MAX)));
+			match cursor.peek_prev() {
+				Some((range, _)) if range.end > address => address = range.end,
+				_ => return false,
+			}
+		}
+		true
+	}
+
+	/// Loads the data into the regions it spans, including read only memory. Unmapped bytes
+	/// are dropped, and the address wraps around at the top of the address space.
+	fn patch(&mut self, mut address: u64, mut data: &[u8]) {
+		while let Some((_, rest)) = data.split_first() {
+			let mut cursor = self
+				.ranges
+				.lower_bound_mut(Bound::Excluded(&Range::new(address, u64::MAX)));
+			match cursor.prev() {
+				Some((range, (memory, _))) if range.end > address => {
+					let offset = address - range.begin;
+					let length = data.len().min((range.end - address) as usize);
+					memory.load(offset, &data[..length]);
+					if let Some(dirty) = &mut self.dirty {
+						let last = offset + length as u64 - 1;
+						for page in (offset & !0xFFF..=last).step_by(1 << 12) {
+							dirty.insert(range.begin + page);
+						}
+					}
+					data = &data[length..];
+					address = address.wrapping_add(length as u64);
+				}
+				_ => {
+					data = rest;
+					address = address.wrapping_add(1);
+				}
+			}
+		}
+	}
+
+	/// Every page holding data, and starts tracking the pages written from now on.
+	fn snapshot(&mut self) -> Pages {
+		self.dirty = Some(BTreeSet::new());
+		self.pages()
+	}
+
+	/// Every page holding data, leaving the tracking of written pages as it is.
+	fn pages(&mut self) -> Pages {
+		let mut pages = Pages::new();
+		for (range, (memory, _)) in &mut self.ranges {
+			for offset in memory.pages() {
+				pages.insert(
+					range.begin + offset,
+					read_page(range, memory.as_mut(), offset),
+				);
+			}
+		}
+		pages
+	}
+
+	/// The pages written since the last snapshot or delta.
+	fn delta(&mut self) -> Pages {
+		let dirty = self.dirty.replace(BTreeSet::new()).unwrap_or_default();
+		let mut pages = Pages::new();
+		for address in dirty {
+			let mut cursor = self
+				.ranges
+				.lower_bound_mut(Bound::Excluded(&Range::new(address, u64::MAX)));
+			if let Some((range, (memory, _))) = cursor.prev() {
+				let page = read_page(range, memory.as_mut(), address - range.begin);
+				pages.insert(address, page);
+			}
+		}
+		pages
+	}
+
+	/// Writes the pages back, after zeroing the writable memory if they are a full snapshot.
+	/// Nothing is written when a page does not lie within a region, as when it was saved with
+	/// another memory layout.
+	fn restore(&mut self, pages: &Pages, full: bool) -> Result<(), String> {
+		for (address, page) in pages {
+			let cursor = self
+				.ranges
+				.lower_bound(Bound::Excluded(&Range::new(*address, u64::MAX)));
+			match cursor.peek_prev() {
+				Some((range, _))
+					if *address < range.end && page.len() as u64 <= range.end - address => {}
+				_ => {
+					return Err(format!(
+						"The page of {} bytes at 0x{address:X} is outside the memory regions",
+						page.len()
+					));
+				}
+			}
+		}
+		if full {
+			self.clear();
+		}
+		for (address, page) in pages {
+			let mut cursor = self
+				.ranges
+				.lower_bound_mut(Bound::Excluded(&Range::new(*address, u64::MAX)));
+			if let Some((range, (memory, _))) = cursor.prev() {
+				memory.load(address - range.begin, page);
+			}
+		}
+		if let Some(dirty) = &mut self.dirty {
+			dirty.clear();
+		}
+		Ok(())
+	}
+}
+
+fn read_page(range: &Range, memory: &mut dyn Memory, offset: u64) -> Vec<u8> {
+	let end = (offset + (1 << 12)).min(range.end - range.begin);
+	(offset..end).map(|offset| memory.read_u8(offset)).collect()
+}
+
+/// A data access through the memory management unit, recorded while tracing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct MemoryAccess {
+	pub address: u64,
+	pub size: u8,
+	pub value: u128,
+	pub write: bool,
+}
+
+/// A line of the page table dump.
+enum PageTableRange {
+	/// Consecutive virtual pages mapped to consecutive physical pages with the same bits.
+	Mapped {
+		virtual_address: u64,
+		physical_address: u64,
+		size: u64,
+		writable: bool,
+		user: bool,
+		no_execute: bool,
+	},
+
+	/// A table which was already listed at the same level, so it is not walked again.
+	Repeated { virtual_address: u64, table: u64 },
+}
+
+/// The bits of a paging entry which must be zero with strict page tables, which are above the
+/// physical address.
+const RESERVED_ENTRY_BITS: u64 = 0x7FF0_0000_0000_0000;
+
+/// Bit 7 of an entry above the page tables, which would select a large page. Large pages are
+/// not supported, so it must be zero with strict page tables. In a page table entry it is the
+/// PAT bit, which is allowed and ignored.
+const LARGE_PAGE_BIT: u64 = 1 << 7;
+
+/// A page fault for a paging structure with reserved bits set, which has the present and the
+/// reserved bit set in the error code.
+fn reserved_bit_fault(virtual_address: u64, access: Access) -> Interrupt {
+	Interrupt::PageFault {
+		error_code: access.error_code() | 0b01001,
+		cr2: virtual_address,
+	}
 }
 
 pub struct MemoryManagementUnit {
-	memory_management_unit: PhysicalMemoryManagementUnit,
+	/// Shared by the processors of a multiprocessor.
+	memory_management_unit: Rc<RefCell<PhysicalMemoryManagementUnit>>,
 	paging_table_address: u64,
+	/// cr3 after a reset.
+	reset_paging_table_address: u64,
+	/// The number of paging levels, which is 4 or 5.
+	levels: u32,
+	/// Fault on accesses crossing from a region into unmapped memory.
+	strict: bool,
+	/// Fault on a cr3 with low bits or paging entries with reserved bits set.
+	strict_page_tables: bool,
+	trace: Option<Vec<MemoryAccess>>,
+	/// The instruction whose accesses are being made, for the access context.
+	instruction_pointer: u64,
 }
 
 impl MemoryManagementUnit {
 	pub fn new(memory_management_unit: PhysicalMemoryManagementUnit) -> MemoryManagementUnit {
 		MemoryManagementUnit {
-			memory_management_unit,
+			memory_management_unit: Rc::new(RefCell::new(memory_management_unit)),
 			paging_table_address: 0,
+			reset_paging_table_address: 0,
+			levels: 4,
+			strict: false,
+			strict_page_tables: false,
+			trace: None,
+			instruction_pointer: 0,
 		}
 	}
 
+	/// Another memory management unit on the same physical memory, for another processor. It
+	/// starts with the same page table, but cr3 is per processor.
+	pub fn share(&self) -> MemoryManagementUnit {
+		MemoryManagementUnit {
+			memory_management_unit: self.memory_management_unit.clone(),
+			paging_table_address: self.paging_table_address,
+			reset_paging_table_address: self.reset_paging_table_address,
+			levels: self.levels,
+			strict: self.strict,
+			strict_page_tables: self.strict_page_tables,
+			trace: None,
+			instruction_pointer: 0,
+		}
+	}
+
+	/// The address in the entry at `index` of the table at `base`, which is a page table at
+	/// level 1.
 	fn extract_address(
 		&mut self,
 		base: u64,
 		index: u64,
+		level: u32,
 		virtual_address: u64,
+		access: Access,
 	) -> Result<u64, Interrupt> {
-		let entry = self.memory_management_unit.read_u64(base + 8 * index);
+		let entry = self
+			.memory_management_unit
+			.borrow_mut()
+			.read_u64(base + 8 * index);
 
 		if entry & 1 == 0 {
 			return Err(Interrupt::PageFault {
-				error_code: 0,
+				error_code: access.error_code(),
 				cr2: virtual_address,
 			});
 		}
+		let reserved = if level > 1 {
+			RESERVED_ENTRY_BITS | LARGE_PAGE_BIT
+		} else {
+			RESERVED_ENTRY_BITS
+		};
+		if self.strict_page_tables && entry & reserved != 0 {
+			return Err(reserved_bit_fault(virtual_address, access));
+		}
 
 		Ok(entry & 0x7FFF_FFFF_FFFF_F000)
 	}
 
-	fn translate(&mut self, virtual_address: u64) -> Result<u64, Interrupt> {
-		is_cannonical(virtual_address)?;
-		let level_1 = (virtual_address >> 39) & 0x1FF;
-		let level_2 = (virtual_address >> 30) & 0x1FF;
-		let level_3 = (virtual_address >> 21) & 0x1FF;
-		let level_4 = (virtual_address >> 12) & 0x1FF;
+	fn translate(&mut self, virtual_address: u64, access: Access) -> Result<u64, Interrupt> {
+		self.check_cannonical(virtual_address)?;
 		let offset = virtual_address & 0xFFF;
-		let level_2_ptr =
-			self.extract_address(self.paging_table_address, level_1, virtual_address)?;
-		let level_3_ptr = self.extract_address(level_2_ptr, level_2, virtual_address)?;
-		let level_4_ptr = self.extract_address(level_3_ptr, level_3, virtual_address)?;
-		self.extract_address(level_4_ptr, level_4, virtual_address)
-			.map(|page| page + offset)
+		let mut table = self.paging_table_address;
+		if self.strict_page_tables && table & 0xFFF != 0 {
+			return Err(reserved_bit_fault(virtual_address, access));
+		}
+		for level in (1..=self.levels).rev() {
+			let index = (virtual_address >> (3 + 9 * level)) & 0x1FF;
+			table = self.extract_address(table, index, level, virtual_address, access)?;
+		}
+		Ok(table + offset)
+	}
+
+	/// Lists the mapped virtual ranges of the current paging tables with their physical
+	/// addresses and the writable, user and no-execute bits combined over all levels. The bits
+	/// are shown as written, although only the present bit is used by translation. A table
+	/// referenced again at the same level is only listed once, so cyclic tables terminate.
+	pub fn dump_page_tables(&mut self) -> String {
+		let mut ranges = Vec::new();
+		let mut visited = HashSet::new();
+		let flags = (true, true, false);
+		let table = self.paging_table_address;
+		self.walk(table, self.levels, 0, flags, &mut visited, &mut ranges);
+		let mut dump = String::new();
+		for range in ranges {
+			match range {
+				PageTableRange::Mapped {
+					virtual_address,
+					physical_address,
+					size,
+					writable,
+					user,
+					no_execute,
+				} => {
+					dump += &format!(
+						"0x{virtual_address:016X}-0x{:016X} -> 0x{physical_address:016X} r{}{}{}\n",
+						virtual_address + (size - 1),
+						if writable { 'w' } else { '-' },
+						if no_execute { '-' } else { 'x' },
+						if user { " user" } else { "" },
+					);
+				}
+				PageTableRange::Repeated {
+					virtual_address,
+					table,
+				} => {
+					dump += &format!("0x{virtual_address:016X}: table at 0x{table:016X} again\n");
+				}
+			}
+		}
+		dump
+	}
+
+	/// Lists the present entries of a table at `level`, where level 1 holds the pages.
+	fn walk(
+		&mut self,
+		table: u64,
+		level: u32,
+		virtual_base: u64,
+		(writable, user, no_execute): (bool, bool, bool),
+		visited: &mut HashSet<(u64, u32)>,
+		ranges: &mut Vec<PageTableRange>,
+	) {
+		if !visited.insert((table, level)) {
+			ranges.push(PageTableRange::Repeated {
+				virtual_address: virtual_base,
+				table,
+			});
+			return;
+		}
+		let shift = 3 + 9 * level;
+		for index in 0..512 {
+			let entry = self
+				.memory_management_unit
+				.borrow_mut()
+				.read_u64(table + 8 * index);
+			if entry & 1 == 0 {
+				continue;
+			}
+			// Sign extend the address from the width of virtual addresses.
+			let unused = 64 - self.virtual_address_bits();
+			let virtual_address =
+				(((virtual_base | index << shift) << unused) as i64 >> unused) as u64;
+			let address = entry & 0x7FFF_FFFF_FFFF_F000;
+			let flags = (
+				writable && entry & 0b010 != 0,
+				user && entry & 0b100 != 0,
+				no_execute || entry >> 63 != 0,
+			);
+			if level > 1 {
+				self.walk(address, level - 1, virtual_address, flags, visited, ranges);
+				continue;
+			}
+			let (writable, user, no_execute) = flags;
+			if let Some(PageTableRange::Mapped {
+				virtual_address: start,
+				physical_address,
+				size,
+				writable: last_writable,
+				user: last_user,
+				no_execute: last_no_execute,
+			}) = ranges.last_mut()
+				&& *start + *size == virtual_address
+				&& *physical_address + *size == address
+				&& (*last_writable, *last_user, *last_no_execute) == flags
+			{
+				*size += 1 << 12;
+				continue;
+			}
+			ranges.push(PageTableRange::Mapped {
+				virtual_address,
+				physical_address: address,
+				size: 1 << 12,
+				writable,
+				user,
+				no_execute,
+			});
+		}
+	}
+
+	/// Resets cr3 to the configured initial paging table and optionally zeroes the writable
+	/// memory.
+	pub fn reset(&mut self, clear: bool) {
+		self.paging_table_address = self.reset_paging_table_address;
+		if clear {
+			self.memory_management_unit.borrow_mut().clear();
+		}
+	}
+
+	pub fn set_strict(&mut self, strict: bool) {
+		self.strict = strict;
+	}
+
+	/// Checks the page table bases for bits which should be zero, like a misaligned cr3, on
+	/// every translation.
+	pub fn set_strict_page_tables(&mut self, strict: bool) {
+		self.strict_page_tables = strict;
+	}
+
+	/// Selects 4 or 5 level paging.
+	pub fn set_paging_levels(&mut self, levels: u32) {
+		assert!(PAGING_LEVELS.contains(&levels));
+		self.levels = levels;
+	}
+
+	/// The width of virtual addresses, which is 48 bits with 4 paging levels and 57 with 5.
+	pub fn virtual_address_bits(&self) -> u32 {
+		12 + 9 * self.levels
+	}
+
+	/// Non-canonical addresses raise a general protection fault.
+	pub fn check_cannonical(&self, address: u64) -> Result<(), Interrupt> {
+		is_cannonical(address, self.virtual_address_bits())
+	}
+
+	/// Translates the address and checks the access against the permissions of its region. A
+	/// violation is a protection page fault. Both kinds of page faults describe the access in
+	/// the error code and have the virtual address in cr2.
+	fn translate_access(&mut self, virtual_address: u64, access: Access) -> Result<u64, Interrupt> {
+		let address = self.translate(virtual_address, access)?;
+		let permissions = self.memory_management_unit.borrow().permissions(address);
+		if !permissions.allows(access) {
+			return Err(Interrupt::PageFault {
+				error_code: access.error_code() | 1,
+				cr2: virtual_address,
+			});
+		}
+		Ok(address)
+	}
+
+	/// Reads an instruction byte, which must be in an executable region.
+	pub fn fetch_u8(&mut self, virtual_address: u64) -> Result<u8, Interrupt> {
+		self.in_context(virtual_address, 1, |mmu| {
+			mmu.translate_access(virtual_address, Access::Execute)
+				.map(|address| mmu.memory_management_unit.borrow_mut().read_u8(address))
+		})
+	}
+
+	fn read_byte(&mut self, virtual_address: u64) -> Result<u8, Interrupt> {
+		self.translate_access(virtual_address, Access::Read)
+			.map(|address| self.memory_management_unit.borrow_mut().read_u8(address))
+	}
+
+	fn write_byte(&mut self, virtual_address: u64, value: u8) -> Result<(), Interrupt> {
+		self.translate_access(virtual_address, Access::Write)
+			.map(|address| {
+				self.memory_management_unit
+					.borrow_mut()
+					.write_u8(address, value)
+			})
+	}
+
+	/// In strict mode an access which is partly in memory and partly in a gap between regions
+	/// raises a general protection fault, as it is most likely a mistake in the layout of the
+	/// regions. Faults of the translation are left to the access.
+	fn check_span(&mut self, virtual_address: u64, size: u64) -> Result<(), Interrupt> {
+		if !self.strict {
+			return Ok(());
+		}
+		let mut mapped = Vec::new();
+		for i in 0..size {
+			let Ok(address) = self.translate(virtual_address.wrapping_add(i), Access::Read) else {
+				return Ok(());
+			};
+			mapped.push(self.memory_management_unit.borrow().mapped(address, 1));
+		}
+		if mapped.contains(&true) && mapped.contains(&false) {
+			return Err(Interrupt::GeneralProtection);
+		}
+		Ok(())
+	}
+
+	/// A write crossing into a page which faults must not change the bytes before the page
+	/// boundary, as the restarted instruction may read them, so every page is checked first.
+	fn check_split_write(&mut self, virtual_address: u64, size: u64) -> Result<(), Interrupt> {
+		if virtual_address & 0xFFF <= 0x1000 - size {
+			return Ok(());
+		}
+		self.can_access(virtual_address, size, true)
+	}
+
+	/// Sets the instruction making the following accesses.
+	pub fn set_instruction_pointer(&mut self, instruction_pointer: u64) {
+		self.instruction_pointer = instruction_pointer;
+	}
+
+	/// Makes an access of `size` bytes at the address with it as the access context of the
+	/// physical memory.
+	fn in_context<T>(
+		&mut self,
+		virtual_address: u64,
+		size: u8,
+		access: impl FnOnce(&mut Self) -> T,
+	) -> T {
+		let context = self.memory_management_unit.borrow().access_context();
+		context.set(Some(AccessContext {
+			instruction_pointer: self.instruction_pointer,
+			virtual_address,
+			size,
+			reported: false,
+		}));
+		let result = access(self);
+		context.set(None);
+		result
+	}
+
+	/// Appends a completed data access to the trace if one is being recorded.
+	fn record(&mut self, address: u64, size: u8, value: u128, write: bool) {
+		if let Some(trace) = &mut self.trace {
+			trace.push(MemoryAccess {
+				address,
+				size,
+				value,
+				write,
+			});
+		}
+	}
+
+	/// Starts recording the data accesses. Instruction fetches are not recorded.
+	pub fn start_trace(&mut self) {
+		self.trace = Some(Vec::new());
+	}
+
+	/// Stops recording and returns the data accesses in the order they completed.
+	pub fn take_trace(&mut self) -> Vec<MemoryAccess> {
+		self.trace.take().unwrap_or_default()
 	}
 
 	pub fn read_u8(&mut self, virtual_address: u64) -> Result<u8, Interrupt> {
-		self.translate(virtual_address)
-			.map(|address| self.memory_management_unit.read_u8(address))
+		self.in_context(virtual_address, 1, |mmu| mmu.read_byte(virtual_address))
+			.inspect(|&value| self.record(virtual_address, 1, value as u128, false))
 	}
-	
+
 	pub fn read_u16(&mut self, virtual_address: u64) -> Result<u16, Interrupt> {
-		std::array::try_from_fn(|i| self.read_u8(virtual_address + i as u64))
-			.map(u16::from_le_bytes)
+		self.check_span(virtual_address, 2)?;
+		self.in_context(virtual_address, 2, |mmu| {
+			std::array::try_from_fn(|i| mmu.read_byte(virtual_address + i as u64))
+		})
+		.map(u16::from_le_bytes)
+		.inspect(|&value| self.record(virtual_address, 2, value as u128, false))
 	}
-	
+
 	pub fn read_u32(&mut self, virtual_address: u64) -> Result<u32, Interrupt> {
-		std::array::try_from_fn(|i| self.read_u8(virtual_address + i as u64))
-			.map(u32::from_le_bytes)
+		self.check_span(virtual_address, 4)?;
+		self.in_context(virtual_address, 4, |mmu| {
+			std::array::try_from_fn(|i| mmu.read_byte(virtual_address + i as u64))
+		})
+		.map(u32::from_le_bytes)
+		.inspect(|&value| self.record(virtual_address, 4, value as u128, false))
 	}
 
 	pub fn read_u64(&mut self, virtual_address: u64) -> Result<u64, Interrupt> {
-		std::array::try_from_fn(|i| self.read_u8(virtual_address + i as u64))
-			.map(u64::from_le_bytes)
+		self.check_span(virtual_address, 8)?;
+		self.in_context(virtual_address, 8, |mmu| {
+			std::array::try_from_fn(|i| mmu.read_byte(virtual_address + i as u64))
+		})
+		.map(u64::from_le_bytes)
+		.inspect(|&value| self.record(virtual_address, 8, value as u128, false))
+	}
+
+	pub fn read_u128(&mut self, virtual_address: u64) -> Result<u128, Interrupt> {
+		self.check_span(virtual_address, 16)?;
+		self.in_context(virtual_address, 16, |mmu| {
+			std::array::try_from_fn(|i| mmu.read_byte(virtual_address + i as u64))
+		})
+		.map(u128::from_le_bytes)
+		.inspect(|&value| self.record(virtual_address, 16, value, false))
 	}
 
 	pub fn write_u8(&mut self, virtual_address: u64, value: u8) -> Result<(), Interrupt> {
-		self.translate(virtual_address)
-			.map(|address| self.memory_management_unit.write_u8(address, value))
+		self.in_context(virtual_address, 1, |mmu| {
+			mmu.write_byte(virtual_address, value)
+		})
+		.inspect(|_| self.record(virtual_address, 1, value as u128, true))
 	}
 
 	pub fn write_u16(&mut self, virtual_address: u64, value: u16) -> Result<(), Interrupt> {
-		value
-			.to_le_bytes()
-			.into_iter()
-			.enumerate()
-			.try_for_each(|(i, value)| self.write_u8(virtual_address + i as u64, value))
+		self.check_span(virtual_address, 2)?;
+		self.check_split_write(virtual_address, 2)?;
+		self.in_context(virtual_address, 2, |mmu| {
+			value
+				.to_le_bytes()
+				.into_iter()
+				.enumerate()
+				.try_for_each(|(i, byte)| mmu.write_byte(virtual_address + i as u64, byte))
+		})
+		.inspect(|_| self.record(virtual_address, 2, value as u128, true))
 	}
-	
+
 	pub fn write_u32(&mut self, virtual_address: u64, value: u32) -> Result<(), Interrupt> {
-		value
-			.to_le_bytes()
-			.into_iter()
-			.enumerate()
-			.try_for_each(|(i, value)| self.write_u8(virtual_address + i as u64, value))
+		self.check_span(virtual_address, 4)?;
+		self.check_split_write(virtual_address, 4)?;
+		self.in_context(virtual_address, 4, |mmu| {
+			value
+				.to_le_bytes()
+				.into_iter()
+				.enumerate()
+				.try_for_each(|(i, byte)| mmu.write_byte(virtual_address + i as u64, byte))
+		})
+		.inspect(|_| self.record(virtual_address, 4, value as u128, true))
 	}
 
 	pub fn write_u64(&mut self, virtual_address: u64, value: u64) -> Result<(), Interrupt> {
-		value
-			.to_le_bytes()
-			.into_iter()
-			.enumerate()
-			.try_for_each(|(i, value)| self.write_u8(virtual_address + i as u64, value))
+		self.check_span(virtual_address, 8)?;
+		self.check_split_write(virtual_address, 8)?;
+		self.in_context(virtual_address, 8, |mmu| {
+			value
+				.to_le_bytes()
+				.into_iter()
+				.enumerate()
+				.try_for_each(|(i, byte)| mmu.write_byte(virtual_address + i as u64, byte))
+		})
+		.inspect(|_| self.record(virtual_address, 8, value as u128, true))
+	}
+
+	pub fn write_u128(&mut self, virtual_address: u64, value: u128) -> Result<(), Interrupt> {
+		self.check_span(virtual_address, 16)?;
+		self.check_split_write(virtual_address, 16)?;
+		self.in_context(virtual_address, 16, |mmu| {
+			value
+				.to_le_bytes()
+				.into_iter()
+				.enumerate()
+				.try_for_each(|(i, byte)| mmu.write_byte(virtual_address + i as u64, byte))
+		})
+		.inspect(|_| self.record(virtual_address, 16, value, true))
+	}
+
+	pub fn paging_table_address(&self) -> u64 {
+		self.paging_table_address
+	}
+
+	/// Reads physical memory without paging. Unmapped bytes read as 0xFF.
+	pub fn read_physical(&mut self, address: u64, buffer: &mut [u8]) {
+		for (i, byte) in buffer.iter_mut().enumerate() {
+			*byte = self
+				.memory_management_unit
+				.borrow_mut()
+				.read_u8(address.wrapping_add(i as u64));
+		}
+	}
+
+	/// Whether every byte of the physical range is backed by memory.
+	pub fn physical_mapped(&self, address: u64, length: u64) -> bool {
+		self.memory_management_unit.borrow().mapped(address, length)
+	}
+
+	/// Adds zeroed RAM to the physical memory, which all processors sharing it see.
+	pub fn hot_add(&mut self, base: u64, size: u64) -> Result<(), String> {
+		self.memory_management_unit.borrow_mut().hot_add(base, size)
+	}
+
+	/// Writes physical memory without paging. Read only memory is written as well, so the host
+	/// can patch it.
+	pub fn write_physical(&mut self, address: u64, data: &[u8]) {
+		self.memory_management_unit
+			.borrow_mut()
+			.patch(address, data);
+	}
+
+	/// Reads virtual memory like the processor, so a fault is returned if any byte is not
+	/// readable. With `force` only the translation has to succeed. The access is not traced.
+	pub fn read_virtual(
+		&mut self,
+		virtual_address: u64,
+		buffer: &mut [u8],
+		force: bool,
+	) -> Result<(), Interrupt> {
+		for (i, byte) in buffer.iter_mut().enumerate() {
+			let virtual_address = virtual_address.wrapping_add(i as u64);
+			*byte = if force {
+				let address = self.translate(virtual_address, Access::Read)?;
+				self.memory_management_unit.borrow_mut().read_u8(address)
+			} else {
+				self.read_byte(virtual_address)?
+			};
+		}
+		Ok(())
+	}
+
+	/// Checks that `length` bytes from the address can be read or written like the processor
+	/// would, without accessing them. The fault of the first byte which cannot is returned.
+	pub fn can_access(
+		&mut self,
+		virtual_address: u64,
+		length: u64,
+		write: bool,
+	) -> Result<(), Interrupt> {
+		let access = if write { Access::Write } else { Access::Read };
+		let Some(last) = length.checked_sub(1) else {
+			return Ok(());
+		};
+		let mut address = virtual_address;
+		loop {
+			self.translate_access(address, access)?;
+			// Every page is checked once at its first byte in the range.
+			let page_end = (address | 0xFFF).wrapping_sub(virtual_address);
+			if page_end >= last {
+				return Ok(());
+			}
+			address = virtual_address.wrapping_add(page_end + 1);
+		}
+	}
+
+	/// Writes virtual memory like the processor, so writes to read only memory are ignored.
+	/// Nothing is written if any byte faults. With `force` only the translation has to succeed
+	/// and read only memory is patched.
+	pub fn write_virtual(
+		&mut self,
+		virtual_address: u64,
+		data: &[u8],
+		force: bool,
+	) -> Result<(), Interrupt> {
+		if !force {
+			self.can_access(virtual_address, data.len() as u64, true)?;
+		}
+		for (i, byte) in data.iter().enumerate() {
+			let virtual_address = virtual_address.wrapping_add(i as u64);
+			if force {
+				let address = self.translate(virtual_address, Access::Write)?;
+				self.memory_management_unit
+					.borrow_mut()
+					.patch(address, &[*byte]);
+			} else {
+				self.write_byte(virtual_address, *byte)?;
+			}
+		}
+		Ok(())
+	}
+
+	/// Every physical page holding data, and starts tracking writes for `delta`.
+	pub fn snapshot(&mut self) -> Pages {
+		self.memory_management_unit.borrow_mut().snapshot()
+	}
+
+	/// Every physical page holding data, without restarting the tracking for `delta`.
+	pub fn pages(&mut self) -> Pages {
+		self.memory_management_unit.borrow_mut().pages()
+	}
+
+	/// The physical pages written since the last snapshot or delta.
+	pub fn delta(&mut self) -> Pages {
+		self.memory_management_unit.borrow_mut().delta()
+	}
+
+	/// Writes back the physical pages of a snapshot or a delta.
+	pub fn restore(&mut self, pages: &Pages, full: bool) -> Result<(), String> {
+		self.memory_management_unit
+			.borrow_mut()
+			.restore(pages, full)
 	}
 
 	pub fn swi4(&mut self, address: u64) {
 		self.paging_table_address = address;
 	}
+
+	/// Sets cr3 now and after every reset.
+	pub fn set_reset_paging_table_address(&mut self, address: u64) {
+		self.reset_paging_table_address = address;
+		self.paging_table_address = address;
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		cell::RefCell,
+		rc::Rc,
+		sync::{Arc, atomic::AtomicBool},
+	};
+
+	use crate::{
+		device::{Device, ShadowControl},
+		interupt::Interrupt,
+		memory::{
+			ConventionalMemory, Memory, MemoryManagementUnit, Permissions,
+			PhysicalMemoryManagementUnit, PoisonMemory, PoisonPolicy, ReadOnlyMemory, ShadowMemory,
+		},
+	};
+
+	#[test]
+	fn load() {
+		let image: Vec<u8> = (0..16 << 20).map(|i: u32| (i ^ (i >> 12)) as u8).collect();
+		let mut memory = ConventionalMemory::create(32 << 20);
+		memory.load(0x123, &image);
+		for address in [0, 0xEDC, 0xEDD, 0x1000, 0xABCDE, image.len() as u64 - 1] {
+			assert_eq!(memory.read_u8(0x123 + address), image[address as usize]);
+		}
+		assert_eq!(memory.read_u8(0x122), 0);
+		assert_eq!(memory.read_u8(0x123 + image.len() as u64), 0);
+	}
+
+	#[test]
+	fn byte_order() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x6000, Permissions::default(), || {
+			ConventionalMemory::create(0x6000)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		// The virtual pages are swapped, so a value across them is split in physical memory.
+		pmu.write_u64(0x3000, 0x5001);
+		pmu.write_u64(0x3008, 0x4001);
+		let mut mmu = MemoryManagementUnit::new(pmu);
+		let physical = |address: u64| match address {
+			0..0x1000 => 0x5000 + address,
+			_ => 0x4000 + address - 0x1000,
+		};
+		let value: u64 = 0x0807_0605_0403_0201;
+
+		// Within a page and across the two pages.
+		for (address, size) in [
+			(0x100, 2),
+			(0xFFF, 2),
+			(0x200, 4),
+			(0xFFE, 4),
+			(0x300, 8),
+			(0xFFC, 8),
+		] {
+			match size {
+				2 => mmu.write_u16(address, value as u16),
+				4 => mmu.write_u32(address, value as u32),
+				_ => mmu.write_u64(address, value),
+			}
+			.unwrap();
+			for i in 0..size {
+				let mut byte = [0];
+				mmu.read_physical(physical(address + i), &mut byte);
+				assert_eq!(byte[0], i as u8 + 1, "0x{address:X} + {i}");
+				assert_eq!(mmu.read_u8(address + i).unwrap(), i as u8 + 1);
+			}
+		}
+
+		// And the other way around, from bytes written one at a time.
+		for address in [0x800, 0xFFB] {
+			for i in 0..8 {
+				mmu.write_physical(physical(address + i), &[i as u8 + 1]);
+			}
+			assert_eq!(mmu.read_u16(address).unwrap(), value as u16);
+			assert_eq!(mmu.read_u32(address).unwrap(), value as u32);
+			assert_eq!(mmu.read_u64(address).unwrap(), value);
+		}
+	}
+
+	#[test]
+	fn strict_span() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x4000, Permissions::default(), || {
+			ConventionalMemory::create(0x4000)
+		});
+		pmu.add(0x4000, 0x800, Permissions::default(), || {
+			ConventionalMemory::create(0x800)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		pmu.write_u64(0x3000, 0x4001);
+		pmu.write_u8(0x47FF, 0xAA);
+		let mut mmu = MemoryManagementUnit::new(pmu);
+
+		// The region ends at virtual 0x800, and the rest of the page is a gap.
+		assert_eq!(mmu.read_u16(0x7FF).unwrap(), 0xFFAA);
+		mmu.set_strict(true);
+		assert!(matches!(
+			mmu.read_u16(0x7FF),
+			Err(Interrupt::GeneralProtection)
+		));
+		assert!(matches!(
+			mmu.write_u64(0x7FC, 0),
+			Err(Interrupt::GeneralProtection)
+		));
+		assert_eq!(mmu.read_u8(0x7FF).unwrap(), 0xAA);
+		// Accesses entirely in memory or entirely in the gap are fine.
+		assert_eq!(mmu.read_u64(0x7F8).unwrap() >> 56, 0xAA);
+		assert_eq!(mmu.read_u64(0x800).unwrap(), u64::MAX);
+		// A fault of the translation is raised by the access.
+		assert!(matches!(
+			mmu.read_u16(0xFFF),
+			Err(Interrupt::PageFault { cr2: 0x1000, .. })
+		));
+	}
+
+	#[test]
+	fn paging_levels() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x6000, Permissions::default(), || {
+			ConventionalMemory::create(0x6000)
+		});
+		for table in 0..5 {
+			pmu.write_u64(table << 12, ((table + 1) << 12) + 1);
+		}
+		pmu.write_u8(0x4010, 0xAA);
+		pmu.write_u8(0x5010, 0xBB);
+		let mut mmu = MemoryManagementUnit::new(pmu);
+		assert_eq!(mmu.read_u8(0x10).unwrap(), 0xAA);
+		mmu.set_paging_levels(5);
+		assert_eq!(mmu.read_u8(0x10).unwrap(), 0xBB);
+	}
+
+	#[test]
+	fn canonical_width() {
+		let mut mmu = MemoryManagementUnit::new(PhysicalMemoryManagementUnit::new());
+		for (address, canonical) in [
+			(0x0000_7FFF_FFFF_FFFF, true),
+			(0x0000_8000_0000_0000, false),
+			(0xFFFF_7FFF_FFFF_FFFF, false),
+			(0xFFFF_8000_0000_0000, true),
+			(0x00FF_FFFF_FFFF_FFFF, false),
+		] {
+			assert_eq!(
+				mmu.check_cannonical(address).is_ok(),
+				canonical,
+				"{address:X}"
+			);
+		}
+		mmu.set_paging_levels(5);
+		assert_eq!(mmu.virtual_address_bits(), 57);
+		for (address, canonical) in [
+			(0x0000_8000_0000_0000, true),
+			(0xFFFF_7FFF_FFFF_FFFF, true),
+			(0x00FF_FFFF_FFFF_FFFF, true),
+			(0x0100_0000_0000_0000, false),
+			(0xFEFF_FFFF_FFFF_FFFF, false),
+			(0xFF00_0000_0000_0000, true),
+		] {
+			assert_eq!(
+				mmu.check_cannonical(address).is_ok(),
+				canonical,
+				"{address:X}"
+			);
+		}
+	}
+
+	#[test]
+	fn shadow_memory() {
+		let writable = Arc::new(AtomicBool::new(false));
+		let mut memory = ShadowMemory::create(&[1, 2, 3], 0x1000, writable.clone());
+		let mut control = ShadowControl::new(writable);
+		memory.write_u8(1, 0xAA);
+		assert_eq!(memory.read_u8(1), 2);
+		assert_eq!(control.in_u8(0), 0);
+
+		control.out_u8(0, 1);
+		assert_eq!(control.in_u8(0), 1);
+		memory.write_u8(1, 0xAA);
+		memory.write_u8(0x800, 0xBB);
+		assert_eq!([memory.read_u8(0), memory.read_u8(1)], [1, 0xAA]);
+		assert_eq!(memory.read_u8(0x800), 0xBB);
+
+		// Switching back keeps the contents but ignores writes again.
+		control.out_u8(0, 0);
+		memory.write_u8(1, 0xCC);
+		assert_eq!(memory.read_u8(1), 0xAA);
+	}
+
+	#[test]
+	fn physical_access() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x1800, Permissions::default(), || {
+			ConventionalMemory::create(0x1800)
+		});
+		pmu.add(0x1800, 0x800, Permissions::default(), || {
+			ReadOnlyMemory::create(&[0; 0x800], 0x800)
+		});
+		let mut mmu = MemoryManagementUnit::new(pmu);
+
+		// The write spans both regions and continues into the ROM.
+		mmu.write_physical(0x17FE, &[1, 2, 3, 4]);
+		let mut buffer = [0; 6];
+		mmu.read_physical(0x17FD, &mut buffer);
+		assert_eq!(buffer, [0, 1, 2, 3, 4, 0]);
+		// Unmapped bytes are dropped and read as 0xFF.
+		mmu.write_physical(0x1FFF, &[5, 6]);
+		mmu.read_physical(0x1FFF, &mut buffer[..2]);
+		assert_eq!(buffer[..2], [5, 0xFF]);
+		// Accesses at the top of the address space wrap around to the start.
+		mmu.write_physical(u64::MAX, &[7, 8]);
+		mmu.read_physical(u64::MAX, &mut buffer[..2]);
+		assert_eq!(buffer[..2], [0xFF, 8]);
+	}
+
+	#[test]
+	fn restore_outside_regions() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x1800, Permissions::default(), || {
+			ConventionalMemory::create(0x1800)
+		});
+		pmu.add(0x10000, 0x1000, Permissions::default(), || {
+			ShadowMemory::create(&[], 0x1000, Arc::new(AtomicBool::new(false)))
+		});
+		let mut mmu = MemoryManagementUnit::new(pmu);
+		let pages = |pages: &[(u64, usize)]| {
+			pages
+				.iter()
+				.map(|&(address, length)| (address, vec![0xAA; length]))
+				.collect()
+		};
+
+		// A page past the end of a region, or reaching past it, writes nothing.
+		assert!(
+			mmu.restore(&pages(&[(0, 0x1000), (0x11000, 0x1000)]), false)
+				.is_err()
+		);
+		assert!(mmu.restore(&pages(&[(0x1000, 0x1000)]), false).is_err());
+		assert!(mmu.restore(&pages(&[(0x20000, 0x1000)]), true).is_err());
+		let mut buffer = [0; 2];
+		mmu.read_physical(0x17FF, &mut buffer);
+		assert_eq!(buffer, [0, 0xFF]);
+		mmu.read_physical(0, &mut buffer);
+		assert_eq!(buffer, [0, 0]);
+
+		assert!(
+			mmu.restore(&pages(&[(0x1000, 0x800), (0x10000, 0x1000)]), false)
+				.is_ok()
+		);
+		mmu.read_physical(0x17FF, &mut buffer);
+		assert_eq!(buffer, [0xAA, 0xFF]);
+		mmu.read_physical(0x10FFF, &mut buffer[..1]);
+		assert_eq!(buffer[0], 0xAA);
+	}
+
+	#[test]
+	fn virtual_access() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x5000, Permissions::default(), || {
+			ConventionalMemory::create(0x5000)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		pmu.write_u64(0x3000, 0x4001);
+		pmu.write_u64(0x3008, 0x5001);
+		pmu.add(0x5000, 0x1000, Permissions::default(), || {
+			ReadOnlyMemory::create(&[0xAA; 0x1000], 0x1000)
+		});
+		let mut mmu = MemoryManagementUnit::new(pmu);
+
+		// Virtual page 1 is ROM, and the writes are ignored like for the processor.
+		mmu.write_virtual(0xFFE, &[1, 2, 3, 4], false).unwrap();
+		let mut buffer = [0; 4];
+		mmu.read_virtual(0xFFE, &mut buffer, false).unwrap();
+		assert_eq!(buffer, [1, 2, 0xAA, 0xAA]);
+		mmu.write_virtual(0xFFE, &[1, 2, 3, 4], true).unwrap();
+		mmu.read_virtual(0xFFE, &mut buffer, true).unwrap();
+		assert_eq!(buffer, [1, 2, 3, 4]);
+
+		// The range straddles virtual page 1 and the unmapped page 2.
+		assert!(mmu.can_access(0x1FF0, 0x10, true).is_ok());
+		assert!(matches!(
+			mmu.can_access(0x1FF0, 0x11, false),
+			Err(Interrupt::PageFault {
+				error_code: 0b00000,
+				cr2: 0x2000
+			})
+		));
+		assert!(mmu.can_access(0x2000, 0, true).is_ok());
+		assert!(matches!(
+			mmu.write_virtual(0x1FFF, &[7, 7], false),
+			Err(Interrupt::PageFault { cr2: 0x2000, .. })
+		));
+		mmu.read_virtual(0x1FFF, &mut buffer[..1], false).unwrap();
+		assert_eq!(buffer[0], 0xAA);
+
+		// Virtual page 2 is not mapped even when forcing.
+		assert!(matches!(
+			mmu.read_virtual(0x1FFF, &mut buffer, true),
+			Err(Interrupt::PageFault { cr2: 0x2000, .. })
+		));
+		assert!(matches!(
+			mmu.write_virtual(0x2000, &[0], true),
+			Err(Interrupt::PageFault {
+				error_code: 0b00010,
+				..
+			})
+		));
+	}
+
+	#[test]
+	fn strict_page_tables() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x6000, Permissions::default(), || {
+			ConventionalMemory::create(0x6000)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		pmu.write_u64(0x3000, 0x4001);
+		// Bit 52 is reserved.
+		pmu.write_u64(0x3008, 0x0010_0000_0000_5001);
+		let mut mmu = MemoryManagementUnit::new(pmu);
+
+		// Without the check the reserved bit is taken as part of the address, which is outside
+		// of every region.
+		mmu.write_u8(0x1000, 0xAB).unwrap();
+		assert_eq!(mmu.read_u8(0x1000).unwrap(), 0xFF);
+
+		mmu.set_strict_page_tables(true);
+		assert_eq!(mmu.read_u8(0x0000).unwrap(), 0);
+		assert!(matches!(
+			mmu.write_u8(0x1000, 0),
+			Err(Interrupt::PageFault {
+				error_code: 0b01011,
+				cr2: 0x1000
+			})
+		));
+
+		// Bit 7 of a page table entry is the PAT bit, which is allowed.
+		mmu.write_physical(0x3000, &0x4081u64.to_le_bytes());
+		assert_eq!(mmu.read_u8(0x0000).unwrap(), 0);
+
+		// A large page in a higher level is reserved.
+		mmu.write_physical(0x2008, &0x4081u64.to_le_bytes());
+		assert!(matches!(
+			mmu.fetch_u8(0x20_0000),
+			Err(Interrupt::PageFault {
+				error_code: 0b11001,
+				cr2: 0x20_0000
+			})
+		));
+
+		// So is a misaligned cr3, even for an otherwise fine access.
+		mmu.swi4(0x8);
+		assert!(matches!(
+			mmu.read_u8(0x0000),
+			Err(Interrupt::PageFault {
+				error_code: 0b01001,
+				cr2: 0
+			})
+		));
+	}
+
+	#[test]
+	fn dump_page_tables() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x10000, Permissions::default(), || {
+			ConventionalMemory::create(0x10000)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x0FF8, 0x1007);
+		pmu.write_u64(0x1000, 0x2003);
+		pmu.write_u64(0x2000, 0x3003);
+		pmu.write_u64(0x2008, 0x3003);
+		for page in 0..4 {
+			pmu.write_u64(0x3000 + 8 * page, 0x8003 + (page << 12));
+		}
+		pmu.write_u64(0x3020, 0x8000_0000_0000_C001);
+		let mut mmu = MemoryManagementUnit::new(pmu);
+		assert_eq!(
+			mmu.dump_page_tables(),
+			"0x0000000000000000-0x0000000000003FFF -> 0x0000000000008000 r-x\n\
+			 0x0000000000004000-0x0000000000004FFF -> 0x000000000000C000 r--\n\
+			 0x0000000000200000: table at 0x0000000000003000 again\n\
+			 0xFFFFFF8000000000: table at 0x0000000000001000 again\n"
+		);
+	}
+
+	#[test]
+	fn region_permissions() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x5000, Permissions::default(), || {
+			ConventionalMemory::create(0x5000)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		pmu.write_u64(0x3000, 0x4001);
+		pmu.write_u64(0x3008, 0x5001);
+		let permissions = Permissions {
+			write: false,
+			execute: false,
+			..Permissions::default()
+		};
+		pmu.add(0x5000, 0x1000, permissions, || {
+			ConventionalMemory::create(0x1000)
+		});
+		let mut mmu = MemoryManagementUnit::new(pmu);
+
+		mmu.write_u8(0x10, 0xAB).unwrap();
+		assert_eq!(mmu.fetch_u8(0x10).unwrap(), 0xAB);
+		assert_eq!(mmu.read_u8(0x1010).unwrap(), 0);
+		assert!(matches!(
+			mmu.write_u8(0x1010, 0xAB),
+			Err(Interrupt::PageFault {
+				error_code: 0b00011,
+				cr2: 0x1010
+			})
+		));
+		assert!(matches!(
+			mmu.fetch_u8(0x1010),
+			Err(Interrupt::PageFault {
+				error_code: 0b10001,
+				cr2: 0x1010
+			})
+		));
+		assert_eq!(mmu.read_u8(0x1010).unwrap(), 0);
+
+		// A write reaching into the read only page changes nothing.
+		assert!(matches!(
+			mmu.write_u32(0xFFE, 0x1234_5678),
+			Err(Interrupt::PageFault {
+				error_code: 0b00011,
+				cr2: 0x1000
+			})
+		));
+		assert_eq!(mmu.read_u16(0xFFE).unwrap(), 0);
+	}
+
+	#[test]
+	fn poison() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x4000, Permissions::default(), || {
+			ConventionalMemory::create(0x4000)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		pmu.write_u64(0x3000, 0x6001);
+		pmu.write_u64(0x3008, 0x5001);
+		pmu.write_u64(0x3010, 0x4001);
+		let reports = Rc::new(RefCell::new(Vec::new()));
+		for (base, policy) in [
+			(0x4000, PoisonPolicy::Fatal),
+			(0x5000, PoisonPolicy::Fill { byte: 0xCC }),
+			(0x6000, PoisonPolicy::Log),
+		] {
+			let context = pmu.access_context();
+			let reports = reports.clone();
+			pmu.add(base, 0x1000, Permissions::default(), || {
+				PoisonMemory::create(base, 0x1000, policy, context, move |report| {
+					reports.borrow_mut().push(report.to_string())
+				})
+			});
+		}
+		let mut mmu = MemoryManagementUnit::new(pmu);
+		mmu.set_instruction_pointer(0x1234);
+
+		// Virtual page 0 logs and keeps the data. Each access is reported once.
+		mmu.write_u32(0x10, 0xDEAD_BEEF).unwrap();
+		assert_eq!(mmu.read_u16(0x12).unwrap(), 0xDEAD);
+		mmu.set_instruction_pointer(0x1238);
+		assert_eq!(mmu.read_u8(0x10).unwrap(), 0xEF);
+		assert_eq!(
+			reports.take(),
+			[
+				"Poisoned memory at 0x6010 written by an access of 4 bytes to 0x10 at rip 0x1234",
+				"Poisoned memory at 0x6012 read by an access of 2 bytes to 0x12 at rip 0x1234",
+				"Poisoned memory at 0x6010 read by an access of 1 bytes to 0x10 at rip 0x1238",
+			]
+		);
+		let mut buffer = [0];
+		mmu.read_physical(0x6011, &mut buffer);
+		assert_eq!(buffer, [0xBE]);
+		assert_eq!(
+			reports.take(),
+			["Poisoned memory at 0x6011 read by the host"]
+		);
+		// Data loaded by the host is not reported.
+		mmu.write_physical(0x6020, &[1, 2]);
+		assert!(reports.borrow().is_empty());
+
+		// Virtual page 1 reads as the fill byte without reports.
+		mmu.write_u16(0x1010, 0x1234).unwrap();
+		assert_eq!(mmu.read_u32(0x100E).unwrap(), 0xCCCC_CCCC);
+		assert!(reports.borrow().is_empty());
+
+		// Virtual page 2 is a machine check, which does not return outside of the test.
+		let _ = mmu.read_u64(0x2008);
+		assert_eq!(
+			reports.take(),
+			[
+				"Machine check: poisoned memory at 0x4008 read by an access of 8 bytes to 0x2008 \
+			  at rip 0x1238"
+			]
+		);
+	}
 }
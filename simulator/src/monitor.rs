@@ -0,0 +1,303 @@
+use std::{
+	collections::HashSet,
+	io::{BufRead, Write},
+	sync::mpsc::{Receiver, TryRecvError, channel},
+	thread,
+};
+
+use crate::{
+	args::parse_number,
+	error::fatal,
+	instruction::decode_with_prefixes,
+	state::{DumpFormat, ProcessorState, RegName, ResetMemory, Snapshot},
+};
+
+/// Spawns a thread reading monitor commands from stdin, such that commands can be received
+/// while the guest is running.
+fn spawn_reader() -> Receiver<String> {
+	let (sender, receiver) = channel();
+	let main_thread = thread::current();
+	thread::spawn(move || {
+		for line in std::io::stdin().lock().lines() {
+			let Ok(line) = line else {
+				break;
+			};
+			if sender.send(line).is_err() {
+				break;
+			}
+			// Wake the processor if it is halted so the command is seen.
+			main_thread.unpark();
+		}
+	});
+	receiver
+}
+
+fn prompt() {
+	eprint!("(x86rs) ");
+	let _ = std::io::stderr().flush();
+}
+
+pub struct Monitor {
+	state: ProcessorState,
+	breakpoints: HashSet<u64>,
+	running: bool,
+	/// A full snapshot followed by the deltas taken after it.
+	snapshots: Vec<Snapshot>,
+}
+
+impl Monitor {
+	pub fn new(state: ProcessorState) -> Monitor {
+		Monitor {
+			state,
+			breakpoints: HashSet::new(),
+			running: false,
+			snapshots: Vec::new(),
+		}
+	}
+
+	pub fn run(mut self) -> ! {
+		let commands = spawn_reader();
+		prompt();
+		loop {
+			if self.running {
+				match commands.try_recv() {
+					Ok(command) => {
+						self.running = false;
+						self.command(&command);
+						prompt();
+					}
+					Err(TryRecvError::Empty) => {
+						self.state.step_instruction();
+						if self.breakpoints.contains(&self.state.instruction_pointer()) {
+							self.running = false;
+							eprintln!(
+								"Breakpoint at 0x{:X} in ring {}",
+								self.state.instruction_pointer(),
+								self.state.cpl()
+							);
+							prompt();
+						}
+						// The reader wakes a halted processor for the next command.
+						self.state.wait_for_interrupt();
+					}
+					Err(TryRecvError::Disconnected) => self.running = false,
+				}
+			} else {
+				match commands.recv() {
+					Ok(command) => {
+						self.command(&command);
+						if !self.running {
+							prompt();
+						}
+					}
+					Err(_) => {
+						self.state.end_events("monitor input closed");
+						fatal("Monitor input closed")
+					}
+				}
+			}
+		}
+	}
+
+	fn command(&mut self, line: &str) {
+		let mut tokens = line.split_whitespace();
+		let Some(command) = tokens.next() else {
+			return;
+		};
+		let word = tokens.next();
+		let argument = word.and_then(parse_number);
+		match command {
+			"step" | "s" => {
+				for _ in 0..argument.unwrap_or(1) {
+					self.state.step_instruction();
+				}
+				self.disassemble(1);
+			}
+			"trace" | "t" => {
+				for _ in 0..argument.unwrap_or(1) {
+					self.trace();
+				}
+			}
+			"continue" | "c" => self.running = true,
+			"regs" => eprint!("{}", self.state.dump(DumpFormat::Human)),
+			"pagetables" => eprint!("{}", self.state.memory().dump_page_tables()),
+			"faults" => eprint!("{}", self.state.describe_page_faults()),
+			"b" => match argument {
+				Some(address) => {
+					self.breakpoints.insert(address);
+				}
+				None => eprintln!("Usage: b ADDRESS"),
+			},
+			"disas" => self.disassemble(argument.unwrap_or(8)),
+			"reset" => {
+				let memory = match word {
+					Some("clear") => ResetMemory::Clear,
+					_ => ResetMemory::Keep,
+				};
+				self.state.reset(memory);
+				self.disassemble(1);
+			}
+			"snapshot" => {
+				let snapshot = self.state.snapshot();
+				eprintln!("Snapshot of {} pages", snapshot.pages.len());
+				self.snapshots = vec![snapshot];
+			}
+			"delta" => {
+				if self.snapshots.is_empty() {
+					eprintln!("Take a snapshot first");
+				} else {
+					let delta = self.state.snapshot_delta();
+					eprintln!("Delta of {} pages", delta.pages.len());
+					self.snapshots.push(delta);
+				}
+			}
+			"restore" => {
+				let count = argument.map_or(self.snapshots.len(), |count| {
+					(count as usize).saturating_add(1).min(self.snapshots.len())
+				});
+				if self.snapshots.is_empty() {
+					eprintln!("Take a snapshot first");
+				} else {
+					let restored: Result<(), String> = self
+						.snapshots
+						.iter()
+						.take(count)
+						.try_for_each(|snapshot| self.state.restore(snapshot));
+					match restored {
+						Ok(()) => {
+							self.snapshots.truncate(count);
+							self.disassemble(1);
+						}
+						Err(err) => eprintln!("Could not restore: {err}"),
+					}
+				}
+			}
+			"poke" | "pokep" => {
+				let address = argument;
+				let value = tokens.next().and_then(parse_number);
+				let size = tokens.next().map_or(Some(8), parse_number);
+				match (address, value, size) {
+					(Some(address), Some(value), Some(size @ (1 | 2 | 4 | 8))) => {
+						let bytes = &value.to_le_bytes()[..size as usize];
+						if command == "pokep" {
+							self.state.memory().write_physical(address, bytes);
+						} else if let Err(interrupt) =
+							self.state.memory().write_virtual(address, bytes, true)
+						{
+							eprintln!("Cannot write 0x{address:X}: {interrupt}");
+						}
+					}
+					_ => eprintln!("Usage: {command} ADDRESS VALUE [1|2|4|8]"),
+				}
+			}
+			"set" => {
+				let name = word.map(str::parse::<RegName>);
+				match (name, tokens.next().and_then(parse_number)) {
+					(Some(Ok(name)), Some(value)) => {
+						let old = self.state.get_reg(name);
+						self.state.set_reg(name, value);
+						eprintln!("0x{old:016X} -> 0x{:016X}", self.state.get_reg(name));
+					}
+					(Some(Err(err)), _) => eprintln!("{err}"),
+					_ => eprintln!("Usage: set REGISTER VALUE"),
+				}
+			}
+			"quit" | "q" => self.state.shutdown(),
+			_ => match command.strip_prefix("xp/").and_then(parse_number) {
+				Some(address) => self.examine_physical(address, argument.unwrap_or(64)),
+				None => match command.strip_prefix("x/").and_then(parse_number) {
+					Some(address) => self.examine(address, argument.unwrap_or(64)),
+					None => eprintln!("Unknown command: {command}"),
+				},
+			},
+		}
+	}
+
+	/// Steps one instruction and prints what it changed.
+	fn trace(&mut self) {
+		let step = self.state.step();
+		match step.instruction {
+			Some(instruction) => eprintln!(
+				"0x{:016X}: {instruction} ({} bytes)",
+				step.instruction_pointer, step.size
+			),
+			None => eprintln!("0x{:016X}: <none>", step.instruction_pointer),
+		}
+		for write in step.registers {
+			eprintln!(
+				"  {}: 0x{:X} -> 0x{:X}",
+				write.register, write.old, write.new
+			);
+		}
+		for access in step.memory {
+			let kind = if access.write { "write" } else { "read" };
+			eprintln!(
+				"  {kind} {} at 0x{:016X}: 0x{:X}",
+				access.size, access.address, access.value
+			);
+		}
+		if let Some(interrupt) = step.interrupt {
+			eprintln!("  interrupt: {interrupt}");
+		}
+	}
+
+	/// Prints `count` bytes of virtual memory starting at `address`.
+	fn examine(&mut self, address: u64, count: u64) {
+		let end = address.saturating_add(count);
+		for line in (address..end).step_by(16) {
+			eprint!("0x{line:016X}:");
+			for address in line..line.saturating_add(16).min(end) {
+				let mut byte = [0];
+				match self.state.memory().read_virtual(address, &mut byte, true) {
+					Ok(()) => eprint!(" {:02X}", byte[0]),
+					Err(_) => eprint!(" ??"),
+				}
+			}
+			eprintln!();
+		}
+	}
+
+	/// Prints `count` bytes of physical memory starting at `address`.
+	fn examine_physical(&mut self, address: u64, count: u64) {
+		let end = address.saturating_add(count);
+		for line in (address..end).step_by(16) {
+			let mut bytes = vec![0; (line.saturating_add(16).min(end) - line) as usize];
+			self.state.memory().read_physical(line, &mut bytes);
+			eprint!("0x{line:016X}:");
+			for byte in bytes {
+				eprint!(" {byte:02X}");
+			}
+			eprintln!();
+		}
+	}
+
+	/// Decodes and prints `count` instructions starting at the instruction pointer.
+	fn disassemble(&mut self, count: u64) {
+		let mut address = self.state.instruction_pointer();
+		for _ in 0..count {
+			let mode = self.state.mode();
+			match decode_with_prefixes(self.state.memory(), address, mode) {
+				Ok(decoded) => {
+					let label = if decoded.instruction.simulator_specific() {
+						" (simulator specific)"
+					} else {
+						""
+					};
+					let prefixes = if decoded.prefixes.is_empty() {
+						String::new()
+					} else {
+						let names: Vec<_> =
+							decoded.prefixes.iter().map(|x| x.to_string()).collect();
+						format!(" ; {}", names.join(" "))
+					};
+					eprintln!("0x{address:016X}: {decoded}{label}{prefixes}");
+					address = address.wrapping_add(decoded.size);
+				}
+				Err(interrupt) => {
+					eprintln!("0x{address:016X}: <{interrupt}>");
+					break;
+				}
+			}
+		}
+	}
+}
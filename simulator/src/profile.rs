@@ -0,0 +1,131 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use crate::error::info;
+
+/// Symbol addresses read from the output of `nm`, used to name the lines of a profile.
+pub struct Symbols {
+	/// Sorted by address.
+	symbols: Vec<(u64, String)>,
+}
+
+impl Symbols {
+	pub fn new() -> Symbols {
+		Symbols {
+			symbols: Vec::new(),
+		}
+	}
+
+	/// Parses lines of a hexadecimal address, a type and a name. Lines without an address, like
+	/// undefined symbols, are skipped.
+	pub fn parse(src: &str) -> Symbols {
+		let mut symbols: Vec<_> = src
+			.lines()
+			.filter_map(|line| {
+				let mut tokens = line.split_whitespace();
+				let address = u64::from_str_radix(tokens.next()?, 16).ok()?;
+				let name = tokens.nth(1)?;
+				Some((address, name.to_string()))
+			})
+			.collect();
+		symbols.sort();
+		Symbols { symbols }
+	}
+
+	/// The closest symbol at or below the address with the offset from it.
+	pub fn resolve(&self, address: u64) -> Option<String> {
+		let index = self
+			.symbols
+			.partition_point(|(symbol, _)| *symbol <= address)
+			.checked_sub(1)?;
+		let (symbol, name) = &self.symbols[index];
+		Some(match address - symbol {
+			0 => name.clone(),
+			offset => format!("{name}+0x{offset:X}"),
+		})
+	}
+}
+
+/// Samples the instruction pointer of every `interval`th retired instruction into lines of
+/// `granularity` bytes.
+pub struct Profile {
+	interval: u64,
+	granularity: u64,
+	symbols: Symbols,
+	/// Write the report here when the simulation terminates.
+	path: PathBuf,
+	/// Retired instructions until the next sample.
+	remaining: u64,
+	samples: HashMap<u64, u64>,
+}
+
+impl Profile {
+	pub fn new(interval: u64, granularity: u64, symbols: Symbols, path: PathBuf) -> Profile {
+		Profile {
+			interval: interval.max(1),
+			granularity: granularity.max(1),
+			symbols,
+			path,
+			remaining: interval.max(1),
+			samples: HashMap::new(),
+		}
+	}
+
+	/// Counts a retired instruction.
+	pub fn retire(&mut self, instruction_pointer: u64) {
+		self.remaining -= 1;
+		if self.remaining == 0 {
+			self.remaining = self.interval;
+			let line = instruction_pointer - instruction_pointer % self.granularity;
+			*self.samples.entry(line).or_default() += 1;
+		}
+	}
+
+	/// The lines from the most to the least sampled, with the share of the samples and the
+	/// symbol they are in.
+	pub fn report(&self) -> String {
+		let total: u64 = self.samples.values().sum();
+		let mut lines: Vec<_> = self.samples.iter().collect();
+		lines.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+		let mut report = format!(
+			"{total} samples, one every {} retired instructions\n",
+			self.interval
+		);
+		for (line, count) in lines {
+			let percent = 100.0 * *count as f64 / total as f64;
+			report += &format!("0x{line:016X} {count:>10} {percent:>6.2}%");
+			if let Some(symbol) = self.symbols.resolve(*line) {
+				report += &format!(" {symbol}");
+			}
+			report += "\n";
+		}
+		report
+	}
+
+	/// Writes the report to the file it was configured with.
+	pub fn write(&self) {
+		if let Err(err) = std::fs::write(&self.path, self.report()) {
+			info(&format!(
+				"Could not write the profile to {}: {err}",
+				self.path.display()
+			));
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::Symbols;
+
+	#[test]
+	fn resolve_symbols() {
+		let symbols = Symbols::parse(
+			"0000000000001040 T second\n\
+			 0000000000001000 T first\n\
+			 \x20                U undefined\n",
+		);
+		assert_eq!(symbols.resolve(0x0FFF), None);
+		assert_eq!(symbols.resolve(0x1000).unwrap(), "first");
+		assert_eq!(symbols.resolve(0x1010).unwrap(), "first+0x10");
+		assert_eq!(symbols.resolve(0x2000).unwrap(), "second+0xFC0");
+	}
+}
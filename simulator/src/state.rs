@@ -1,15 +1,65 @@
-use std::{sync::{OnceLock, atomic::{AtomicU8, Ordering}}, thread};
+use std::{cell::RefCell, rc::Rc};
 
 use crate::{
-	device::PortDevices,
+	device::{Bus, InjectedInterrupts, PortDevices},
 	error::{fatal, info},
-	instruction::{Instruction, RM, Reg, decode},
-	interupt::{Interrupt, InteruptDescriptorEntry},
-	memory::MemoryManagementUnit,
+	events::{Event, Events},
+	history::History,
+	instruction::{Condition, Instruction, Mode, RM, Reg, SegmentOverride, decode_in, legacy_hint},
+	interupt::{DeliveryFailure, Interrupt, InteruptDescriptorEntry},
+	memory::{MemoryManagementUnit, PhysicalMemoryManagementUnit, Stack},
+	profile::Profile,
+	state::{alu::AluOperation, bmi::ShiftOperation},
 };
 
+/// The limit of the interrupt descriptor table, which always has 256 entries of 16 bytes.
+const IDT_LIMIT: u16 = 256 * 16 - 1;
+
+/// Model specific register numbers of the segment bases, which are written with wrmsr along
+/// with the config registers.
+const FS_BASE: u32 = 0xC000_0100;
+const GS_BASE: u32 = 0xC000_0101;
+const KERNEL_GS_BASE: u32 = 0xC000_0102;
+const TSC_AUX: u32 = 0xC000_0103;
+/// The interrupt stack pointer, which is also config register 1.
+const INTERRUPT_STACK: u32 = 0xC000_0104;
+
+/// Long mode runs with protection, paging and native x87 errors enabled. Only the bits in the
+/// machine status word below can be changed, which is done with lmsw.
+const CR0_DEFAULT: u64 = CR0_PE | CR0_ET | CR0_NE | CR0_WP | CR0_PG;
+const CR0_PE: u64 = 1 << 0;
+const CR0_LMSW: u64 = 0xF;
+const CR0_ET: u64 = 1 << 4;
+const CR0_NE: u64 = 1 << 5;
+const CR0_WP: u64 = 1 << 16;
+const CR0_PG: u64 = 1 << 31;
+
+/// The bit of cr4, which is config register 3, enabling the fs and gs base instructions.
+const CR4_FSGSBASE: u64 = 1 << 16;
+
+/// The state components which can be enabled in xcr0. The x87 state must always be enabled.
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_SUPPORTED: u64 = XCR0_X87 | XCR0_SSE;
+
+/// The reset value of mxcsr, which masks all floating point exceptions. Only the low 16 bits
+/// are defined.
+const MXCSR_DEFAULT: u32 = 0x1F80;
+const MXCSR_RESERVED: u32 = 0xFFFF_0000;
+
+/// The x87 control word after fninit, which masks all exceptions and selects double extended
+/// precision.
+const FPU_CONTROL_WORD_DEFAULT: u16 = 0x037F;
+
+/// The single step bit of dr6, which is set when the trap flag causes a debug exception.
+const DR6_BS: u64 = 1 << 14;
+
 const A: Reg = Reg(0);
+const C: Reg = Reg(1);
+const D: Reg = Reg(2);
 const SP: Reg = Reg(4);
+const SI: Reg = Reg(6);
+const DI: Reg = Reg(7);
 
 pub struct Registers {
 	/// The primary register file which is always available.
@@ -17,6 +67,9 @@ pub struct Registers {
 
 	/// Config registers.
 	config_registers: [u64; 256],
+
+	/// The sse register file.
+	xmm_registers: [u128; 16],
 }
 
 impl Registers {
@@ -24,12 +77,154 @@ impl Registers {
 		Registers {
 			primary_registers: [0; 16],
 			config_registers: [0; 256],
+			xmm_registers: [0; 16],
+		}
+	}
+}
+
+/// Canonical names of the primary registers by register number.
+pub const REGISTER_NAMES: [&str; 16] = [
+	"rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12", "r13",
+	"r14", "r15",
+];
+
+/// A register the host reads and writes by name, such as the monitor. The primary registers
+/// come first in the order of their numbers, so converting one to `usize` gives its number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegName {
+	Rax,
+	Rcx,
+	Rdx,
+	Rbx,
+	Rsp,
+	Rbp,
+	Rsi,
+	Rdi,
+	R8,
+	R9,
+	R10,
+	R11,
+	R12,
+	R13,
+	R14,
+	R15,
+	Rip,
+	Rflags,
+}
+
+impl RegName {
+	/// The primary registers by register number, named like [`REGISTER_NAMES`].
+	pub const PRIMARY: [RegName; 16] = [
+		RegName::Rax,
+		RegName::Rcx,
+		RegName::Rdx,
+		RegName::Rbx,
+		RegName::Rsp,
+		RegName::Rbp,
+		RegName::Rsi,
+		RegName::Rdi,
+		RegName::R8,
+		RegName::R9,
+		RegName::R10,
+		RegName::R11,
+		RegName::R12,
+		RegName::R13,
+		RegName::R14,
+		RegName::R15,
+	];
+}
+
+impl std::str::FromStr for RegName {
+	type Err = String;
+
+	fn from_str(name: &str) -> Result<RegName, String> {
+		match name {
+			"rip" => Ok(RegName::Rip),
+			"rflags" => Ok(RegName::Rflags),
+			_ => REGISTER_NAMES
+				.iter()
+				.position(|register| *register == name)
+				.map(|index| RegName::PRIMARY[index])
+				.ok_or_else(|| format!("Unknown register {name}")),
 		}
 	}
 }
 
-static IRQ: AtomicU8 = AtomicU8::new(0);
-static MAIN_THREAD: OnceLock<thread::Thread> = OnceLock::new();
+mod alu;
+mod apic;
+mod bmi;
+mod checkpoint;
+mod controller;
+mod cost;
+mod faults;
+mod hypercall;
+mod pmc;
+mod smp;
+mod snapshot;
+mod sse;
+mod string;
+mod trace;
+mod watchdog;
+
+pub use controller::Controller;
+pub use cost::Costs;
+pub use smp::Multiprocessor;
+pub use snapshot::Snapshot;
+pub use watchdog::WatchdogAction;
+
+pub const CF: u64 = 1 << 0;
+pub const PF: u64 = 1 << 2;
+pub const AF: u64 = 1 << 4;
+pub const ZF: u64 = 1 << 6;
+pub const SF: u64 = 1 << 7;
+pub const TF: u64 = 1 << 8;
+pub const IF: u64 = 1 << 9;
+pub const DF: u64 = 1 << 10;
+pub const OF: u64 = 1 << 11;
+pub const IOPL: u64 = 3 << 12;
+
+/// Bit 1 of rflags is reserved and always reads as set on x86.
+const RFLAGS_RESERVED: u64 = 1 << 1;
+
+/// Unlike on hardware interrupts are enabled after a reset, as guests written before IF was
+/// modelled rely on receiving them without `sti`.
+const RFLAGS_RESET: u64 = RFLAGS_RESERVED | IF;
+
+/// Mnemonics of the defined bits of rflags.
+const FLAG_NAMES: [(u32, &str); 9] = [
+	(0, "CF"),
+	(2, "PF"),
+	(4, "AF"),
+	(6, "ZF"),
+	(7, "SF"),
+	(8, "TF"),
+	(9, "IF"),
+	(10, "DF"),
+	(11, "OF"),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum DumpFormat {
+	/// Hex values with register names, one per line.
+	Human,
+
+	/// A single JSON object.
+	Json,
+}
+
+/// Number of retired instructions kept for diagnostics.
+const RETIRED_HISTORY: usize = 32;
+
+/// Number of raised interrupts kept for diagnostics.
+const INTERRUPT_HISTORY: usize = 16;
+
+/// The default number of consecutive exceptions without a retired instruction before the
+/// simulation is aborted.
+pub const DEFAULT_EXCEPTION_LIMIT: u32 = 64;
+
+/// The default number of bytes below rsp which an interrupt of ring 0 code leaves alone, the
+/// red zone of the System V ABI.
+pub const DEFAULT_RED_ZONE: u64 = 128;
 
 pub struct ProcessorState {
 	/// The register file. Note c3 is not a register but a field in the memory management unit.
@@ -48,8 +243,154 @@ pub struct ProcessorState {
 	/// The current instruction pointer (virtual address).
 	instruction_pointer: u64,
 
+	/// The address of the instruction after the executing one, which rip-relative operands are
+	/// relative to.
+	next_instruction_pointer: u64,
+
 	/// Flags
 	rflags: u64,
+
+	/// Base addresses added to memory operands with an fs or gs segment override.
+	fs_base: u64,
+	gs_base: u64,
+
+	/// The gs base swapped in by swapgs on kernel entry.
+	kernel_gs_base: u64,
+
+	/// Control register 0. It is only observable through smsw and lmsw.
+	cr0: u64,
+
+	/// There is no clock, so the time stamp counter counts the cycles of the retired
+	/// instructions given by `costs`.
+	time_stamp_counter: u64,
+
+	/// The cycles of each instruction, which is 1 by default.
+	costs: Costs,
+
+	/// The deterministic timer and the instruction limit count cycles instead of instructions.
+	count_cycles: bool,
+
+	/// The times the devices are clocked by the next step. It is the cost of the instruction
+	/// retired by the previous step when counting cycles, and 1 otherwise.
+	ticks: u64,
+
+	/// Stop after this many retired instructions, or cycles when counting cycles.
+	max_instructions: Option<u64>,
+
+	/// The executing instruction has used a memory operand.
+	memory_operand: bool,
+
+	/// The value returned in ecx by rdtscp, which is usually the processor number.
+	tsc_aux: u32,
+
+	/// Extended control register 0, which enables state components for xsave.
+	xcr0: u64,
+
+	/// The sse control and status register. It is only stored, so exceptions are never raised
+	/// and the rounding mode is always round to nearest.
+	mxcsr: u32,
+
+	/// The x87 control word. There is no x87 stack or arithmetic, so it is only stored.
+	fpu_control_word: u16,
+
+	/// Debug registers dr0 to dr7. Only the data breakpoints in dr0 to dr3 are supported.
+	debug_registers: [u64; 8],
+
+	/// A data breakpoint was hit by the current instruction.
+	debug_trap: bool,
+
+	/// The interrupt raised by int3 or int, which is delivered once the instruction retired.
+	software_interrupt: Option<Interrupt>,
+
+	/// Dump the state in this format when the simulation terminates.
+	dump_on_exit: Option<DumpFormat>,
+
+	/// The most recently retired instructions with their instruction pointers.
+	retired: History<(u64, Instruction)>,
+
+	/// The number of instructions retired since the last reset.
+	retired_count: u64,
+
+	/// The other events the guest can count with rdpmc.
+	counters: pmc::PerformanceCounters,
+
+	/// The most recently raised interrupts.
+	interrupts: History<InterruptRecord>,
+
+	/// Exceptions raised since the last retired instruction. Interrupt requests are not
+	/// counted.
+	consecutive_exceptions: u32,
+
+	/// Abort when more consecutive exceptions than this are raised, as the guest is stuck
+	/// faulting, for example on an idt entry whose service routine faults.
+	exception_limit: u32,
+
+	/// Bytes below rsp which are skipped when ring 0 code is interrupted on the interrupt
+	/// stack, as leaf functions may keep data there.
+	red_zone: u64,
+
+	/// Devices are clocked by processor steps, so a halted processor must keep being stepped
+	/// instead of waiting for another thread.
+	deterministic: bool,
+
+	/// The default operand and address sizes instructions are decoded with.
+	mode: Mode,
+
+	/// Waiting in hlt for an interrupt. Stepping a halted processor only delivers the interrupts
+	/// and clocks the devices, and the caller decides whether to wait for one.
+	halted: bool,
+
+	/// Interrupts sent to this processor by the processors.
+	ipis: apic::InterruptQueue,
+
+	/// Interrupts raised by the interrupt injector devices, which are wired to the first
+	/// processor like the other devices.
+	injected: Option<Rc<RefCell<InjectedInterrupts>>>,
+
+	/// Set by sti enabling interrupts, so they are only taken after the next instruction.
+	interrupt_shadow: bool,
+
+	/// The interrupt queues of all processors by number, including this one.
+	processors: Vec<apic::InterruptQueue>,
+
+	/// The instruction pointer after a reset.
+	reset_vector: u64,
+
+	/// The stack rsp points at after a reset.
+	stack: Option<Stack>,
+
+	/// Detects a guest which stopped making progress.
+	watchdog: Option<watchdog::Watchdog>,
+
+	/// Samples where the guest spends its time.
+	profile: Option<Profile>,
+
+	/// Saves the state periodically during long runs.
+	checkpoints: Option<checkpoint::Checkpoints>,
+
+	/// The most recent page faults, when enabled.
+	page_faults: Option<faults::PageFaultLog>,
+
+	/// Where the events are emitted, when enabled.
+	events: Option<Rc<Events>>,
+}
+
+/// An interrupt and the instruction pointer it was raised at.
+#[derive(Clone, Copy, Debug)]
+struct InterruptRecord {
+	instruction_pointer: u64,
+	vector: u64,
+	interrupt: Interrupt,
+}
+
+/// What happens to memory on a reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResetMemory {
+	/// RAM keeps its contents.
+	Keep,
+
+	/// RAM is zeroed. ROM always keeps its contents.
+	Clear,
 }
 
 macro_rules! read_write_rm {
@@ -57,42 +398,9 @@ macro_rules! read_write_rm {
 		fn ${concat(write_rm_, $size)}(&mut self, rm: RM, value: $size) -> Result<(), Interrupt> {
 			match rm {
 				RM::Reg(reg) => Ok(self.${concat(write_reg_, $size)}(Reg(reg), value)),
-				RM::RipRel {
-					displacement,
-					address_override,
-				} => {
-					let rip = if address_override {
-						self.instruction_pointer & 0xFFFF
-					} else {
-						self.instruction_pointer
-					};
-					self.memory.${concat(write_, $size)}(rip + displacement as u64, value)
-				}
-				RM::Mem {
-					index,
-					scale,
-					base,
-					displacement,
-					address_override,
-					#[allow(unused)]
-					segment_override,
-				} => {
-					let base = if base == 0xFF {
-						0
-					} else {
-						self.read_reg_u64(Reg(base))
-					};
-					let index = if index == 4 {
-						0
-					} else {
-						self.read_reg_u64(Reg(index))
-					};
-					let address = base + (index << scale) + displacement as u64;
-					let address = if address_override {
-						address & 0xFFFF
-					} else {
-						address
-					};
+				_ => {
+					let address = self.effective_address(rm);
+					self.watch(address, size_of::<$size>() as u64, true);
 					self.memory.${concat(write_, $size)}(address, value)
 				}
 			}
@@ -101,42 +409,9 @@ macro_rules! read_write_rm {
 		fn ${concat(read_rm_, $size)}(&mut self, rm: RM) -> Result<$size, Interrupt> {
 			match rm {
 				RM::Reg(reg) => Ok(self.${concat(read_reg_, $size)}(Reg(reg))),
-				RM::RipRel {
-					displacement,
-					address_override,
-				} => {
-					let rip = if address_override {
-						self.instruction_pointer & 0xFFFF
-					} else {
-						self.instruction_pointer
-					};
-					self.memory.${concat(read_, $size)}(rip + displacement as u64)
-				}
-				RM::Mem {
-					index,
-					scale,
-					base,
-					displacement,
-					address_override,
-					#[allow(unused)]
-					segment_override,
-				} => {
-					let base = if base == 0xFF {
-						0
-					} else {
-						self.read_reg_u64(Reg(base))
-					};
-					let index = if index == 4 {
-						0
-					} else {
-						self.read_reg_u64(Reg(index))
-					};
-					let address = base + (index << scale) + displacement as u64;
-					let address = if address_override {
-						address & 0xFFFF
-					} else {
-						address
-					};
+				_ => {
+					let address = self.effective_address(rm);
+					self.watch(address, size_of::<$size>() as u64, false);
 					self.memory.${concat(read_, $size)}(address)
 				}
 			}
@@ -146,73 +421,356 @@ macro_rules! read_write_rm {
 
 impl ProcessorState {
 	pub fn new(memory: MemoryManagementUnit, devices: PortDevices) -> ProcessorState {
-		let _ = MAIN_THREAD.set(thread::current());
+		let ipis = apic::InterruptQueue::default();
 		ProcessorState {
 			registers: Registers::new(),
 			memory,
 			devices,
 			cpl: 0,
 			instruction_pointer: 0,
-			rflags: 0,
+			next_instruction_pointer: 0,
+			rflags: RFLAGS_RESET,
+			fs_base: 0,
+			gs_base: 0,
+			kernel_gs_base: 0,
+			cr0: CR0_DEFAULT,
+			time_stamp_counter: 0,
+			costs: Costs::default(),
+			count_cycles: false,
+			ticks: 1,
+			max_instructions: None,
+			memory_operand: false,
+			tsc_aux: 0,
+			xcr0: XCR0_X87,
+			mxcsr: MXCSR_DEFAULT,
+			fpu_control_word: FPU_CONTROL_WORD_DEFAULT,
+			debug_registers: [0; 8],
+			debug_trap: false,
+			software_interrupt: None,
+			dump_on_exit: None,
+			retired: History::new(RETIRED_HISTORY),
+			retired_count: 0,
+			counters: pmc::PerformanceCounters::default(),
+			interrupts: History::new(INTERRUPT_HISTORY),
+			consecutive_exceptions: 0,
+			exception_limit: DEFAULT_EXCEPTION_LIMIT,
+			red_zone: DEFAULT_RED_ZONE,
+			deterministic: false,
+			mode: Mode::Bits64,
+			halted: false,
+			processors: vec![ipis.clone()],
+			ipis,
+			injected: None,
+			interrupt_shadow: false,
+			reset_vector: 0,
+			stack: None,
+			watchdog: None,
+			profile: None,
+			checkpoints: None,
+			page_faults: None,
+			events: None,
+		}
+	}
+
+	/// Restores the state after boot, keeping the memory regions, the devices and the
+	/// settings. There is no TLB to flush.
+	pub fn reset(&mut self, memory: ResetMemory) {
+		let placeholder = MemoryManagementUnit::new(PhysicalMemoryManagementUnit::new());
+		let mut mmu = std::mem::replace(&mut self.memory, placeholder);
+		mmu.reset(memory == ResetMemory::Clear);
+		let devices = std::mem::replace(&mut self.devices, PortDevices::new());
+		*self = ProcessorState {
+			instruction_pointer: self.reset_vector,
+			dump_on_exit: self.dump_on_exit,
+			deterministic: self.deterministic,
+			mode: self.mode,
+			ipis: self.ipis.clone(),
+			processors: std::mem::take(&mut self.processors),
+			reset_vector: self.reset_vector,
+			stack: self.stack.take(),
+			exception_limit: self.exception_limit,
+			red_zone: self.red_zone,
+			costs: std::mem::take(&mut self.costs),
+			count_cycles: self.count_cycles,
+			max_instructions: self.max_instructions,
+			watchdog: self.watchdog.take(),
+			profile: self.profile.take(),
+			checkpoints: self.checkpoints.take(),
+			page_faults: self.page_faults.take(),
+			events: self.events.take(),
+			injected: self.injected.take(),
+			..ProcessorState::new(mmu, devices)
+		};
+		if let Some(stack) = &self.stack {
+			self.registers.primary_registers[4] = stack.top;
+		}
+	}
+
+	pub fn set_deterministic(&mut self, deterministic: bool) {
+		self.deterministic = deterministic;
+	}
+
+	pub fn set_mode(&mut self, mode: Mode) {
+		self.mode = mode;
+	}
+
+	pub fn mode(&self) -> Mode {
+		self.mode
+	}
+
+	pub fn set_exception_limit(&mut self, limit: u32) {
+		self.exception_limit = limit;
+	}
+
+	pub fn set_red_zone(&mut self, red_zone: u64) {
+		self.red_zone = red_zone;
+	}
+
+	pub fn set_costs(&mut self, costs: Costs) {
+		self.costs = costs;
+	}
+
+	/// Clocks the devices once per cycle of the retired instructions instead of once per
+	/// step, and applies the instruction limit to the time stamp counter.
+	pub fn set_count_cycles(&mut self, count_cycles: bool) {
+		self.count_cycles = count_cycles;
+	}
+
+	pub fn set_max_instructions(&mut self, limit: Option<u64>) {
+		self.max_instructions = limit;
+	}
+
+	pub fn set_profile(&mut self, profile: Profile) {
+		self.profile = Some(profile);
+	}
+
+	/// Takes the interrupts raised by interrupt injector devices or the host.
+	pub fn set_injected_interrupts(&mut self, interrupts: Rc<RefCell<InjectedInterrupts>>) {
+		self.injected = Some(interrupts);
+	}
+
+	/// Emits the events of the processor and its devices.
+	pub fn set_events(&mut self, events: Rc<Events>) {
+		self.devices.set_events(events.clone());
+		self.events = Some(events);
+	}
+
+	fn emit(&self, instruction_pointer: u64, event: Event) {
+		if let Some(events) = &self.events {
+			events.emit(instruction_pointer, self.retired_count, event);
+		}
+	}
+
+	/// Emits `run_ended` with the reason and waits until the events are written.
+	pub fn end_events(&self, reason: &str) {
+		if let Some(events) = &self.events {
+			events.finish(self.instruction_pointer, self.retired_count, reason);
+		}
+	}
+
+	pub fn set_dump_on_exit(&mut self, format: Option<DumpFormat>) {
+		self.dump_on_exit = format;
+	}
+
+	pub fn instruction_pointer(&self) -> u64 {
+		self.instruction_pointer
+	}
+
+	/// Reads a register by name.
+	pub fn get_reg(&self, name: RegName) -> u64 {
+		match name {
+			RegName::Rip => self.instruction_pointer,
+			RegName::Rflags => self.rflags,
+			primary => self.registers.primary_registers[primary as usize],
+		}
+	}
+
+	/// Writes a register by name. The reserved bit 1 of rflags stays set.
+	pub fn set_reg(&mut self, name: RegName, value: u64) {
+		match name {
+			RegName::Rip => self.instruction_pointer = value,
+			RegName::Rflags => self.rflags = value | RFLAGS_RESERVED,
+			primary => self.registers.primary_registers[primary as usize] = value,
+		}
+	}
+
+	/// Sets the instruction pointer used now and after a reset.
+	pub fn set_reset_vector(&mut self, reset_vector: u64) {
+		self.reset_vector = reset_vector;
+		self.instruction_pointer = reset_vector;
+	}
+
+	/// Points rsp at the top of the stack now and after a reset. Page faults in its guard page
+	/// are reported as a likely stack overflow. The stack is at the same virtual and physical
+	/// address, so cr2 is compared with the physical guard page.
+	pub fn set_stack(&mut self, stack: Stack) {
+		self.registers.primary_registers[4] = stack.top;
+		self.stack = Some(stack);
+	}
+
+	/// A note on the cause of an interrupt for the log.
+	fn hint(&self, interrupt: Interrupt) -> String {
+		match (interrupt, &self.stack) {
+			(Interrupt::PageFault { cr2, .. }, Some(stack)) if stack.guard.contains(&cr2) => {
+				", likely stack overflow".to_string()
+			}
+			(Interrupt::Undefined { opcode }, _) if self.mode == Mode::Bits64 => {
+				legacy_hint(&opcode).map_or(String::new(), |hint| format!(", {hint}"))
+			}
+			_ => String::new(),
 		}
 	}
 
+	/// The current privilege level.
+	pub fn cpl(&self) -> i8 {
+		self.cpl
+	}
+
+	/// The memory management unit translating with the current paging tables.
+	pub fn memory(&mut self) -> &mut MemoryManagementUnit {
+		&mut self.memory
+	}
+
 	fn interrupt(&mut self, interrupt: Interrupt) {
 		info(&format!(
-			"Rip: 0x{:X}, Interrupt: {interrupt}",
-			self.instruction_pointer
+			"Rip: 0x{:X}, Interrupt: {interrupt}{}",
+			self.instruction_pointer,
+			self.hint(interrupt)
 		));
-		let (vector, error) = match interrupt {
-			Interrupt::Undefined => (0x06, 0x00),
-			Interrupt::DoubleFault => (0x08, 0x00),
-			Interrupt::GeneralProtection => (0x0D, 0x00),
+		let vector = interrupt.vector();
+		let error = match interrupt {
 			Interrupt::PageFault { error_code, cr2 } => {
 				self.registers.config_registers[2] = cr2;
-				(0x0E, error_code)
+				self.counters.page_faults += 1;
+				self.record_page_fault(cr2, error_code);
+				self.emit(
+					self.instruction_pointer,
+					Event::PageFault { cr2, error_code },
+				);
+				error_code
 			}
-			Interrupt::InterruptRequest(irq) => (irq as u64, 0x00),
+			_ => 0x00,
 		};
-		let interrupt_entry_ptr = self.registers.config_registers[0] + 16 * vector;
-		if try {
-			let data: [u8; 16] =
-				std::array::try_from_fn(|i| self.memory.read_u8(interrupt_entry_ptr + i as u64))?;
-			let entry: InteruptDescriptorEntry = unsafe { std::mem::transmute(data) };
-			if !entry.present || entry.rpl < self.cpl {
-				// This should only be checked
-				// on software interrupts.
-				Err(Interrupt::DoubleFault)?;
-			}
-			let stack_pointer = self.registers.primary_registers[4];
-			let new_stack_pointer = self.registers.config_registers[1];
-			self.memory
-				.write_u64(new_stack_pointer - 8, stack_pointer)?;
-			self.memory.write_u64(new_stack_pointer - 16, self.rflags)?;
-			self.memory
-				.write_u64(new_stack_pointer - 24, self.instruction_pointer)?;
-			self.memory
-				.write_u64(new_stack_pointer - 32, error as u64)?;
-			self.instruction_pointer = entry.service_routine;
-			self.registers.primary_registers[4] = new_stack_pointer - 32;
-			self.cpl = 0;
+		self.interrupts.push(InterruptRecord {
+			instruction_pointer: self.instruction_pointer,
+			vector,
+			interrupt,
+		});
+		if !matches!(interrupt, Interrupt::InterruptRequest(_)) {
+			self.consecutive_exceptions += 1;
+			if self.consecutive_exceptions > self.exception_limit {
+				self.end_events("exception limit");
+				self.report();
+				eprint!("{}", self.describe_idt_entry(vector));
+				fatal(&format!(
+					"{} exceptions without retiring an instruction",
+					self.consecutive_exceptions
+				));
+			}
 		}
-		.is_err()
-		{
+		let instruction_pointer = self.instruction_pointer;
+		if let Err(failure) = self.deliver(vector, error as u64) {
+			info(&format!("Delivering {interrupt} failed: {failure}"));
+			if matches!(interrupt, Interrupt::PageFault { .. }) {
+				self.escalate_page_fault();
+			}
 			if matches!(interrupt, Interrupt::DoubleFault) {
+				self.end_events("triple fault");
+				self.report();
 				fatal("Tripple fault");
 			} else {
 				self.interrupt(Interrupt::DoubleFault);
 			}
+		} else if self.events.is_some() {
+			let event = Event::Interrupt {
+				vector,
+				interrupt: &interrupt.to_string(),
+				error_code: error as u64,
+			};
+			self.emit(instruction_pointer, event);
+		}
+	}
+
+	/// Pushes the interrupt frame on the interrupt stack and enters the service routine for
+	/// `vector` in ring 0. The sse registers are left as they are, since there is no lazy saving
+	/// of the floating point state to model, so a service routine using them must save them
+	/// itself.
+	fn deliver(&mut self, vector: u64, error: u64) -> Result<(), DeliveryFailure> {
+		let interrupt_entry_ptr = self.registers.config_registers[0].wrapping_add(16 * vector);
+		let data: [u8; 16] = std::array::try_from_fn(|i| {
+			let address = interrupt_entry_ptr.wrapping_add(i as u64);
+			self.memory
+				.read_u8(address)
+				.map_err(|fault| DeliveryFailure::EntryFault { address, fault })
+		})?;
+		let entry =
+			InteruptDescriptorEntry::parse(&data).map_err(|offset| DeliveryFailure::Malformed {
+				vector,
+				offset,
+				byte: data[offset],
+			})?;
+		if !entry.present {
+			Err(DeliveryFailure::NotPresent { vector })?;
+		}
+		if self.memory.check_cannonical(entry.service_routine).is_err() {
+			Err(DeliveryFailure::NonCanonical {
+				vector,
+				service_routine: entry.service_routine,
+			})?;
+		}
+		if entry.rpl < self.cpl {
+			// This should only be checked
+			// on software interrupts.
+			Err(DeliveryFailure::Privilege {
+				vector,
+				rpl: entry.rpl,
+				cpl: self.cpl,
+			})?;
+		}
+		let stack_pointer = self.registers.primary_registers[4];
+		// Ring 0 code interrupted below the top of the interrupt stack, like a service routine
+		// or a kernel running on the interrupt stack, keeps its frame and red zone. The frame
+		// holds the rsp from before this adjustment, which iret restores.
+		let mut new_stack_pointer = self.registers.config_registers[1];
+		if self.cpl == 0 {
+			new_stack_pointer = new_stack_pointer.min(stack_pointer.wrapping_sub(self.red_zone));
 		}
+		let new_stack_pointer = new_stack_pointer & !0xF;
+		// The upper half of rflags is reserved, so the frame keeps the interrupted cpl there for
+		// iret to return to.
+		let rflags = self.rflags | (self.cpl as u64) << 32;
+		let frame = [stack_pointer, rflags, self.instruction_pointer, error];
+		for (i, value) in frame.into_iter().enumerate() {
+			let address = new_stack_pointer.wrapping_sub(8 * (i as u64 + 1));
+			self.memory
+				.write_u64(address, value)
+				.map_err(|fault| DeliveryFailure::StackFault { address, fault })?;
+		}
+		// The handler is not single stepped. The trap flag is restored by iret, like IF when
+		// the entry disables interrupts.
+		self.rflags &= !TF;
+		if entry.disable_interrupt {
+			self.rflags &= !IF;
+		}
+		self.instruction_pointer = entry.service_routine;
+		self.registers.primary_registers[4] = new_stack_pointer.wrapping_sub(32);
+		self.cpl = 0;
+		Ok(())
 	}
 
 	fn write_reg_u8(&mut self, Reg(reg): Reg, value: u8) {
+		if reg >= Reg::HIGH_BYTE {
+			let handle = &mut self.registers.primary_registers[(reg - Reg::HIGH_BYTE) as usize];
+			*handle ^= (*handle & 0xFF00) ^ (value as u64) << 8;
+			return;
+		}
 		let handle = &mut self.registers.primary_registers[reg as usize];
 		*handle ^= (*handle & 0xFF) ^ value as u64;
 	}
 
 	fn write_reg_u16(&mut self, Reg(reg): Reg, value: u16) {
 		let handle = &mut self.registers.primary_registers[reg as usize];
-		*handle ^= (*handle & 0xFF) ^ value as u64;
+		*handle ^= (*handle & 0xFFFF) ^ value as u64;
 	}
 
 	fn write_reg_u32(&mut self, Reg(reg): Reg, value: u32) {
@@ -224,6 +782,9 @@ impl ProcessorState {
 	}
 
 	fn read_reg_u8(&mut self, Reg(reg): Reg) -> u8 {
+		if reg >= Reg::HIGH_BYTE {
+			return (self.registers.primary_registers[(reg - Reg::HIGH_BYTE) as usize] >> 8) as u8;
+		}
 		self.registers.primary_registers[reg as usize] as u8
 	}
 
@@ -239,6 +800,210 @@ impl ProcessorState {
 		self.registers.primary_registers[reg as usize]
 	}
 
+	/// The virtual address of a memory operand.
+	fn effective_address(&mut self, rm: RM) -> u64 {
+		self.memory_operand = true;
+		match rm {
+			RM::Reg(_) => unreachable!("register operands have no address"),
+			RM::RipRel {
+				displacement,
+				address_override,
+			} => {
+				if address_override {
+					(self.next_instruction_pointer as u32).wrapping_add(displacement) as u64
+				} else {
+					self.next_instruction_pointer
+						.wrapping_add(displacement as i32 as u64)
+				}
+			}
+			RM::Mem {
+				index,
+				scale,
+				base,
+				displacement,
+				address_override,
+				segment_override,
+			} => {
+				let base = if base == 0xFF {
+					0
+				} else {
+					self.read_reg_u64(Reg(base))
+				};
+				let index = if index == 4 {
+					0
+				} else {
+					self.read_reg_u64(Reg(index))
+				};
+				// With an address size override the registers are read as 32 bits and the sum
+				// wraps at 32 bits. The segment base is still added in 64 bits.
+				let address = if address_override {
+					(base as u32)
+						.wrapping_add((index as u32) << scale)
+						.wrapping_add(displacement) as u64
+				} else {
+					base.wrapping_add(index << scale)
+						.wrapping_add(displacement as i32 as u64)
+				};
+				match segment_override {
+					SegmentOverride::None => address,
+					SegmentOverride::Fs => address.wrapping_add(self.fs_base),
+					SegmentOverride::Gs => address.wrapping_add(self.gs_base),
+				}
+			}
+		}
+	}
+
+	/// Checks an access against the data breakpoints enabled in dr7 and records the hits in dr6.
+	/// Execution and io breakpoints are ignored.
+	fn watch(&mut self, address: u64, size: u64, write: bool) {
+		let dr7 = self.debug_registers[7];
+		for i in 0..4 {
+			if (dr7 >> (2 * i)) & 0b11 == 0 {
+				continue;
+			}
+			let triggered = match (dr7 >> (16 + 4 * i)) & 0b11 {
+				0b01 => write,
+				0b11 => true,
+				_ => false,
+			};
+			let length = match (dr7 >> (18 + 4 * i)) & 0b11 {
+				0b00 => 1,
+				0b01 => 2,
+				0b10 => 8,
+				_ => 4,
+			};
+			let watched = self.debug_registers[i] & !(length - 1);
+			// The ends saturate, so accesses and breakpoints at the top of the address space do
+			// not overflow.
+			if triggered
+				&& address < watched.saturating_add(length)
+				&& watched < address.saturating_add(size)
+			{
+				self.debug_registers[6] |= 1 << i;
+				self.debug_trap = true;
+			}
+		}
+	}
+
+	/// Reads an xmm register or a 128 bit memory operand. Memory operands of the aligned moves
+	/// must be 16 byte aligned.
+	fn read_xmm_rm(&mut self, rm: RM, aligned: bool) -> Result<u128, Interrupt> {
+		match rm {
+			RM::Reg(reg) => Ok(self.registers.xmm_registers[reg as usize]),
+			_ => {
+				let address = self.effective_address(rm);
+				if aligned && !address.is_multiple_of(16) {
+					Err(Interrupt::GeneralProtection)?;
+				}
+				self.watch(address, 16, false);
+				self.memory.read_u128(address)
+			}
+		}
+	}
+
+	/// Writes an xmm register or a 128 bit memory operand. Memory operands of the aligned moves
+	/// must be 16 byte aligned.
+	fn write_xmm_rm(&mut self, rm: RM, value: u128, aligned: bool) -> Result<(), Interrupt> {
+		match rm {
+			RM::Reg(reg) => {
+				self.registers.xmm_registers[reg as usize] = value;
+				Ok(())
+			}
+			_ => {
+				let address = self.effective_address(rm);
+				if aligned && !address.is_multiple_of(16) {
+					Err(Interrupt::GeneralProtection)?;
+				}
+				self.watch(address, 16, true);
+				self.memory.write_u128(address, value)
+			}
+		}
+	}
+
+	/// Evaluates a condition code against the flags.
+	fn condition(&self, Condition(condition): Condition) -> bool {
+		let flag = |flag| self.rflags & flag != 0;
+		let result = match condition >> 1 {
+			0 => flag(OF),
+			1 => flag(CF),
+			2 => flag(ZF),
+			3 => flag(CF) || flag(ZF),
+			4 => flag(SF),
+			5 => flag(PF),
+			6 => flag(SF) != flag(OF),
+			_ => flag(ZF) || flag(SF) != flag(OF),
+		};
+		result ^ (condition & 1 == 1)
+	}
+
+	/// Continues at `target` after the current instruction of `size` bytes. Every executed
+	/// instruction ends by advancing the instruction pointer by its size, so branches subtract it
+	/// here instead of returning early. A non-canonical target faults on the branch itself.
+	fn branch_to(&mut self, target: u64, size: u64) -> Result<(), Interrupt> {
+		self.memory.check_cannonical(target)?;
+		self.instruction_pointer = target.wrapping_sub(size);
+		self.counters.branches += 1;
+		Ok(())
+	}
+
+	/// Branches by `displacement` relative to the end of the current instruction.
+	fn relative_branch(&mut self, displacement: i64, size: u64) -> Result<(), Interrupt> {
+		let next = self.instruction_pointer.wrapping_add(size);
+		self.branch_to(next.wrapping_add(displacement as u64), size)
+	}
+
+	/// Lets the device owning the port access the machine after the guest wrote it.
+	fn ring(&mut self, port: u16) {
+		let mut bus = Bus {
+			memory: &mut self.memory,
+			instruction_pointer: self.instruction_pointer,
+			time_stamp_counter: self.time_stamp_counter,
+		};
+		self.devices.doorbell(port, &mut bus);
+	}
+
+	/// Whether an injected interrupt would be taken, when the maskable ones are taken if
+	/// `maskable`.
+	fn injected_pending(&self, maskable: bool) -> bool {
+		(self.injected.as_ref()).is_some_and(|injected| injected.borrow().pending(maskable))
+	}
+
+	/// Privileged instructions raise a general protection fault outside ring 0.
+	fn require_cpl0(&self) -> Result<(), Interrupt> {
+		if self.cpl > 0 {
+			Err(Interrupt::GeneralProtection)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// The fs and gs base instructions are undefined unless they are enabled in cr4.
+	fn check_fsgsbase(&self) -> Result<(), Interrupt> {
+		if self.registers.config_registers[3] & CR4_FSGSBASE == 0 {
+			Err(Interrupt::UNDEFINED)
+		} else {
+			Ok(())
+		}
+	}
+
+	/// Raises the debug exception for a completed instruction if it was single stepped or hit
+	/// a data breakpoint. The interrupt of int3 or int is raised instead, so their frame returns
+	/// past them and, as the handler is entered with TF clear, they are not single stepped.
+	fn trap(&mut self, single_step: bool) -> Result<(), Interrupt> {
+		if let Some(interrupt) = self.software_interrupt.take() {
+			self.debug_trap = false;
+			return Err(interrupt);
+		}
+		if single_step {
+			self.debug_registers[6] |= DR6_BS;
+		}
+		if std::mem::take(&mut self.debug_trap) || single_step {
+			Err(Interrupt::Debug)
+		} else {
+			Ok(())
+		}
+	}
+
 	read_write_rm!(u8);
 	read_write_rm!(u16);
 	read_write_rm!(u32);
@@ -246,94 +1011,450 @@ impl ProcessorState {
 
 	/// Steps one instruction execution
 	pub fn step_instruction(&mut self) {
+		if self.watchdog.is_some() {
+			self.watched_step();
+		} else {
+			self.execute();
+		}
+	}
+
+	/// Parks the thread while the processor waits in hlt, until a device raises an irq or the
+	/// thread is unparked for another reason, such as a command for the caller. Devices clocked
+	/// by the steps can only raise an irq when the processor is stepped, so then it returns
+	/// immediately.
+	pub fn wait_for_interrupt(&self) {
+		if self.waiting() && !self.deterministic {
+			self.devices.interrupt_line().wait();
+		}
+	}
+
+	/// Executes one instruction and delivers the interrupt it raised, if any. Returns the
+	/// decoded instruction with its size and the interrupt.
+	fn execute(&mut self) -> (Option<(Instruction, u64)>, Option<Interrupt>) {
+		for _ in 0..std::mem::replace(&mut self.ticks, 1) {
+			self.devices.tick();
+		}
+		self.memory
+			.set_instruction_pointer(self.instruction_pointer);
+		self.devices
+			.set_instruction(self.instruction_pointer, self.retired_count);
+		let mut decoded = None;
+		// While IF is clear the requests stay pending, apart from the NMI.
+		let maskable = self.rflags & IF != 0 && !std::mem::take(&mut self.interrupt_shadow);
 		if let Err(interrupt) = try {
-			let irq = IRQ.load(Ordering::Relaxed);
-			IRQ.store(0, Ordering::Relaxed);
-			if irq != 0 {
-				Err(Interrupt::InterruptRequest(irq))?;
+			if maskable && let Some(vector) = self.devices.interrupt_line().take() {
+				self.halted = false;
+				Err(Interrupt::InterruptRequest(vector))?;
+			}
+			if maskable && let Some(vector) = self.ipis.take() {
+				self.halted = false;
+				Err(Interrupt::InterruptRequest(vector))?;
+			}
+			if let Some(injected) = &self.injected
+				&& let Some(vector) = injected.borrow_mut().take(maskable)
+			{
+				self.halted = false;
+				Err(Interrupt::InterruptRequest(vector))?;
+			}
+			if self.halted {
+				return (None, None);
 			}
-			let (instruction, size) = decode(&mut self.memory, self.instruction_pointer)?;
+			let instruction_pointer = self.instruction_pointer;
+			let single_step = self.rflags & TF != 0;
+			let (instruction, size) = decode_in(&mut self.memory, instruction_pointer, self.mode)?;
+			decoded = Some((instruction, size));
+			self.next_instruction_pointer = instruction_pointer.wrapping_add(size);
+			self.memory_operand = false;
 			match instruction {
-				Instruction::Hlt {} => thread::park(),
-				Instruction::In8 { operand0 } => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					let value = self.devices.in_u8(operand0.0 as u16);
-					self.write_reg_u8(A, value);
+				Instruction::AdcA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Adc, RM::Reg(operand0.0), operand1.0 as u8)?
 				}
-				#[allow(unused)]
-				Instruction::In16 { operand0 } => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					fatal("16 bit devices are not implemented");
+				Instruction::AdcA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Adc, RM::Reg(operand0.0), operand1.0 as u16)?
 				}
-				#[allow(unused)]
-				Instruction::In32 { operand0 } => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					fatal("32 bit devices are not implemented");
+				Instruction::AdcA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Adc, RM::Reg(operand0.0), operand1.0 as u32)?
 				}
-				Instruction::In8D {} => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					let port = self.read_reg_u16(Reg(2));
-					let value = self.devices.in_u8(port);
-					self.write_reg_u8(A, value);
+				Instruction::AdcA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Adc, RM::Reg(operand0.0), value)?
 				}
-				Instruction::In16D {} => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					fatal("16 bit devices are not implemented");
+				Instruction::AddA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Add, RM::Reg(operand0.0), operand1.0 as u8)?
 				}
-				Instruction::In32D {} => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					fatal("32 bit devices are not implemented");
+				Instruction::AddA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Add, RM::Reg(operand0.0), operand1.0 as u16)?
 				}
-				Instruction::IncRM8 { operand0 } => {
-					let value = self.read_rm_u8(operand0)?.wrapping_add(1);
-					self.write_rm_u8(operand0, value)?
+				Instruction::AddA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Add, RM::Reg(operand0.0), operand1.0 as u32)?
 				}
-				Instruction::IncRM16 { operand0 } => {
-					let value = self.read_rm_u16(operand0)?.wrapping_add(1);
-					self.write_rm_u16(operand0, value)?
+				Instruction::AddA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Add, RM::Reg(operand0.0), value)?
 				}
-				Instruction::IncRM32 { operand0 } => {
-					let value = self.read_rm_u32(operand0)?.wrapping_add(1);
-					self.write_rm_u32(operand0, value)?
+				Instruction::AndA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::And, RM::Reg(operand0.0), operand1.0 as u8)?
 				}
-				Instruction::IncRM64 { operand0 } => {
-					let value = self.read_rm_u64(operand0)?.wrapping_add(1);
-					self.write_rm_u64(operand0, value)?
+				Instruction::AndA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::And, RM::Reg(operand0.0), operand1.0 as u16)?
 				}
-				Instruction::Iret {} => {
-					let rsp = self.read_reg_u64(SP);
-					let instruction_pointer = self.memory.read_u64(rsp + 8)?;
-					let rflags = self.memory.read_u64(rsp + 16)?;
-					let stack_pointer = self.memory.read_u64(rsp + 24)?;
-					self.instruction_pointer = instruction_pointer;
-					self.rflags = rflags;
-					self.write_reg_u64(SP, stack_pointer);
-					self.cpl = ((rflags as i64) >> 32) as i8;
-					return; // Skip incrementing the instruction pointer as
-					// this changes the instruction pointer as part of
-					// the instruction.
+				Instruction::AndA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::And, RM::Reg(operand0.0), operand1.0 as u32)?
+				}
+				Instruction::AndA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::And, RM::Reg(operand0.0), value)?
+				}
+				Instruction::CmpA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Cmp, RM::Reg(operand0.0), operand1.0 as u8)?
+				}
+				Instruction::CmpA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Cmp, RM::Reg(operand0.0), operand1.0 as u16)?
+				}
+				Instruction::CmpA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Cmp, RM::Reg(operand0.0), operand1.0 as u32)?
+				}
+				Instruction::CmpA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Cmp, RM::Reg(operand0.0), value)?
+				}
+				Instruction::Cmps8 {} | Instruction::RepeCmps8 {} | Instruction::RepneCmps8 {} => {
+					self.repeat_compare(instruction, ProcessorState::cmps_u8)?
+				}
+				Instruction::Cmps16 {}
+				| Instruction::RepeCmps16 {}
+				| Instruction::RepneCmps16 {} => self.repeat_compare(instruction, ProcessorState::cmps_u16)?,
+				Instruction::Cmps32 {}
+				| Instruction::RepeCmps32 {}
+				| Instruction::RepneCmps32 {} => self.repeat_compare(instruction, ProcessorState::cmps_u32)?,
+				Instruction::Cmps64 {}
+				| Instruction::RepeCmps64 {}
+				| Instruction::RepneCmps64 {} => self.repeat_compare(instruction, ProcessorState::cmps_u64)?,
+				Instruction::OrA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Or, RM::Reg(operand0.0), operand1.0 as u8)?
+				}
+				Instruction::OrA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Or, RM::Reg(operand0.0), operand1.0 as u16)?
+				}
+				Instruction::OrA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Or, RM::Reg(operand0.0), operand1.0 as u32)?
+				}
+				Instruction::OrA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Or, RM::Reg(operand0.0), value)?
+				}
+				Instruction::SbbA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Sbb, RM::Reg(operand0.0), operand1.0 as u8)?
+				}
+				Instruction::SbbA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Sbb, RM::Reg(operand0.0), operand1.0 as u16)?
+				}
+				Instruction::SbbA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Sbb, RM::Reg(operand0.0), operand1.0 as u32)?
+				}
+				Instruction::SbbA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Sbb, RM::Reg(operand0.0), value)?
+				}
+				Instruction::Scas8 {} | Instruction::RepeScas8 {} | Instruction::RepneScas8 {} => {
+					self.repeat_compare(instruction, ProcessorState::scas_u8)?
+				}
+				Instruction::Scas16 {}
+				| Instruction::RepeScas16 {}
+				| Instruction::RepneScas16 {} => self.repeat_compare(instruction, ProcessorState::scas_u16)?,
+				Instruction::Scas32 {}
+				| Instruction::RepeScas32 {}
+				| Instruction::RepneScas32 {} => self.repeat_compare(instruction, ProcessorState::scas_u32)?,
+				Instruction::Scas64 {}
+				| Instruction::RepeScas64 {}
+				| Instruction::RepneScas64 {} => self.repeat_compare(instruction, ProcessorState::scas_u64)?,
+				Instruction::SubA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Sub, RM::Reg(operand0.0), operand1.0 as u8)?
+				}
+				Instruction::SubA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Sub, RM::Reg(operand0.0), operand1.0 as u16)?
+				}
+				Instruction::SubA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Sub, RM::Reg(operand0.0), operand1.0 as u32)?
+				}
+				Instruction::SubA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Sub, RM::Reg(operand0.0), value)?
+				}
+				Instruction::XorA8Imm { operand0, operand1 } => {
+					self.alu_rm_u8(AluOperation::Xor, RM::Reg(operand0.0), operand1.0 as u8)?
+				}
+				Instruction::XorA16Imm { operand0, operand1 } => {
+					self.alu_rm_u16(AluOperation::Xor, RM::Reg(operand0.0), operand1.0 as u16)?
+				}
+				Instruction::XorA32Imm { operand0, operand1 } => {
+					self.alu_rm_u32(AluOperation::Xor, RM::Reg(operand0.0), operand1.0 as u32)?
+				}
+				Instruction::XorA64Imm { operand0, operand1 } => {
+					let value = operand1.0 as i32 as i64 as u64;
+					self.alu_rm_u64(AluOperation::Xor, RM::Reg(operand0.0), value)?
+				}
+				Instruction::AdcxReg32RM { operand0, operand1 } => {
+					self.carry_chain_u32(CF, operand0, operand1)?
+				}
+				Instruction::AdcxReg64RM { operand0, operand1 } => {
+					self.carry_chain_u64(CF, operand0, operand1)?
+				}
+				Instruction::AddsdXmmRM { operand0, operand1 } => {
+					self.scalar_double(operand0, operand1, |left, right| left + right)?
+				}
+				Instruction::AdoxReg32RM { operand0, operand1 } => {
+					self.carry_chain_u32(OF, operand0, operand1)?
+				}
+				Instruction::AdoxReg64RM { operand0, operand1 } => {
+					self.carry_chain_u64(OF, operand0, operand1)?
+				}
+				Instruction::AndnReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.andn_u32(operand0, operand1, operand2)?,
+				Instruction::AndnReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.andn_u64(operand0, operand1, operand2)?,
+				Instruction::BextrReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.bextr_u32(operand0, operand1, operand2)?,
+				Instruction::BextrReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.bextr_u64(operand0, operand1, operand2)?,
+				Instruction::BzhiReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.bzhi_u32(operand0, operand1, operand2)?,
+				Instruction::BzhiReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.bzhi_u64(operand0, operand1, operand2)?,
+				Instruction::Cld {} => self.set_flag(DF, false),
+				Instruction::Cli {} => {
+					self.require_cpl0()?;
+					self.set_flag(IF, false);
+				}
+				Instruction::Clflush { operand0: _ } => {
+					// There is no cache, so there is nothing to flush.
+				}
+				Instruction::Cvtsi2sdXmmRM32 { operand0, operand1 } => {
+					let value = self.read_rm_u32(operand1)? as i32;
+					self.write_scalar_double(operand0, value as f64);
+				}
+				Instruction::Cvtsi2sdXmmRM64 { operand0, operand1 } => {
+					let value = self.read_rm_u64(operand1)? as i64;
+					self.write_scalar_double(operand0, value as f64);
+				}
+				Instruction::Cvttsd2siReg32RM { operand0, operand1 } => {
+					let value = self.read_scalar_double(operand1)?;
+					self.write_reg_u32(operand0, sse::truncate_i32(value) as u32);
+				}
+				Instruction::Cvttsd2siReg64RM { operand0, operand1 } => {
+					let value = self.read_scalar_double(operand1)?;
+					self.write_reg_u64(operand0, sse::truncate_i64(value) as u64);
+				}
+				Instruction::DivsdXmmRM { operand0, operand1 } => {
+					self.scalar_double(operand0, operand1, |left, right| left / right)?
+				}
+				Instruction::Fldcw { operand0 } => {
+					self.fpu_control_word = self.read_rm_u16(operand0)?;
+				}
+				Instruction::Fninit {} => self.fpu_control_word = FPU_CONTROL_WORD_DEFAULT,
+				Instruction::Fnstcw { operand0 } => {
+					self.write_rm_u16(operand0, self.fpu_control_word)?
+				}
+				Instruction::Fwait {} => {
+					// There are no pending x87 exceptions to wait for.
+				}
+				Instruction::Hlt {} => {
+					self.require_cpl0()?;
+					self.halted = true;
+				}
+				Instruction::In8 { operand0 } => {
+					self.require_cpl0()?;
+					let value = self.devices.in_u8(operand0.0 as u16);
+					self.write_reg_u8(A, value);
+				}
+				Instruction::In16 { operand0 } => {
+					self.require_cpl0()?;
+					let value = self.devices.in_u16(operand0.0 as u16);
+					self.write_reg_u16(A, value);
+				}
+				Instruction::In32 { operand0 } => {
+					self.require_cpl0()?;
+					let value = self.devices.in_u32(operand0.0 as u16);
+					self.write_reg_u32(A, value);
+				}
+				Instruction::In8D {} => {
+					self.require_cpl0()?;
+					let port = self.read_reg_u16(Reg(2));
+					let value = self.devices.in_u8(port);
+					self.write_reg_u8(A, value);
+				}
+				Instruction::In16D {} => {
+					self.require_cpl0()?;
+					let port = self.read_reg_u16(Reg(2));
+					let value = self.devices.in_u16(port);
+					self.write_reg_u16(A, value);
+				}
+				Instruction::In32D {} => {
+					self.require_cpl0()?;
+					let port = self.read_reg_u16(Reg(2));
+					let value = self.devices.in_u32(port);
+					self.write_reg_u32(A, value);
+				}
+				Instruction::IncRM8 { operand0 } => {
+					let value = self.read_rm_u8(operand0)?.wrapping_add(1);
+					self.write_rm_u8(operand0, value)?
+				}
+				Instruction::IncRM16 { operand0 } => {
+					let value = self.read_rm_u16(operand0)?.wrapping_add(1);
+					self.write_rm_u16(operand0, value)?
+				}
+				Instruction::IncRM32 { operand0 } => {
+					let value = self.read_rm_u32(operand0)?.wrapping_add(1);
+					self.write_rm_u32(operand0, value)?
+				}
+				Instruction::IncRM64 { operand0 } => {
+					let value = self.read_rm_u64(operand0)?.wrapping_add(1);
+					self.write_rm_u64(operand0, value)?
+				}
+				Instruction::Ins8 {} | Instruction::RepIns8 {} => {
+					self.require_cpl0()?;
+					let rep = matches!(instruction, Instruction::RepIns8 {});
+					self.repeat(rep, ProcessorState::ins_u8)?
+				}
+				Instruction::Ins16 {} | Instruction::RepIns16 {} => {
+					self.require_cpl0()?;
+					let rep = matches!(instruction, Instruction::RepIns16 {});
+					self.repeat(rep, ProcessorState::ins_u16)?
+				}
+				Instruction::Ins32 {} | Instruction::RepIns32 {} => {
+					self.require_cpl0()?;
+					let rep = matches!(instruction, Instruction::RepIns32 {});
+					self.repeat(rep, ProcessorState::ins_u32)?
+				}
+				Instruction::Invlpg { operand0: _ } => {
+					self.require_cpl0()?;
+					// There is no TLB, so there is nothing to invalidate.
+				}
+				Instruction::Int { operand0 } => {
+					self.software_interrupt = Some(Interrupt::InterruptRequest(operand0.0 as u8));
+				}
+				Instruction::Int3 {} => {
+					self.software_interrupt = Some(Interrupt::Breakpoint);
+				}
+				Instruction::Iret {} => {
+					let rsp = self.read_reg_u64(SP);
+					let target = self.memory.read_u64(rsp + 8)?;
+					let rflags = self.memory.read_u64(rsp + 16)?;
+					let stack_pointer = self.memory.read_u64(rsp + 24)?;
+					// A corrupted frame faults before anything is restored, rather than returning
+					// to a cpl outside 0 to 3, to a more privileged level or to a non-canonical
+					// address.
+					let cpl = rflags >> 32;
+					if cpl > 3 || (cpl as i8) < self.cpl {
+						Err(Interrupt::GeneralProtection)?;
+					}
+					self.memory.check_cannonical(stack_pointer)?;
+					self.branch_to(target, size)?;
+					// Outside ring 0 IF and the io privilege level are kept, like on hardware with
+					// an io privilege level of 0.
+					let kept = if self.cpl > 0 { IF | IOPL } else { 0 };
+					self.rflags = rflags & u32::MAX as u64 & !kept | self.rflags & kept;
+					self.write_reg_u64(SP, stack_pointer);
+					self.cpl = cpl as i8;
+					if let Some(injected) = &self.injected {
+						injected.borrow_mut().end_nmi();
+					}
+				}
+				Instruction::JccRel8 { operand0, operand1 } => {
+					if self.condition(operand0) {
+						self.relative_branch(operand1.0 as i8 as i64, size)?
+					}
+				}
+				Instruction::JmpRM64 { operand0 } => {
+					let target = self.read_rm_u64(operand0)?;
+					self.branch_to(target, size)?
 				}
 				Instruction::JmpRel8 { operand0 } => {
-					self.instruction_pointer = self
-						.instruction_pointer
-						.wrapping_add(operand0.0 as i8 as i64 as u64)
+					self.relative_branch(operand0.0 as i8 as i64, size)?
 				}
 				Instruction::JmpRel32 { operand0 } => {
-					self.instruction_pointer = self
-						.instruction_pointer
-						.wrapping_add(operand0.0 as i32 as i64 as u64)
+					self.relative_branch(operand0.0 as i32 as i64, size)?
+				}
+				Instruction::Ldmxcsr { operand0 } => {
+					let value = self.read_rm_u32(operand0)?;
+					if value & MXCSR_RESERVED != 0 {
+						Err(Interrupt::GeneralProtection)?;
+					}
+					self.mxcsr = value;
+				}
+				Instruction::Lfence {} | Instruction::Mfence {} | Instruction::Sfence {} => {
+					// Instructions are executed one at a time, so memory is always ordered.
+				}
+				Instruction::Lidt { operand0 } => {
+					self.require_cpl0()?;
+					// The limit is ignored, as the table always has 256 entries.
+					let address = self.effective_address(operand0);
+					let base = self.memory.read_u64(address.wrapping_add(2))?;
+					self.registers.config_registers[0] = base;
+				}
+				Instruction::Lmsw { operand0 } => {
+					self.require_cpl0()?;
+					// Protection can be enabled but not disabled again.
+					let value = self.read_rm_u16(operand0)? as u64 | (self.cr0 & CR0_PE);
+					self.cr0 = (self.cr0 & !CR0_LMSW) | (value & CR0_LMSW);
+				}
+				Instruction::MovapsRMXmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize];
+					self.write_xmm_rm(operand0, value, true)?
+				}
+				Instruction::MovapsXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, true)?;
+					self.registers.xmm_registers[operand0.0 as usize] = value;
+				}
+				Instruction::MovdqaRMXmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize];
+					self.write_xmm_rm(operand0, value, true)?
+				}
+				Instruction::MovdqaXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, true)?;
+					self.registers.xmm_registers[operand0.0 as usize] = value;
+				}
+				Instruction::MovdquRMXmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize];
+					self.write_xmm_rm(operand0, value, false)?
+				}
+				Instruction::MovdquXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, false)?;
+					self.registers.xmm_registers[operand0.0 as usize] = value;
+				}
+				Instruction::MovdRM32Xmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize];
+					self.write_rm_u32(operand0, value as u32)?
+				}
+				Instruction::MovdXmmRM32 { operand0, operand1 } => {
+					let value = self.read_rm_u32(operand1)?;
+					self.registers.xmm_registers[operand0.0 as usize] = value as u128;
+				}
+				Instruction::MovDrRM { operand0, operand1 } => {
+					self.require_cpl0()?;
+					if matches!(operand0.0, 4 | 5 | 8..) {
+						Err(Interrupt::UNDEFINED)?;
+					}
+					let value = self.read_rm_u64(operand1)?;
+					self.debug_registers[operand0.0 as usize] = value;
 				}
 				Instruction::MovReg8Imm { operand0, operand1 } => {
 					self.write_reg_u8(operand0, operand1.0 as u8)
@@ -379,27 +1500,157 @@ impl ProcessorState {
 					let value = self.read_reg_u64(operand1);
 					self.write_rm_u64(operand0, value)?;
 				}
-				Instruction::Out8 { operand0 } => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
+				Instruction::MovRMDr { operand0, operand1 } => {
+					self.require_cpl0()?;
+					if matches!(operand1.0, 4 | 5 | 8..) {
+						Err(Interrupt::UNDEFINED)?;
 					}
+					let value = self.debug_registers[operand1.0 as usize];
+					self.write_rm_u64(operand0, value)?;
+				}
+				Instruction::MovqRM64Xmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize];
+					self.write_rm_u64(operand0, value as u64)?
+				}
+				Instruction::MovqXmmRM64 { operand0, operand1 } => {
+					let value = self.read_rm_u64(operand1)?;
+					self.registers.xmm_registers[operand0.0 as usize] = value as u128;
+				}
+				Instruction::MovsdRMXmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize] as u64;
+					match operand0 {
+						RM::Reg(reg) => self.write_scalar_double(Reg(reg), f64::from_bits(value)),
+						_ => self.write_rm_u64(operand0, value)?,
+					}
+				}
+				Instruction::MovsdXmmRM { operand0, operand1 } => {
+					let value = self.read_scalar_double(operand1)?;
+					match operand1 {
+						RM::Reg(_) => self.write_scalar_double(operand0, value),
+						// Loads from memory clear the upper half.
+						_ => {
+							self.registers.xmm_registers[operand0.0 as usize] =
+								value.to_bits() as u128
+						}
+					}
+				}
+				Instruction::MovupsRMXmm { operand0, operand1 } => {
+					let value = self.registers.xmm_registers[operand1.0 as usize];
+					self.write_xmm_rm(operand0, value, false)?
+				}
+				Instruction::MovupsXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, false)?;
+					self.registers.xmm_registers[operand0.0 as usize] = value;
+				}
+				Instruction::MulsdXmmRM { operand0, operand1 } => {
+					self.scalar_double(operand0, operand1, |left, right| left * right)?
+				}
+				Instruction::MulxReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.mulx_u32(operand0, operand1, operand2)?,
+				Instruction::MulxReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.mulx_u64(operand0, operand1, operand2)?,
+				Instruction::Out8 { operand0 } => {
+					self.require_cpl0()?;
 					let value = self.read_reg_u8(A);
 					self.devices.out_u8(operand0.0 as u16, value);
+					self.ring(operand0.0 as u16);
 				}
-				#[allow(unused)]
 				Instruction::Out16 { operand0 } => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
-					fatal("16 bit devices are not implemented");
+					self.require_cpl0()?;
+					let value = self.read_reg_u16(A);
+					self.devices.out_u16(operand0.0 as u16, value);
+					self.ring(operand0.0 as u16);
 				}
-				#[allow(unused)]
 				Instruction::Out32 { operand0 } => {
-					if self.cpl > 0 {
-						Err(Interrupt::GeneralProtection)?;
-					}
+					self.require_cpl0()?;
 					let value = self.read_reg_u32(A);
 					self.devices.out_u32(operand0.0 as u16, value);
+					self.ring(operand0.0 as u16);
+				}
+				Instruction::Out8D {} => {
+					self.require_cpl0()?;
+					let port = self.read_reg_u16(Reg(2));
+					let value = self.read_reg_u8(A);
+					self.devices.out_u8(port, value);
+					self.ring(port);
+				}
+				Instruction::Out16D {} => {
+					self.require_cpl0()?;
+					let port = self.read_reg_u16(Reg(2));
+					let value = self.read_reg_u16(A);
+					self.devices.out_u16(port, value);
+					self.ring(port);
+				}
+				Instruction::Out32D {} => {
+					self.require_cpl0()?;
+					let port = self.read_reg_u16(Reg(2));
+					let value = self.read_reg_u32(A);
+					self.devices.out_u32(port, value);
+					self.ring(port);
+				}
+				Instruction::Outs8 {} | Instruction::RepOuts8 {} => {
+					self.require_cpl0()?;
+					let rep = matches!(instruction, Instruction::RepOuts8 {});
+					self.repeat(rep, ProcessorState::outs_u8)?
+				}
+				Instruction::Outs16 {} | Instruction::RepOuts16 {} => {
+					self.require_cpl0()?;
+					let rep = matches!(instruction, Instruction::RepOuts16 {});
+					self.repeat(rep, ProcessorState::outs_u16)?
+				}
+				Instruction::Outs32 {} | Instruction::RepOuts32 {} => {
+					self.require_cpl0()?;
+					let rep = matches!(instruction, Instruction::RepOuts32 {});
+					self.repeat(rep, ProcessorState::outs_u32)?
+				}
+				Instruction::PaddbXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 8, u64::wrapping_add)?
+				}
+				Instruction::PadddXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 32, u64::wrapping_add)?
+				}
+				Instruction::PaddqXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 64, u64::wrapping_add)?
+				}
+				Instruction::PaddwXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 16, u64::wrapping_add)?
+				}
+				Instruction::PandXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, true)?;
+					self.registers.xmm_registers[operand0.0 as usize] &= value;
+				}
+				Instruction::PandnXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, true)?;
+					let register = &mut self.registers.xmm_registers[operand0.0 as usize];
+					*register = !*register & value;
+				}
+				Instruction::PcmpeqbXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 8, |left, right| {
+						if left == right { u64::MAX } else { 0 }
+					})?
+				}
+				Instruction::PcmpeqdXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 32, |left, right| {
+						if left == right { u64::MAX } else { 0 }
+					})?
+				}
+				Instruction::PcmpeqwXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 16, |left, right| {
+						if left == right { u64::MAX } else { 0 }
+					})?
+				}
+				Instruction::PmovmskbReg32Xmm { operand0, operand1 } => {
+					let RM::Reg(source) = operand1 else {
+						unreachable!("the source is always a register")
+					};
+					let mask = sse::move_mask(self.registers.xmm_registers[source as usize]);
+					self.write_reg_u32(operand0, mask);
 				}
 				Instruction::PopReg16 { operand0 } => {
 					let rsp = self.read_reg_u64(SP);
@@ -413,46 +1664,3358 @@ impl ProcessorState {
 					self.write_reg_u64(SP, rsp.wrapping_add(8));
 					self.write_reg_u64(operand0, value);
 				}
+				Instruction::PorXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, true)?;
+					self.registers.xmm_registers[operand0.0 as usize] |= value;
+				}
+				Instruction::PsubbXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 8, u64::wrapping_sub)?
+				}
+				Instruction::PsubdXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 32, u64::wrapping_sub)?
+				}
+				Instruction::PsubqXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 64, u64::wrapping_sub)?
+				}
+				Instruction::PsubwXmmRM { operand0, operand1 } => {
+					self.packed(operand0, operand1, 16, u64::wrapping_sub)?
+				}
 				Instruction::PushReg16 { operand0 } => {
 					let value = self.read_reg_u16(operand0);
-					let rsp = self.read_reg_u64(SP);
-					self.write_reg_u64(SP, rsp.wrapping_sub(2));
-					self.memory.write_u16(rsp.wrapping_sub(2), value)?;
+					let rsp = self.read_reg_u64(SP).wrapping_sub(2);
+					self.memory.write_u16(rsp, value)?;
+					self.write_reg_u64(SP, rsp);
 				}
 				Instruction::PushReg64 { operand0 } => {
 					let value = self.read_reg_u64(operand0);
-					let rsp = self.read_reg_u64(SP);
-					self.write_reg_u64(SP, rsp.wrapping_sub(8));
-					self.memory.write_u64(rsp.wrapping_sub(8), value)?;
+					let rsp = self.read_reg_u64(SP).wrapping_sub(8);
+					self.memory.write_u64(rsp, value)?;
+					self.write_reg_u64(SP, rsp);
+				}
+				Instruction::PxorXmmRM { operand0, operand1 } => {
+					let value = self.read_xmm_rm(operand1, true)?;
+					self.registers.xmm_registers[operand0.0 as usize] ^= value;
+				}
+				Instruction::Rdfsbase32 { operand0 } => {
+					self.check_fsgsbase()?;
+					self.write_rm_u32(operand0, self.fs_base as u32)?
+				}
+				Instruction::Rdfsbase64 { operand0 } => {
+					self.check_fsgsbase()?;
+					self.write_rm_u64(operand0, self.fs_base)?
+				}
+				Instruction::Rdgsbase32 { operand0 } => {
+					self.check_fsgsbase()?;
+					self.write_rm_u32(operand0, self.gs_base as u32)?
+				}
+				Instruction::Rdgsbase64 { operand0 } => {
+					self.check_fsgsbase()?;
+					self.write_rm_u64(operand0, self.gs_base)?
+				}
+				Instruction::Rdcr {} => {
+					self.require_cpl0()?;
+					let value = match self.read_reg_u32(C) {
+						FS_BASE => self.fs_base,
+						GS_BASE => self.gs_base,
+						KERNEL_GS_BASE => self.kernel_gs_base,
+						TSC_AUX => self.tsc_aux as u64,
+						INTERRUPT_STACK => self.registers.config_registers[1],
+						index @ 0..256 => self.registers.config_registers[index as usize],
+						_ => Err(Interrupt::GeneralProtection)?,
+					};
+					self.write_reg_u32(A, value as u32);
+					self.write_reg_u32(D, (value >> 32) as u32);
+				}
+				Instruction::Rdpmc {} => self.rdpmc()?,
+				Instruction::Rdtsc {} => {
+					self.write_reg_u32(A, self.time_stamp_counter as u32);
+					self.write_reg_u32(D, (self.time_stamp_counter >> 32) as u32);
+				}
+				Instruction::Rdtscp {} => {
+					self.write_reg_u32(A, self.time_stamp_counter as u32);
+					self.write_reg_u32(D, (self.time_stamp_counter >> 32) as u32);
+					self.write_reg_u32(C, self.tsc_aux);
+				}
+				Instruction::SarxReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.shift_u32(ShiftOperation::Sar, operand0, operand1, operand2)?,
+				Instruction::SarxReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.shift_u64(ShiftOperation::Sar, operand0, operand1, operand2)?,
+				Instruction::ShlxReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.shift_u32(ShiftOperation::Shl, operand0, operand1, operand2)?,
+				Instruction::ShlxReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.shift_u64(ShiftOperation::Shl, operand0, operand1, operand2)?,
+				Instruction::ShrxReg32RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.shift_u32(ShiftOperation::Shr, operand0, operand1, operand2)?,
+				Instruction::ShrxReg64RM {
+					operand0,
+					operand1,
+					operand2,
+				} => self.shift_u64(ShiftOperation::Shr, operand0, operand1, operand2)?,
+				Instruction::Sgdt { operand0 } => {
+					// There are no segment descriptors, so the table is always empty.
+					let address = self.effective_address(operand0);
+					self.memory.write_u16(address, 0)?;
+					self.memory.write_u64(address.wrapping_add(2), 0)?;
+				}
+				Instruction::Sidt { operand0 } => {
+					let address = self.effective_address(operand0);
+					let base = self.registers.config_registers[0];
+					self.memory.write_u16(address, IDT_LIMIT)?;
+					self.memory.write_u64(address.wrapping_add(2), base)?;
+				}
+				Instruction::Smsw { operand0 } => match operand0 {
+					RM::Reg(_) => self.write_rm_u32(operand0, self.cr0 as u32)?,
+					_ => self.write_rm_u16(operand0, self.cr0 as u16)?,
+				},
+				Instruction::Std {} => self.set_flag(DF, true),
+				Instruction::Sti {} => {
+					self.require_cpl0()?;
+					// Like on hardware the instruction after sti runs before an interrupt is taken,
+					// so `sti; hlt` cannot miss the interrupt it waits for.
+					self.interrupt_shadow = self.rflags & IF == 0;
+					self.set_flag(IF, true);
+				}
+				Instruction::Stmxcsr { operand0 } => self.write_rm_u32(operand0, self.mxcsr)?,
+				Instruction::SubsdXmmRM { operand0, operand1 } => {
+					self.scalar_double(operand0, operand1, |left, right| left - right)?
+				}
+				Instruction::Swapgs {} => {
+					self.require_cpl0()?;
+					std::mem::swap(&mut self.gs_base, &mut self.kernel_gs_base);
 				}
 				Instruction::Swi4 { operand0 } => {
+					self.require_cpl0()?;
 					let value = self.read_rm_u64(operand0)?;
 					self.memory.swi4(value)
 				}
-				Instruction::Wrcr { operand0, operand1 } => {
+				Instruction::TestRM8Reg { operand0, operand1 } => {
+					let value = self.read_reg_u8(operand1);
+					self.alu_rm_u8(AluOperation::Test, operand0, value)?
+				}
+				Instruction::TestRM16Reg { operand0, operand1 } => {
+					let value = self.read_reg_u16(operand1);
+					self.alu_rm_u16(AluOperation::Test, operand0, value)?
+				}
+				Instruction::TestRM32Reg { operand0, operand1 } => {
+					let value = self.read_reg_u32(operand1);
+					self.alu_rm_u32(AluOperation::Test, operand0, value)?
+				}
+				Instruction::TestRM64Reg { operand0, operand1 } => {
+					let value = self.read_reg_u64(operand1);
+					self.alu_rm_u64(AluOperation::Test, operand0, value)?
+				}
+				Instruction::TzcntReg16RM { operand0, operand1 } => {
+					let value = self.read_rm_u16(operand1)?;
+					let count = value.trailing_zeros();
+					self.set_flag(CF, value == 0);
+					self.set_flag(ZF, count == 0);
+					self.write_reg_u16(operand0, count as u16);
+				}
+				Instruction::TzcntReg32RM { operand0, operand1 } => {
+					let value = self.read_rm_u32(operand1)?;
+					let count = value.trailing_zeros();
+					self.set_flag(CF, value == 0);
+					self.set_flag(ZF, count == 0);
+					self.write_reg_u32(operand0, count as u32);
+				}
+				Instruction::TzcntReg64RM { operand0, operand1 } => {
 					let value = self.read_rm_u64(operand1)?;
-					self.registers.config_registers[operand0.0 as usize] = value;
+					let count = value.trailing_zeros();
+					self.set_flag(CF, value == 0);
+					self.set_flag(ZF, count == 0);
+					self.write_reg_u64(operand0, count as u64);
+				}
+				Instruction::UcomisdXmmRM { operand0, operand1 } => {
+					self.compare_double(operand0, operand1)?
+				}
+				Instruction::Vmcall {} => self.hypercall()?,
+				Instruction::Wbinvd {} => {
+					self.require_cpl0()?;
+					// There is no cache, so there is nothing to write back.
+				}
+				Instruction::Wrcr {} => {
+					self.require_cpl0()?;
+					let index = self.read_reg_u32(C);
+					let value = ((self.read_reg_u32(D) as u64) << 32) | self.read_reg_u32(A) as u64;
+					match index {
+						FS_BASE => self.fs_base = value,
+						GS_BASE => self.gs_base = value,
+						KERNEL_GS_BASE => self.kernel_gs_base = value,
+						TSC_AUX => self.tsc_aux = value as u32,
+						INTERRUPT_STACK => self.registers.config_registers[1] = value,
+						apic::X2APIC_ICR => self.send_ipi(value),
+						0..256 => self.registers.config_registers[index as usize] = value,
+						_ => Err(Interrupt::GeneralProtection)?,
+					}
+				}
+				Instruction::Wrfsbase32 { operand0 } => {
+					self.check_fsgsbase()?;
+					self.fs_base = self.read_rm_u32(operand0)? as u64;
+				}
+				Instruction::Wrfsbase64 { operand0 } => {
+					self.check_fsgsbase()?;
+					let value = self.read_rm_u64(operand0)?;
+					self.memory.check_cannonical(value)?;
+					self.fs_base = value;
+				}
+				Instruction::Wrgsbase32 { operand0 } => {
+					self.check_fsgsbase()?;
+					self.gs_base = self.read_rm_u32(operand0)? as u64;
+				}
+				Instruction::Wrgsbase64 { operand0 } => {
+					self.check_fsgsbase()?;
+					let value = self.read_rm_u64(operand0)?;
+					self.memory.check_cannonical(value)?;
+					self.gs_base = value;
+				}
+				Instruction::XchgRM8Reg { operand0, operand1 } => {
+					let value = self.read_rm_u8(operand0)?;
+					let register = self.read_reg_u8(operand1);
+					self.write_rm_u8(operand0, register)?;
+					self.write_reg_u8(operand1, value);
+				}
+				Instruction::XchgRM16Reg { operand0, operand1 } => {
+					let value = self.read_rm_u16(operand0)?;
+					let register = self.read_reg_u16(operand1);
+					self.write_rm_u16(operand0, register)?;
+					self.write_reg_u16(operand1, value);
+				}
+				Instruction::XchgRM32Reg { operand0, operand1 } => {
+					let value = self.read_rm_u32(operand0)?;
+					let register = self.read_reg_u32(operand1);
+					self.write_rm_u32(operand0, register)?;
+					self.write_reg_u32(operand1, value);
+				}
+				Instruction::XchgRM64Reg { operand0, operand1 } => {
+					let value = self.read_rm_u64(operand0)?;
+					let register = self.read_reg_u64(operand1);
+					self.write_rm_u64(operand0, register)?;
+					self.write_reg_u64(operand1, value);
+				}
+				Instruction::Xgetbv {} => {
+					if self.read_reg_u32(C) != 0 {
+						Err(Interrupt::GeneralProtection)?;
+					}
+					self.write_reg_u32(A, self.xcr0 as u32);
+					self.write_reg_u32(D, (self.xcr0 >> 32) as u32);
+				}
+				Instruction::Xsetbv {} => {
+					self.require_cpl0()?;
+					if self.read_reg_u32(C) != 0 {
+						Err(Interrupt::GeneralProtection)?;
+					}
+					let value = ((self.read_reg_u32(D) as u64) << 32) | self.read_reg_u32(A) as u64;
+					if value & !XCR0_SUPPORTED != 0 || value & XCR0_X87 == 0 {
+						Err(Interrupt::GeneralProtection)?;
+					}
+					self.xcr0 = value;
 				}
 			};
 			self.instruction_pointer = self.instruction_pointer.wrapping_add(size);
+			let cost = self.costs.cost(&instruction, self.memory_operand);
+			self.time_stamp_counter = self.time_stamp_counter.wrapping_add(cost);
+			if self.count_cycles {
+				self.ticks = cost;
+			}
+			self.retired.push((instruction_pointer, instruction));
+			self.retired_count += 1;
+			if let Some(events) = &self.events
+				&& events.samples_retired(self.retired_count)
+			{
+				let event = Event::Retired {
+					instruction: &instruction.to_string(),
+				};
+				self.emit(instruction_pointer, event);
+			}
+			self.retire_page_faults(instruction_pointer);
+			if let Some(profile) = &mut self.profile {
+				profile.retire(instruction_pointer);
+			}
+			self.checkpoint();
+			self.stop_at_limit();
+			self.consecutive_exceptions = 0;
+			self.trap(single_step)?;
 		} {
+			// A faulting instruction did not complete, so its breakpoint hits are discarded.
+			self.debug_trap = false;
+			let interrupt = interrupt.with_opcode(&mut self.memory, self.instruction_pointer);
 			self.interrupt(interrupt);
+			return (decoded, Some(interrupt));
+		}
+		(decoded, None)
+	}
+
+	/// Formats the architectural state.
+	pub fn dump(&self, format: DumpFormat) -> String {
+		let registers = &self.registers.primary_registers;
+		let cr2 = self.registers.config_registers[2];
+		let cr3 = self.memory.paging_table_address();
+		match format {
+			DumpFormat::Human => {
+				let mut dump = String::new();
+				for (name, value) in REGISTER_NAMES.iter().zip(registers) {
+					dump += &format!("{name:>3}: 0x{value:016X}\n");
+				}
+				let flags: Vec<_> = FLAG_NAMES
+					.iter()
+					.filter(|(bit, _)| self.rflags >> bit & 1 == 1)
+					.map(|(_, name)| *name)
+					.collect();
+				dump += &format!("rip: 0x{:016X}\n", self.instruction_pointer);
+				dump += &format!("rflags: 0x{:016X} [{}]\n", self.rflags, flags.join(" "));
+				dump += &format!("cpl: {}\n", self.cpl);
+				dump += &format!("cr2: 0x{cr2:016X}\n");
+				dump += &format!("cr3: 0x{cr3:016X}\n");
+				for (index, value) in self.registers.xmm_registers.iter().enumerate() {
+					let (high, low) = (value >> 64, *value as u64);
+					dump += &format!("xmm{index}: 0x{high:016X}_{low:016X}\n");
+				}
+				dump
+			}
+			DumpFormat::Json => {
+				let registers: Vec<_> = REGISTER_NAMES
+					.iter()
+					.zip(registers)
+					.map(|(name, value)| format!("\"{name}\":{value}"))
+					.collect();
+				// The halves keep the values within the 64-bit integers most JSON readers
+				// support.
+				let xmm_registers: Vec<_> = self
+					.registers
+					.xmm_registers
+					.iter()
+					.map(|value| format!("{{\"high\":{},\"low\":{}}}", value >> 64, *value as u64))
+					.collect();
+				format!(
+					"{{\"registers\":{{{}}},\"rip\":{},\"rflags\":{},\"cpl\":{},\"cr2\":{cr2},\"cr3\":{cr3},\"xmm\":[{}]}}\n",
+					registers.join(","),
+					self.instruction_pointer,
+					self.rflags,
+					self.cpl,
+					xmm_registers.join(","),
+				)
+			}
+		}
+	}
+
+	/// The most recently retired instructions from oldest to newest.
+	pub fn retired(&self) -> impl Iterator<Item = &(u64, Instruction)> {
+		self.retired.iter()
+	}
+
+	/// The raw bytes of the idt entry for `vector`, read without tracing.
+	fn describe_idt_entry(&mut self, vector: u64) -> String {
+		let address = self.registers.config_registers[0].wrapping_add(16 * vector);
+		let mut entry = [0; 16];
+		match self.memory.read_virtual(address, &mut entry, true) {
+			Ok(()) => {
+				let bytes: Vec<_> = entry.iter().map(|byte| format!("{byte:02X}")).collect();
+				format!(
+					"Idt entry 0x{vector:02X} at 0x{address:016X}: {}\n",
+					bytes.join(" ")
+				)
+			}
+			Err(fault) => format!("Idt entry 0x{vector:02X} at 0x{address:016X}: {fault}\n"),
+		}
+	}
+
+	/// Prints the state, the most recently retired instructions and the most recently raised
+	/// interrupts.
+	pub fn report(&self) {
+		eprint!(
+			"{}",
+			self.dump(self.dump_on_exit.unwrap_or(DumpFormat::Human))
+		);
+		eprintln!("Last retired instructions:");
+		for (instruction_pointer, instruction) in self.retired() {
+			eprintln!("0x{instruction_pointer:016X}: {instruction}");
+		}
+		eprintln!("Time stamp counter: {} cycles", self.time_stamp_counter);
+		eprintln!("Last interrupts:");
+		for record in self.interrupts.iter() {
+			eprintln!(
+				"0x{:016X}: {} (vector 0x{:02X}{})",
+				record.instruction_pointer,
+				record.interrupt,
+				record.vector,
+				self.hint(record.interrupt)
+			);
+		}
+		if self.page_faults.is_some() {
+			eprintln!("Page faults:");
+			eprint!("{}", self.describe_page_faults());
+		}
+		if let Some(profile) = &self.profile {
+			profile.write();
+		}
+	}
+
+	fn dump_at_exit(&self) {
+		if let Some(format) = self.dump_on_exit {
+			eprint!("{}", self.dump(format));
+		}
+		if self.page_faults.is_some() {
+			eprintln!("Page faults:");
+			eprint!("{}", self.describe_page_faults());
+		}
+		if let Some(profile) = &self.profile {
+			profile.write();
 		}
 	}
 
-	pub fn eprint_primary_registers(&self) {
-		eprintln!("rax: {}", self.registers.primary_registers[0]);
-		eprintln!("rbx: {}", self.registers.primary_registers[3]);
-		eprintln!("rcx: {}", self.registers.primary_registers[1]);
-		eprintln!("rdx: {}", self.registers.primary_registers[2]);
-		eprintln!("rdi: {}", self.registers.primary_registers[7]);
-		eprintln!("rsi: {}", self.registers.primary_registers[6]);
-		eprintln!("rbp: {}", self.registers.primary_registers[5]);
-		eprintln!("rsp: {}", self.registers.primary_registers[4]);
+	/// Terminates the simulation normally.
+	pub fn shutdown(&self) -> ! {
+		self.end_events("shutdown");
+		self.dump_at_exit();
+		std::process::exit(0)
 	}
 }
 
-pub fn schedule_interrupt(irq: u8) {
-	IRQ.store(irq, Ordering::Relaxed);
-	MAIN_THREAD.get().expect("processor is running").unpark();
+#[cfg(test)]
+mod test {
+	use std::{
+		cell::RefCell,
+		path::PathBuf,
+		rc::Rc,
+		sync::mpsc::{Sender, channel},
+		time::Duration,
+	};
+
+	use crate::{
+		args::Config,
+		device::{
+			Device, FaultInjector, InjectedInterrupts, InterruptInjector, Irq, MemoryController,
+			PortDevices, Random, Timer,
+		},
+		events::Events,
+		instruction::{Immediate, Instruction, RM, Reg, SegmentOverride},
+		interupt::{DeliveryFailure, Interrupt},
+		memory::{
+			ConventionalMemory, MemoryAccess, MemoryManagementUnit, Permissions,
+			PhysicalMemoryManagementUnit, PoisonMemory, PoisonPolicy, ReadOnlyMemory,
+		},
+		profile::{Profile, Symbols},
+		state::{
+			AluOperation, CF, CR4_FSGSBASE, Controller, Costs, DR6_BS, DumpFormat, IF, IOPL,
+			MXCSR_DEFAULT, Multiprocessor, OF, PF, ProcessorState, REGISTER_NAMES, RFLAGS_RESERVED,
+			RFLAGS_RESET, RegName, ResetMemory, SF, Snapshot, TF, WatchdogAction, ZF, sse,
+			trace::RegisterWrite,
+		},
+	};
+
+	/// Creates a processor with `code` at virtual address 0. The first 32 virtual pages are
+	/// mapped to RAM at physical address 0x4000 and the page tables are at physical address 0.
+	fn machine(code: &[u8]) -> ProcessorState {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 1 << 20, Permissions::default(), || {
+			ConventionalMemory::create(1 << 20)
+		});
+		pmu.write_u64(0x0000, 0x1001);
+		pmu.write_u64(0x1000, 0x2001);
+		pmu.write_u64(0x2000, 0x3001);
+		for page in 0..32 {
+			pmu.write_u64(0x3000 + 8 * page, 0x4000 + (page << 12) + 1);
+		}
+		for (i, byte) in code.iter().enumerate() {
+			pmu.write_u8(0x4000 + i as u64, *byte);
+		}
+		ProcessorState::new(MemoryManagementUnit::new(pmu), PortDevices::new())
+	}
+
+	/// Points the idt at virtual 0x8000 with a present entry for the vector and the interrupt
+	/// stack at virtual 0x10000.
+	fn install_handler(state: &mut ProcessorState, vector: u64, service_routine: u64) {
+		state.registers.config_registers[0] = 0x8000;
+		state.registers.config_registers[1] = 0x10000;
+		let entry = 0x8000 + 16 * vector;
+		state.memory.write_u8(entry, 1).unwrap();
+		state.memory.write_u64(entry + 8, service_routine).unwrap();
+	}
+
+	#[test]
+	fn dump_json() {
+		let memory = MemoryManagementUnit::new(PhysicalMemoryManagementUnit::new());
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.registers.primary_registers = std::array::from_fn(|i| i as u64 * 0x100);
+		state.registers.primary_registers[15] = u64::MAX;
+		state.instruction_pointer = 0x1000;
+		state.rflags = 0x246;
+		state.registers.config_registers[2] = 0xDEAD;
+		state.registers.xmm_registers[1] = (1 << 65) - 1;
+		state.memory.swi4(0x2000);
+		assert_eq!(
+			state.dump(DumpFormat::Json),
+			concat!(
+				"{\"registers\":{\"rax\":0,\"rcx\":256,\"rdx\":512,\"rbx\":768,\"rsp\":1024,",
+				"\"rbp\":1280,\"rsi\":1536,\"rdi\":1792,\"r8\":2048,\"r9\":2304,\"r10\":2560,",
+				"\"r11\":2816,\"r12\":3072,\"r13\":3328,\"r14\":3584,",
+				"\"r15\":18446744073709551615},",
+				"\"rip\":4096,\"rflags\":582,\"cpl\":0,\"cr2\":57005,\"cr3\":8192,\"xmm\":[",
+				"{\"high\":0,\"low\":0},{\"high\":1,\"low\":18446744073709551615},",
+				"{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},",
+				"{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},",
+				"{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},",
+				"{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},{\"high\":0,\"low\":0},",
+				"{\"high\":0,\"low\":0},{\"high\":0,\"low\":0}]}\n"
+			)
+		);
+	}
+
+	#[test]
+	fn dump_human() {
+		let memory = MemoryManagementUnit::new(PhysicalMemoryManagementUnit::new());
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.registers.primary_registers[4] = 0xFFFF_8000_0000_0000;
+		state.rflags = 0x246;
+		let dump = state.dump(DumpFormat::Human);
+		assert!(dump.contains("rsp: 0xFFFF800000000000\n"));
+		assert!(dump.contains("r15: 0x0000000000000000\n"));
+		assert!(dump.contains("rflags: 0x0000000000000246 [PF ZF IF]\n"));
+	}
+
+	#[test]
+	fn retired_instructions() {
+		let mut state = machine(&[
+			0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+			0x48, 0xFF, 0xC0, // inc rax
+			0xEB, 0xFB, // jmp -5
+		]);
+		for _ in 0..40 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], 21);
+		let retired: Vec<_> = state.retired().copied().collect();
+		assert_eq!(retired.len(), 32);
+		assert_eq!(
+			retired[30..],
+			[
+				(
+					8,
+					Instruction::JmpRel8 {
+						operand0: Immediate(0xFB)
+					}
+				),
+				(
+					5,
+					Instruction::IncRM64 {
+						operand0: RM::Reg(0)
+					}
+				),
+			]
+		);
+		assert_eq!(
+			retired[0],
+			(
+				8,
+				Instruction::JmpRel8 {
+					operand0: Immediate(0xFB)
+				}
+			)
+		);
+		assert!(!retired.contains(&(
+			0,
+			Instruction::MovReg32Imm {
+				operand0: Reg(0),
+				operand1: Immediate(1)
+			}
+		)));
+	}
+
+	#[test]
+	fn negative_displacement() {
+		let mut state = machine(&[
+			0x48, 0x8B, 0x83, 0xF8, 0xFF, 0xFF, 0xFF, // mov rax, [rbx - 8]
+			0x48, 0x8B, 0x4B, 0xF8, // mov rcx, [rbx - 8]
+		]);
+		state.memory.write_u64(0x9000, 0x1234).unwrap();
+		state.registers.primary_registers[3] = 0x9008;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x1234);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[1], 0x1234);
+	}
+
+	#[test]
+	fn accumulator_immediate() {
+		let mut state = machine(&[
+			0x48, 0x05, 0x00, 0x01, 0x00, 0x00, // add rax, 0x100
+			0x48, 0x05, 0xFF, 0xFF, 0xFF, 0xFF, // add rax, -1
+			0x3C, 0x7F, // cmp al, 0x7f
+		]);
+		state.registers.primary_registers[0] = 0xFFFF_FFFF_FFFF_FF00;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 6);
+		assert_eq!(state.registers.primary_registers[0], 0);
+		assert_eq!(state.rflags & (CF | ZF | SF | OF), CF | ZF);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], u64::MAX);
+		assert_eq!(state.rflags & (CF | ZF | SF | OF), SF);
+		state.registers.primary_registers[0] = 0x80;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 14);
+		assert_eq!(state.registers.primary_registers[0], 0x80);
+		assert_eq!(state.rflags & (CF | ZF | SF | OF), OF);
+	}
+
+	#[test]
+	fn relative_branches() {
+		let code = [
+			0x3C, 0x00, // cmp al, 0
+			0x74, 0xFC, // jz short $-2
+		];
+		let mut state = machine(&code);
+		for _ in 0..2 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 0);
+
+		// A backward jump from the bottom of the address space wraps around.
+		let mut state = machine(&[0xE9, 0xF0, 0xFF, 0xFF, 0xFF]); // jmp near $-11
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0xFFFF_FFFF_FFFF_FFF5);
+
+		// The displacement is sign extended, so it never reaches past 2 GiB.
+		let mut state = machine(&[0xE9, 0x00, 0x00, 0x00, 0x80]); // jmp near $-0x7FFFFFFB
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0xFFFF_FFFF_8000_0005);
+	}
+
+	#[test]
+	fn non_canonical_branch() {
+		let mut state = machine(&[0xFF, 0xE0]); // jmp rax
+		install_handler(&mut state, 0x0D, 0x100);
+		state.registers.primary_registers[0] = 0x8000_0000_0000_0000;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		// The saved instruction pointer is the jmp itself.
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 0);
+		assert_eq!(state.retired().count(), 0);
+
+		let mut state = machine(&[0xFF, 0xE0]);
+		state.registers.primary_registers[0] = 0xFFFF_8000_0000_0000;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0xFFFF_8000_0000_0000);
+
+		// A bogus idt entry fails the delivery instead of jumping.
+		let mut state = machine(&[]);
+		install_handler(&mut state, 0x06, 0x0001_0000_0000_0000);
+		assert!(matches!(
+			state.deliver(0x06, 0),
+			Err(DeliveryFailure::NonCanonical { vector: 0x06, .. })
+		));
+	}
+
+	#[test]
+	fn iret_outside_ring_0() {
+		let mut state = machine(&[
+			0xCF, // iret
+			0xCF, // iret
+		]);
+		state.cpl = 3;
+		state.registers.primary_registers[4] = 0xA000;
+		let frames = [
+			// IF is cleared and the io privilege level raised in the frame.
+			(
+				0xA000,
+				[0, 1, RFLAGS_RESERVED | CF | IOPL | 3 << 32, 0xB000],
+			),
+			// IF is set in the frame.
+			(0xB000, [0, 2, RFLAGS_RESERVED | IF | 3 << 32, 0xC000]),
+		];
+		for (address, frame) in frames {
+			for (i, value) in frame.into_iter().enumerate() {
+				state
+					.memory
+					.write_u64(address + 8 * i as u64, value)
+					.unwrap();
+			}
+		}
+
+		// Only the other flags are taken from the frame.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 1);
+		assert_eq!(state.rflags, RFLAGS_RESERVED | CF | IF);
+		state.rflags = RFLAGS_RESERVED;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 2);
+		assert_eq!(state.rflags, RFLAGS_RESERVED);
+	}
+
+	#[test]
+	fn iret_to_user_mode() {
+		let mut code = vec![0xCF]; // iret
+		code.resize(0x10, 0x90);
+		code.extend([
+			0xB8, 0x05, 0x00, 0x00, 0x00, // mov eax, 5
+			0xB8, 0x06, 0x00, 0x00, 0x00, // mov eax, 6
+		]);
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x20, 0x100);
+		state.memory.write_u8(0x8000 + 16 * 0x20 + 2, 3).unwrap();
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		let user_rflags = 0x202 | 3 << 32;
+		state.registers.primary_registers[4] = 0xA000;
+		for (i, value) in [0, 0x10, user_rflags, 0x7000].into_iter().enumerate() {
+			state
+				.memory
+				.write_u64(0xA000 + 8 * i as u64, value)
+				.unwrap();
+		}
+
+		// The kernel enters user mode through a crafted frame.
+		state.step_instruction();
+		assert_eq!((state.instruction_pointer, state.cpl()), (0x10, 3));
+		assert_eq!(state.registers.primary_registers[4], 0x7000);
+		assert_eq!(state.rflags, 0x202);
+		state.step_instruction();
+
+		// An interrupt in user mode switches to the interrupt stack and returns to the same
+		// place, stack and privilege level.
+		state.ipis.post(0x20);
+		state.step_instruction();
+		assert_eq!((state.instruction_pointer, state.cpl()), (0x100, 0));
+		assert_eq!(state.registers.primary_registers[4], 0x10000 - 32);
+		assert_eq!(state.memory.read_u64(0x10000 - 16).unwrap(), user_rflags);
+		state.step_instruction();
+		assert_eq!((state.instruction_pointer, state.cpl()), (0x15, 3));
+		assert_eq!(state.registers.primary_registers[4], 0x7000);
+		assert_eq!(state.rflags, 0x202);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 6);
+
+		// A corrupted frame is a general protection fault at the iret, which restores nothing.
+		for (cpl, rip, rflags, rsp) in [
+			(0, 0x10, 0x202 | 4 << 32, 0x7000),
+			(0, 0x10, 0xFFFF_FFFF_0000_0202, 0x7000),
+			(0, 0x10, 0x202, 0x0000_8000_0000_0000),
+			(0, 0x0000_8000_0000_0000, 0x202, 0x7000),
+			(3, 0x10, 0x202, 0x7000),
+		] {
+			let mut state = machine(&[0xCF]);
+			install_handler(&mut state, 0x0D, 0x200);
+			state.memory.write_u8(0x8000 + 16 * 0x0D + 2, 3).unwrap();
+			state.cpl = cpl;
+			state.registers.primary_registers[4] = 0xA000;
+			for (i, value) in [0, rip, rflags, rsp].into_iter().enumerate() {
+				state
+					.memory
+					.write_u64(0xA000 + 8 * i as u64, value)
+					.unwrap();
+			}
+			state.step_instruction();
+			assert_eq!((state.instruction_pointer, state.cpl()), (0x200, 0));
+			assert_eq!(state.rflags, RFLAGS_RESET);
+			let frame = state.registers.primary_registers[4];
+			assert_eq!(state.memory.read_u64(frame + 8).unwrap(), 0);
+		}
+	}
+
+	#[test]
+	fn step_record() {
+		let code = [
+			0x48, 0x8B, 0x06, // mov rax, [rsi]
+			0x48, 0x89, 0x07, // mov [rdi], rax
+			0x0F, 0x0B, // ud2
+		];
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x06, 0x100);
+		state.memory.write_u64(0x9000, 0x1234).unwrap();
+		state.registers.primary_registers[6] = 0x9000;
+		state.registers.primary_registers[7] = 0x9100;
+
+		let step = state.step();
+		assert_eq!(step.instruction_pointer, 0);
+		assert_eq!(
+			step.instruction.map(|i| i.to_string()).unwrap(),
+			"mov rax, qword [rsi]"
+		);
+		assert_eq!(step.size, 3);
+		assert_eq!(
+			step.registers,
+			[
+				RegisterWrite {
+					register: "rax",
+					old: 0,
+					new: 0x1234
+				},
+				RegisterWrite {
+					register: "rip",
+					old: 0,
+					new: 3
+				},
+			]
+		);
+		assert_eq!(
+			step.memory,
+			[MemoryAccess {
+				address: 0x9000,
+				size: 8,
+				value: 0x1234,
+				write: false
+			}]
+		);
+		assert!(step.interrupt.is_none());
+
+		let step = state.step();
+		assert_eq!(step.registers.len(), 1);
+		assert_eq!(
+			step.memory,
+			[MemoryAccess {
+				address: 0x9100,
+				size: 8,
+				value: 0x1234,
+				write: true
+			}]
+		);
+
+		let step = state.step();
+		assert!(step.instruction.is_none());
+		assert!(matches!(
+			step.interrupt,
+			Some(Interrupt::Undefined {
+				opcode: [0x0F, 0x0B, 0x00, 0x00]
+			})
+		));
+		// The delivery reads the idt entry and pushes the interrupt frame.
+		assert_eq!(step.memory.len(), 16 + 4);
+		assert_eq!(
+			step.memory.last(),
+			Some(&MemoryAccess {
+				address: 0x10000 - 32,
+				size: 8,
+				value: 0,
+				write: true
+			})
+		);
+	}
+
+	#[test]
+	fn address_size_override() {
+		let code = [
+			0x67, 0x8B, 0x43, 0x08, // mov eax, [ebx + 8]
+			0x67, 0x8B, 0x04, 0x8B, // mov eax, [ebx + ecx * 4]
+			0x67, 0x8B, 0x05, 0xE0, 0xFF, 0xFF, 0xFF, // mov eax, [eip - 0x20]
+		];
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x0E, 0x100);
+		state.memory.write_u32(0x10008, 0x1111).unwrap();
+		state.memory.write_u32(0x9000, 0x2222).unwrap();
+		// Only the low 32 bits of the registers are used.
+		state.registers.primary_registers[3] = 0xFFFF_FFFF_0001_0000;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x1111);
+
+		// The sum wraps at 32 bits.
+		state.registers.primary_registers[3] = 0xFFFF_F000;
+		state.registers.primary_registers[1] = 0x2800;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x2222);
+
+		// The instruction pointer is also truncated, so going below zero wraps to the top of the
+		// 32 bit address space.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.config_registers[2], 0xFFFF_FFEF);
+	}
+
+	#[test]
+	fn rip_relative() {
+		let code = [
+			0x48, 0x8B, 0x05, 0xF9, 0x8F, 0x00, 0x00, // mov rax, [rel 0x9000]
+			0x48, 0x89, 0x05, 0xFA, 0x8F, 0x00, 0x00, // mov [rel 0x9008], rax
+			0xF3, 0x0F, 0x6F, 0x0D, 0xEA, 0x8F, 0x00, 0x00, // movdqu xmm1, [rel 0x9000]
+			0x67, 0x8B, 0x15, 0xEB, 0x8F, 0x00, 0x00, // mov edx, [rel 0x9008] with eip
+		];
+		let mut state = machine(&code);
+		state
+			.memory
+			.write_u64(0x9000, 0x1122_3344_5566_7788)
+			.unwrap();
+		for _ in 0..4 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], 0x1122_3344_5566_7788);
+		assert_eq!(
+			state.memory.read_u64(0x9008).unwrap(),
+			0x1122_3344_5566_7788
+		);
+		assert_eq!(
+			state.registers.xmm_registers[1],
+			0x1122_3344_5566_7788_1122_3344_5566_7788
+		);
+		assert_eq!(state.registers.primary_registers[2], 0x5566_7788);
+	}
+
+	#[test]
+	fn fetch_fault() {
+		let code = [
+			0xE9, 0xFB, 0xFF, 0x01, 0x00, // jmp near $+0x20000
+			0x8B, 0x04, 0x25, 0x00, 0x00, 0x03, 0x00, // mov eax, [0x30000]
+		];
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x0E, 0x100);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x20000);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.config_registers[2], 0x20000);
+		assert_eq!(state.memory.read_u64(0x10000 - 32).unwrap(), 0b10000);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 0x20000);
+
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x0E, 0x100);
+		state.instruction_pointer = 5;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.config_registers[2], 0x30000);
+		assert_eq!(state.memory.read_u64(0x10000 - 32).unwrap(), 0);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 5);
+	}
+
+	#[test]
+	fn immediate_fetch_fault() {
+		let mut state = machine(&[]);
+		install_handler(&mut state, 0x0E, 0x100);
+		// mov eax, 0x12345678 with the last two bytes of the immediate on the unmapped page.
+		state
+			.memory
+			.write_virtual(0x1FFFD, &[0xB8, 0x78, 0x56], false)
+			.unwrap();
+		state.instruction_pointer = 0x1FFFD;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.config_registers[2], 0x20000);
+		assert_eq!(state.memory.read_u64(0x10000 - 32).unwrap(), 0b10000);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 0x1FFFD);
+		assert_eq!(state.registers.primary_registers[0], 0);
+
+		// The saved rip retries the whole instruction once the page is mapped.
+		state
+			.memory
+			.write_physical(0x3000 + 8 * 32, &0x24001u64.to_le_bytes());
+		state.memory.write_physical(0x24000, &[0x34, 0x12]);
+		state.instruction_pointer = 0x1FFFD;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x20002);
+		assert_eq!(state.registers.primary_registers[0], 0x1234_5678);
+	}
+
+	#[test]
+	fn page_fault_log() {
+		let mut state = machine(&[
+			0x8B, 0x04, 0x25, 0x00, 0x00, 0x02, 0x00, // mov eax, [0x20000]
+			0x8B, 0x04, 0x25, 0x00, 0x00, 0x03, 0x00, // mov eax, [0x30000]
+		]);
+		state.set_page_fault_log(8);
+		install_handler(&mut state, 0x0E, 0x100);
+		install_handler(&mut state, 0x08, 0x200);
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+
+		// The handler maps the page and the instruction is retried.
+		state
+			.memory
+			.write_physical(0x3000 + 8 * 32, &0x24001u64.to_le_bytes());
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 7);
+
+		// Without a page fault handler the second fault escalates.
+		state.memory.write_u8(0x8000 + 16 * 0x0E, 0).unwrap();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x200);
+		assert_eq!(
+			state.describe_page_faults(),
+			concat!(
+				"0x0000000000000000: cr2 0x0000000000020000 error 0x00 handled and retried ",
+				"(after nothing)\n",
+				"0x0000000000000007: cr2 0x0000000000030000 error 0x00 escalated to double fault ",
+				"(after 0x0 <- 0x100)\n",
+			)
+		);
+	}
+
+	#[test]
+	fn events() {
+		let mut state = machine(&[
+			0xB8, 0x00, 0x00, 0x20, 0x00, // mov eax, 0x200000
+			0xE7, 0x20, // out 0x20, eax
+			0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+			0xE7, 0x22, // out 0x22, eax
+			0x8B, 0x04, 0x25, 0x00, 0x00, 0x03, 0x00, // mov eax, [0x30000]
+		]);
+		let mut devices = PortDevices::new();
+		devices.add(&[0x20, 0x21, 0x22], MemoryController::new());
+		state.devices = devices;
+		install_handler(&mut state, 0x0E, 0x100);
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		let path = std::env::temp_dir().join(format!("x86rs-events-{}", std::process::id()));
+		let file = std::fs::File::create(&path).unwrap();
+		state.set_events(Rc::new(Events::new(file, Some(2))));
+		for _ in 0..5 {
+			state.step_instruction();
+		}
+		state.end_events("test");
+		// Nothing is emitted after the end.
+		state.step_instruction();
+		let output = std::fs::read_to_string(&path).unwrap();
+		let _ = std::fs::remove_file(&path);
+
+		let header = |kind: &str, instructions: u64, rip: u64| {
+			format!(
+				"{{\"version\":1,\"type\":\"{kind}\",\"instructions\":{instructions},\"rip\":{rip}"
+			)
+		};
+		let expected = [
+			header("io", 1, 5)
+				+ ",\"port\":32,\"bits\":32,\"value\":2097152,\"direction\":\"out\",\"device\":\"MemoryController\"}",
+			header("retired", 2, 5) + ",\"instruction\":\"out 0x20, eax\"}",
+			header("io", 3, 12)
+				+ ",\"port\":34,\"bits\":32,\"value\":1,\"direction\":\"out\",\"device\":\"MemoryController\"}",
+			header("device", 3, 12)
+				+ ",\"device\":\"MemoryController\",\"name\":\"hot_add\",\"data\":{\"base\":2097152,\"pages\":1,\"added\":1}}",
+			header("retired", 4, 12) + ",\"instruction\":\"out 0x22, eax\"}",
+			header("page_fault", 4, 14) + ",\"cr2\":196608,\"error_code\":0}",
+			header("interrupt", 4, 14)
+				+ ",\"vector\":14,\"interrupt\":\"PF(0, 30000)\",\"error_code\":0}",
+			header("run_ended", 4, 0x100) + ",\"reason\":\"test\",\"dropped\":0}",
+		];
+		let lines: Vec<_> = output.lines().collect();
+		assert_eq!(lines, expected);
+	}
+
+	#[test]
+	fn events_of_every_processor() {
+		let mut state = machine(&[0xEB, 0xFE]); // jmp $
+		let path = std::env::temp_dir().join(format!("x86rs-smp-events-{}", std::process::id()));
+		let file = std::fs::File::create(&path).unwrap();
+		state.set_events(Rc::new(Events::new(file, Some(1))));
+		let mut multiprocessor = Multiprocessor::new(state, 2);
+		multiprocessor.step();
+		// The second processor ends the run for both.
+		multiprocessor.processors()[1].end_events("test");
+		multiprocessor.step();
+		let output = std::fs::read_to_string(&path).unwrap();
+		let _ = std::fs::remove_file(&path);
+		let retired = "{\"version\":1,\"type\":\"retired\",\"instructions\":1,\"rip\":0,\"instruction\":\"jmp short $+0x0\"}";
+		let lines: Vec<_> = output.lines().collect();
+		assert_eq!(lines.len(), 3);
+		assert_eq!(lines[..2], [retired, retired]);
+		assert!(lines[2].contains("\"type\":\"run_ended\""));
+	}
+
+	#[test]
+	fn config_registers() {
+		let mut state = machine(&[
+			0x0F, 0x30, // wrmsr
+			0x0F, 0x32, // rdmsr
+			0xB8, 0x00, 0x00, 0x00, 0x00, // mov eax, 0
+			0x0F, 0x01, 0xC1, // vmcall
+			0xB8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+			0x0F, 0x01, 0xC1, // vmcall
+		]);
+		install_handler(&mut state, 0x06, 0x100);
+		state.registers.primary_registers[0] = 0x5678;
+		state.registers.primary_registers[1] = 3;
+		state.registers.primary_registers[2] = 0x1234;
+		state.step_instruction();
+		assert_eq!(state.registers.config_registers[3], 0x1234_0000_5678);
+		state.registers.primary_registers[0] = u64::MAX;
+		state.registers.primary_registers[2] = u64::MAX;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x5678);
+		assert_eq!(state.registers.primary_registers[2], 0x1234);
+
+		// The probe advertises the hypercalls.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 1);
+		assert_eq!(
+			state.registers.primary_registers[3],
+			u64::from_le_bytes(*b"x86rs\0\0\0")
+		);
+
+		// Unknown hypercalls are undefined.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 17);
+	}
+
+	#[test]
+	fn retry_after_fault() {
+		let mut state = machine(&[
+			0x48, 0x89, 0x04, 0x25, 0x00, 0x00, 0x02, 0x00, // mov [0x20000], rax
+			0x53, // push rbx
+		]);
+		install_handler(&mut state, 0x0E, 0x100);
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		state.registers.primary_registers[0] = 0x1122_3344_5566_7788;
+		state.registers.primary_registers[3] = 0x99;
+		state.registers.primary_registers[4] = 0x22000;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 0);
+
+		// The handler maps the page and returns to the store, which completes.
+		state
+			.memory
+			.write_physical(0x3000 + 8 * 32, &0x24001u64.to_le_bytes());
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 8);
+		assert_eq!(
+			state.memory.read_u64(0x20000).unwrap(),
+			0x1122_3344_5566_7788
+		);
+
+		// A faulting push leaves rsp for the retry.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.memory.read_u64(0x10000 - 32).unwrap(), 0b10);
+		assert_eq!(state.memory.read_u64(0x10000 - 8).unwrap(), 0x22000);
+		state
+			.memory
+			.write_physical(0x3000 + 8 * 33, &0x25001u64.to_le_bytes());
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[4], 0x22000);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 9);
+		assert_eq!(state.registers.primary_registers[4], 0x21FF8);
+		assert_eq!(state.memory.read_u64(0x21FF8).unwrap(), 0x99);
+	}
+
+	#[test]
+	fn red_zone() {
+		let mut state = machine(&[
+			0x48, 0x89, 0x44, 0x24, 0xF8, // mov [rsp - 8], rax
+			0x48, 0x8B, 0x5C, 0x24, 0xF8, // mov rbx, [rsp - 8]
+		]);
+		install_handler(&mut state, 0x20, 0x100);
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		state.registers.primary_registers[0] = 0x1234;
+		state.registers.primary_registers[4] = 0x10000;
+		state.step_instruction();
+
+		// A timer interrupt between the store and the load pushes its frame below the red zone.
+		state.ipis.post(0x20);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.primary_registers[4], 0x10000 - 128 - 32);
+		assert_eq!(state.memory.read_u64(0x10000 - 128 - 8).unwrap(), 0x10000);
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[3], 0x1234);
+		assert_eq!(state.registers.primary_registers[4], 0x10000);
+
+		// The frame is aligned to 16 bytes.
+		state.set_red_zone(0);
+		state.registers.primary_registers[4] = 0x10000 - 0x38;
+		state.ipis.post(0x20);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[4], 0x10000 - 0x40 - 32);
+		assert_eq!(
+			state.memory.read_u64(0x10000 - 0x40 - 8).unwrap(),
+			0x10000 - 0x38
+		);
+	}
+
+	#[test]
+	fn precise_faults() {
+		let mut state = machine(&[
+			0x48, 0xFF, 0x04, 0x25, 0xFC, 0xFF, 0x01, 0x00, // inc qword [0x1FFFC]
+		]);
+		install_handler(&mut state, 0x0E, 0x100);
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		state.memory.write_u32(0x1FFFC, u32::MAX).unwrap();
+		for (i, register) in state.registers.primary_registers.iter_mut().enumerate() {
+			*register = 0x1111_0000 + i as u64;
+		}
+		state.rflags = 0x246;
+		let registers = state.registers.primary_registers;
+
+		// The upper half of the operand is unmapped, so nothing changes.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.config_registers[2], 0x20000);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 0);
+		assert_eq!(state.memory.read_u64(0x10000 - 16).unwrap(), 0x246);
+		assert_eq!(state.memory.read_u32(0x1FFFC).unwrap(), u32::MAX);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0);
+		assert_eq!(state.registers.primary_registers, registers);
+		assert_eq!(state.rflags, 0x246);
+
+		state
+			.memory
+			.write_physical(0x3000 + 8 * 32, &0x24001u64.to_le_bytes());
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 8);
+		assert_eq!(state.memory.read_u64(0x1FFFC).unwrap(), 1 << 32);
+	}
+
+	#[test]
+	fn faulting_alu_write() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		let read_only = Permissions {
+			read: true,
+			write: false,
+			execute: false,
+		};
+		pmu.add(0x100000, 0x1000, read_only, || {
+			ReadOnlyMemory::create(&u64::MAX.to_le_bytes(), 0x1000)
+		});
+		pmu.add_identity_page_tables(0x300000, 4, None).unwrap();
+		let mut memory = MemoryManagementUnit::new(pmu);
+		memory.set_reset_paging_table_address(0x300000);
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.rflags = 0x2;
+		let rm = RM::Mem {
+			index: 4,
+			scale: 0,
+			base: 0xFF,
+			displacement: 0x100000,
+			address_override: false,
+			segment_override: SegmentOverride::None,
+		};
+
+		// The read succeeds and the add sets the flags, but they are restored when the write of
+		// the result faults.
+		assert!(matches!(
+			state.alu_rm_u64(AluOperation::Add, rm, 1),
+			Err(Interrupt::PageFault {
+				error_code: 0b11,
+				cr2: 0x100000,
+			})
+		));
+		assert_eq!(state.rflags, 0x2);
+		assert_eq!(state.memory.read_u64(0x100000).unwrap(), u64::MAX);
+
+		// Compare does not write, so it sets the flags.
+		state.alu_rm_u64(AluOperation::Cmp, rm, u64::MAX).unwrap();
+		assert_eq!(state.rflags, 0x2 | ZF | PF);
+	}
+
+	#[test]
+	fn straddling_fetch() {
+		let code = [0x48, 0x89, 0x04, 0x25, 0x00, 0x90, 0x00, 0x00]; // mov [0x9000], rax
+		// Split after the REX prefix, the opcode, ModRM, SIB and within the displacement.
+		for split in 1..code.len() as u64 {
+			let mut state = machine(&[]);
+			install_handler(&mut state, 0x0E, 0x100);
+			state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+			let start = 0x20000 - split;
+			state.memory.write_physical(0x4000 + start, &code);
+			state.instruction_pointer = start;
+			state.registers.primary_registers[0] = 0x1234;
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, 0x100);
+			assert_eq!(state.registers.config_registers[2], 0x20000);
+			assert_eq!(state.memory.read_u64(0x10000 - 32).unwrap(), 0b10000);
+			assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), start);
+
+			state
+				.memory
+				.write_physical(0x3000 + 8 * 32, &0x24001u64.to_le_bytes());
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, start);
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, 0x20000 - split + 8);
+			assert_eq!(state.memory.read_u64(0x9000).unwrap(), 0x1234);
+		}
+	}
+
+	#[test]
+	fn performance_counters() {
+		let mut state = machine(&[
+			0xB9, 0x00, 0x00, 0x00, 0x00, // mov ecx, 0
+			0x0F, 0x33, // rdpmc
+			0x89, 0xC3, // mov ebx, eax
+			0xB8, 0x00, 0x00, 0x00, 0x00, // mov eax, 0
+			0x48, 0xFF, 0xC0, // inc rax
+			0x48, 0x3D, 0x05, 0x00, 0x00, 0x00, // cmp rax, 5
+			0x75, 0xF5, // jne -11
+			0x0F, 0x33, // rdpmc
+			0xB9, 0x01, 0x00, 0x00, 0x00, // mov ecx, 1
+			0x0F, 0x33, // rdpmc
+			0xB9, 0x03, 0x00, 0x00, 0x00, // mov ecx, 3
+			0x0F, 0x33, // rdpmc
+		]);
+		install_handler(&mut state, 0x0D, 0x100);
+		for _ in 0..20 {
+			state.step_instruction();
+		}
+		// The first rdpmc, two moves and five iterations of three instructions retired.
+		assert_eq!(state.registers.primary_registers[3], 1);
+		assert_eq!(state.registers.primary_registers[0], 1 + 1 + 2 + 5 * 3);
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 4);
+		assert_eq!(state.registers.primary_registers[2], 0);
+
+		// There is no counter 3.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+
+		// Ring 3 needs the PCE bit of cr4.
+		state.memory.write_u8(0x8000 + 16 * 0x0D + 2, 3).unwrap();
+		state.instruction_pointer = 25;
+		state.registers.primary_registers[1] = 0;
+		state.cpl = 3;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		state.instruction_pointer = 25;
+		state.cpl = 3;
+		state.registers.config_registers[3] = 1 << 8;
+		state.step_instruction();
+		// The faulting rdpmc did not retire.
+		assert_eq!(state.instruction_pointer, 27);
+		assert_eq!(state.registers.primary_registers[0], 23);
+	}
+
+	#[test]
+	fn page_fault_counter() {
+		let mut state = machine(&[
+			0x8B, 0x04, 0x25, 0x00, 0x00, 0x02, 0x00, // mov eax, [0x20000]
+			0xB9, 0x02, 0x00, 0x00, 0x00, // mov ecx, 2
+			0x0F, 0x33, // rdpmc
+		]);
+		install_handler(&mut state, 0x0E, 0x100);
+		state.memory.write_u8(0x100, 0xCF).unwrap(); // iret
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+
+		// The retried load does not fault again, so one page fault is counted.
+		state
+			.memory
+			.write_physical(0x3000 + 8 * 32, &0x24001u64.to_le_bytes());
+		for _ in 0..4 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 14);
+		assert_eq!(state.registers.primary_registers[0], 1);
+		assert_eq!(state.registers.primary_registers[2], 0);
+	}
+
+	#[test]
+	fn registers_by_name() {
+		let mut state = machine(&[]);
+		for name in ["rax", "rsp", "r15", "rip", "rflags"] {
+			let name: RegName = name.parse().unwrap();
+			state.set_reg(name, 0x1234_5678_9ABC_DEF2);
+			assert_eq!(state.get_reg(name), 0x1234_5678_9ABC_DEF2);
+		}
+		assert_eq!(state.registers.primary_registers[4], 0x1234_5678_9ABC_DEF2);
+		assert_eq!(state.instruction_pointer, 0x1234_5678_9ABC_DEF2);
+		state.set_reg(RegName::Rflags, 0);
+		assert_eq!(state.get_reg(RegName::Rflags), RFLAGS_RESERVED);
+		assert!("eax".parse::<RegName>().is_err());
+		for (number, name) in RegName::PRIMARY.into_iter().enumerate() {
+			assert_eq!(name, REGISTER_NAMES[number].parse().unwrap());
+			state.set_reg(name, number as u64);
+		}
+		assert_eq!(state.registers.primary_registers[9], 9);
+		assert_eq!(state.get_reg(RegName::R15), 15);
+	}
+
+	#[test]
+	fn reset() {
+		let mut state = machine(&[0x48, 0x05, 0x00, 0x01, 0x00, 0x00]); // add rax, 0x100
+		state.set_reset_vector(0);
+		state.registers.primary_registers[0] = u64::MAX;
+		state.registers.xmm_registers[3] = 1;
+		state.mxcsr = 0;
+		state.cpl = 3;
+		state.step_instruction();
+		assert_ne!(state.rflags, RFLAGS_RESET);
+		state.memory.write_u8(0x9000, 0xAB).unwrap();
+
+		state.reset(ResetMemory::Keep);
+		assert_eq!(state.registers.primary_registers, [0; 16]);
+		assert_eq!(state.registers.xmm_registers, [0; 16]);
+		assert_eq!(state.rflags, RFLAGS_RESET);
+		assert_eq!(state.mxcsr, MXCSR_DEFAULT);
+		assert_eq!(state.instruction_pointer, 0);
+		assert_eq!(state.cpl(), 0);
+		assert_eq!(state.retired().count(), 0);
+		assert_eq!(state.memory.read_u8(0x9000).unwrap(), 0xAB);
+		// The machine runs again from the reset vector.
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x100);
+
+		// Clearing memory also removes the paging tables.
+		state.reset(ResetMemory::Clear);
+		assert!(matches!(
+			state.memory.read_u8(0x9000),
+			Err(Interrupt::PageFault { .. })
+		));
+	}
+
+	#[test]
+	fn snapshot_delta() {
+		let code = [
+			0x48, 0x89, 0x07, // mov [rdi], rax
+			0x48, 0x89, 0x87, 0x00, 0x10, 0x00, 0x00, // mov [rdi + 0x1000], rax
+			0x48, 0x89, 0x87, 0x00, 0x20, 0x00, 0x00, // mov [rdi + 0x2000], rax
+		];
+		let mut state = machine(&code);
+		state.registers.primary_registers[0] = 0x0123_4567_89AB_CDEF;
+		state.registers.primary_registers[7] = 0x9008;
+		let base = state.snapshot();
+		assert!(base.full);
+		for _ in 0..3 {
+			state.step_instruction();
+		}
+		let delta = state.snapshot_delta();
+		assert!(!delta.full);
+		assert_eq!(
+			delta.pages.keys().copied().collect::<Vec<_>>(),
+			[0xD000, 0xE000, 0xF000]
+		);
+
+		// Restore on a machine which has diverged from the base.
+		let mut other = machine(&code);
+		other.memory.write_u64(0x18000, 0x55).unwrap();
+		other.registers.primary_registers[3] = 1;
+		other.step_instruction();
+		other.restore(&base).unwrap();
+		other.restore(&delta).unwrap();
+		assert_eq!(other.instruction_pointer, 17);
+		assert_eq!(
+			other.memory.read_u64(0xB008).unwrap(),
+			0x0123_4567_89AB_CDEF
+		);
+		assert_eq!(other.snapshot(), state.snapshot());
+
+		// Nothing was written after the last delta.
+		assert!(state.snapshot_delta().pages.is_empty());
+	}
+
+	#[test]
+	fn patch_loop_bound() {
+		let mut state = machine(&[
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0x48, 0x3D, 0x64, 0x00, 0x00, 0x00, // cmp rax, 100
+			0x75, 0xF2, // jne 0
+		]);
+		for _ in 0..3 * 3 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], 3);
+
+		// Lower the immediate of the cmp while the loop is running.
+		state
+			.memory
+			.write_virtual(8, &5u32.to_le_bytes(), false)
+			.unwrap();
+		let mut steps = 0;
+		while state.instruction_pointer != 14 {
+			state.step_instruction();
+			steps += 1;
+		}
+		assert_eq!(state.registers.primary_registers[0], 5);
+		assert_eq!(steps, 2 * 3);
+	}
+
+	#[test]
+	fn checkpoints() {
+		// inc rax; jmp $-3
+		let code = [0x48, 0xFF, 0xC0, 0xEB, 0xFB];
+		let mut state = machine(&code);
+		state.registers.xmm_registers[7] = 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+		state.memory.write_u64(0x9000, 0xAB).unwrap();
+		let path = std::env::temp_dir().join(format!("x86rs-checkpoint-{}", std::process::id()));
+		state.set_checkpoints(4, 2, path.clone());
+		// Checkpoints are written after 4, 8 and 12 retired instructions, so the last one
+		// replaced the first.
+		for _ in 0..13 {
+			state.step_instruction();
+		}
+		let read = |index| {
+			let mut file = path.clone().into_os_string();
+			file.push(format!(".{index}"));
+			let data = std::fs::read(&file).unwrap();
+			std::fs::remove_file(&file).unwrap();
+			Snapshot::from_bytes(&data).unwrap()
+		};
+		let (last, previous) = (read(0), read(1));
+		assert!(last.full);
+
+		let mut restored = machine(&[]);
+		restored.restore(&previous).unwrap();
+		assert_eq!(restored.registers.primary_registers[0], 4);
+		restored.restore(&last).unwrap();
+		assert_eq!(restored.registers.primary_registers[0], 6);
+		assert_eq!(restored.instruction_pointer, 0);
+		assert_eq!(restored.memory.read_u64(0x9000).unwrap(), 0xAB);
+		assert_eq!(
+			restored.registers.xmm_registers,
+			state.registers.xmm_registers
+		);
+		restored.step_instruction();
+		assert_eq!(restored.snapshot(), state.snapshot());
+
+		// Damaged files are rejected.
+		let data = last.to_bytes();
+		assert_eq!(Snapshot::from_bytes(&data), Ok(last));
+		assert!(Snapshot::from_bytes(&data[..data.len() - 1]).is_err());
+		assert!(Snapshot::from_bytes(&[data.as_slice(), &[0]].concat()).is_err());
+		assert!(Snapshot::from_bytes(b"not a snapshot").is_err());
+	}
+
+	#[test]
+	fn checkpoint_keeps_delta() {
+		let code = [
+			0x48, 0x89, 0x07, // mov [rdi], rax
+			0xEB, 0xFE, // jmp $
+		];
+		let mut state = machine(&code);
+		state.registers.primary_registers[7] = 0x9008;
+		state.snapshot();
+		let path = std::env::temp_dir().join(format!("x86rs-delta-{}", std::process::id()));
+		state.set_checkpoints(2, 1, path.clone());
+		state.step_instruction();
+		state.step_instruction();
+		let mut file = path.into_os_string();
+		file.push(".0");
+		std::fs::remove_file(&file).unwrap();
+		// The page written before the checkpoint is still in the delta.
+		assert_eq!(
+			state
+				.snapshot_delta()
+				.pages
+				.keys()
+				.copied()
+				.collect::<Vec<_>>(),
+			[0xD000]
+		);
+	}
+
+	#[test]
+	fn xmm_state() {
+		let mut state = machine(&[0x0F, 0x0B]); // ud2
+		let value = 0x0011_2233_4455_6677_8899_AABB_CCDD_EEFF;
+		state.registers.xmm_registers[7] = value;
+		install_handler(&mut state, 6, 0x100);
+
+		// Interrupt entry keeps the sse registers, which is visible in the dump.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.registers.xmm_registers[7], value);
+		let dump = state.dump(DumpFormat::Human);
+		assert!(dump.contains("xmm7: 0x0011223344556677_8899AABBCCDDEEFF\n"));
+		assert!(dump.contains("xmm8: 0x0000000000000000_0000000000000000\n"));
+
+		// And they survive a snapshot written to a file and read back.
+		let data = state.snapshot().to_bytes();
+		let mut restored = machine(&[]);
+		restored
+			.restore(&Snapshot::from_bytes(&data).unwrap())
+			.unwrap();
+		assert_eq!(restored.registers.xmm_registers[7], value);
+		assert_eq!(restored.dump(DumpFormat::Human), dump);
+	}
+
+	#[test]
+	fn segment_override() {
+		let mut state = machine(&[
+			0x48, 0x8B, 0x03, // mov rax, [rbx]
+			0x64, 0x48, 0x8B, 0x03, // mov rax, [fs:rbx]
+			0x65, 0x48, 0x8B, 0x03, // mov rax, [gs:rbx]
+			0x26, 0x48, 0x8B, 0x03, // mov rax, [es:rbx]
+			0x2E, 0x48, 0x8B, 0x03, // mov rax, [cs:rbx]
+			0x36, 0x48, 0x8B, 0x03, // mov rax, [ss:rbx]
+			0x3E, 0x48, 0x8B, 0x03, // mov rax, [ds:rbx]
+		]);
+		state.memory.write_u64(0x8000, 1).unwrap();
+		state.memory.write_u64(0x10000, 2).unwrap();
+		state.memory.write_u64(0x18000, 3).unwrap();
+		state.registers.primary_registers[3] = 0x8000;
+		state.fs_base = 0x8000;
+		state.gs_base = 0x10000;
+		for expected in [1, 2, 3, 1, 1, 1, 1] {
+			state.step_instruction();
+			assert_eq!(state.registers.primary_registers[0], expected);
+		}
+		assert_eq!(state.instruction_pointer, 27);
+	}
+
+	#[test]
+	fn push_pop() {
+		let mut state = machine(&[
+			0x53, // push rbx
+			0x55, // push rbp
+			0x41, 0x50, 0x41, 0x51, 0x41, 0x52, 0x41, 0x53, // push r8 .. r11
+			0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57, // push r12 .. r15
+			0x58, 0x59, 0x5A, 0x5E, 0x5F, // pop rax, rcx, rdx, rsi, rdi
+			0x41, 0x58, 0x41, 0x59, 0x41, 0x5A, 0x41, 0x5B, 0x41, 0x5C, // pop r8 .. r12
+			0x54, // push rsp
+			0x5C, // pop rsp
+		]);
+		for (i, register) in state.registers.primary_registers.iter_mut().enumerate() {
+			*register = 0x1111_0000 + i as u64;
+		}
+		state.registers.primary_registers[4] = 0x10000;
+		for _ in 0..22 {
+			state.step_instruction();
+		}
+		let popped = [0, 1, 2, 6, 7, 8, 9, 10, 11, 12];
+		let pushed = [15, 14, 13, 12, 11, 10, 9, 8, 5, 3];
+		for (register, original) in popped.into_iter().zip(pushed) {
+			assert_eq!(
+				state.registers.primary_registers[register],
+				0x1111_0000 + original
+			);
+		}
+		assert_eq!(state.registers.primary_registers[4], 0x10000);
+		assert_eq!(state.memory.read_u64(0xFFF8).unwrap(), 0x10000);
+		assert_eq!(state.instruction_pointer, 35);
+	}
+
+	#[test]
+	fn data_breakpoint() {
+		let mut state = machine(&[
+			0x0F, 0x23, 0xC0, // mov dr0, rax
+			0x0F, 0x23, 0xFB, // mov dr7, rbx
+			0x48, 0x8B, 0x11, // mov rdx, [rcx]
+			0x48, 0x89, 0x31, // mov [rcx], rsi
+		]);
+		install_handler(&mut state, 0x01, 0x100);
+		state.registers.primary_registers[0] = 0x9004;
+		// Enable dr0 as a 4 byte write breakpoint.
+		state.registers.primary_registers[3] = 0b01 | (0b01 << 16) | (0b11 << 18);
+		state.registers.primary_registers[1] = 0x9000;
+		state.registers.primary_registers[6] = 0x1234;
+		for _ in 0..3 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 9);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.debug_registers[6] & 0b1111, 0b0001);
+		assert_eq!(state.memory.read_u64(0x9000).unwrap(), 0x1234);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 12);
+		assert_eq!(state.memory.read_u64(0x10000 - 32).unwrap(), 0);
+
+		// A breakpoint on the last 8 bytes of the address space.
+		let mut state = machine(&[]);
+		state.debug_registers[0] = u64::MAX;
+		state.debug_registers[7] = 0b01 | (0b11 << 16) | (0b10 << 18);
+		state.watch(u64::MAX - 3, 4, false);
+		assert_eq!(state.debug_registers[6] & 0b1111, 0b0001);
+	}
+
+	#[test]
+	fn extended_control_registers() {
+		let code = [
+			0x0F, 0x01, 0xD0, // xgetbv
+			0x0F, 0x01, 0xD1, // xsetbv
+		];
+		let mut state = machine(&code);
+		state.registers.primary_registers[0] = u64::MAX;
+		state.registers.primary_registers[2] = u64::MAX;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 1);
+		assert_eq!(state.registers.primary_registers[2], 0);
+		state.step_instruction();
+		assert_eq!(state.xcr0, 1);
+		assert_eq!(state.instruction_pointer, 6);
+
+		// Reading or writing an unknown register, clearing the x87 bit or setting an unsupported
+		// bit is a general protection fault.
+		for (ecx, eax) in [(1, 1), (0, 0), (0, 7)] {
+			let mut state = machine(&code);
+			state.registers.primary_registers[1] = ecx;
+			state.registers.primary_registers[0] = eax;
+			state.instruction_pointer = if ecx == 0 { 3 } else { 0 };
+			install_handler(&mut state, 0x0D, 0x100);
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, 0x100);
+			assert_eq!(state.xcr0, 1);
+		}
+	}
+
+	#[test]
+	fn single_step() {
+		let mut state = machine(&[
+			0x48, 0xFF, 0xC0, // inc rax
+			0x48, 0xFF, 0xC0, // inc rax
+		]);
+		// The handler returns immediately.
+		state.memory.write_u8(0x100, 0x48).unwrap();
+		state.memory.write_u8(0x101, 0xCF).unwrap();
+		install_handler(&mut state, 0x01, 0x100);
+		state.rflags = TF;
+		for (count, return_address) in [(1, 3), (2, 6)] {
+			state.step_instruction();
+			assert_eq!(state.registers.primary_registers[0], count);
+			assert_eq!(state.instruction_pointer, 0x100);
+			assert_eq!(state.rflags & TF, 0);
+			assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), return_address);
+			assert_ne!(state.debug_registers[6] & DR6_BS, 0);
+			// The iret of the handler is not single stepped, but restores the trap flag.
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, return_address);
+			assert_eq!(state.rflags & TF, TF);
+		}
+	}
+
+	#[test]
+	fn software_interrupts() {
+		let mut state = machine(&[
+			0xB8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+			0x89, 0x04, 0x25, 0x30, 0x80, 0x00, 0x00, // mov [0x8030], eax
+			0x89, 0x04, 0x25, 0x00, 0x88, 0x00, 0x00, // mov [0x8800], eax
+			0xB8, 0x00, 0x02, 0x00, 0x00, // mov eax, 0x200
+			0x48, 0x89, 0x04, 0x25, 0x38, 0x80, 0x00, 0x00, // mov [0x8038], rax
+			0xB8, 0x10, 0x02, 0x00, 0x00, // mov eax, 0x210
+			0x48, 0x89, 0x04, 0x25, 0x08, 0x88, 0x00, 0x00, // mov [0x8808], rax
+			0xB8, 0x00, 0x80, 0x00, 0x00, // mov eax, 0x8000
+			0x48, 0x89, 0x04, 0x25, 0x02, 0x01, 0x00, 0x00, // mov [0x102], rax
+			0x0F, 0x01, 0x1C, 0x25, 0x00, 0x01, 0x00, 0x00, // lidt [0x100]
+			0xB9, 0x04, 0x01, 0x00, 0xC0, // mov ecx, 0xC0000104
+			0xB8, 0x00, 0xF0, 0x00, 0x00, // mov eax, 0xF000
+			0xBA, 0x00, 0x00, 0x00, 0x00, // mov edx, 0
+			0x0F, 0x30, // wrmsr
+			0xCC, // int3
+			0xCD, 0x80, // int 0x80
+			0xF4, // hlt
+		]);
+		// The breakpoint handler counts in rbx, and the handler of vector 0x80 reads its return
+		// address.
+		for (address, byte) in [0x48, 0xFF, 0xC3, 0xCF].into_iter().enumerate() {
+			state.memory.write_u8(0x200 + address as u64, byte).unwrap(); // inc rbx; iret
+		}
+		for (address, byte) in [0x48, 0x8B, 0x54, 0x24, 0x08, 0xCF].into_iter().enumerate() {
+			state.memory.write_u8(0x210 + address as u64, byte).unwrap(); // mov rdx, [rsp + 8]; iret
+		}
+
+		// The guest loads its idt and interrupt stack, and int3 enters the handler past it.
+		for _ in 0..15 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.config_registers[0], 0x8000);
+		assert_eq!(state.registers.config_registers[1], 0xF000);
+		assert_eq!(state.instruction_pointer, 0x200);
+		assert_eq!(state.memory.read_u64(0xF000 - 24).unwrap(), 84);
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 84);
+		assert_eq!(state.registers.primary_registers[3], 1);
+
+		// int 0x80 returns past its immediate.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x210);
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[2], 86);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 87);
+		assert_eq!(
+			state
+				.interrupts
+				.iter()
+				.map(|record| record.vector)
+				.collect::<Vec<_>>(),
+			[3, 0x80]
+		);
+	}
+
+	#[test]
+	fn segment_base() {
+		let code = [
+			0xF3, 0x48, 0x0F, 0xAE, 0xD3, // wrfsbase rbx
+			0x64, 0x48, 0x8B, 0x01, // mov rax, [fs:rcx]
+			0xF3, 0x48, 0x0F, 0xAE, 0xC2, // rdfsbase rdx
+		];
+		let mut state = machine(&code);
+		state.registers.config_registers[3] = CR4_FSGSBASE;
+		state.cpl = 3;
+		state.memory.write_u64(0x9010, 0x1234).unwrap();
+		state.registers.primary_registers[3] = 0x9000;
+		state.registers.primary_registers[1] = 0x10;
+		for _ in 0..3 {
+			state.step_instruction();
+		}
+		assert_eq!(state.fs_base, 0x9000);
+		assert_eq!(state.registers.primary_registers[0], 0x1234);
+		assert_eq!(state.registers.primary_registers[2], 0x9000);
+		assert_eq!(state.instruction_pointer, 14);
+
+		// Without the cr4 bit the instructions are undefined.
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x06, 0x100);
+		state.registers.primary_registers[3] = 0x9000;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.fs_base, 0);
+	}
+
+	#[test]
+	fn xmm_move() {
+		let mut code = Vec::new();
+		for offset in [0x00, 0x10, 0x20, 0x30] {
+			code.extend([0xF3, 0x0F, 0x6F, 0x46, offset]); // movdqu xmm0, [rsi + offset]
+			code.extend([0xF3, 0x0F, 0x7F, 0x47, offset]); // movdqu [rdi + offset], xmm0
+		}
+		let mut state = machine(&code);
+		let source: Vec<u8> = (0..64).collect();
+		for (i, byte) in source.iter().enumerate() {
+			state.memory.write_u8(0x9001 + i as u64, *byte).unwrap();
+		}
+		state.registers.primary_registers[6] = 0x9001;
+		state.registers.primary_registers[7] = 0xA003;
+		for _ in 0..8 {
+			state.step_instruction();
+		}
+		let copy: Vec<u8> = (0..64)
+			.map(|i| state.memory.read_u8(0xA003 + i).unwrap())
+			.collect();
+		assert_eq!(copy, source);
+		assert_eq!(state.instruction_pointer, 40);
+
+		// The aligned moves fault on the same misaligned buffer.
+		let mut state = machine(&[0x66, 0x0F, 0x6F, 0x06]); // movdqa xmm0, [rsi]
+		install_handler(&mut state, 0x0D, 0x100);
+		state.registers.primary_registers[6] = 0x9001;
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+	}
+
+	#[test]
+	fn xmm_logic() {
+		let mut state = machine(&[
+			0x66, 0x0F, 0xEF, 0xC1, // pxor xmm0, xmm1
+			0x66, 0x0F, 0xEB, 0xD3, // por xmm2, xmm3
+			0x66, 0x0F, 0xDB, 0xE5, // pand xmm4, xmm5
+			0x66, 0x0F, 0xDF, 0xF7, // pandn xmm6, xmm7
+			0x66, 0x0F, 0xEF, 0x06, // pxor xmm0, [rsi]
+			0x66, 0x48, 0x0F, 0x7E, 0xC0, // movq rax, xmm0
+			0x66, 0x0F, 0x7E, 0xD1, // movd ecx, xmm2
+			0x66, 0x48, 0x0F, 0x6E, 0xC2, // movq xmm0, rdx
+			0x66, 0x0F, 0x6E, 0xCA, // movd xmm1, edx
+		]);
+		let a = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210_u128;
+		let b = 0xFFFF_0000_F0F0_0F0F_1234_5678_9ABC_DEF0_u128;
+		let c = 0x5555_AAAA_0000_FFFF_8000_0001_7FFF_FFFE_u128;
+		for register in [0, 2, 4, 6] {
+			state.registers.xmm_registers[register] = a;
+			state.registers.xmm_registers[register + 1] = b;
+		}
+		state.memory.write_u128(0x9000, c).unwrap();
+		state.registers.primary_registers[6] = 0x9000;
+		state.registers.primary_registers[1] = u64::MAX;
+		state.registers.primary_registers[2] = 0x1122_3344_5566_7788;
+		for _ in 0..5 {
+			state.step_instruction();
+		}
+		let xmm = state.registers.xmm_registers;
+		assert_eq!(xmm[0], a ^ b ^ c);
+		assert_eq!(xmm[2], a | b);
+		assert_eq!(xmm[4], a & b);
+		assert_eq!(xmm[6], !a & b);
+		for _ in 0..4 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], (a ^ b ^ c) as u64);
+		assert_eq!(state.registers.primary_registers[1], (a | b) as u32 as u64);
+		assert_eq!(state.registers.xmm_registers[0], 0x1122_3344_5566_7788);
+		assert_eq!(state.registers.xmm_registers[1], 0x5566_7788);
+		assert_eq!(state.instruction_pointer, 38);
+	}
+
+	#[test]
+	fn privileged_instructions() {
+		for code in [
+			&[0xF4][..],         // hlt
+			&[0xE6, 0x80],       // out 0x80, al
+			&[0x0F, 0x22, 0xD8], // mov cr3, rax
+			&[0x0F, 0x01, 0x18], // lidt [rax]
+			&[0x0F, 0x09],       // wbinvd
+			&[0xF3, 0x6C],       // rep insb
+			&[0x0F, 0x01, 0xF0], // lmsw ax
+			&[0x0F, 0x01, 0xF8], // swapgs
+		] {
+			let mut state = machine(code);
+			install_handler(&mut state, 0x0D, 0x100);
+			// The rpl of the entry is also checked for faults, so it must allow ring 3.
+			state.memory.write_u8(0x8000 + 16 * 0x0D + 2, 3).unwrap();
+			state.cpl = 3;
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, 0x100);
+			assert_eq!(state.cpl(), 0);
+			assert_eq!(state.retired().count(), 0);
+		}
+	}
+
+	#[test]
+	fn watchdog() {
+		let mut state = machine(&[
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0xEB, 0xFE, // jmp $
+		]);
+		state.set_watchdog(4, WatchdogAction::Warn);
+		let idle_steps = |state: &ProcessorState| state.watchdog.as_ref().unwrap().idle_steps;
+		state.step_instruction();
+		assert_eq!(idle_steps(&state), 0);
+		// Reaching the threshold only warns, so the loop keeps running.
+		for _ in 0..6 {
+			state.step_instruction();
+		}
+		assert_eq!(idle_steps(&state), 6);
+		assert_eq!(state.instruction_pointer, 6);
+	}
+
+	#[test]
+	fn exception_limit() {
+		let mut state = machine(&[0x48, 0x05, 0x01, 0x00, 0x00, 0x00]); // add rax, 1
+		// The page fault handler is not mapped, so fetching it faults again.
+		install_handler(&mut state, 0x0E, 0x40000);
+		state.set_reset_vector(0x40000);
+		state.set_exception_limit(8);
+		for _ in 0..8 {
+			state.step_instruction();
+		}
+		assert_eq!(state.consecutive_exceptions, 8);
+		let record = state.interrupts.iter().last().unwrap();
+		assert_eq!((record.instruction_pointer, record.vector), (0x40000, 0x0E));
+		assert_eq!(
+			state.describe_idt_entry(0x0E),
+			"Idt entry 0x0E at 0x00000000000080E0: \
+			 01 00 00 00 00 00 00 00 00 00 04 00 00 00 00 00\n"
+		);
+
+		// A retired instruction ends the streak.
+		state.instruction_pointer = 0;
+		state.step_instruction();
+		assert_eq!(state.consecutive_exceptions, 0);
+	}
+
+	#[test]
+	fn inter_processor_interrupt() {
+		let mut code = vec![
+			0xB9, 0x30, 0x08, 0x00, 0x00, // mov ecx, 0x830
+			0xB8, 0x40, 0x00, 0x00, 0x00, // mov eax, 0x40
+			0xBA, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+			0x0F, 0x30, // wrmsr
+			0xF4, // hlt
+		];
+		code.resize(0x20, 0);
+		code.push(0xF4); // hlt
+		code.resize(0x30, 0);
+		code.extend([
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0xEB, 0xFE, // jmp $
+		]);
+		let mut multiprocessor = Multiprocessor::new(machine(&code), 2);
+		let processors = multiprocessor.processors();
+		processors[1].instruction_pointer = 0x20;
+		install_handler(&mut processors[1], 0x40, 0x30);
+
+		// The second processor halts while the first one prepares the interrupt.
+		for _ in 0..3 {
+			multiprocessor.step();
+		}
+		assert!(multiprocessor.processors()[1].halted);
+		multiprocessor.step();
+		let processors = multiprocessor.processors();
+		assert!(!processors[1].halted);
+		assert_eq!(processors[1].instruction_pointer, 0x30);
+		assert_eq!(processors[1].memory.read_u64(0x10000 - 24).unwrap(), 0x21);
+		for _ in 0..4 {
+			multiprocessor.step();
+		}
+		let processors = multiprocessor.processors();
+		assert!(processors[0].halted);
+		assert_eq!(processors[1].registers.primary_registers[0], 1);
+		assert_eq!(processors[1].instruction_pointer, 0x36);
+
+		// An interrupt for a processor which does not exist is dropped.
+		processors[0].send_ipi(5 << 32 | 0x40);
+		assert!(processors.iter().all(|processor| processor.ipis.is_empty()));
+	}
+
+	#[test]
+	fn controller() {
+		let controller = Controller::spawn(|| {
+			let mut code = vec![
+				0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+				0xEB, 0xF8, // jmp $-0x6
+			];
+			code.resize(0x40, 0);
+			code.extend([
+				0x48, 0x05, 0x00, 0x10, 0x00, 0x00, // add rax, 0x1000
+				0xEB, 0xFE, // jmp $
+			]);
+			let mut state = machine(&code);
+			install_handler(&mut state, 0x20, 0x40);
+			state
+		});
+		let register = |name| {
+			let registers = controller.read_registers();
+			registers
+				.into_iter()
+				.find(|(register, _)| *register == name)
+				.unwrap()
+				.1
+		};
+		controller.step(10);
+		assert_eq!(register("rax"), 5);
+		assert_eq!(register("rip"), 0);
+
+		// The guest keeps counting while it runs.
+		controller.resume();
+		while register("rax") < 100 {}
+		controller.pause();
+		let rax = register("rax");
+		assert_eq!(register("rax"), rax);
+
+		controller.inject_irq(0x20);
+		controller.step(2);
+		assert_eq!(register("rip"), 0x46);
+		assert_eq!(register("rax"), rax + 0x1000);
+		controller.shutdown();
+	}
+
+	#[test]
+	fn controller_hlt() {
+		/// Reports the bytes written to it.
+		struct Signal(Sender<u8>);
+
+		impl Device for Signal {
+			fn out_u8(&mut self, _port: u16, byte: u8) {
+				let _ = self.0.send(byte);
+			}
+
+			fn in_u8(&mut self, _port: u16) -> u8 {
+				0xFF
+			}
+		}
+
+		let (irqs, irq) = channel();
+		let (signals, signal) = channel();
+		let controller = Controller::spawn(move || {
+			let mut code = vec![
+				0xF4, // hlt
+				0xEB, 0xFE, // jmp $
+			];
+			code.resize(0x40, 0);
+			code.extend([
+				0xE6, 0x60, // out 0x60, al
+				0xEB, 0xFE, // jmp $
+			]);
+			let mut state = machine(&code);
+			install_handler(&mut state, 0x20, 0x40);
+			state.devices.add(&[0x60], Signal(signals));
+			irqs.send(state.devices.irq(0x20)).unwrap();
+			state
+		});
+		let irq: Irq = irq.recv().unwrap();
+		let rip = || {
+			let registers = controller.read_registers();
+			registers
+				.into_iter()
+				.find(|(register, _)| *register == "rip")
+				.unwrap()
+				.1
+		};
+		// Steps of a halted processor return without an interrupt.
+		controller.step(3);
+		assert_eq!(rip(), 1);
+
+		// The irq of a device raised on another thread wakes the running processor.
+		controller.resume();
+		irq.raise();
+		assert!(signal.recv_timeout(Duration::from_secs(10)).is_ok());
+		controller.pause();
+		assert_eq!(rip(), 0x42);
+		controller.shutdown();
+	}
+
+	#[test]
+	fn execute_only_memory() {
+		let mut code = vec![
+			0x48, 0x8B, 0x04, 0x25, 0x00, 0x00, 0x10, 0x00, // mov rax, [0x100000]
+		];
+		code.resize(0x10, 0);
+		code.push(0xF4); // hlt
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		let execute_only = Permissions {
+			read: false,
+			write: false,
+			execute: true,
+		};
+		pmu.add(0x100000, 0x1000, execute_only, || {
+			ReadOnlyMemory::create(&code, 0x1000)
+		});
+		pmu.add(0x200000, 0x2000, Permissions::default(), || {
+			ConventionalMemory::create(0x2000)
+		});
+		pmu.add_identity_page_tables(0x300000, 4, None).unwrap();
+		let mut memory = MemoryManagementUnit::new(pmu);
+		memory.set_reset_paging_table_address(0x300000);
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.set_reset_vector(0x100000);
+		state.registers.config_registers[0] = 0x200000;
+		state.registers.config_registers[1] = 0x202000;
+		state.memory.write_u8(0x200000 + 16 * 0x0E, 1).unwrap();
+		state
+			.memory
+			.write_u64(0x200000 + 16 * 0x0E + 8, 0x100010)
+			.unwrap();
+
+		// The code is fetched, but reading it as data is a protection fault.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100010);
+		assert_eq!(state.registers.config_registers[2], 0x100000);
+		assert_eq!(state.memory.read_u64(0x202000 - 32).unwrap(), 0b00001);
+		assert_eq!(state.memory.read_u64(0x202000 - 24).unwrap(), 0x100000);
+		assert_eq!(state.registers.primary_registers[0], 0);
+	}
+
+	#[test]
+	fn identity_map() {
+		let code = [
+			0x48, 0x05, 0x34, 0x12, 0x00, 0x00, // add rax, 0x1234
+			0x50, // push rax
+			0x48, 0x8B, 0x1C, 0x24, // mov rbx, qword [rsp]
+		];
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0x100000, 0x1000, Permissions::default(), || {
+			ReadOnlyMemory::create(&code, 0x1000)
+		});
+		pmu.add(0x200000, 0x2000, Permissions::default(), || {
+			ConventionalMemory::create(0x2000)
+		});
+		let alias = 0xFFFF_8000_0000_0000;
+		pmu.add_identity_page_tables(0x300000, 4, Some(alias))
+			.unwrap();
+		// The tables must not overlap a region.
+		assert!(pmu.add_identity_page_tables(0x201000, 4, None).is_err());
+		let mut memory = MemoryManagementUnit::new(pmu);
+		memory.set_reset_paging_table_address(0x300000);
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.set_reset_vector(0x100000);
+		state.registers.primary_registers[4] = 0x202000;
+		for _ in 0..3 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 0x10000B);
+		assert_eq!(state.registers.primary_registers[3], 0x1234);
+		let mut buffer = [0; 8];
+		state
+			.memory
+			.read_virtual(alias + 0x201FF8, &mut buffer, false)
+			.unwrap();
+		assert_eq!(u64::from_le_bytes(buffer), 0x1234);
+		// Nothing is mapped outside of the regions, including the tables.
+		assert!(state.memory.read_u8(0x101000).is_err());
+		assert!(state.memory.read_u8(0x300000).is_err());
+
+		// The tables are read only memory and stay in cr3 across a reset.
+		state.reset(ResetMemory::Clear);
+		assert_eq!(state.memory.paging_table_address(), 0x300000);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x1234);
+	}
+
+	#[test]
+	fn boot_page_tables() {
+		let code = [
+			0xB8, 0x34, 0x12, 0x00, 0x00, // mov eax, 0x1234
+			0x48, 0x89, 0x05, 0xF4, 0x0F, 0x00, 0x00, // mov qword [rip + 0xFF4], rax
+		];
+		// Four tables mapping the code at 0xFFFF800000000000 and the page after it to RAM.
+		let mut tables = vec![0; 4 << 12];
+		let entries = [
+			(256 * 8, 0x6003u64),
+			(0x1000, 0x7003),
+			(0x2000, 0x8003),
+			(0x3000, 0x100003),
+			(0x3008, 0x200003),
+		];
+		for (offset, entry) in entries {
+			tables[offset..offset + 8].copy_from_slice(&entry.to_le_bytes());
+		}
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0x5000, 4 << 12, Permissions::default(), || {
+			ReadOnlyMemory::create(&tables, 4 << 12)
+		});
+		pmu.add(0x100000, 0x1000, Permissions::default(), || {
+			ReadOnlyMemory::create(&code, 0x1000)
+		});
+		pmu.add(0x200000, 0x1000, Permissions::default(), || {
+			ConventionalMemory::create(0x1000)
+		});
+		assert!(pmu.check_page_tables(0x5008).is_err());
+		assert!(pmu.check_page_tables(0x9000).is_err());
+		pmu.check_page_tables(0x5000).unwrap();
+		let mut memory = MemoryManagementUnit::new(pmu);
+		memory.set_reset_paging_table_address(0x5000);
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		let entry = 0xFFFF_8000_0000_0000;
+		state.set_reset_vector(entry);
+
+		// The first fetch is already translated by the tables.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, entry + 12);
+		let mut buffer = [0; 8];
+		state.memory.read_physical(0x200000, &mut buffer);
+		assert_eq!(u64::from_le_bytes(buffer), 0x1234);
+
+		state.reset(ResetMemory::Clear);
+		assert_eq!(state.memory.paging_table_address(), 0x5000);
+		assert_eq!(state.instruction_pointer, entry);
+	}
+
+	#[test]
+	fn poisoned_memory() {
+		let code = [
+			0x8B, 0x03, // mov eax, dword [rbx]
+			0x89, 0x43, 0x04, // mov dword [rbx + 4], eax
+		];
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0x100000, 0x1000, Permissions::default(), || {
+			ReadOnlyMemory::create(&code, 0x1000)
+		});
+		let reports = Rc::new(RefCell::new(Vec::new()));
+		let context = pmu.access_context();
+		let sink = reports.clone();
+		pmu.add(0x200000, 0x1000, Permissions::default(), || {
+			PoisonMemory::create(
+				0x200000,
+				0x1000,
+				PoisonPolicy::Log,
+				context,
+				move |report| sink.borrow_mut().push(report.to_string()),
+			)
+		});
+		let alias = 0xFFFF_8000_0000_0000;
+		pmu.add_identity_page_tables(0x300000, 4, Some(alias))
+			.unwrap();
+		let mut memory = MemoryManagementUnit::new(pmu);
+		memory.swi4(0x300000);
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.set_reset_vector(alias + 0x100000);
+		state.registers.primary_registers[3] = alias + 0x200010;
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(
+			reports.take(),
+			[
+				"Poisoned memory at 0x200010 read by an access of 4 bytes to \
+				 0xFFFF800000200010 at rip 0xFFFF800000100000",
+				"Poisoned memory at 0x200014 written by an access of 4 bytes to \
+				 0xFFFF800000200014 at rip 0xFFFF800000100002",
+			]
+		);
+	}
+
+	#[test]
+	fn trace_io() {
+		let code = [
+			0xB8, 0xE8, 0x03, 0x00, 0x00, // mov eax, 1000
+			0xE7, 0x40, // out 0x40, eax
+			0xB0, 0x01, // mov al, 1
+			0xE6, 0x44, // out 0x44, al
+			0xE4, 0x50, // in al, 0x50
+		];
+		let run = |device: Option<&str>| {
+			let mut pmu = PhysicalMemoryManagementUnit::new();
+			pmu.add(0x100000, 0x1000, Permissions::default(), || {
+				ReadOnlyMemory::create(&code, 0x1000)
+			});
+			pmu.add_identity_page_tables(0x300000, 4, None).unwrap();
+			let mut memory = MemoryManagementUnit::new(pmu);
+			memory.set_reset_paging_table_address(0x300000);
+			let mut devices = PortDevices::new();
+			let ports = [0x40, 0x41, 0x42, 0x43, 0x44];
+			let timer = Timer::new(devices.irq(32), true);
+			devices.add_named("pit", &ports, Box::new(timer));
+			devices.add(&[0x50], Random::new(Some(1)));
+			let lines = Rc::new(RefCell::new(Vec::new()));
+			let sink = lines.clone();
+			devices
+				.trace(device.map(str::to_string), move |line| {
+					sink.borrow_mut().push(line)
+				})
+				.unwrap();
+			let mut state = ProcessorState::new(memory, devices);
+			state.set_reset_vector(0x100000);
+			for _ in 0..5 {
+				state.step_instruction();
+			}
+			lines.take()
+		};
+		let timer = [
+			"Io out 0x0040 32-bit 0x000003E8 pit (rip 0x0000000000100005 instruction 1)",
+			"Io out 0x0044 8-bit 0x01 pit (rip 0x0000000000100009 instruction 3)",
+		];
+		assert_eq!(run(Some("pit")), timer);
+		let all = run(None);
+		assert_eq!(all[..2], timer);
+		assert_eq!(all.len(), 3);
+		assert!(all[2].starts_with("Io in 0x0050 8-bit 0x"));
+		assert!(all[2].ends_with(" Random (rip 0x000000000010000B instruction 4)"));
+
+		assert!(
+			PortDevices::new()
+				.trace(Some("pit".to_string()), |_| ())
+				.is_err()
+		);
+	}
+
+	#[test]
+	fn byte_register_immediates() {
+		let before: [u64; 16] = std::array::from_fn(|i| 0x0101_0101_0101_0101 * (i as u64 + 1));
+		// Without REX 4 to 7 select ah to bh, and with any REX spl to dil.
+		let mut cases = Vec::new();
+		for low in 0..8u8 {
+			let (register, shift) = if low < 4 { (low, 0) } else { (low - 4, 8) };
+			cases.push((vec![0xB0 + low, 0xAB], register, shift));
+			cases.push((vec![0x40, 0xB0 + low, 0xAB], low, 0));
+			cases.push((vec![0x41, 0xB0 + low, 0xAB], low + 8, 0));
+		}
+		for (code, register, shift) in cases {
+			let mut state = machine(&code);
+			state.registers.primary_registers = before;
+			state.step_instruction();
+			let mut expected = before;
+			expected[register as usize] &= !(0xFF << shift);
+			expected[register as usize] |= 0xAB << shift;
+			assert_eq!(state.registers.primary_registers, expected, "{code:02X?}");
+			assert_eq!(state.instruction_pointer, code.len() as u64);
+		}
+	}
+
+	#[test]
+	fn narrow_loads() {
+		let mut state = machine(&[
+			0x8A, 0x03, // mov al, byte [rbx]
+			0x66, 0x8B, 0x03, // mov ax, word [rbx]
+			0x44, 0x8A, 0x0B, // mov r9b, byte [rbx]
+			0x66, 0x44, 0x8B, 0x13, // mov r10w, word [rbx]
+		]);
+		state.memory.write_u16(0x9000, 0xBEEF).unwrap();
+		state.registers.primary_registers[3] = 0x9000;
+		let before = 0x1122_3344_5566_7788;
+		for register in [0, 9, 10] {
+			state.registers.primary_registers[register] = before;
+		}
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x1122_3344_5566_77EF);
+		state.registers.primary_registers[0] = before;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x1122_3344_5566_BEEF);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[9], 0x1122_3344_5566_77EF);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[10], 0x1122_3344_5566_BEEF);
+	}
+
+	#[test]
+	fn spinlock() {
+		let state = machine(&[
+			0x48, 0x8B, 0xC3, // mov rax, rbx
+			0x48, 0x87, 0x07, // xchg qword [rdi], rax
+			0x48, 0x3D, 0x00, 0x00, 0x00, 0x00, // cmp rax, 0
+			0x75, 0xF2, // jne $-0xC
+			0x48, 0x8B, 0x06, // mov rax, qword [rsi]
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0x48, 0x89, 0x06, // mov qword [rsi], rax
+			0x48, 0x89, 0x0F, // mov qword [rdi], rcx
+			0x48, 0xFF, 0x02, // inc qword [rdx]
+			0x48, 0x8B, 0x02, // mov rax, qword [rdx]
+			0x48, 0x3D, 0x64, 0x00, 0x00, 0x00, // cmp rax, 100
+			0x75, 0xD5, // jne $-0x29
+			0xF4, // hlt
+		]);
+		let mut multiprocessor = Multiprocessor::new(state, 2);
+		for (index, processor) in multiprocessor.processors().iter_mut().enumerate() {
+			let registers = &mut processor.registers.primary_registers;
+			registers[1] = 0; // rcx: unlocked
+			registers[2] = 0x9010 + 8 * index as u64; // rdx: iterations of this processor
+			registers[3] = 1; // rbx: locked
+			registers[6] = 0x9008; // rsi: shared counter
+			registers[7] = 0x9000; // rdi: lock
+			assert_eq!(processor.tsc_aux, index as u32);
+		}
+		for _ in 0..10_000 {
+			if multiprocessor.halted() {
+				break;
+			}
+			multiprocessor.step();
+		}
+		assert!(multiprocessor.halted());
+		// Each increment is three instructions, so without the lock updates would be lost.
+		let memory = multiprocessor.processors()[1].memory();
+		assert_eq!(memory.read_u64(0x9008).unwrap(), 200);
+		assert_eq!(memory.read_u64(0x9010).unwrap(), 100);
+		assert_eq!(memory.read_u64(0x9018).unwrap(), 100);
+	}
+
+	#[test]
+	fn profile() {
+		let mut code = vec![
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0x48, 0x3D, 0x0A, 0x00, 0x00, 0x00, // cmp rax, 10
+			0x75, 0xF2, // jne 0
+			0xEB, 0x30, // jmp 0x40
+		];
+		code.resize(0x40, 0);
+		code.extend([
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0x48, 0x3D, 0x6E, 0x00, 0x00, 0x00, // cmp rax, 110
+			0x75, 0xF2, // jne 0x40
+		]);
+		let mut state = machine(&code);
+		let symbols = Symbols::parse("0000000000000000 T short\n0000000000000040 T long\n");
+		state.set_profile(Profile::new(3, 16, symbols, PathBuf::new()));
+		for _ in 0..10 * 3 + 1 + 100 * 3 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 0x4E);
+		assert_eq!(
+			state.profile.as_ref().unwrap().report(),
+			"110 samples, one every 3 retired instructions\n\
+			 0x0000000000000040        100  90.91% long\n\
+			 0x0000000000000000         10   9.09% short\n"
+		);
+	}
+
+	#[test]
+	fn cycle_costs() {
+		let mut state = machine(&[
+			0x48, 0x05, 0x01, 0x00, 0x00, 0x00, // add rax, 1
+			0x48, 0x8B, 0x19, // mov rbx, [rcx]
+			0x48, 0x3D, 0x0A, 0x00, 0x00, 0x00, // cmp rax, 10
+			0x48, 0x89, 0xC2, // mov rdx, rax
+			0x0F, 0x01, 0xF9, // rdtscp
+			0x0F, 0x31, // rdtsc
+		]);
+		state.registers.primary_registers[1] = 0x9000;
+		let costs = Costs {
+			memory_operand: 2,
+			instructions: [("mov".to_string(), 3), ("cmp".to_string(), 4)].into(),
+			..Costs::default()
+		};
+		state.set_costs(costs);
+		for _ in 0..5 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], 1 + 5 + 4 + 3);
+		assert_eq!(state.time_stamp_counter, 1 + 5 + 4 + 3 + 1);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 1 + 5 + 4 + 3 + 1);
+		assert_eq!(state.registers.primary_registers[2], 0);
+	}
+
+	#[test]
+	fn cycle_timer() {
+		let mut state = machine(&[0xEB, 0xFE]); // jmp $
+		install_handler(&mut state, 32, 0x100);
+		state.set_costs(Costs {
+			default: 3,
+			..Costs::default()
+		});
+		state.set_count_cycles(true);
+		let mut timer = Timer::new(state.devices.irq(32), true);
+		timer.out_u32(0, 6);
+		timer.out_u8(4, 1);
+		state.devices.add(&[0x40, 0x41, 0x42, 0x43, 0x44], timer);
+		// The first step clocks the timer once and the next ones 3 times for the jmp retired
+		// before, so the sixth tick is in the third step.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+	}
+
+	#[test]
+	fn syscall_swapgs() {
+		let mut code = vec![
+			0x65, 0x48, 0x8B, 0x33, // mov rsi, [gs:rbx]
+			0x0F, 0x0B, // ud2
+			0x65, 0x48, 0x8B, 0x3B, // mov rdi, [gs:rbx]
+		];
+		code.resize(0x100, 0x90);
+		code.extend([
+			0x0F, 0x01, 0xF8, // swapgs
+			0x65, 0x48, 0x8B, 0x13, // mov rdx, [gs:rbx]
+			0x0F, 0x01, 0xF8, // swapgs
+			0x48, 0x8B, 0x44, 0x24, 0x08, // mov rax, [rsp + 8]
+			0x48, 0x05, 0x02, 0x00, 0x00, 0x00, // add rax, 2
+			0x48, 0x89, 0x44, 0x24, 0x08, // mov [rsp + 8], rax
+			0xCF, // iret
+		]);
+		let mut state = machine(&code);
+		// The undefined opcode stands in for syscall, so user mode may raise it.
+		install_handler(&mut state, 0x06, 0x100);
+		state.memory.write_u8(0x8000 + 16 * 0x06 + 2, 3).unwrap();
+		state.memory.write_u64(0x9000, 0x1111).unwrap();
+		state.memory.write_u64(0x9800, 0x2222).unwrap();
+		state.gs_base = 0x9000;
+		state.kernel_gs_base = 0x9800;
+		state.cpl = 3;
+
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!((state.instruction_pointer, state.cpl()), (0x100, 0));
+		state.step_instruction();
+		assert_eq!((state.gs_base, state.kernel_gs_base), (0x9800, 0x9000));
+		for _ in 0..6 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 6);
+		assert_eq!((state.gs_base, state.kernel_gs_base), (0x9000, 0x9800));
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[6], 0x1111);
+		assert_eq!(state.registers.primary_registers[2], 0x2222);
+		assert_eq!(state.registers.primary_registers[7], 0x1111);
+	}
+
+	#[test]
+	fn double_fault() {
+		let mut state = machine(&[0x0F, 0x0B]); // ud2
+		install_handler(&mut state, 0x06, 0x100);
+		install_handler(&mut state, 0x08, 0x200);
+		// The interrupt stack is not mapped.
+		state.registers.config_registers[1] = 0x80000;
+		let failure = state.deliver(0x06, 0).unwrap_err();
+		assert!(matches!(
+			failure,
+			DeliveryFailure::StackFault {
+				address: 0x7FFF8,
+				fault: Interrupt::PageFault { .. },
+			}
+		));
+		assert_eq!(
+			failure.to_string(),
+			"writing the interrupt frame to 0x7FFF8 raised PF(2, 7FFF8)"
+		);
+		assert_eq!(state.instruction_pointer, 0);
+
+		// A missing entry double faults onto the same stack.
+		state.registers.config_registers[1] = 0x10000;
+		state.memory.write_u8(0x8000 + 16 * 0x06, 0).unwrap();
+		assert!(matches!(
+			state.deliver(0x06, 0),
+			Err(DeliveryFailure::NotPresent { vector: 0x06 })
+		));
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x200);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 0);
+	}
+
+	#[test]
+	fn malformed_idt_entry() {
+		let mut state = machine(&[0x0F, 0x0B]); // ud2
+		install_handler(&mut state, 0x06, 0x100);
+		install_handler(&mut state, 0x08, 0x200);
+		state.memory.write_u8(0x8000 + 16 * 0x06, 0xFF).unwrap();
+		let failure = state.deliver(0x06, 0).unwrap_err();
+		assert!(matches!(
+			failure,
+			DeliveryFailure::Malformed {
+				vector: 0x06,
+				offset: 0,
+				byte: 0xFF,
+			}
+		));
+		assert_eq!(
+			failure.to_string(),
+			"the idt entry for vector 0x6 has the malformed byte 0xFF at offset 0"
+		);
+
+		// Garbage in the disable interrupt flag double faults just the same.
+		state.memory.write_u8(0x8000 + 16 * 0x06, 1).unwrap();
+		state.memory.write_u8(0x8000 + 16 * 0x06 + 1, 0x02).unwrap();
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x200);
+	}
+
+	#[test]
+	fn system_group() {
+		let code = [
+			0x0F, 0x01, 0x00, // sgdt [rax]
+			0x0F, 0x01, 0x09, // sidt [rcx]
+			0x0F, 0x01, 0xE6, // smsw esi
+			0x0F, 0x01, 0xF3, // lmsw bx
+			0x0F, 0x01, 0xF8, // swapgs
+			0x0F, 0x01, 0xF9, // rdtscp
+		];
+		let mut state = machine(&code);
+		state.registers.config_registers[0] = 0x8000;
+		state.memory.write_u64(0x9000, u64::MAX).unwrap();
+		state.memory.write_u64(0x9008, u64::MAX).unwrap();
+		state.registers.primary_registers[0] = 0x9000;
+		state.registers.primary_registers[1] = 0x9010;
+		// Clearing the protection enable bit is ignored.
+		state.registers.primary_registers[3] = 0xE;
+		state.gs_base = 0x1000;
+		state.kernel_gs_base = 0x2000;
+		state.tsc_aux = 7;
+		for _ in 0..6 {
+			state.step_instruction();
+		}
+		assert_eq!(state.memory.read_u16(0x9000).unwrap(), 0);
+		assert_eq!(state.memory.read_u64(0x9002).unwrap(), 0);
+		assert_eq!(state.memory.read_u16(0x9010).unwrap(), 0x0FFF);
+		assert_eq!(state.memory.read_u64(0x9012).unwrap(), 0x8000);
+		assert_eq!(state.cr0, super::CR0_DEFAULT | 0xE);
+		assert_eq!(state.gs_base, 0x2000);
+		assert_eq!(state.kernel_gs_base, 0x1000);
+		assert_eq!(state.registers.primary_registers[6], 0x8001_0031);
+		// Five instructions retired before rdtscp.
+		assert_eq!(state.registers.primary_registers[0], 5);
+		assert_eq!(state.registers.primary_registers[1], 7);
+		assert_eq!(state.registers.primary_registers[2], 0);
+		assert_eq!(state.instruction_pointer, 18);
+	}
+
+	#[test]
+	fn packed_integer() {
+		let a = 0x8001_7FFF_0000_FFFF_0102_0304_F0E0_D0C0_u128;
+		let b = 0x8001_0001_FFFF_FFFF_0102_0403_1020_3040_u128;
+		let lanes = |value: u128, bits: u32| -> Vec<u128> {
+			(0..128 / bits)
+				.map(|i| (value >> (i * bits)) & (u128::MAX >> (128 - bits)))
+				.collect()
+		};
+		let join = |lanes: Vec<u128>, bits: u32| -> u128 {
+			let mask = u128::MAX >> (128 - bits);
+			lanes.into_iter().enumerate().fold(0, |result, (i, lane)| {
+				result | (lane & mask) << (i as u32 * bits)
+			})
+		};
+		let expected = |bits: u32, operation: fn(u128, u128) -> u128| {
+			let lanes = lanes(a, bits).into_iter().zip(lanes(b, bits));
+			join(lanes.map(|(a, b)| operation(a, b)).collect(), bits)
+		};
+		let add: fn(u128, u128) -> u128 = |a, b| a + b;
+		let sub: fn(u128, u128) -> u128 = |a, b| a.wrapping_sub(b);
+		let eq: fn(u128, u128) -> u128 = |a, b| if a == b { u128::MAX } else { 0 };
+		let cases = [
+			(0xFC, 8, add),
+			(0xFD, 16, add),
+			(0xFE, 32, add),
+			(0xD4, 64, add),
+			(0xF8, 8, sub),
+			(0xF9, 16, sub),
+			(0xFA, 32, sub),
+			(0xFB, 64, sub),
+			(0x74, 8, eq),
+			(0x75, 16, eq),
+			(0x76, 32, eq),
+		];
+		for (opcode, bits, operation) in cases {
+			let mut state = machine(&[0x66, 0x0F, opcode, 0xC1]); // op xmm0, xmm1
+			state.registers.xmm_registers[0] = a;
+			state.registers.xmm_registers[1] = b;
+			state.step_instruction();
+			assert_eq!(
+				state.registers.xmm_registers[0],
+				expected(bits, operation),
+				"{opcode:02X}"
+			);
+		}
+	}
+
+	#[test]
+	fn strlen() {
+		let mut state = machine(&[
+			0x66, 0x0F, 0xEF, 0xC0, // pxor xmm0, xmm0
+			0xF3, 0x0F, 0x6F, 0x0C, 0x07, // loop: movdqu xmm1, [rdi + rax]
+			0x66, 0x0F, 0x74, 0xC8, // pcmpeqb xmm1, xmm0
+			0x66, 0x0F, 0xD7, 0xC9, // pmovmskb ecx, xmm1
+			0x85, 0xC9, // test ecx, ecx
+			0x75, 0x08, // jnz found
+			0x48, 0x05, 0x10, 0x00, 0x00, 0x00, // add rax, 0x10
+			0xEB, 0xE7, // jmp loop
+			0xF3, 0x0F, 0xBC, 0xC9, // found: tzcnt ecx, ecx
+		]);
+		for i in 0..100 {
+			state
+				.memory
+				.write_u8(0x9000 + i, b'a' + (i % 26) as u8)
+				.unwrap();
+		}
+		state.registers.primary_registers[7] = 0x9000;
+		state.registers.xmm_registers[0] = u128::MAX;
+		for _ in 0..100 {
+			if state.instruction_pointer == 33 {
+				break;
+			}
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 33);
+		let registers = state.registers.primary_registers;
+		assert_eq!(registers[0] + registers[1], 100);
+	}
+
+	#[test]
+	fn scalar_double() {
+		let mut state = machine(&[
+			0xF2, 0x0F, 0x10, 0x07, // movsd xmm0, [rdi]
+			0xF2, 0x0F, 0x58, 0x47, 0x08, // addsd xmm0, [rdi + 8]
+			0xF2, 0x0F, 0x59, 0xC1, // mulsd xmm0, xmm1
+			0xF2, 0x0F, 0x5C, 0xC2, // subsd xmm0, xmm2
+			0xF2, 0x0F, 0x5E, 0xC3, // divsd xmm0, xmm3
+			0xF2, 0x48, 0x0F, 0x2C, 0xC0, // cvttsd2si rax, xmm0
+			0xF2, 0x48, 0x0F, 0x2A, 0xE0, // cvtsi2sd xmm4, rax
+			0xF2, 0x0F, 0x11, 0x67, 0x10, // movsd [rdi + 16], xmm4
+			0x66, 0x0F, 0x2E, 0xC1, // ucomisd xmm0, xmm1
+			0x7A, 0x05, // jp unordered
+		]);
+		state.memory.write_u64(0x9000, 1.5f64.to_bits()).unwrap();
+		state.memory.write_u64(0x9008, 2.25f64.to_bits()).unwrap();
+		state.registers.primary_registers[7] = 0x9000;
+		state.registers.xmm_registers[0] = u128::MAX;
+		state.registers.xmm_registers[1] = 1 << 64 | 3.0f64.to_bits() as u128;
+		state.registers.xmm_registers[2] = 0.5f64.to_bits() as u128;
+		state.registers.xmm_registers[3] = (-2.0f64).to_bits() as u128;
+		for _ in 0..8 {
+			state.step_instruction();
+		}
+		let expected = ((1.5f64 + 2.25) * 3.0 - 0.5) / -2.0;
+		// The load from memory clears the upper half.
+		assert_eq!(state.registers.xmm_registers[0], expected.to_bits() as u128);
+		assert_eq!(
+			state.registers.primary_registers[0],
+			expected.trunc() as i64 as u64
+		);
+		assert_eq!(
+			state.memory.read_u64(0x9010).unwrap(),
+			expected.trunc().to_bits()
+		);
+
+		// Less than sets only CF, so the parity jump is not taken.
+		state.step_instruction();
+		assert_eq!(state.rflags & (ZF | PF | CF), CF);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x2A);
+
+		// A NaN operand is unordered, which sets ZF, PF and CF.
+		state.registers.xmm_registers[1] = f64::NAN.to_bits() as u128;
+		state.instruction_pointer = 0x24;
+		state.step_instruction();
+		assert_eq!(state.rflags & (ZF | PF | CF), ZF | PF | CF);
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x2F);
+
+		// NaN and out of range values convert to the integer indefinite value.
+		state.instruction_pointer = 0x15;
+		state.registers.xmm_registers[0] = f64::NAN.to_bits() as u128;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], i64::MIN as u64);
+		assert_eq!(sse::truncate_i32(1e10), i32::MIN);
+		assert_eq!(sse::truncate_i32(-2.5), -2);
+	}
+
+	#[test]
+	fn bmi() {
+		let mut state = machine(&[
+			0xC4, 0xE2, 0x60, 0xF2, 0xC1, // andn eax, ebx, ecx
+			0xC4, 0xE2, 0x70, 0xF7, 0xC3, // bextr eax, ebx, ecx
+			0xC4, 0xE2, 0x70, 0xF5, 0xC3, // bzhi eax, ebx, ecx
+			0xC4, 0xE2, 0xF1, 0xF7, 0xC3, // shlx rax, rbx, rcx
+			0xC4, 0xE2, 0xF3, 0xF7, 0xC3, // shrx rax, rbx, rcx
+			0xC4, 0xE2, 0xF2, 0xF7, 0xC3, // sarx rax, rbx, rcx
+			0xC4, 0xE2, 0xE3, 0xF6, 0xC1, // mulx rax, rbx, rcx
+		]);
+		let sign = 1 << 63;
+		for (instruction_pointer, rbx, rcx, rax) in [
+			(0x00, 0xFF00_FF00, 0x0FF0_0FF0, 0x00F0_00F0),
+			// Start at bit 4 with a length of 8 bits.
+			(0x05, 0x1234_5678, 0x0804, 0x67),
+			(0x05, 0x1234_5678, 0xFF04, 0x0123_4567),
+			(0x0A, 0xFFFF_FFFF, 12, 0xFFF),
+			(0x0A, 0xFFFF_FFFF, 40, 0xFFFF_FFFF),
+			// The count is masked to 6 bits.
+			(0x0F, 1, 65, 2),
+			(0x14, sign, 63, 1),
+			(0x19, sign, 63, u64::MAX),
+		] {
+			state.instruction_pointer = instruction_pointer;
+			state.registers.primary_registers[0] = u64::MAX;
+			state.registers.primary_registers[1] = rcx;
+			state.registers.primary_registers[3] = rbx;
+			state.step_instruction();
+			assert_eq!(state.instruction_pointer, instruction_pointer + 5);
+			assert_eq!(state.registers.primary_registers[0], rax);
+		}
+		// The last bzhi had an index out of range.
+		assert_eq!(state.rflags & CF, CF);
+
+		state.registers.primary_registers[1] = 3;
+		state.registers.primary_registers[2] = u64::MAX;
+		state.step_instruction();
+		let product = u64::MAX as u128 * 3;
+		assert_eq!(state.registers.primary_registers[0], (product >> 64) as u64);
+		assert_eq!(state.registers.primary_registers[3], product as u64);
+		// mulx does not change the flags.
+		assert_eq!(state.rflags & CF, CF);
+	}
+
+	#[test]
+	fn carry_chains() {
+		let mut state = machine(&[
+			0x66, 0x48, 0x0F, 0x38, 0xF6, 0xC3, // adcx rax, rbx
+			0xF3, 0x48, 0x0F, 0x38, 0xF6, 0xCA, // adox rcx, rdx
+			0x66, 0x0F, 0x38, 0xF6, 0xC3, // adcx eax, ebx
+			0xF3, 0x0F, 0x38, 0xF6, 0xCA, // adox ecx, edx
+		]);
+		state.rflags = CF | SF | PF;
+		state.registers.primary_registers[0] = u64::MAX;
+		state.registers.primary_registers[1] = u64::MAX;
+		state.registers.primary_registers[2] = 1;
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0);
+		assert_eq!(state.registers.primary_registers[1], 0);
+		// Only the chain flags change, so ZF stays clear for the zero results.
+		assert_eq!(state.rflags, CF | OF | SF | PF);
+
+		state.registers.primary_registers[0] = 1;
+		state.registers.primary_registers[3] = 2;
+		state.registers.primary_registers[1] = 0x7FFF_FFFF;
+		state.registers.primary_registers[2] = 0x7FFF_FFFF;
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 4);
+		// adox sets OF to the unsigned carry, not the signed overflow.
+		assert_eq!(state.registers.primary_registers[1], 0xFFFF_FFFF);
+		assert_eq!(state.rflags, SF | PF);
+	}
+
+	#[test]
+	fn x87_control_word() {
+		let mut state = machine(&[
+			0xDB, 0xE3, // fninit
+			0xD9, 0x3F, // fnstcw [rdi]
+			0xD9, 0x6F, 0x04, // fldcw [rdi + 4]
+			0x9B, // fwait
+			0xD9, 0x7F, 0x02, // fnstcw [rdi + 2]
+		]);
+		state.fpu_control_word = 0;
+		state.registers.primary_registers[7] = 0x9000;
+		state.memory.write_u16(0x9004, 0x0E7F).unwrap();
+		for _ in 0..5 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 11);
+		assert_eq!(state.memory.read_u16(0x9000).unwrap(), 0x037F);
+		assert_eq!(state.memory.read_u16(0x9002).unwrap(), 0x0E7F);
+	}
+
+	/// Counts up on reads and records writes.
+	struct Recorder {
+		count: u8,
+		written: Rc<RefCell<Vec<u8>>>,
+	}
+
+	impl Device for Recorder {
+		fn out_u8(&mut self, _port: u16, byte: u8) {
+			self.written.borrow_mut().push(byte);
+		}
+
+		fn in_u8(&mut self, _port: u16) -> u8 {
+			self.count += 1;
+			self.count
+		}
+	}
+
+	/// Records the direction, width and value of every port access.
+	struct WidthRecorder {
+		accesses: Rc<RefCell<Vec<(&'static str, u32)>>>,
+	}
+
+	impl Device for WidthRecorder {
+		fn out_u8(&mut self, _port: u16, byte: u8) {
+			self.accesses.borrow_mut().push(("out8", byte as u32));
+		}
+
+		fn in_u8(&mut self, _port: u16) -> u8 {
+			self.accesses.borrow_mut().push(("in8", 0));
+			0x21
+		}
+
+		fn out_u16(&mut self, _port: u16, value: u16) {
+			self.accesses.borrow_mut().push(("out16", value as u32));
+		}
+
+		fn out_u32(&mut self, _port: u16, value: u32) {
+			self.accesses.borrow_mut().push(("out32", value));
+		}
+
+		fn in_u16(&mut self, _port: u16) -> u16 {
+			self.accesses.borrow_mut().push(("in16", 0));
+			0x4321
+		}
+
+		fn in_u32(&mut self, _port: u16) -> u32 {
+			self.accesses.borrow_mut().push(("in32", 0));
+			0x8765_4321
+		}
+	}
+
+	#[test]
+	fn port_widths() {
+		let code = [
+			0xE4, 0x10, // in al, 0x10
+			0x66, 0xE5, 0x10, // in ax, 0x10
+			0xE5, 0x10, // in eax, 0x10
+			0xEC, // in al, dx
+			0x66, 0xED, // in ax, dx
+			0xED, // in eax, dx
+			0xE6, 0x10, // out 0x10, al
+			0x66, 0xE7, 0x10, // out 0x10, ax
+			0xE7, 0x10, // out 0x10, eax
+			0xEE, // out dx, al
+			0x66, 0xEF, // out dx, ax
+			0xEF, // out dx, eax
+			0x6C, // insb
+			0x66, 0x6D, // insw
+			0x6D, // insd
+			0x6E, // outsb
+			0x66, 0x6F, // outsw
+			0x6F, // outsd
+		];
+		let mut state = machine(&code);
+		let accesses = Rc::new(RefCell::new(Vec::new()));
+		state.devices.add(
+			&[0x10],
+			WidthRecorder {
+				accesses: accesses.clone(),
+			},
+		);
+		state.registers.primary_registers[2] = 0x10;
+		state.registers.primary_registers[6] = 0x9000;
+		state.registers.primary_registers[7] = 0x9000;
+		for _ in 0..6 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], 0x8765_4321);
+		state.registers.primary_registers[0] = 0x1122_3344_5566_7788;
+		// The string forms write what they read, so outs sees the bytes ins stored.
+		while state.instruction_pointer < code.len() as u64 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[6], 0x9007);
+		assert_eq!(state.registers.primary_registers[7], 0x9007);
+		assert_eq!(
+			*accesses.borrow(),
+			[
+				("in8", 0),
+				("in16", 0),
+				("in32", 0),
+				("in8", 0),
+				("in16", 0),
+				("in32", 0),
+				("out8", 0x88),
+				("out16", 0x7788),
+				("out32", 0x5566_7788),
+				("out8", 0x88),
+				("out16", 0x7788),
+				("out32", 0x5566_7788),
+				("in8", 0),
+				("in16", 0),
+				("in32", 0),
+				("out8", 0x21),
+				("out16", 0x4321),
+				("out32", 0x8765_4321),
+			]
+		);
+	}
+
+	#[test]
+	fn wide_io() {
+		let mut state = machine(&[
+			0x66, 0xE7, 0x10, // out 0x10, ax
+			0xE5, 0x10, // in eax, 0x10
+			0x66, 0xED, // in ax, dx
+		]);
+		let written = Rc::new(RefCell::new(Vec::new()));
+		state.devices.add(
+			&[0x10],
+			Recorder {
+				count: 0,
+				written: written.clone(),
+			},
+		);
+		state.registers.primary_registers[0] = 0xAABB;
+		state.registers.primary_registers[2] = 0x10;
+		state.step_instruction();
+		assert_eq!(*written.borrow(), [0xBB, 0xAA]);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x0403_0201);
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x0403_0605);
+	}
+
+	#[test]
+	fn timer_vector() {
+		let config: Config = toml::from_str("memory = []\ndevice = []").unwrap();
+		let mut state = machine(&[0xEB, 0xFE]); // jmp $
+		install_handler(&mut state, 32, 0x100);
+		let mut timer = Timer::new(state.devices.irq(config.irq_vector(0).unwrap()), true);
+		timer.out_u32(0, 1);
+		timer.out_u8(4, 1);
+		state.devices.add(&[0x40, 0x41, 0x42, 0x43, 0x44], timer);
+		// The timer fires on the first tick, which precedes the first instruction.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+	}
+
+	#[test]
+	fn deterministic_hlt() {
+		let mut state = machine(&[
+			0xB0, 0x03, // mov al, 3
+			0xE6, 0x40, // out 0x40, al
+			0xB0, 0x01, // mov al, 1
+			0xE6, 0x44, // out 0x44, al
+			0xF4, // hlt
+			0xEB, 0xFE, // jmp $
+		]);
+		install_handler(&mut state, 32, 0x100);
+		state.set_deterministic(true);
+		let timer = Timer::new(state.devices.irq(32), true);
+		state.devices.add(&[0x40, 0x41, 0x42, 0x43, 0x44], timer);
+		// Each step of the halted processor returns after clocking the timer once.
+		for _ in 0..6 {
+			state.step_instruction();
+		}
+		assert!(state.halted);
+		assert_eq!(state.instruction_pointer, 9);
+		// The third tick after the timer was started raises its irq, which ends the hlt.
+		state.step_instruction();
+		assert!(!state.halted);
+		assert_eq!(state.instruction_pointer, 0x100);
+	}
+
+	#[test]
+	fn spurious_irq() {
+		let mut state = machine(&[
+			0xB0, 0x01, // mov al, 1
+			0xE6, 0x72, // out 0x72, al
+			0xEB, 0xFE, // jmp $
+		]);
+		install_handler(&mut state, 37, 0x100);
+		let injector = FaultInjector::new(Some(state.devices.irq(37)), None);
+		state.devices.inject_faults(injector.armed());
+		state.devices.add(&[0x70, 0x71, 0x72], injector);
+		state.step_instruction();
+		state.step_instruction();
+		// The irq arrives at the next instruction boundary.
+		state.step_instruction();
+		assert_eq!(state.instruction_pointer, 0x100);
+		assert_eq!(state.memory.read_u64(0x10000 - 24).unwrap(), 4);
+	}
+
+	#[test]
+	fn nested_interrupts() {
+		let mut code = vec![
+			0xB0, 0x30, // mov al, 0x30
+			0xE6, 0x60, // out 0x60, al
+			0xEB, 0xFE, // jmp $
+		];
+		code.resize(0x100, 0);
+		code.extend([
+			0xB0, 0x31, // mov al, 0x31
+			0xE6, 0x60, // out 0x60, al
+			0xE4, 0x62, // in al, 0x62
+			0xFB, // sti
+			0xE4, 0x62, // in al, 0x62
+			0xFA, // cli
+			0xCF, // iret
+		]);
+		code.resize(0x200, 0);
+		code.extend([
+			0xE4, 0x62, // in al, 0x62
+			0xCF, // iret
+		]);
+		code.resize(0x300, 0);
+		code.extend([
+			0xE4, 0x62, // in al, 0x62
+			0xCF, // iret
+		]);
+		let mut state = machine(&code);
+		install_handler(&mut state, 0x30, 0x100);
+		install_handler(&mut state, 0x31, 0x200);
+		install_handler(&mut state, 0x02, 0x300);
+		// The first handler runs with interrupts disabled.
+		state.memory.write_u8(0x8000 + 16 * 0x30 + 1, 1).unwrap();
+		let interrupts = Rc::new(RefCell::new(InjectedInterrupts::default()));
+		let injector = InterruptInjector::new(interrupts.clone());
+		state.devices.add(&[0x60, 0x61, 0x62], injector);
+		state.set_injected_interrupts(interrupts.clone());
+		let step = |state: &mut ProcessorState| {
+			state.step_instruction();
+			(state.instruction_pointer, state.rflags & IF != 0)
+		};
+
+		// The guest raises an interrupt against its own handler, taken at the next boundary.
+		step(&mut state);
+		assert_eq!(step(&mut state), (4, true));
+		assert_eq!(step(&mut state), (0x100, false));
+		// An interrupt raised while IF is clear waits, also for the instruction after sti.
+		step(&mut state);
+		assert_eq!(step(&mut state), (0x104, false));
+		assert_eq!(step(&mut state), (0x106, false));
+		assert_eq!(state.registers.primary_registers[0] & 0xFF, 1);
+		assert_eq!(step(&mut state), (0x107, true));
+		assert_eq!(step(&mut state), (0x109, true));
+		// The nested interrupt returns to the first handler, which returns with IF restored.
+		assert_eq!(step(&mut state), (0x200, true));
+		assert_eq!(
+			state.memory.read_u64(0x10000 - 32 - 128 - 24).unwrap(),
+			0x109
+		);
+		step(&mut state);
+		assert_eq!(state.registers.primary_registers[0] & 0xFF, 2);
+		assert_eq!(step(&mut state), (0x109, true));
+		assert_eq!(step(&mut state), (0x10A, false));
+		assert_eq!(step(&mut state), (4, true));
+
+		// The NMI is taken while IF is clear, unlike the queued interrupt.
+		state.rflags &= !IF;
+		interrupts.borrow_mut().raise(0x31);
+		interrupts.borrow_mut().raise_nmi();
+		assert_eq!(step(&mut state), (0x300, false));
+		assert_eq!(interrupts.borrow().delivered(), 3);
+		assert!(interrupts.borrow().pending(true));
+		// Another NMI waits for the iret of the NMI handler.
+		interrupts.borrow_mut().raise_nmi();
+		assert!(!interrupts.borrow().pending(false));
+		assert_eq!(step(&mut state), (0x302, false));
+		assert_eq!(step(&mut state), (4, false));
+		assert_eq!(step(&mut state), (0x300, false));
+		assert_eq!(interrupts.borrow().delivered(), 4);
+	}
+
+	#[test]
+	fn hlt_with_if_clear() {
+		let mut state = machine(&[0xF4]); // hlt
+		install_handler(&mut state, 0x02, 0x300);
+		let interrupts = Rc::new(RefCell::new(InjectedInterrupts::default()));
+		state.set_injected_interrupts(interrupts.clone());
+		state.rflags &= !IF;
+		state.step_instruction();
+
+		// A maskable interrupt does not wake the processor while IF is clear.
+		interrupts.borrow_mut().raise(0x31);
+		state.ipis.post(0x32);
+		assert!(state.waiting());
+		state.step_instruction();
+		assert!(state.halted);
+		assert_eq!(state.instruction_pointer, 1);
+
+		// The NMI does.
+		interrupts.borrow_mut().raise_nmi();
+		assert!(!state.waiting());
+		state.step_instruction();
+		assert!(!state.halted);
+		assert_eq!(state.instruction_pointer, 0x300);
+	}
+
+	#[test]
+	fn stack_overflow() {
+		let mut pmu = PhysicalMemoryManagementUnit::new();
+		pmu.add(0, 0x10000, Permissions::default(), || {
+			ConventionalMemory::create(0x10000)
+		});
+		let stack = pmu.add_stack(0x20000, 0x1008).unwrap();
+		assert_eq!(stack.top, 0x21000);
+		assert_eq!(stack.guard, 0x1F000..0x20000);
+		assert!(pmu.add_stack(0x21800, 0x1000).is_err());
+		assert!(pmu.add_stack(0x800, 0x1000).is_err());
+		pmu.add_identity_page_tables(0x100000, 4, None).unwrap();
+		// push rax; jmp $-1
+		for (i, byte) in [0x50, 0xEB, 0xFD].into_iter().enumerate() {
+			pmu.write_u8(0x1000 + i as u64, byte);
+		}
+		let mut memory = MemoryManagementUnit::new(pmu);
+		memory.swi4(0x100000);
+		let mut state = ProcessorState::new(memory, PortDevices::new());
+		state.set_reset_vector(0x1000);
+		state.set_stack(stack);
+		install_handler(&mut state, 14, 0x2000);
+		assert_eq!(state.registers.primary_registers[4], 0x21000);
+		for _ in 0..2 * 0x1000 / 8 + 1 {
+			state.step_instruction();
+		}
+		assert_eq!(state.instruction_pointer, 0x2000);
+		assert_eq!(state.registers.config_registers[2], 0x1FFF8);
+		let record = state.interrupts.iter().last().unwrap();
+		assert_eq!(state.hint(record.interrupt), ", likely stack overflow");
+		let other = Interrupt::PageFault {
+			error_code: 0b10,
+			cr2: 0x20000,
+		};
+		assert_eq!(state.hint(other), "");
+		state.reset(ResetMemory::Keep);
+		assert_eq!(state.registers.primary_registers[4], 0x21000);
+	}
+
+	#[test]
+	fn memory_hotplug() {
+		let mut state = machine(&[
+			0x89, 0x0B, // mov [rbx], ecx
+			0x8B, 0x13, // mov edx, [rbx]
+			0xB8, 0x00, 0x00, 0x10, 0x00, // mov eax, 0x100000
+			0xE7, 0x40, // out 0x40, eax
+			0xB8, 0x00, 0x00, 0x00, 0x00, // mov eax, 0
+			0xE7, 0x41, // out 0x41, eax
+			0xB8, 0x00, 0x01, 0x00, 0x00, // mov eax, 0x100
+			0xE7, 0x42, // out 0x42, eax
+			0xE4, 0x42, // in al, 0x42
+			0x89, 0x0B, // mov [rbx], ecx
+			0x8B, 0x13, // mov edx, [rbx]
+			0xE7, 0x42, // out 0x42, eax
+			0xE4, 0x42, // in al, 0x42
+		]);
+		state
+			.devices
+			.add(&[0x40, 0x41, 0x42], MemoryController::new());
+		// Virtual 0x20000 maps the first page after the RAM of the machine.
+		state
+			.memory
+			.write_physical(0x3100, &0x10_0001u64.to_le_bytes());
+		state.registers.primary_registers[1] = 0x1234_5678;
+		state.registers.primary_registers[3] = 0x20000;
+
+		// Without memory the write is lost and the read returns ones.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[2], 0xFFFF_FFFF);
+		for _ in 0..7 {
+			state.step_instruction();
+		}
+		assert_eq!(state.registers.primary_registers[0], 0x101);
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[2], 0x1234_5678);
+
+		// Adding memory at the same base again overlaps it.
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[0], 0x100);
+	}
+
+	#[test]
+	fn string_io() {
+		let mut state = machine(&[
+			0xF3, 0x6C, // rep insb
+			0xFD, // std
+			0xF3, 0x6E, // rep outsb
+		]);
+		let written = Rc::new(RefCell::new(Vec::new()));
+		state.devices.add(
+			&[0x10],
+			Recorder {
+				count: 0,
+				written: written.clone(),
+			},
+		);
+		state.registers.primary_registers[1] = 16;
+		state.registers.primary_registers[2] = 0x10;
+		state.registers.primary_registers[7] = 0x9000;
+		state.step_instruction();
+		for i in 0..16 {
+			assert_eq!(state.memory.read_u8(0x9000 + i).unwrap(), i as u8 + 1);
+		}
+		assert_eq!(state.registers.primary_registers[1], 0);
+		assert_eq!(state.registers.primary_registers[7], 0x9010);
+
+		// With DF set the source moves downwards.
+		state.registers.primary_registers[1] = 4;
+		state.registers.primary_registers[6] = 0x900F;
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(*written.borrow(), [16, 15, 14, 13]);
+		assert_eq!(state.registers.primary_registers[6], 0x900B);
+	}
+
+	#[test]
+	fn string_compare() {
+		let mut state = machine(&[
+			0xF3, 0xA6, // repe cmpsb
+			0xF3, 0xA6, // repe cmpsb
+			0xF3, 0xA6, // repe cmpsb
+			0xF2, 0xAE, // repne scasb
+			0xFD, // std
+			0x66, 0xF2, 0xAF, // repne scasw
+		]);
+		let write = |state: &mut ProcessorState, address, bytes: &[u8]| {
+			for (i, byte) in bytes.iter().enumerate() {
+				state.memory.write_u8(address + i as u64, *byte).unwrap();
+			}
+		};
+		write(&mut state, 0x9000, b"hello world");
+		write(&mut state, 0x9100, b"hello there");
+
+		// repe stops after the first pair which differs, with the flags of that comparison.
+		state.registers.primary_registers[1] = 11;
+		state.registers.primary_registers[6] = 0x9000;
+		state.registers.primary_registers[7] = 0x9100;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[1], 4);
+		assert_eq!(state.registers.primary_registers[6], 0x9007);
+		assert_eq!(state.registers.primary_registers[7], 0x9107);
+		assert_eq!(state.rflags & (ZF | CF | SF), 0);
+
+		// Equal strings run until rcx is zero.
+		state.registers.primary_registers[1] = 5;
+		state.registers.primary_registers[6] = 0x9000;
+		state.registers.primary_registers[7] = 0x9100;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[1], 0);
+		assert_eq!(state.registers.primary_registers[6], 0x9005);
+		assert_ne!(state.rflags & ZF, 0);
+
+		// Without iterations the flags are unchanged.
+		state.rflags |= CF;
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[6], 0x9005);
+		assert_eq!(state.rflags & (ZF | CF), ZF | CF);
+
+		// strlen: repne scasb with rcx = -1 stops after the terminator.
+		write(&mut state, 0x9200, b"abc\0");
+		state.registers.primary_registers[0] = 0x1200;
+		state.registers.primary_registers[1] = u64::MAX;
+		state.registers.primary_registers[7] = 0x9200;
+		state.step_instruction();
+		assert_eq!(!state.registers.primary_registers[1] - 1, 3);
+		assert_eq!(state.registers.primary_registers[7], 0x9204);
+		assert_ne!(state.rflags & ZF, 0);
+
+		// Searching downwards for a word which is not there ends with rcx.
+		state.registers.primary_registers[0] = 0xFFFF;
+		state.registers.primary_registers[1] = 3;
+		state.step_instruction();
+		state.step_instruction();
+		assert_eq!(state.registers.primary_registers[1], 0);
+		assert_eq!(state.registers.primary_registers[7], 0x91FE);
+		assert_eq!(state.rflags & ZF, 0);
+	}
 }
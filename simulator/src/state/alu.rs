@@ -0,0 +1,124 @@
+use crate::{
+	instruction::{RM, Reg},
+	interupt::Interrupt,
+	state::{AF, CF, OF, PF, ProcessorState, SF, ZF},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AluOperation {
+	Add,
+	Or,
+	Adc,
+	Sbb,
+	And,
+	Sub,
+	Xor,
+	Cmp,
+	/// And without writing the result back.
+	Test,
+}
+
+macro_rules! alu {
+	($size:ident) => {
+		/// Computes `left operation right` and sets the arithmetic flags accordingly.
+		pub(super) fn ${concat(alu_, $size)}(
+			&mut self,
+			operation: AluOperation,
+			left: $size,
+			right: $size,
+		) -> $size {
+			let carry = (self.rflags & CF) as u128;
+			let (result, carry_out, overflow) = match operation {
+				AluOperation::Add | AluOperation::Adc => {
+					let carry = if operation == AluOperation::Adc { carry } else { 0 };
+					let full = left as u128 + right as u128 + carry;
+					let result = full as $size;
+					let overflow = (left ^ result) & (right ^ result);
+					(result, full >> $size::BITS != 0, overflow)
+				}
+				AluOperation::Sub | AluOperation::Sbb | AluOperation::Cmp => {
+					let carry = if operation == AluOperation::Sbb { carry } else { 0 };
+					let subtrahend = right as u128 + carry;
+					let result = (left as u128).wrapping_sub(subtrahend) as $size;
+					let overflow = (left ^ right) & (left ^ result);
+					(result, (left as u128) < subtrahend, overflow)
+				}
+				AluOperation::And | AluOperation::Test => (left & right, false, 0),
+				AluOperation::Or => (left | right, false, 0),
+				AluOperation::Xor => (left ^ right, false, 0),
+			};
+			let sign = 1 << ($size::BITS - 1);
+			let logical = matches!(
+				operation,
+				AluOperation::And | AluOperation::Or | AluOperation::Xor | AluOperation::Test
+			);
+			self.set_flag(CF, carry_out);
+			self.set_flag(PF, (result as u8).count_ones() % 2 == 0);
+			self.set_flag(AF, !logical && (left ^ right ^ result) & 0x10 != 0);
+			self.set_flag(ZF, result == 0);
+			self.set_flag(SF, result & sign != 0);
+			self.set_flag(OF, overflow & sign != 0);
+			result
+		}
+
+		/// Applies `operation` to an operand and writes the result back, except for compare and
+		/// test. When writing the result faults the flags are restored, so the instruction can
+		/// be restarted.
+		pub(super) fn ${concat(alu_rm_, $size)}(
+			&mut self,
+			operation: AluOperation,
+			rm: RM,
+			value: $size,
+		) -> Result<(), Interrupt> {
+			let left = self.${concat(read_rm_, $size)}(rm)?;
+			let rflags = self.rflags;
+			let result = self.${concat(alu_, $size)}(operation, left, value);
+			if !matches!(operation, AluOperation::Cmp | AluOperation::Test)
+				&& let Err(fault) = self.${concat(write_rm_, $size)}(rm, result)
+			{
+				self.rflags = rflags;
+				return Err(fault);
+			}
+			Ok(())
+		}
+	};
+}
+
+macro_rules! carry_chain {
+	($size:ident) => {
+		/// Adds with the carry in `flag` and sets only `flag` to the carry out, which is CF for
+		/// adcx and OF for adox.
+		pub(super) fn ${concat(carry_chain_, $size)}(
+			&mut self,
+			flag: u64,
+			destination: Reg,
+			source: RM,
+		) -> Result<(), Interrupt> {
+			let right = self.${concat(read_rm_, $size)}(source)?;
+			let left = self.${concat(read_reg_, $size)}(destination);
+			let carry = self.rflags & flag != 0;
+			let (result, carry_out) = left.carrying_add(right, carry);
+			self.set_flag(flag, carry_out);
+			self.${concat(write_reg_, $size)}(destination, result);
+			Ok(())
+		}
+	};
+}
+
+impl ProcessorState {
+	pub(super) fn set_flag(&mut self, flag: u64, value: bool) {
+		if value {
+			self.rflags |= flag;
+		} else {
+			self.rflags &= !flag;
+		}
+	}
+
+	alu!(u8);
+	alu!(u16);
+	alu!(u32);
+	alu!(u64);
+
+	carry_chain!(u32);
+	carry_chain!(u64);
+}
@@ -0,0 +1,45 @@
+use std::{cell::RefCell, collections::BTreeSet, rc::Rc};
+
+use crate::state::{IF, ProcessorState};
+
+/// The x2APIC interrupt command register. Writing it sends the vector in bits 0 to 7 to the
+/// processor numbered by bits 32 to 63. Only fixed delivery to a single processor is
+/// modelled, so the other fields are ignored.
+pub(super) const X2APIC_ICR: u32 = 0x830;
+
+/// Interrupts sent to a processor by the processors, which are delivered from the highest
+/// vector.
+#[derive(Clone, Default)]
+pub(super) struct InterruptQueue(Rc<RefCell<BTreeSet<u8>>>);
+
+impl InterruptQueue {
+	pub(super) fn post(&self, vector: u8) {
+		self.0.borrow_mut().insert(vector);
+	}
+
+	pub(super) fn take(&self) -> Option<u8> {
+		self.0.borrow_mut().pop_last()
+	}
+
+	pub(super) fn is_empty(&self) -> bool {
+		self.0.borrow().is_empty()
+	}
+}
+
+impl ProcessorState {
+	/// Sends an inter-processor interrupt. Like on a real local APIC, an interrupt for a
+	/// processor which does not exist is dropped.
+	pub(super) fn send_ipi(&mut self, command: u64) {
+		let vector = command as u8;
+		let destination = (command >> 32) as usize;
+		if let Some(queue) = self.processors.get(destination) {
+			queue.post(vector);
+		}
+	}
+
+	/// Waits in hlt without an interrupt to wake it. While IF is clear only the NMI does.
+	pub(super) fn waiting(&self) -> bool {
+		let maskable = self.rflags & IF != 0;
+		self.halted && !(maskable && !self.ipis.is_empty()) && !self.injected_pending(maskable)
+	}
+}
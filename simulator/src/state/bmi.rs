@@ -0,0 +1,122 @@
+use crate::{
+	instruction::{RM, Reg},
+	interupt::Interrupt,
+	state::{CF, D, OF, ProcessorState, SF, ZF},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShiftOperation {
+	Shl,
+	Shr,
+	Sar,
+}
+
+macro_rules! bmi {
+	($size:ident, $signed:ident, $double:ident) => {
+		/// Sets ZF and SF from the result and clears CF and OF like the logical instructions.
+		fn ${concat(logical_flags_, $size)}(&mut self, result: $size) {
+			self.set_flag(ZF, result == 0);
+			self.set_flag(SF, result >> ($size::BITS - 1) != 0);
+			self.set_flag(CF, false);
+			self.set_flag(OF, false);
+		}
+
+		/// Computes `!left & right`.
+		pub(super) fn ${concat(andn_, $size)}(
+			&mut self,
+			destination: Reg,
+			left: Reg,
+			right: RM,
+		) -> Result<(), Interrupt> {
+			let left = self.${concat(read_reg_, $size)}(left);
+			let right = self.${concat(read_rm_, $size)}(right)?;
+			let result = !left & right;
+			self.${concat(logical_flags_, $size)}(result);
+			self.${concat(write_reg_, $size)}(destination, result);
+			Ok(())
+		}
+
+		/// Extracts the bit field starting at bit 0 to 7 of `control` with the length in bits 8
+		/// to 15.
+		pub(super) fn ${concat(bextr_, $size)}(
+			&mut self,
+			destination: Reg,
+			source: RM,
+			control: Reg,
+		) -> Result<(), Interrupt> {
+			let source = self.${concat(read_rm_, $size)}(source)?;
+			let control = self.${concat(read_reg_, $size)}(control);
+			let start = control as u8 as u32;
+			let length = (control >> 8) as u8 as u32;
+			let field = source.checked_shr(start).unwrap_or(0);
+			let result = if length < $size::BITS {
+				field & ((1 << length) - 1)
+			} else {
+				field
+			};
+			self.${concat(logical_flags_, $size)}(result);
+			self.${concat(write_reg_, $size)}(destination, result);
+			Ok(())
+		}
+
+		/// Clears the bits from the index in bits 0 to 7 of `index` upwards. CF is set when the
+		/// index is out of range.
+		pub(super) fn ${concat(bzhi_, $size)}(
+			&mut self,
+			destination: Reg,
+			source: RM,
+			index: Reg,
+		) -> Result<(), Interrupt> {
+			let source = self.${concat(read_rm_, $size)}(source)?;
+			let index = self.${concat(read_reg_, $size)}(index) as u8 as u32;
+			let result = if index < $size::BITS {
+				source & ((1 << index) - 1)
+			} else {
+				source
+			};
+			self.${concat(logical_flags_, $size)}(result);
+			self.set_flag(CF, index >= $size::BITS);
+			self.${concat(write_reg_, $size)}(destination, result);
+			Ok(())
+		}
+
+		/// Shifts without changing the flags. The count is masked like the legacy shifts.
+		pub(super) fn ${concat(shift_, $size)}(
+			&mut self,
+			operation: ShiftOperation,
+			destination: Reg,
+			source: RM,
+			count: Reg,
+		) -> Result<(), Interrupt> {
+			let source = self.${concat(read_rm_, $size)}(source)?;
+			let count = self.${concat(read_reg_, $size)}(count) as u32 % $size::BITS;
+			let result = match operation {
+				ShiftOperation::Shl => source << count,
+				ShiftOperation::Shr => source >> count,
+				ShiftOperation::Sar => (source as $signed >> count) as $size,
+			};
+			self.${concat(write_reg_, $size)}(destination, result);
+			Ok(())
+		}
+
+		/// Multiplies rdx by the source without changing the flags. The low half is written
+		/// before the high half, so the high half is kept if both are the same register.
+		pub(super) fn ${concat(mulx_, $size)}(
+			&mut self,
+			high: Reg,
+			low: Reg,
+			source: RM,
+		) -> Result<(), Interrupt> {
+			let source = self.${concat(read_rm_, $size)}(source)?;
+			let product = self.${concat(read_reg_, $size)}(D) as $double * source as $double;
+			self.${concat(write_reg_, $size)}(low, product as $size);
+			self.${concat(write_reg_, $size)}(high, (product >> $size::BITS) as $size);
+			Ok(())
+		}
+	};
+}
+
+impl ProcessorState {
+	bmi!(u32, i32, u64);
+	bmi!(u64, i64, u128);
+}
@@ -0,0 +1,60 @@
+use std::path::PathBuf;
+
+use crate::{error::info, state::ProcessorState};
+
+/// Writes a full snapshot every `interval` retired instructions, rotating through `count`
+/// files named after `path` with the number of the checkpoint appended.
+pub(super) struct Checkpoints {
+	interval: u64,
+	count: u64,
+	path: PathBuf,
+	/// Retired instructions until the next checkpoint.
+	remaining: u64,
+	/// The number of checkpoints written.
+	written: u64,
+}
+
+impl Checkpoints {
+	/// The file of the `index`th checkpoint.
+	fn file(&self, index: u64) -> PathBuf {
+		let mut file = self.path.clone().into_os_string();
+		file.push(format!(".{}", index % self.count));
+		file.into()
+	}
+}
+
+impl ProcessorState {
+	/// Saves the state to a rotating set of `count` files every `interval` retired
+	/// instructions, so a long run can be resumed from a recent checkpoint with `restore`.
+	/// Devices are not saved, and checkpoints do not change what `snapshot_delta` returns.
+	pub fn set_checkpoints(&mut self, interval: u64, count: u64, path: PathBuf) {
+		self.checkpoints = Some(Checkpoints {
+			interval,
+			count,
+			path,
+			remaining: interval,
+			written: 0,
+		});
+	}
+
+	/// Counts a retired instruction and writes a checkpoint when the interval is reached.
+	pub(super) fn checkpoint(&mut self) {
+		let Some(checkpoints) = &mut self.checkpoints else {
+			return;
+		};
+		checkpoints.remaining -= 1;
+		if checkpoints.remaining != 0 {
+			return;
+		}
+		checkpoints.remaining = checkpoints.interval;
+		let file = checkpoints.file(checkpoints.written);
+		checkpoints.written += 1;
+		let data = self.snapshot_untracked().to_bytes();
+		if let Err(err) = std::fs::write(&file, data) {
+			info(&format!(
+				"Could not write checkpoint {}: {err}",
+				file.display()
+			));
+		}
+	}
+}
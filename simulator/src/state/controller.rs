@@ -0,0 +1,142 @@
+use std::{
+	sync::mpsc::{Receiver, Sender, TryRecvError, channel},
+	thread::{self, JoinHandle},
+};
+
+use crate::state::ProcessorState;
+
+enum Command {
+	Pause(Sender<()>),
+	Resume,
+	Step(u64, Sender<()>),
+	InjectIrq(u8),
+	ReadRegisters(Sender<Vec<(&'static str, u128)>>),
+	Report(Sender<()>),
+	EndEvents(&'static str, Sender<()>),
+	Shutdown,
+}
+
+/// Runs a processor on a thread of its own, so the caller is not blocked by the guest. The
+/// commands are handled between instructions, and while the processor waits in hlt the irqs
+/// of the devices and the commands wake the thread.
+pub struct Controller {
+	commands: Sender<Command>,
+	thread: JoinHandle<()>,
+}
+
+impl Controller {
+	/// Builds the processor on a new thread, where it waits paused for commands. The processor
+	/// is built there because the devices and the memory can not be sent between threads.
+	pub(crate) fn spawn(build: impl FnOnce() -> ProcessorState + Send + 'static) -> Controller {
+		let (commands, receiver) = channel();
+		let thread = thread::spawn(move || run(build(), receiver));
+		Controller { commands, thread }
+	}
+
+	/// Returns once the guest has stopped.
+	pub fn pause(&self) {
+		let (sender, receiver) = channel();
+		self.send(Command::Pause(sender));
+		let _ = receiver.recv();
+	}
+
+	pub fn resume(&self) {
+		self.send(Command::Resume);
+	}
+
+	/// Steps `count` instructions while paused and returns when they are done.
+	pub fn step(&self, count: u64) {
+		let (sender, receiver) = channel();
+		self.send(Command::Step(count, sender));
+		let _ = receiver.recv();
+	}
+
+	/// Raises an interrupt request like the irq of a device, which is delivered before the next
+	/// instruction while IF is set.
+	pub fn inject_irq(&self, vector: u8) {
+		self.send(Command::InjectIrq(vector));
+	}
+
+	/// The general purpose registers, the xmm registers, rip and rflags by name.
+	pub fn read_registers(&self) -> Vec<(&'static str, u128)> {
+		let (sender, receiver) = channel();
+		self.send(Command::ReadRegisters(sender));
+		receiver.recv().unwrap_or_default()
+	}
+
+	/// Prints the state of the processor like when the simulation is interrupted.
+	pub fn report(&self) {
+		let (sender, receiver) = channel();
+		self.send(Command::Report(sender));
+		let _ = receiver.recv();
+	}
+
+	/// Emits the last event with the reason the run ended and waits until the events are
+	/// written.
+	pub fn end_events(&self, reason: &'static str) {
+		let (sender, receiver) = channel();
+		self.send(Command::EndEvents(reason, sender));
+		let _ = receiver.recv();
+	}
+
+	/// Stops the guest and waits for the thread to finish.
+	pub fn shutdown(self) {
+		self.send(Command::Shutdown);
+		let _ = self.thread.join();
+	}
+
+	/// A processor waiting in hlt parks the thread, so it is woken to see the command.
+	fn send(&self, command: Command) {
+		let _ = self.commands.send(command);
+		self.thread.thread().unpark();
+	}
+}
+
+fn run(mut state: ProcessorState, commands: Receiver<Command>) {
+	let mut running = false;
+	loop {
+		let command = if running {
+			match commands.try_recv() {
+				Ok(command) => command,
+				Err(TryRecvError::Empty) => {
+					state.step_instruction();
+					// A halted processor waits until an interrupt or a command wakes it.
+					state.wait_for_interrupt();
+					continue;
+				}
+				Err(TryRecvError::Disconnected) => return,
+			}
+		} else {
+			match commands.recv() {
+				Ok(command) => command,
+				Err(_) => return,
+			}
+		};
+		match command {
+			Command::Pause(done) => {
+				running = false;
+				let _ = done.send(());
+			}
+			Command::Resume => running = true,
+			Command::Step(count, done) => {
+				for _ in 0..count {
+					state.step_instruction();
+				}
+				let _ = done.send(());
+			}
+			Command::InjectIrq(vector) => state.devices.irq(vector).raise(),
+			Command::ReadRegisters(reply) => {
+				let _ = reply.send(state.register_values());
+			}
+			Command::Report(done) => {
+				state.report();
+				let _ = done.send(());
+			}
+			Command::EndEvents(reason, done) => {
+				state.end_events(reason);
+				let _ = done.send(());
+			}
+			Command::Shutdown => return,
+		}
+	}
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+use crate::{error::fatal, instruction::Instruction, state::ProcessorState};
+
+/// The number of cycles each retired instruction adds to the time stamp counter, giving a
+/// stable measure of the work done by the guest independent of the host.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct Costs {
+	/// The cost of an instruction without an entry in `instructions`.
+	pub default: u64,
+
+	/// Added for every instruction with a memory operand. String instructions address memory
+	/// through registers and are not counted.
+	pub memory_operand: u64,
+
+	/// Costs by mnemonic in NASM syntax, like `div = 20` or `jne = 2`.
+	pub instructions: HashMap<String, u64>,
+}
+
+impl Default for Costs {
+	fn default() -> Costs {
+		Costs {
+			default: 1,
+			memory_operand: 0,
+			instructions: HashMap::new(),
+		}
+	}
+}
+
+impl Costs {
+	pub fn cost(&self, instruction: &Instruction, memory_operand: bool) -> u64 {
+		// Formatting is only needed when a mnemonic has a cost of its own.
+		let base = if self.instructions.is_empty() {
+			self.default
+		} else {
+			let text = instruction.to_string();
+			let mnemonic = text.split_whitespace().next().unwrap_or_default();
+			self.instructions
+				.get(mnemonic)
+				.copied()
+				.unwrap_or(self.default)
+		};
+		if memory_operand {
+			base + self.memory_operand
+		} else {
+			base
+		}
+	}
+}
+
+impl ProcessorState {
+	/// Terminates with a report once the retired instructions, or the cycles when counting
+	/// cycles, reach the instruction limit.
+	pub(super) fn stop_at_limit(&self) {
+		let Some(limit) = self.max_instructions else {
+			return;
+		};
+		let (count, unit) = if self.count_cycles {
+			(self.time_stamp_counter, "cycles")
+		} else {
+			(self.retired_count, "instructions")
+		};
+		if count < limit {
+			return;
+		}
+		self.end_events("max-instructions");
+		self.report();
+		fatal(&format!("Stopped after {limit} {unit}"))
+	}
+}
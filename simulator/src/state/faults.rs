@@ -0,0 +1,137 @@
+use std::fmt::{Display, Write};
+
+use crate::{history::History, state::ProcessorState};
+
+/// Number of retired instruction pointers leading up to a page fault which are recorded with
+/// it.
+const BACKTRACE: usize = 4;
+
+/// What became of a page fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageFaultOutcome {
+	/// Delivered to the guest handler, and the faulting instruction has not retired since.
+	Handled,
+
+	/// Delivered to the guest handler, after which the faulting instruction retired. This is
+	/// the expected outcome for demand paging.
+	Retried,
+
+	/// The page fault could not be delivered and was escalated to a double fault.
+	Escalated,
+}
+
+impl Display for PageFaultOutcome {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.write_str(match self {
+			PageFaultOutcome::Handled => "handled",
+			PageFaultOutcome::Retried => "handled and retried",
+			PageFaultOutcome::Escalated => "escalated to double fault",
+		})
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct PageFaultRecord {
+	pub instruction_pointer: u64,
+	pub cr2: u64,
+	pub error_code: u32,
+	pub outcome: PageFaultOutcome,
+	/// The instruction pointers of the last retired instructions, newest first.
+	pub backtrace: Vec<u64>,
+}
+
+/// A chronological log of the most recent page faults.
+pub(super) struct PageFaultLog {
+	records: History<PageFaultRecord>,
+}
+
+impl ProcessorState {
+	/// Records the most recent `capacity` page faults, with whether the guest resolved them.
+	pub fn set_page_fault_log(&mut self, capacity: usize) {
+		self.page_faults = Some(PageFaultLog {
+			records: History::new(capacity),
+		});
+	}
+
+	/// The recorded page faults from oldest to newest, if the log is enabled.
+	pub fn page_faults(&self) -> Option<impl Iterator<Item = &PageFaultRecord>> {
+		self.page_faults.as_ref().map(|log| log.records.iter())
+	}
+
+	/// The page fault log in a human readable format, one fault per line.
+	pub fn describe_page_faults(&self) -> String {
+		let Some(faults) = self.page_faults() else {
+			return "The page fault log is disabled\n".to_string();
+		};
+		let mut out = String::new();
+		for record in faults {
+			let backtrace = record
+				.backtrace
+				.iter()
+				.map(|address| format!("0x{address:X}"))
+				.collect::<Vec<_>>()
+				.join(" <- ");
+			writeln!(
+				out,
+				"0x{:016X}: cr2 0x{:016X} error 0x{:02X} {} (after {})",
+				record.instruction_pointer,
+				record.cr2,
+				record.error_code,
+				record.outcome,
+				if backtrace.is_empty() {
+					"nothing"
+				} else {
+					&backtrace
+				},
+			)
+			.unwrap();
+		}
+		out
+	}
+
+	/// Logs a page fault raised at the current instruction pointer, assuming it is handled
+	/// until delivery fails.
+	pub(super) fn record_page_fault(&mut self, cr2: u64, error_code: u32) {
+		let Some(log) = &mut self.page_faults else {
+			return;
+		};
+		let backtrace = self
+			.retired
+			.iter()
+			.rev()
+			.take(BACKTRACE)
+			.map(|(instruction_pointer, _)| *instruction_pointer)
+			.collect();
+		log.records.push(PageFaultRecord {
+			instruction_pointer: self.instruction_pointer,
+			cr2,
+			error_code,
+			outcome: PageFaultOutcome::Handled,
+			backtrace,
+		});
+	}
+
+	/// Marks the most recent page fault as escalated, as its delivery failed.
+	pub(super) fn escalate_page_fault(&mut self) {
+		if let Some(log) = &mut self.page_faults
+			&& let Some(record) = log.records.last_mut()
+			&& record.outcome == PageFaultOutcome::Handled
+		{
+			record.outcome = PageFaultOutcome::Escalated;
+		}
+	}
+
+	/// Marks the handled page faults of the instruction at `instruction_pointer` as retried.
+	pub(super) fn retire_page_faults(&mut self, instruction_pointer: u64) {
+		let Some(log) = &mut self.page_faults else {
+			return;
+		};
+		for record in log.records.iter_mut() {
+			if record.outcome == PageFaultOutcome::Handled
+				&& record.instruction_pointer == instruction_pointer
+			{
+				record.outcome = PageFaultOutcome::Retried;
+			}
+		}
+	}
+}
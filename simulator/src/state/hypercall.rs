@@ -0,0 +1,39 @@
+use crate::{
+	error::info,
+	instruction::Reg,
+	interupt::Interrupt,
+	state::{A, ProcessorState},
+};
+
+/// Returns the highest hypercall number in eax and the signature in rbx.
+pub const HYPERCALL_PROBE: u32 = 0;
+/// Prints rbx on the host, for bring-up code without a console.
+pub const HYPERCALL_PRINT: u32 = 1;
+
+/// The signature returned by the probe, "x86rs" as a little endian string.
+pub const HYPERCALL_SIGNATURE: u64 = u64::from_le_bytes(*b"x86rs\0\0\0");
+
+const B: Reg = Reg(3);
+
+impl ProcessorState {
+	/// Executes the hypercall selected by eax, as issued by `vmcall`. An unknown hypercall
+	/// raises an invalid opcode exception like `vmcall` outside a virtual machine.
+	pub(super) fn hypercall(&mut self) -> Result<(), Interrupt> {
+		self.require_cpl0()?;
+		match self.read_reg_u32(A) {
+			HYPERCALL_PROBE => {
+				self.write_reg_u32(A, HYPERCALL_PRINT);
+				self.write_reg_u64(B, HYPERCALL_SIGNATURE);
+			}
+			HYPERCALL_PRINT => {
+				let value = self.read_reg_u64(B);
+				info(&format!(
+					"Hypercall at rip 0x{:016X}: rbx = 0x{value:016X}",
+					self.instruction_pointer
+				));
+			}
+			_ => Err(Interrupt::UNDEFINED)?,
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,41 @@
+use crate::{
+	interupt::Interrupt,
+	state::{A, C, D, ProcessorState},
+};
+
+/// Allows rdpmc outside ring 0 when set in cr4.
+pub(super) const CR4_PCE: u64 = 1 << 8;
+
+/// The counter of retired instructions, which are counted since the last reset.
+pub const PMC_INSTRUCTIONS: u32 = 0;
+/// The counter of taken branches, including iret.
+pub const PMC_BRANCHES: u32 = 1;
+/// The counter of raised page faults.
+pub const PMC_PAGE_FAULTS: u32 = 2;
+
+/// Events counted for the guest, which reads them with rdpmc.
+#[derive(Clone, Default)]
+pub(super) struct PerformanceCounters {
+	pub(super) branches: u64,
+	pub(super) page_faults: u64,
+}
+
+impl ProcessorState {
+	/// Reads the counter selected by ecx into edx:eax. Outside ring 0 this needs the PCE bit of
+	/// cr4, and selecting a counter which does not exist raises a general protection fault.
+	/// There is no TLB, so there is no counter of TLB misses.
+	pub(super) fn rdpmc(&mut self) -> Result<(), Interrupt> {
+		if self.cpl > 0 && self.registers.config_registers[3] & CR4_PCE == 0 {
+			Err(Interrupt::GeneralProtection)?;
+		}
+		let value = match self.read_reg_u32(C) {
+			PMC_INSTRUCTIONS => self.retired_count,
+			PMC_BRANCHES => self.counters.branches,
+			PMC_PAGE_FAULTS => self.counters.page_faults,
+			_ => Err(Interrupt::GeneralProtection)?,
+		};
+		self.write_reg_u32(A, value as u32);
+		self.write_reg_u32(D, (value >> 32) as u32);
+		Ok(())
+	}
+}
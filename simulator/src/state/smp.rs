@@ -0,0 +1,66 @@
+use crate::{device::PortDevices, state::ProcessorState};
+
+/// Processors sharing the physical memory, stepped one instruction each in turn on the current
+/// thread. Each processor has its own registers, cr3 and devices. A processor executes a whole
+/// instruction before the next one steps, so every instruction is atomic and the lock prefix
+/// is not needed for it. The interrupt requests of the devices go to the first processor, and
+/// the processors interrupt each other through the x2APIC interrupt command register.
+pub struct Multiprocessor {
+	processors: Vec<ProcessorState>,
+}
+
+impl Multiprocessor {
+	/// Adds processors to the first one until there are `count`. They get the settings and the
+	/// events of the first processor but no devices, and start at its reset vector with tsc_aux set to their
+	/// index, so the guest can tell them apart with rdtscp.
+	pub fn new(first: ProcessorState, count: usize) -> Multiprocessor {
+		let mut processors = vec![first];
+		for index in 1..count {
+			let first = &processors[0];
+			let processor = ProcessorState {
+				instruction_pointer: first.reset_vector,
+				tsc_aux: index as u32,
+				costs: first.costs.clone(),
+				count_cycles: first.count_cycles,
+				max_instructions: first.max_instructions,
+				exception_limit: first.exception_limit,
+				red_zone: first.red_zone,
+				deterministic: first.deterministic,
+				mode: first.mode,
+				reset_vector: first.reset_vector,
+				events: first.events.clone(),
+				..ProcessorState::new(first.memory.share(), PortDevices::new())
+			};
+			processors.push(processor);
+		}
+		let queues: Vec<_> = processors
+			.iter()
+			.map(|processor| processor.ipis.clone())
+			.collect();
+		for processor in &mut processors {
+			processor.processors = queues.clone();
+		}
+		Multiprocessor { processors }
+	}
+
+	pub fn processors(&mut self) -> &mut [ProcessorState] {
+		&mut self.processors
+	}
+
+	/// Steps every processor once in order. When all of them are halted the thread is parked
+	/// until an interrupt is scheduled, unless the devices are clocked by the steps.
+	pub fn step(&mut self) {
+		if self.halted() {
+			// Only the first processor has devices raising irqs.
+			self.processors[0].wait_for_interrupt();
+		}
+		for processor in &mut self.processors {
+			processor.step_instruction();
+		}
+	}
+
+	/// Whether every processor waits in hlt for an interrupt.
+	pub fn halted(&self) -> bool {
+		self.processors.iter().all(ProcessorState::waiting)
+	}
+}
@@ -0,0 +1,256 @@
+use crate::{
+	memory::Pages,
+	state::{ProcessorState, Registers},
+};
+
+/// The start of a snapshot file.
+const MAGIC: &[u8; 8] = b"x86rsnap";
+
+/// The version of the snapshot file format, increased when fields are added.
+const VERSION: u32 = 1;
+
+/// Reads the fields of a snapshot file in order.
+struct Reader<'a> {
+	data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+	fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+		if self.data.len() < length {
+			return Err("The snapshot file is truncated".to_string());
+		}
+		let (bytes, rest) = self.data.split_at(length);
+		self.data = rest;
+		Ok(bytes)
+	}
+
+	fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+		Ok(self.take(N)?.try_into().unwrap())
+	}
+
+	fn u64(&mut self) -> Result<u64, String> {
+		self.array().map(u64::from_le_bytes)
+	}
+
+	fn u64s<const N: usize>(&mut self) -> Result<[u64; N], String> {
+		std::array::try_from_fn(|_| self.u64())
+	}
+}
+
+/// The architectural state of the processor and the contents of memory. A full snapshot holds
+/// every page with data, while a delta only holds the pages written since the previous
+/// snapshot or delta and must be restored on top of it. Devices are not saved.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Snapshot {
+	pub full: bool,
+	primary_registers: [u64; 16],
+	config_registers: Vec<u64>,
+	xmm_registers: [u128; 16],
+	cr3: u64,
+	cpl: i8,
+	instruction_pointer: u64,
+	rflags: u64,
+	fs_base: u64,
+	gs_base: u64,
+	kernel_gs_base: u64,
+	cr0: u64,
+	time_stamp_counter: u64,
+	tsc_aux: u32,
+	xcr0: u64,
+	mxcsr: u32,
+	fpu_control_word: u16,
+	debug_registers: [u64; 8],
+	pub pages: Pages,
+}
+
+impl ProcessorState {
+	/// Takes a full snapshot and starts tracking the pages written for `snapshot_delta`.
+	pub fn snapshot(&mut self) -> Snapshot {
+		let pages = self.memory.snapshot();
+		self.capture(true, pages)
+	}
+
+	/// Takes a full snapshot without restarting the tracking of written pages, so the next
+	/// `snapshot_delta` still holds every page written since the previous snapshot or delta.
+	pub fn snapshot_untracked(&mut self) -> Snapshot {
+		let pages = self.memory.pages();
+		self.capture(true, pages)
+	}
+
+	/// Takes a snapshot of the pages written since the previous snapshot or delta, which is much
+	/// cheaper than a full snapshot of a large guest.
+	pub fn snapshot_delta(&mut self) -> Snapshot {
+		let pages = self.memory.delta();
+		self.capture(false, pages)
+	}
+
+	/// Restores a full snapshot, or a delta on top of the snapshots it was taken after. A
+	/// snapshot with pages outside the memory regions is an error and leaves the state as it
+	/// was.
+	pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+		self.memory.restore(&snapshot.pages, snapshot.full)?;
+		self.memory.swi4(snapshot.cr3);
+		self.registers = Registers {
+			primary_registers: snapshot.primary_registers,
+			config_registers: snapshot.config_registers.clone().try_into().unwrap(),
+			xmm_registers: snapshot.xmm_registers,
+		};
+		self.cpl = snapshot.cpl;
+		self.instruction_pointer = snapshot.instruction_pointer;
+		self.rflags = snapshot.rflags;
+		self.fs_base = snapshot.fs_base;
+		self.gs_base = snapshot.gs_base;
+		self.kernel_gs_base = snapshot.kernel_gs_base;
+		self.cr0 = snapshot.cr0;
+		self.time_stamp_counter = snapshot.time_stamp_counter;
+		self.tsc_aux = snapshot.tsc_aux;
+		self.xcr0 = snapshot.xcr0;
+		self.mxcsr = snapshot.mxcsr;
+		self.fpu_control_word = snapshot.fpu_control_word;
+		self.debug_registers = snapshot.debug_registers;
+		self.debug_trap = false;
+		self.software_interrupt = None;
+		Ok(())
+	}
+
+	fn capture(&self, full: bool, pages: Pages) -> Snapshot {
+		Snapshot {
+			full,
+			primary_registers: self.registers.primary_registers,
+			config_registers: self.registers.config_registers.to_vec(),
+			xmm_registers: self.registers.xmm_registers,
+			cr3: self.memory.paging_table_address(),
+			cpl: self.cpl,
+			instruction_pointer: self.instruction_pointer,
+			rflags: self.rflags,
+			fs_base: self.fs_base,
+			gs_base: self.gs_base,
+			kernel_gs_base: self.kernel_gs_base,
+			cr0: self.cr0,
+			time_stamp_counter: self.time_stamp_counter,
+			tsc_aux: self.tsc_aux,
+			xcr0: self.xcr0,
+			mxcsr: self.mxcsr,
+			fpu_control_word: self.fpu_control_word,
+			debug_registers: self.debug_registers,
+			pages,
+		}
+	}
+}
+
+impl Snapshot {
+	/// Encodes the snapshot for a file. Values are little endian and every page is stored
+	/// with its address and length.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut data = MAGIC.to_vec();
+		data.extend(VERSION.to_le_bytes());
+		data.push(self.full as u8);
+		let words = self
+			.primary_registers
+			.iter()
+			.copied()
+			.chain([self.config_registers.len() as u64])
+			.chain(self.config_registers.iter().copied());
+		for word in words {
+			data.extend(word.to_le_bytes());
+		}
+		for xmm in self.xmm_registers {
+			data.extend(xmm.to_le_bytes());
+		}
+		data.extend(self.cr3.to_le_bytes());
+		data.extend(self.cpl.to_le_bytes());
+		for word in [
+			self.instruction_pointer,
+			self.rflags,
+			self.fs_base,
+			self.gs_base,
+			self.kernel_gs_base,
+			self.cr0,
+			self.time_stamp_counter,
+		] {
+			data.extend(word.to_le_bytes());
+		}
+		data.extend(self.tsc_aux.to_le_bytes());
+		data.extend(self.xcr0.to_le_bytes());
+		data.extend(self.mxcsr.to_le_bytes());
+		data.extend(self.fpu_control_word.to_le_bytes());
+		for word in self.debug_registers {
+			data.extend(word.to_le_bytes());
+		}
+		data.extend((self.pages.len() as u64).to_le_bytes());
+		for (address, page) in &self.pages {
+			data.extend(address.to_le_bytes());
+			data.extend((page.len() as u64).to_le_bytes());
+			data.extend(page);
+		}
+		data
+	}
+
+	/// Decodes a snapshot written by `to_bytes`.
+	pub fn from_bytes(data: &[u8]) -> Result<Snapshot, String> {
+		let mut reader = Reader { data };
+		if reader.take(MAGIC.len()) != Ok(MAGIC) {
+			return Err("Not a snapshot file".to_string());
+		}
+		let version = u32::from_le_bytes(reader.array()?);
+		if version != VERSION {
+			return Err(format!("Unsupported snapshot version {version}"));
+		}
+		let full = reader.array::<1>()?[0] != 0;
+		let primary_registers = reader.u64s()?;
+		let config_registers = match reader.u64()? {
+			256 => reader.u64s::<256>()?.to_vec(),
+			count => return Err(format!("Expected 256 config registers, found {count}")),
+		};
+		let xmm_registers = std::array::try_from_fn(|_| reader.array().map(u128::from_le_bytes))?;
+		let cr3 = reader.u64()?;
+		let cpl = i8::from_le_bytes(reader.array()?);
+		let [
+			instruction_pointer,
+			rflags,
+			fs_base,
+			gs_base,
+			kernel_gs_base,
+			cr0,
+			time_stamp_counter,
+		] = reader.u64s()?;
+		let tsc_aux = u32::from_le_bytes(reader.array()?);
+		let xcr0 = reader.u64()?;
+		let mxcsr = u32::from_le_bytes(reader.array()?);
+		let fpu_control_word = u16::from_le_bytes(reader.array()?);
+		let debug_registers = reader.u64s()?;
+		let mut pages = Pages::new();
+		for _ in 0..reader.u64()? {
+			let address = reader.u64()?;
+			let length = reader.u64()?;
+			if length > 1 << 12 {
+				return Err(format!("The page at 0x{address:X} is longer than a page"));
+			}
+			pages.insert(address, reader.take(length as usize)?.to_vec());
+		}
+		if !reader.data.is_empty() {
+			return Err("The snapshot file has trailing data".to_string());
+		}
+		Ok(Snapshot {
+			full,
+			primary_registers,
+			config_registers,
+			xmm_registers,
+			cr3,
+			cpl,
+			instruction_pointer,
+			rflags,
+			fs_base,
+			gs_base,
+			kernel_gs_base,
+			cr0,
+			time_stamp_counter,
+			tsc_aux,
+			xcr0,
+			mxcsr,
+			fpu_control_word,
+			debug_registers,
+			pages,
+		})
+	}
+}
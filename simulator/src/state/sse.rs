@@ -0,0 +1,113 @@
+use crate::{
+	instruction::{RM, Reg},
+	interupt::Interrupt,
+	state::{AF, CF, OF, PF, ProcessorState, SF, ZF},
+};
+
+/// Applies `operation` to each `bits` wide lane of the operands. Results are truncated to the
+/// lane width.
+fn lanewise(left: u128, right: u128, bits: u32, operation: impl Fn(u64, u64) -> u64) -> u128 {
+	let mask = u64::MAX >> (64 - bits);
+	(0..128 / bits).fold(0, |result, lane| {
+		let shift = lane * bits;
+		let left = (left >> shift) as u64 & mask;
+		let right = (right >> shift) as u64 & mask;
+		result | ((operation(left, right) & mask) as u128) << shift
+	})
+}
+
+/// The sign bit of each byte lane.
+pub(super) fn move_mask(value: u128) -> u32 {
+	(0..16).fold(0, |mask, lane| {
+		mask | ((value >> (8 * lane + 7)) as u32 & 1) << lane
+	})
+}
+
+/// Truncates a double towards zero. Values out of range, including NaN, are converted to the
+/// integer indefinite value, which is the minimum integer.
+pub(super) fn truncate_i32(value: f64) -> i32 {
+	let value = value.trunc();
+	if value >= i32::MIN as f64 && value <= i32::MAX as f64 {
+		value as i32
+	} else {
+		i32::MIN
+	}
+}
+
+/// 64 bit variant of [`truncate_i32`]. Note that `i64::MAX` is not representable, so the bound
+/// is exclusive.
+pub(super) fn truncate_i64(value: f64) -> i64 {
+	let value = value.trunc();
+	if value >= i64::MIN as f64 && value < i64::MAX as f64 {
+		value as i64
+	} else {
+		i64::MIN
+	}
+}
+
+impl ProcessorState {
+	/// Applies a lane wise `operation` to an xmm register and an xmm or memory operand, and
+	/// writes the result to the register.
+	pub(super) fn packed(
+		&mut self,
+		destination: Reg,
+		source: RM,
+		bits: u32,
+		operation: impl Fn(u64, u64) -> u64,
+	) -> Result<(), Interrupt> {
+		let right = self.read_xmm_rm(source, true)?;
+		let register = &mut self.registers.xmm_registers[destination.0 as usize];
+		*register = lanewise(*register, right, bits, operation);
+		Ok(())
+	}
+
+	/// Reads the low double of an xmm register or a 64 bit memory operand.
+	pub(super) fn read_scalar_double(&mut self, rm: RM) -> Result<f64, Interrupt> {
+		match rm {
+			RM::Reg(reg) => Ok(f64::from_bits(
+				self.registers.xmm_registers[reg as usize] as u64,
+			)),
+			_ => Ok(f64::from_bits(self.read_rm_u64(rm)?)),
+		}
+	}
+
+	/// Writes the low double of an xmm register and keeps the upper half.
+	pub(super) fn write_scalar_double(&mut self, Reg(reg): Reg, value: f64) {
+		let register = &mut self.registers.xmm_registers[reg as usize];
+		*register = *register & !(u64::MAX as u128) | value.to_bits() as u128;
+	}
+
+	/// Applies `operation` to the low doubles of an xmm register and an xmm or memory operand,
+	/// and writes the result to the low double of the register.
+	pub(super) fn scalar_double(
+		&mut self,
+		destination: Reg,
+		source: RM,
+		operation: impl Fn(f64, f64) -> f64,
+	) -> Result<(), Interrupt> {
+		let right = self.read_scalar_double(source)?;
+		let left = f64::from_bits(self.registers.xmm_registers[destination.0 as usize] as u64);
+		self.write_scalar_double(destination, operation(left, right));
+		Ok(())
+	}
+
+	/// Compares the low doubles like ucomisd. Unordered operands set ZF, PF and CF, otherwise
+	/// ZF and CF are set like an unsigned comparison.
+	pub(super) fn compare_double(&mut self, left: Reg, right: RM) -> Result<(), Interrupt> {
+		let right = self.read_scalar_double(right)?;
+		let left = f64::from_bits(self.registers.xmm_registers[left.0 as usize] as u64);
+		let (zf, pf, cf) = match left.partial_cmp(&right) {
+			None => (true, true, true),
+			Some(std::cmp::Ordering::Less) => (false, false, true),
+			Some(std::cmp::Ordering::Equal) => (true, false, false),
+			Some(std::cmp::Ordering::Greater) => (false, false, false),
+		};
+		self.set_flag(ZF, zf);
+		self.set_flag(PF, pf);
+		self.set_flag(CF, cf);
+		self.set_flag(OF, false);
+		self.set_flag(SF, false);
+		self.set_flag(AF, false);
+		Ok(())
+	}
+}
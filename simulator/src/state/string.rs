@@ -0,0 +1,181 @@
+use crate::{
+	instruction::Instruction,
+	interupt::Interrupt,
+	state::{A, C, D, DF, DI, ProcessorState, SI, ZF, alu::AluOperation},
+};
+
+macro_rules! compare_string {
+	($size:ident) => {
+		/// Compares [rsi] with [rdi], setting the flags like `cmp`.
+		pub(super) fn ${concat(cmps_, $size)}(&mut self) -> Result<(), Interrupt> {
+			let size = size_of::<$size>() as u64;
+			let source = self.read_reg_u64(SI);
+			let destination = self.read_reg_u64(DI);
+			self.watch(source, size, false);
+			let left = self.memory.${concat(read_, $size)}(source)?;
+			self.watch(destination, size, false);
+			let right = self.memory.${concat(read_, $size)}(destination)?;
+			self.${concat(alu_, $size)}(AluOperation::Cmp, left, right);
+			let step = self.string_step(size);
+			self.write_reg_u64(SI, source.wrapping_add(step));
+			self.write_reg_u64(DI, destination.wrapping_add(step));
+			Ok(())
+		}
+
+		/// Compares the accumulator with [rdi], setting the flags like `cmp`.
+		pub(super) fn ${concat(scas_, $size)}(&mut self) -> Result<(), Interrupt> {
+			let size = size_of::<$size>() as u64;
+			let address = self.read_reg_u64(DI);
+			self.watch(address, size, false);
+			let right = self.memory.${concat(read_, $size)}(address)?;
+			let left = self.${concat(read_reg_, $size)}(A);
+			self.${concat(alu_, $size)}(AluOperation::Cmp, left, right);
+			self.write_reg_u64(DI, address.wrapping_add(self.string_step(size)));
+			Ok(())
+		}
+	};
+}
+
+impl ProcessorState {
+	/// The amount rsi and rdi advance by, which is negative when DF is set.
+	fn string_step(&self, size: u64) -> u64 {
+		if self.rflags & DF != 0 {
+			size.wrapping_neg()
+		} else {
+			size
+		}
+	}
+
+	/// Executes a string operation once, or rcx times with a rep prefix. rcx is decremented
+	/// after each iteration, so a fault leaves the remaining count for the restarted
+	/// instruction.
+	pub(super) fn repeat(
+		&mut self,
+		rep: bool,
+		operation: fn(&mut ProcessorState) -> Result<(), Interrupt>,
+	) -> Result<(), Interrupt> {
+		if !rep {
+			return operation(self);
+		}
+		while self.read_reg_u64(C) != 0 {
+			operation(self)?;
+			let count = self.read_reg_u64(C);
+			self.write_reg_u64(C, count - 1);
+		}
+		Ok(())
+	}
+
+	/// Executes a compare string operation once, or with a repe or repne prefix until rcx is
+	/// zero or the comparison ends it. repe stops after the first pair which differs and repne
+	/// after the first which is equal, so ZF tells which condition ended it. The flags are those
+	/// of the last comparison, and are unchanged when rcx starts at zero.
+	pub(super) fn repeat_compare(
+		&mut self,
+		instruction: Instruction,
+		operation: fn(&mut ProcessorState) -> Result<(), Interrupt>,
+	) -> Result<(), Interrupt> {
+		let while_equal = match instruction {
+			Instruction::RepeCmps8 {}
+			| Instruction::RepeCmps16 {}
+			| Instruction::RepeCmps32 {}
+			| Instruction::RepeCmps64 {}
+			| Instruction::RepeScas8 {}
+			| Instruction::RepeScas16 {}
+			| Instruction::RepeScas32 {}
+			| Instruction::RepeScas64 {} => true,
+			Instruction::RepneCmps8 {}
+			| Instruction::RepneCmps16 {}
+			| Instruction::RepneCmps32 {}
+			| Instruction::RepneCmps64 {}
+			| Instruction::RepneScas8 {}
+			| Instruction::RepneScas16 {}
+			| Instruction::RepneScas32 {}
+			| Instruction::RepneScas64 {} => false,
+			_ => return operation(self),
+		};
+		while self.read_reg_u64(C) != 0 {
+			operation(self)?;
+			let count = self.read_reg_u64(C);
+			self.write_reg_u64(C, count - 1);
+			if (self.rflags & ZF != 0) != while_equal {
+				break;
+			}
+		}
+		Ok(())
+	}
+
+	compare_string!(u8);
+	compare_string!(u16);
+	compare_string!(u32);
+	compare_string!(u64);
+
+	/// Reads a byte from the port in dx to [rdi].
+	pub(super) fn ins_u8(&mut self) -> Result<(), Interrupt> {
+		let address = self.read_reg_u64(DI);
+		// The port is only read once the write cannot fault, so no input is lost.
+		self.memory.can_access(address, 1, true)?;
+		let port = self.read_reg_u16(D);
+		let value = self.devices.in_u8(port);
+		self.watch(address, 1, true);
+		self.memory.write_u8(address, value)?;
+		self.write_reg_u64(DI, address.wrapping_add(self.string_step(1)));
+		Ok(())
+	}
+
+	/// Reads a word from the ports starting at dx to [rdi].
+	pub(super) fn ins_u16(&mut self) -> Result<(), Interrupt> {
+		let address = self.read_reg_u64(DI);
+		self.memory.can_access(address, 2, true)?;
+		let port = self.read_reg_u16(D);
+		let value = self.devices.in_u16(port);
+		self.watch(address, 2, true);
+		self.memory.write_u16(address, value)?;
+		self.write_reg_u64(DI, address.wrapping_add(self.string_step(2)));
+		Ok(())
+	}
+
+	/// Reads a double word from the ports starting at dx to [rdi].
+	pub(super) fn ins_u32(&mut self) -> Result<(), Interrupt> {
+		let address = self.read_reg_u64(DI);
+		self.memory.can_access(address, 4, true)?;
+		let port = self.read_reg_u16(D);
+		let value = self.devices.in_u32(port);
+		self.watch(address, 4, true);
+		self.memory.write_u32(address, value)?;
+		self.write_reg_u64(DI, address.wrapping_add(self.string_step(4)));
+		Ok(())
+	}
+
+	/// Writes a byte from [rsi] to the port in dx.
+	pub(super) fn outs_u8(&mut self) -> Result<(), Interrupt> {
+		let address = self.read_reg_u64(SI);
+		self.watch(address, 1, false);
+		let value = self.memory.read_u8(address)?;
+		let port = self.read_reg_u16(D);
+		self.devices.out_u8(port, value);
+		self.write_reg_u64(SI, address.wrapping_add(self.string_step(1)));
+		Ok(())
+	}
+
+	/// Writes a word from [rsi] to the ports starting at dx.
+	pub(super) fn outs_u16(&mut self) -> Result<(), Interrupt> {
+		let address = self.read_reg_u64(SI);
+		self.watch(address, 2, false);
+		let value = self.memory.read_u16(address)?;
+		let port = self.read_reg_u16(D);
+		self.devices.out_u16(port, value);
+		self.write_reg_u64(SI, address.wrapping_add(self.string_step(2)));
+		Ok(())
+	}
+
+	/// Writes a double word from [rsi] to the ports starting at dx.
+	pub(super) fn outs_u32(&mut self) -> Result<(), Interrupt> {
+		let address = self.read_reg_u64(SI);
+		self.watch(address, 4, false);
+		let value = self.memory.read_u32(address)?;
+		let port = self.read_reg_u16(D);
+		self.devices.out_u32(port, value);
+		self.write_reg_u64(SI, address.wrapping_add(self.string_step(4)));
+		Ok(())
+	}
+}
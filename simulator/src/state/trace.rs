@@ -0,0 +1,96 @@
+use serde::Serializer;
+
+use crate::{
+	instruction::Instruction,
+	interupt::Interrupt,
+	memory::MemoryAccess,
+	state::{ProcessorState, REGISTER_NAMES},
+};
+
+const XMM_NAMES: [&str; 16] = [
+	"xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7", "xmm8", "xmm9", "xmm10",
+	"xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
+];
+
+/// A register whose value was changed by a step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct RegisterWrite {
+	pub register: &'static str,
+	pub old: u128,
+	pub new: u128,
+}
+
+/// Everything observable that happened in one step, for comparing execution against a
+/// reference.
+#[derive(Clone, Debug, serde::Serialize)]
+pub struct Step {
+	/// The instruction pointer the step started at.
+	pub instruction_pointer: u64,
+
+	/// The decoded instruction, which is missing if decoding faulted or an external interrupt
+	/// was delivered instead. It is serialized in NASM syntax.
+	#[serde(serialize_with = "serialize_instruction")]
+	pub instruction: Option<Instruction>,
+
+	/// The length of the decoded instruction in bytes.
+	pub size: u64,
+
+	/// The registers that changed, including rip and rflags.
+	pub registers: Vec<RegisterWrite>,
+
+	/// The data accesses in the order they completed, including those of interrupt delivery.
+	pub memory: Vec<MemoryAccess>,
+
+	/// The interrupt raised by the step.
+	pub interrupt: Option<Interrupt>,
+}
+
+fn serialize_instruction<S: Serializer>(
+	instruction: &Option<Instruction>,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	match instruction {
+		Some(instruction) => serializer.serialize_some(&instruction.to_string()),
+		None => serializer.serialize_none(),
+	}
+}
+
+impl ProcessorState {
+	/// Steps one instruction like `step_instruction` and records what it changed.
+	pub fn step(&mut self) -> Step {
+		let instruction_pointer = self.instruction_pointer;
+		let before = self.register_values();
+		self.memory.start_trace();
+		let (decoded, interrupt) = self.execute();
+		let memory = self.memory.take_trace();
+		let registers = before
+			.into_iter()
+			.zip(self.register_values())
+			.filter(|((_, old), (_, new))| old != new)
+			.map(|((register, old), (_, new))| RegisterWrite { register, old, new })
+			.collect();
+		Step {
+			instruction_pointer,
+			instruction: decoded.map(|(instruction, _)| instruction),
+			size: decoded.map_or(0, |(_, size)| size),
+			registers,
+			memory,
+			interrupt,
+		}
+	}
+
+	/// The registers compared by `step` with their names.
+	pub(super) fn register_values(&self) -> Vec<(&'static str, u128)> {
+		let primary = REGISTER_NAMES
+			.into_iter()
+			.zip(self.registers.primary_registers.map(u128::from));
+		let xmm = XMM_NAMES.into_iter().zip(self.registers.xmm_registers);
+		primary
+			.chain(xmm)
+			.chain([
+				("rip", self.instruction_pointer as u128),
+				("rflags", self.rflags as u128),
+			])
+			.collect()
+	}
+}
@@ -0,0 +1,66 @@
+use crate::{
+	error::{fatal, info},
+	state::ProcessorState,
+};
+
+/// What the watchdog does when the guest stops making progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum WatchdogAction {
+	/// Log a warning and keep running.
+	Warn,
+	/// Print the processor state and the last retired instructions, and terminate.
+	Stop,
+}
+
+/// Detects a guest spinning on an instruction which branches to itself, like `jmp $`.
+pub(super) struct Watchdog {
+	threshold: u64,
+	action: WatchdogAction,
+	/// The number of consecutive steps which changed no register.
+	pub(super) idle_steps: u64,
+}
+
+impl ProcessorState {
+	/// Reacts when `threshold` consecutive steps change neither rip nor any other register. An
+	/// instruction writing memory without moving rip also changes a register, like `rep stosb`
+	/// counting down rcx, so memory does not have to be compared. A halted processor does not
+	/// step, so idling in hlt never triggers it.
+	pub fn set_watchdog(&mut self, threshold: u64, action: WatchdogAction) {
+		self.watchdog = Some(Watchdog {
+			threshold,
+			action,
+			idle_steps: 0,
+		});
+	}
+
+	/// Steps one instruction and updates the watchdog.
+	pub(super) fn watched_step(&mut self) {
+		let before = self.register_values();
+		self.execute();
+		// Waiting in hlt for an interrupt is not spinning.
+		let progress = self.halted || before != self.register_values();
+		let Some(watchdog) = &mut self.watchdog else {
+			return;
+		};
+		if progress {
+			watchdog.idle_steps = 0;
+			return;
+		}
+		watchdog.idle_steps += 1;
+		if watchdog.idle_steps != watchdog.threshold {
+			return;
+		}
+		let message = format!(
+			"No progress at 0x{:016X} for {} steps",
+			self.instruction_pointer, watchdog.threshold
+		);
+		match watchdog.action {
+			WatchdogAction::Warn => info(&message),
+			WatchdogAction::Stop => {
+				self.end_events("watchdog");
+				self.report();
+				fatal(&message)
+			}
+		}
+	}
+}